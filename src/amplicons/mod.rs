@@ -1,5 +1,7 @@
 use crate::amplicons::preprocess::{ReadsClassifier, ReadsWithPairedPrimers};
+use crate::amplicons::umi::{UmiEnds, UmiOption, UmiSummary, dedup_by_umi};
 use crate::primer_barcode::{BARCODES, Barcode, Primer};
+use crate::split::ChimeraSplitter;
 use crate::utils::{
     check_and_create_dir, init_log, quit_with_error, run_abpoa, run_minimap2_and_index,
 };
@@ -14,6 +16,7 @@ use std::path::Path;
 
 mod _consensus;
 pub mod preprocess;
+pub mod umi;
 
 struct FileNameSuffix {
     clean: &'static str,
@@ -82,9 +85,9 @@ fn amplicon_with_known_primers(
         .collect::<Vec<_>>();
 
     check_and_create_dir(output_dir);
-    primer_reads
+    let umi_summaries = primer_reads
         .into_par_iter()
-        .for_each(|(primer_name, mut reads_with_primer)| {
+        .map(|(primer_name, mut reads_with_primer)| {
             let total_reads_with_paired_primers = reads_with_primer.reads.len();
             let this_primer_fastq = format!(
                 "{output_dir}/{primer_name}{}",
@@ -132,7 +135,44 @@ fn amplicon_with_known_primers(
                 reads_with_primer.reads.len()
             );
             run_abpoa(&this_primer_fastq, output_dir, &primer_name, abpoa);
+
+            // the downsampled reads are copies of the same molecules, so all good reads are used
+            classifier.umi_option.as_ref().map(|umi_option| {
+                let good_reads = reads_with_primer
+                    .reads
+                    .iter()
+                    .chain(reads_with_primer.redundant_reads.iter())
+                    .map(|(idx, read)| (*idx, read))
+                    .collect::<HashMap<_, _>>();
+                let summary = dedup_by_umi(
+                    &primer_name,
+                    &good_reads,
+                    &classifier.umis,
+                    umi_option,
+                    output_dir,
+                    abpoa,
+                );
+                info!(
+                    "{primer_name}: {} good reads with UMI, {} unique molecules, {} consensus saved into {output_dir}/{primer_name}.umi_consensus.fasta",
+                    summary.reads, summary.molecules, summary.consensus
+                );
+                summary
+            })
         })
+        .collect::<Vec<_>>();
+
+    if classifier.umi_option.is_some() {
+        let mut umi_summaries = umi_summaries.into_iter().flatten().collect::<Vec<_>>();
+        umi_summaries.sort_by(|a, b| a.amplicon.cmp(&b.amplicon));
+        let summary_file = format!("{output_dir}/{}.umi_summary.tsv", classifier.analysis_name);
+        let mut contents = format!("{}\n", UmiSummary::header());
+        for summary in umi_summaries {
+            contents.push_str(&format!("{summary}\n"));
+        }
+        std::fs::write(&summary_file, contents)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to write {summary_file}")));
+        info!("UMI family sizes and unique molecules of each amplicon saved into {summary_file}");
+    }
 }
 
 fn amplicon_with_unknown_primers(
@@ -373,7 +413,7 @@ fn get_reads_name_from_bam(bam_file: &str, min_mapq: u8) -> HashSet<String> {
     read_names
 }
 
-fn parse_primers_from_cli(primers: &str, analysis_name: &str) -> HashMap<String, Primer> {
+pub fn parse_primers_from_cli(primers: &str, analysis_name: &str) -> HashMap<String, Primer> {
     let pattern =
         r"^([A-Za-z0-9_-]+,[ATCGatcg]+,[ATCGatcg]+)(?:;([A-Za-z0-9_-]+,[ATCGatcg]+,[ATCGatcg]+))*$";
     let pattern = regex::Regex::new(pattern).unwrap();
@@ -397,6 +437,7 @@ pub fn run_amplicons(amp_cmd: &ArgMatches) {
     let downsample = amp_cmd.get_one::<usize>("downsample").unwrap();
     let min_qual = amp_cmd.get_one::<f64>("min_qual").unwrap();
     let save_failed = amp_cmd.get_one::<bool>("retain_failed").unwrap();
+    let split_chimeras = amp_cmd.get_flag("split_chimeras");
     let len_range = amp_cmd.get_one::<f64>("len_range").unwrap();
     let amp_numbers = amp_cmd.get_one::<usize>("number").unwrap();
     let lead_length = amp_cmd.get_one::<usize>("lead").unwrap();
@@ -408,12 +449,22 @@ pub fn run_amplicons(amp_cmd: &ArgMatches) {
     let samtools = amp_cmd.get_one::<String>("samtools").map(|x| x.as_ref());
     let analysis_name = amp_cmd.get_one::<String>("prefix").unwrap();
     let thread = amp_cmd.get_one::<u16>("thread").unwrap();
+    let umi_option = amp_cmd.get_one::<u64>("umi_len").map(|len| UmiOption {
+        len: *len as usize,
+        offset: *amp_cmd.get_one::<usize>("umi_offset").unwrap(),
+        ends: UmiEnds::from_arg(amp_cmd.get_one::<String>("umi_ends").unwrap()),
+        max_distance: *amp_cmd.get_one::<u32>("umi_distance").unwrap(),
+        min_family_size: *amp_cmd.get_one::<usize>("min_family_size").unwrap(),
+    });
 
     let primers_is_set = amp_cmd.value_source("primers") == Some(ValueSource::CommandLine);
     let amplicon_number_is_set = amp_cmd.value_source("number") == Some(ValueSource::CommandLine);
     if primers_is_set && amplicon_number_is_set {
         quit_with_error("--primers and number couldn't be specified together")
     }
+    if umi_option.is_some() && primers_opt.is_none() {
+        quit_with_error("--umi_len needs the known primers by --primers")
+    }
     init_log();
     {
         eprintln!("Args...........");
@@ -429,6 +480,8 @@ pub fn run_amplicons(amp_cmd: &ArgMatches) {
         eprintln!("\t--min_qual\t{min_qual}");
         eprintln!("\t--len_range\t{len_range}");
         eprintln!("\t--retain_failed\t{save_failed}");
+        eprintln!("\t--split_chimeras\t{split_chimeras}");
+        eprintln!("\t--umi\t{umi_option:?}");
         eprintln!("\t--lead\t{lead_length}");
         eprintln!("\t--prefix\t{analysis_name}");
         eprintln!("\t--detect_rev_primer_reads\t{detect_rev_primer_reads_number}");
@@ -456,7 +509,20 @@ pub fn run_amplicons(amp_cmd: &ArgMatches) {
         *right,
         *distance,
     );
-    let all_reads = reads_collector.collect_fastqs(*thread as usize);
+    let mut all_reads = reads_collector.collect_fastqs(*thread as usize);
+    if split_chimeras {
+        // the primers near the read ends are searched by ReadsClassifier in --left/--right bases
+        let min_len = *left.min(right);
+        let splitter = match primers_opt {
+            Some(primers) => ChimeraSplitter::from_primers(
+                &parse_primers_from_cli(primers, analysis_name),
+                *distance,
+                min_len,
+            ),
+            None => ChimeraSplitter::from_adapter(*distance, min_len),
+        };
+        all_reads = preprocess::split_chimeric_reads(all_reads, &splitter);
+    }
     let classifier = ReadsClassifier::new(
         all_reads,
         *lead_length,
@@ -464,6 +530,7 @@ pub fn run_amplicons(amp_cmd: &ArgMatches) {
         *right,
         *distance,
         analysis_name.to_string(),
+        umi_option,
     );
     check_and_create_dir(output);
     classifier.save_clean_fastq(&format!(
@@ -595,6 +662,40 @@ pub fn amplicons_cmd() -> Command {
             .long("retain_failed")
             .action(ArgAction::SetTrue)
             .help("whether to save reads with paired primers but failing quality/length filters")
+    ).arg(
+        Arg::new("split_chimeras")
+            .long("split_chimeras")
+            .action(ArgAction::SetTrue)
+            .help("split the concatemers at the internal primers (or ligation adapters in unknown primers mode) before classifying reads, like nanofq split")
+    ).arg(
+        Arg::new("umi_len")
+            .long("umi_len")
+            .value_parser(value_parser!(u64).range(1..=64))
+            .help("[known primers mode]: length of the UMI next to each primer, the reads of each amplicon are grouped by UMI into families, and one consensus is built for each family. not set means no UMI")
+    ).arg(
+        Arg::new("umi_offset")
+            .long("umi_offset")
+            .default_value("0")
+            .value_parser(value_parser!(usize))
+            .help("[known primers mode]: number of bases between the UMI and the primer, e.g., a fixed spacer")
+    ).arg(
+        Arg::new("umi_ends")
+            .long("umi_ends")
+            .default_value("both")
+            .value_parser(["both", "fwd", "rev"])
+            .help("[known primers mode]: the UMI is next to both primers, only the forward primer or only the reverse primer. the reads without the whole UMI are not deduplicated")
+    ).arg(
+        Arg::new("umi_distance")
+            .long("umi_distance")
+            .default_value("2")
+            .value_parser(value_parser!(u32))
+            .help("[known primers mode]: max edit distance between the UMIs of one family")
+    ).arg(
+        Arg::new("min_family_size")
+            .long("min_family_size")
+            .default_value("3")
+            .value_parser(value_parser!(usize))
+            .help("[known primers mode]: min reads of a UMI family to build its consensus, the smaller families are only counted")
     ).arg(
         Arg::new("lead")
             .long("lead")
@@ -1,5 +1,7 @@
+use crate::amplicons::umi::UmiOption;
 use crate::fastq::{FastqRecord, read_fastq};
 use crate::primer_barcode::{Barcode, PO, Primer, get_myers_from_primers};
+use crate::split::ChimeraSplitter;
 use ahash::{HashMap, HashSet, RandomState};
 use bio::alignment::pairwise::Aligner;
 use bio::alphabets::dna::revcomp;
@@ -118,6 +120,39 @@ impl<'a> ReadsCollector<'a> {
         all_reads
     }
 }
+/// replace the chimeric reads by their segments, reads are renumbered in the input order
+pub fn split_chimeric_reads(
+    all_reads: HashMap<usize, FastqRecord>,
+    splitter: &ChimeraSplitter,
+) -> HashMap<usize, FastqRecord> {
+    let mut reads = all_reads.into_iter().collect::<Vec<_>>();
+    reads.sort_by_key(|x| x.0);
+    let all_points = reads
+        .par_iter()
+        .map_init(
+            || splitter.clone(),
+            |splitter, (_, read)| splitter.find_split_points(&read.seq),
+        )
+        .collect::<Vec<_>>();
+    let mut split_reads = HashMap::with_capacity_and_hasher(reads.len(), RandomState::new());
+    let mut chimeric_reads = 0;
+    for ((_, read), points) in reads.into_iter().zip(all_points) {
+        if points.is_empty() {
+            split_reads.insert(split_reads.len(), read);
+        } else {
+            chimeric_reads += 1;
+            for segment in splitter.split(&read, &points) {
+                split_reads.insert(split_reads.len(), segment);
+            }
+        }
+    }
+    info!(
+        "{chimeric_reads} chimeric reads split, {} reads after splitting",
+        split_reads.len()
+    );
+    split_reads
+}
+
 pub struct DetectedPrimer {
     fwd_primer: Vec<u8>,
     pub fwd_primer_reads: Vec<usize>,
@@ -145,6 +180,9 @@ pub struct ReadsClassifier {
     right_range: usize, // 100
     max_distance: u8,
     pub analysis_name: String,
+    pub umi_option: Option<UmiOption>,
+    // UMI of each read with paired primers, only with umi_option
+    pub umis: HashMap<usize, Vec<u8>>,
 }
 
 impl ReadsClassifier {
@@ -155,6 +193,7 @@ impl ReadsClassifier {
         right_range: usize,
         max_distance: u8,
         analysis_name: String,
+        umi_option: Option<UmiOption>,
     ) -> Self {
         ReadsClassifier {
             all_reads,
@@ -163,6 +202,8 @@ impl ReadsClassifier {
             right_range,
             max_distance,
             analysis_name,
+            umi_option,
+            umis: HashMap::with_hasher(RandomState::new()),
         }
     }
 
@@ -413,10 +454,17 @@ impl ReadsClassifier {
             .min_by_key(|x| x.len())
             .unwrap()
             .len();
+        let mut umis = HashMap::with_hasher(RandomState::new());
         for (idx, read) in self.all_reads.iter_mut() {
             if (read.len() as usize) < lead_length {
                 continue;
             }
+            // the UMIs are outside the primers, so they are taken from the read before trimming
+            let raw_len = read.seq.len();
+            let raw = self.umi_option.map(|_| read.seq.clone());
+            let umi_of = |start: usize, len: usize, reversed: bool| {
+                self.umi_option?.extract(raw.as_ref()?, start, start + len, reversed)
+            };
             let read_lead_seq = &read.seq[..lead_length];
             match primer_seq2name.get(read_lead_seq) {
                 Some((po, primer_name)) => {
@@ -428,6 +476,9 @@ impl ReadsClassifier {
                             self.right_range,
                             self.max_distance,
                         ) {
+                            if let Some(umi) = umi_of(0, read.seq.len(), false) {
+                                umis.insert(*idx, umi);
+                            }
                             match primer_name2reads.get_mut(*primer_name) {
                                 None => {
                                     primer_name2reads.insert(primer_name.to_string(), vec![*idx]);
@@ -443,6 +494,9 @@ impl ReadsClassifier {
                             self.right_range,
                             self.max_distance,
                         ) {
+                            if let Some(umi) = umi_of(0, read.seq.len(), true) {
+                                umis.insert(*idx, umi);
+                            }
                             read.reversed();
                             match primer_name2reads.get_mut(*primer_name) {
                                 None => {
@@ -468,11 +522,15 @@ impl ReadsClassifier {
                             self.left_range,
                             self.max_distance,
                         ) {
+                            let start = raw_len - read.seq.len();
                             if read.truncate_at_rev_primer_start(
                                 rev_rc_primer_pat,
                                 self.right_range,
                                 self.max_distance,
                             ) {
+                                if let Some(umi) = umi_of(start, read.seq.len(), false) {
+                                    umis.insert(*idx, umi);
+                                }
                                 match primer_name2reads.get_mut(primer_name) {
                                     None => {
                                         primer_name2reads
@@ -489,11 +547,15 @@ impl ReadsClassifier {
                             self.left_range,
                             self.max_distance,
                         ) {
+                            let start = raw_len - read.seq.len();
                             if read.truncate_at_rev_primer_start(
                                 fwd_rc_primer_pat,
                                 self.right_range,
                                 self.max_distance,
                             ) {
+                                if let Some(umi) = umi_of(start, read.seq.len(), true) {
+                                    umis.insert(*idx, umi);
+                                }
                                 read.reversed();
                                 match primer_name2reads.get_mut(primer_name) {
                                     None => {
@@ -509,6 +571,7 @@ impl ReadsClassifier {
                 }
             }
         }
+        self.umis.extend(umis);
         primer_name2reads
     }
 
@@ -0,0 +1,383 @@
+use crate::fastq::FastqRecord;
+use crate::utils::{check_and_create_dir, quit_with_error, run_abpoa};
+use ahash::{HashMap, RandomState};
+use bio::alignment::distance::levenshtein;
+use bio::alphabets::dna::revcomp;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// which primer sides of the amplicon carry a UMI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UmiEnds {
+    Both,
+    Fwd,
+    Rev,
+}
+
+impl UmiEnds {
+    pub fn from_arg(arg: &str) -> Self {
+        match arg {
+            "both" => UmiEnds::Both,
+            "fwd" => UmiEnds::Fwd,
+            "rev" => UmiEnds::Rev,
+            _ => quit_with_error(&format!(
+                "Unknown --umi_ends {arg}, should be both, fwd or rev"
+            )),
+        }
+    }
+}
+
+///
+/// UMIs of len bases at offset bases outside the primers, e.g., UMI-spacer-FwdPrimer-insert-
+/// RevPrimerRc-spacer-UMIRc. The reads in the same UMI family are copies of one molecule
+///
+#[derive(Debug, Clone, Copy)]
+pub struct UmiOption {
+    pub len: usize,
+    pub offset: usize,
+    pub ends: UmiEnds,
+    pub max_distance: u32,
+    pub min_family_size: usize,
+}
+
+impl UmiOption {
+    ///
+    /// UMI of the read whose primers span raw[start..end] before trimming, the UMI of the rev
+    /// primer side follows the one of the fwd primer side. Each is read 5'->3' towards its own
+    /// primer, so the reads of both strands of a molecule get the same UMI. None if any UMI is
+    /// outside the read
+    ///
+    pub fn extract(&self, raw: &[u8], start: usize, end: usize, reversed: bool) -> Option<Vec<u8>> {
+        let before = start
+            .checked_sub(self.offset + self.len)
+            .map(|x| &raw[x..x + self.len]);
+        let after = raw.get(end + self.offset..end + self.offset + self.len);
+        // a reversed read starts with the rev primer
+        let (fwd_side, rev_side) = if reversed {
+            (after.map(revcomp), before.map(|x| x.to_vec()))
+        } else {
+            (before.map(|x| x.to_vec()), after.map(revcomp))
+        };
+        match self.ends {
+            UmiEnds::Both => Some([fwd_side?, rev_side?].concat()),
+            UmiEnds::Fwd => fwd_side,
+            UmiEnds::Rev => rev_side,
+        }
+    }
+}
+
+/// the reads of one molecule, umi is the most abundant UMI of them
+pub struct UmiFamily {
+    pub umi: Vec<u8>,
+    pub reads_idx: Vec<usize>,
+    pub distinct_umis: usize,
+}
+
+///
+/// Pigeonhole index of the family UMIs. Each UMI is cut into max_distance + 1 segments, a UMI
+/// within max_distance edits of it contains at least one of the segments exactly, shifted by at
+/// most max_distance bases, so only the families sharing a segment are compared
+///
+struct UmiIndex {
+    max_distance: usize,
+    lengths: BTreeSet<usize>,
+    // (UMI length, segment index, segment) -> families
+    segments: HashMap<(usize, usize, Vec<u8>), Vec<usize>>,
+}
+
+impl UmiIndex {
+    fn new(max_distance: u32) -> Self {
+        UmiIndex {
+            max_distance: max_distance as usize,
+            lengths: BTreeSet::new(),
+            segments: HashMap::with_hasher(RandomState::new()),
+        }
+    }
+
+    fn segment_range(&self, umi_len: usize, segment_idx: usize) -> (usize, usize) {
+        let parts = self.max_distance + 1;
+        (
+            umi_len * segment_idx / parts,
+            umi_len * (segment_idx + 1) / parts,
+        )
+    }
+
+    fn insert(&mut self, umi: &[u8], family_idx: usize) {
+        self.lengths.insert(umi.len());
+        for segment_idx in 0..=self.max_distance {
+            let (start, end) = self.segment_range(umi.len(), segment_idx);
+            self.segments
+                .entry((umi.len(), segment_idx, umi[start..end].to_vec()))
+                .or_default()
+                .push(family_idx);
+        }
+    }
+
+    /// sorted families that may be within max_distance edits of umi
+    fn candidates(&self, umi: &[u8]) -> Vec<usize> {
+        let d = self.max_distance;
+        let mut candidates = vec![];
+        for &len in self
+            .lengths
+            .range(umi.len().saturating_sub(d)..=umi.len() + d)
+        {
+            for segment_idx in 0..=d {
+                let (start, end) = self.segment_range(len, segment_idx);
+                for shifted in start.saturating_sub(d)..=start + d {
+                    let Some(segment) = umi.get(shifted..shifted + end - start) else {
+                        break;
+                    };
+                    if let Some(families) = self.segments.get(&(len, segment_idx, segment.to_vec()))
+                    {
+                        candidates.extend(families);
+                    }
+                }
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+///
+/// Group reads by UMI with edit distance tolerance. The distinct UMIs are visited from the most
+/// abundant one, each joins the first family whose UMI is within max_distance edits, otherwise it
+/// starts a new family. The candidate families are found by UmiIndex instead of comparing with
+/// all families. The families are sorted by size
+///
+pub fn cluster_umis(umis: &HashMap<usize, Vec<u8>>, max_distance: u32) -> Vec<UmiFamily> {
+    let mut umi2reads: HashMap<&[u8], Vec<usize>> = HashMap::with_hasher(RandomState::new());
+    for (idx, umi) in umis {
+        umi2reads.entry(umi.as_slice()).or_default().push(*idx);
+    }
+    let mut distinct_umis = umi2reads.into_iter().collect::<Vec<_>>();
+    distinct_umis.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+    let mut families: Vec<UmiFamily> = vec![];
+    let mut index = UmiIndex::new(max_distance);
+    for (umi, reads_idx) in distinct_umis {
+        let family_idx = index
+            .candidates(umi)
+            .into_iter()
+            .find(|x| levenshtein(&families[*x].umi, umi) <= max_distance);
+        match family_idx {
+            Some(family_idx) => {
+                let family = &mut families[family_idx];
+                family.reads_idx.extend(reads_idx);
+                family.distinct_umis += 1;
+            }
+            None => {
+                index.insert(umi, families.len());
+                families.push(UmiFamily {
+                    umi: umi.to_vec(),
+                    reads_idx,
+                    distinct_umis: 1,
+                })
+            }
+        }
+    }
+    families.iter_mut().for_each(|x| x.reads_idx.sort());
+    families.sort_by_key(|x| Reverse(x.reads_idx.len()));
+    families
+}
+
+/// one line of the umi summary tsv
+pub struct UmiSummary {
+    pub amplicon: String,
+    pub reads: usize,
+    pub reads_without_umi: usize,
+    pub molecules: usize,
+    pub consensus: usize,
+    pub median_family_size: f64,
+}
+
+impl UmiSummary {
+    pub fn header() -> &'static str {
+        "Amplicon\tReadsWithUmi\tReadsWithoutUmi\tUniqueMolecules\tConsensusMolecules\tMedianFamilySize"
+    }
+}
+
+impl std::fmt::Display for UmiSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{:.1}",
+            self.amplicon,
+            self.reads,
+            self.reads_without_umi,
+            self.molecules,
+            self.consensus,
+            self.median_family_size
+        )
+    }
+}
+
+///
+/// Cluster the UMIs of the good reads of one amplicon and build one consensus by abpoa for each
+/// family with at least min_family_size reads. Outputs:
+///     {output_dir}/{amplicon}.umi_families.tsv: UMI, family size, distinct UMIs and consensus
+///     {output_dir}/{amplicon}.umi_consensus.fasta: consensus of each family
+///     {output_dir}/{amplicon}.umi_families.fastq: reads of these families, tagged by uf:Z:{family}
+/// The abpoa inputs and outputs of each family are removed once its consensus is read
+///
+pub fn dedup_by_umi(
+    amplicon: &str,
+    reads: &HashMap<usize, &FastqRecord>,
+    umis: &HashMap<usize, Vec<u8>>,
+    umi_option: &UmiOption,
+    output_dir: &str,
+    abpoa: Option<&str>,
+) -> UmiSummary {
+    let umis = umis
+        .iter()
+        .filter(|(idx, _)| reads.contains_key(idx))
+        .map(|(idx, umi)| (*idx, umi.clone()))
+        .collect::<HashMap<_, _>>();
+    let families = cluster_umis(&umis, umi_option.max_distance);
+    let work_dir = format!("{output_dir}/{amplicon}.umi_families.tmp");
+    check_and_create_dir(&work_dir);
+    let families_fastq = format!("{output_dir}/{amplicon}.umi_families.fastq");
+    let mut reads_writer =
+        BufWriter::new(File::create(&families_fastq).unwrap_or_else(|x| {
+            quit_with_error(&format!("{x}, Failed to create {families_fastq}"))
+        }));
+    for (family_idx, family) in families.iter().enumerate() {
+        if family.reads_idx.len() < umi_option.min_family_size {
+            continue;
+        }
+        let tag = format!("uf:Z:{amplicon}_umi{}", family_idx + 1);
+        for read_idx in &family.reads_idx {
+            reads[read_idx]
+                .write_with_tag(&mut reads_writer, Some(&tag))
+                .unwrap();
+        }
+    }
+    reads_writer.flush().unwrap();
+    drop(reads_writer);
+
+    let consensus = families
+        .par_iter()
+        .enumerate()
+        .map(|(family_idx, family)| {
+            if family.reads_idx.len() < umi_option.min_family_size {
+                return None;
+            }
+            let family_name = format!("{amplicon}_umi{}", family_idx + 1);
+            let family_fastq = format!("{work_dir}/{family_name}.fastq");
+            let mut writer = BufWriter::new(File::create(&family_fastq).unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, Failed to create {family_fastq}"))
+            }));
+            for read_idx in &family.reads_idx {
+                reads[read_idx].write(&mut writer).unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+            let fasta = run_abpoa(&family_fastq, &work_dir, &family_name, abpoa);
+            let contents = std::fs::read_to_string(&fasta)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {fasta}")));
+            for file in [family_fastq, fasta, format!("{work_dir}/{family_name}.log")] {
+                let _ = std::fs::remove_file(file);
+            }
+            contents.lines().nth(1).map(|x| x.to_string())
+        })
+        .collect::<Vec<_>>();
+    let _ = std::fs::remove_dir(&work_dir);
+
+    let families_tsv = format!("{output_dir}/{amplicon}.umi_families.tsv");
+    let mut writer = BufWriter::new(
+        File::create(&families_tsv)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {families_tsv}"))),
+    );
+    writeln!(
+        writer,
+        "Family\tUMI\tFamilySize\tDistinctUMIs\tConsensusLength"
+    )
+    .unwrap();
+    let consensus_fasta = format!("{output_dir}/{amplicon}.umi_consensus.fasta");
+    let mut fasta_writer =
+        BufWriter::new(File::create(&consensus_fasta).unwrap_or_else(|x| {
+            quit_with_error(&format!("{x}, Failed to create {consensus_fasta}"))
+        }));
+    for (family_idx, (family, seq)) in families.iter().zip(&consensus).enumerate() {
+        let family_name = format!("{amplicon}_umi{}", family_idx + 1);
+        let umi = std::str::from_utf8(&family.umi).unwrap();
+        writeln!(
+            writer,
+            "{family_name}\t{umi}\t{}\t{}\t{}",
+            family.reads_idx.len(),
+            family.distinct_umis,
+            seq.as_ref()
+                .map_or("NA".to_string(), |x| x.len().to_string())
+        )
+        .unwrap();
+        if let Some(seq) = seq {
+            writeln!(
+                fasta_writer,
+                ">{family_name} umi={umi} size={}\n{seq}",
+                family.reads_idx.len()
+            )
+            .unwrap();
+        }
+    }
+
+    let mut sizes = families
+        .iter()
+        .map(|x| x.reads_idx.len())
+        .collect::<Vec<_>>();
+    sizes.sort();
+    let median_family_size = match sizes.len() {
+        0 => 0.0,
+        n if n % 2 == 0 => (sizes[n / 2 - 1] + sizes[n / 2]) as f64 / 2.0,
+        n => sizes[n / 2] as f64,
+    };
+    UmiSummary {
+        amplicon: amplicon.to_string(),
+        reads: umis.len(),
+        reads_without_umi: reads.len() - umis.len(),
+        molecules: families.len(),
+        consensus: consensus.iter().filter(|x| x.is_some()).count(),
+        median_family_size,
+    }
+}
+
+#[cfg(test)]
+mod umi_test {
+    use super::*;
+
+    #[test]
+    fn cluster() {
+        let umis = [
+            (0, b"ACGTACGTACGT".to_vec()),
+            (1, b"ACGTACGTACGT".to_vec()),
+            (2, b"ACGTACGTACGT".to_vec()),
+            // one mismatch
+            (3, b"ACGTACCTACGT".to_vec()),
+            // one deletion and one insertion
+            (4, b"ACGACGTACGTT".to_vec()),
+            // one deletion, shorter UMI
+            (5, b"CGTACGTACGT".to_vec()),
+            (6, b"TTGGCCAATTGG".to_vec()),
+            (7, b"TTGGCCAATTGG".to_vec()),
+            // three edits to both families
+            (8, b"TTGGCCAAACGT".to_vec()),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        let families = cluster_umis(&umis, 2);
+        assert_eq!(families.len(), 3);
+        assert_eq!(families[0].umi, b"ACGTACGTACGT");
+        assert_eq!(families[0].reads_idx, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(families[0].distinct_umis, 4);
+        assert_eq!(families[1].umi, b"TTGGCCAATTGG");
+        assert_eq!(families[1].reads_idx, vec![6, 7]);
+        assert_eq!(families[2].reads_idx, vec![8]);
+
+        // the same families as comparing with all families
+        let families = cluster_umis(&umis, 0);
+        assert_eq!(families.len(), 6);
+        assert_eq!(families[0].reads_idx, vec![0, 1, 2]);
+    }
+}
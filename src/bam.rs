@@ -1,11 +1,16 @@
-use crate::fastq::RecordEachStats;
-use crate::utils::{calculate_quality, quit_with_error, };
+use crate::calibration::QualityCalibration;
+use crate::duplex::DuplexClass;
+use crate::fastq::{RecordEachStats, SeqFeatures};
+use crate::modification::ModificationStats;
+use crate::profile::PositionProfile;
+use crate::utils::{QscoreSource, calculate_quality, quit_with_error, };
 use rayon::prelude::*;
 use rust_htslib::bam::index;
-use rust_htslib::bam::record::{Aux, Cigar};
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
 use rust_htslib::bam::{self, FetchDefinition, HeaderView, IndexedReader, Read};
+use serde_json::{Value, json};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
 use std::ops::AddAssign;
 use std::path::Path;
@@ -41,7 +46,13 @@ pub fn get_encoded_bases_gc_count_table() -> &'static HashMap<u8, usize> {
 pub trait BamRecordStats {
     fn gc_count(&self) -> f32;
     fn calculate_read_quality(&self, use_dorado_q: bool) -> f32;
-    fn stats(&self, gc: bool, use_dorado_q: bool) -> RecordEachStats;
+    fn stats(
+        &self,
+        features: SeqFeatures,
+        use_dorado_q: bool,
+        qscore_source: QscoreSource,
+    ) -> RecordEachStats;
+    fn add_to_profile(&self, profile: &mut PositionProfile);
 }
 impl BamRecordStats for rust_htslib::bam::Record {
     fn gc_count(&self) -> f32 {
@@ -59,16 +70,35 @@ impl BamRecordStats for rust_htslib::bam::Record {
         calculate_quality(self.qual(), use_dorado_q, true)
     }
 
-    fn stats(&self, gc: bool, use_dorado_q: bool) -> RecordEachStats {
+    fn stats(
+        &self,
+        features: SeqFeatures,
+        use_dorado_q: bool,
+        qscore_source: QscoreSource,
+    ) -> RecordEachStats {
         let len = self.qual().len();
         let read_quality = self.calculate_read_quality(use_dorado_q);
-        let gc = if gc { Some(self.gc_count()) } else { None };
-        RecordEachStats::new(
+        let gc = if features.gc {
+            Some(self.gc_count())
+        } else {
+            None
+        };
+        let mut each_stats = RecordEachStats::new(
             str::from_utf8(self.qname()).unwrap().to_string(),
             len,
             read_quality,
             gc,
-        )
+        );
+        if features.complexity || features.poly_tail {
+            each_stats.set_seq_features(&self.seq().as_bytes(), features);
+        }
+        each_stats.duplex = DuplexClass::from_bam_record(self);
+        each_stats.set_reported_qual(get_qs_aux(self), qscore_source);
+        each_stats
+    }
+
+    fn add_to_profile(&self, profile: &mut PositionProfile) {
+        profile.add_read(&self.seq().as_bytes(), self.qual(), 0, self.is_reverse())
     }
 }
 
@@ -84,8 +114,14 @@ pub struct BasicBamStatistics {
     bases_mapped: usize,            // primary_alignment seq_len()
     bases_mapped_cigar: usize, //  Match + Ins of (primary_alignment and supplementary_alignment), more accurate
     mismatches: usize,         // NM tag
+    insertions: usize,         // Ins of (primary_alignment and supplementary_alignment)
+    deletions: usize,          // Del of (primary_alignment and supplementary_alignment)
     error_rate: f64,           // mismatches / bases_mapped_cigar
     map_rate: f64,             // reads_mapped / (reads_mapped + reads_unmapped)
+    mapq_counts: Vec<usize>,   // primary alignments number of each map quality
+    contigs: Vec<ContigStats>, // index is tid
+    mods: ModificationStats,   // only when MM/ML tags are parsed
+    calibration: QualityCalibration, // base quality calibration of primary alignments
 }
 impl BasicBamStatistics {
     pub fn is_empty(&self) -> bool {
@@ -101,9 +137,131 @@ impl BasicBamStatistics {
             && self.bases_mapped_cigar == 0
             && self.mismatches == 0
     }
+
+    /// make one empty ContigStats for each contig in bam header
+    pub fn init_contigs(&mut self, header: &HeaderView) {
+        self.contigs = (0..header.target_count())
+            .map(|tid| {
+                ContigStats::new(
+                    String::from_utf8_lossy(header.tid2name(tid)).to_string(),
+                    header.target_len(tid).unwrap_or(0),
+                )
+            })
+            .collect();
+    }
+
+    pub fn contigs(&self) -> &[ContigStats] {
+        &self.contigs
+    }
+
+    pub fn mods(&self) -> &ModificationStats {
+        &self.mods
+    }
+
+    pub fn calibration(&self) -> &QualityCalibration {
+        &self.calibration
+    }
+
+    pub fn has_mapped_reads(&self) -> bool {
+        self.reads_mapped > 0
+    }
+
+    /// None for fastq input and bam without reference sequences in header
+    pub fn map_rate(&self) -> Option<f64> {
+        if self.is_empty() || self.contigs.is_empty() {
+            None
+        } else {
+            Some(self.map_rate)
+        }
+    }
+
+    fn add_mapq(&mut self, mapq: u8) {
+        let mapq = mapq as usize;
+        if self.mapq_counts.len() <= mapq {
+            self.mapq_counts.resize(mapq + 1, 0);
+        }
+        self.mapq_counts[mapq] += 1;
+    }
+
+    fn add_alignment(&mut self, nm: usize, cigar_counts: &CigarCounts) {
+        self.mismatches += nm;
+        self.insertions += cigar_counts.insertions;
+        self.deletions += cigar_counts.deletions;
+        self.bases_mapped_cigar += cigar_counts.matches + cigar_counts.insertions;
+    }
+
+    /// substitutions, insertions and deletions, substitutions is NM minus Ins and Del
+    pub fn error_breakdown(&self) -> [(&'static str, usize); 3] {
+        [
+            (
+                "Substitutions",
+                self.mismatches
+                    .saturating_sub(self.insertions + self.deletions),
+            ),
+            ("Insertions", self.insertions),
+            ("Deletions", self.deletions),
+        ]
+    }
+
+    /// primary alignments number of each map quality, index is map quality
+    pub fn mapq_counts(&self) -> &[usize] {
+        &self.mapq_counts
+    }
+
+    pub fn bases_mapped_cigar(&self) -> usize {
+        self.bases_mapped_cigar
+    }
+
+    pub fn to_json(&self) -> Value {
+        let [substitutions, insertions, deletions] = self.error_breakdown();
+        json!({
+            "ReadsMapped": self.reads_mapped,
+            "ReadsUnmapped": self.reads_unmapped,
+            "ReadsMapQ0": self.reads_mq0,
+            "PrimaryAlignment": self.primary_alignment,
+            "SupplementaryAlignment": self.supplementary_alignment,
+            "SecondaryAlignment": self.secondary_alignment,
+            "BasesMapped": self.bases_mapped,
+            "BasesMappedCigar": self.bases_mapped_cigar,
+            "Mismatches": self.mismatches,
+            "Substitutions": substitutions.1,
+            "Insertions": insertions.1,
+            "Deletions": deletions.1,
+            "ErrorRate": self.error_rate,
+            "MapRate": self.map_rate,
+            "MapQCounts": self.mapq_counts,
+            "Contigs": self.contigs.iter().map(|x| x.to_json()).collect::<Vec<_>>(),
+            "Modifications": self.mods.to_json(),
+            "QualityCalibration": self.calibration.to_json(),
+        })
+    }
+
+    pub fn dict_basic_info(&self) -> Vec<(&'static str, String)> {
+        let mut items = vec![
+            ("ReadsMapped", self.reads_mapped.to_string()),
+            ("ReadsUnmapped", self.reads_unmapped.to_string()),
+            ("MapRate", format!("{:.4}", self.map_rate)),
+            ("ReadsMapQ0", self.reads_mq0.to_string()),
+            ("PrimaryAlignment", self.primary_alignment.to_string()),
+            (
+                "SupplementaryAlignment",
+                self.supplementary_alignment.to_string(),
+            ),
+            ("SecondaryAlignment", self.secondary_alignment.to_string()),
+            ("BasesMapped", self.bases_mapped.to_string()),
+            ("BasesMappedCigar", self.bases_mapped_cigar.to_string()),
+            ("Mismatches", self.mismatches.to_string()),
+            ("ErrorRate", format!("{:.6}", self.error_rate)),
+        ];
+        for (class, count) in self.error_breakdown() {
+            items.push((class, count.to_string()));
+        }
+        items
+    }
 }
 impl Display for BasicBamStatistics {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [substitutions, insertions, deletions] = self.error_breakdown();
         write!(
             f,
             "#BamStatsSummary\n\
@@ -116,6 +274,9 @@ impl Display for BasicBamStatistics {
             BasesMapped\t{}\t# primary alignments seq_len, ignore clipping\n\
             BasesMappedCigar\t{}\t# Match + Ins of (primary alignments and supplementary alignments), more accurate\n\
             Mismatches\t{}\t# NM tag\n\
+            Substitutions\t{}\t# Mismatches - Insertions - Deletions\n\
+            Insertions\t{}\t# Ins of (primary alignments and supplementary alignments)\n\
+            Deletions\t{}\t# Del of (primary alignments and supplementary alignments)\n\
             ErrorRate\t{:.6}\t# Mismatches / BasesMappedCigar\n\
             MapRate\t{:.6}\t# ReadsMapped / (ReadsMapped + ReadsUnmapped)\n",
             self.reads_mapped,
@@ -127,9 +288,25 @@ impl Display for BasicBamStatistics {
             self.bases_mapped,
             self.bases_mapped_cigar,
             self.mismatches,
+            substitutions.1,
+            insertions.1,
+            deletions.1,
             self.error_rate,
             self.map_rate
-        )
+        )?;
+        if self.has_mapped_reads() {
+            writeln!(
+                f,
+                "#ContigStats\nContig\tLength\tReads\tBases\tMeanDepth\tBreadth\tMeanIdentity\tMQ0Fraction"
+            )?;
+            for contig in self.contigs.iter() {
+                writeln!(f, "{contig}")?;
+            }
+        }
+        if !self.mods.is_empty() {
+            write!(f, "{}", self.mods)?;
+        }
+        Ok(())
     }
 }
 
@@ -144,10 +321,188 @@ impl AddAssign for BasicBamStatistics {
         self.bases_mapped += rhs.bases_mapped;
         self.bases_mapped_cigar += rhs.bases_mapped_cigar;
         self.mismatches += rhs.mismatches;
+        self.insertions += rhs.insertions;
+        self.deletions += rhs.deletions;
+        if self.mapq_counts.len() < rhs.mapq_counts.len() {
+            self.mapq_counts.resize(rhs.mapq_counts.len(), 0);
+        }
+        for (mapq, count) in rhs.mapq_counts.into_iter().enumerate() {
+            self.mapq_counts[mapq] += count;
+        }
+        self.mods += rhs.mods;
+        self.calibration += rhs.calibration;
+        if self.contigs.is_empty() {
+            self.contigs = rhs.contigs;
+        } else {
+            for (contig, rhs_contig) in self.contigs.iter_mut().zip(rhs.contigs) {
+                *contig += rhs_contig;
+            }
+        }
         self.error_rate = self.mismatches as f64 / self.bases_mapped_cigar as f64;
         self.map_rate = self.reads_mapped as f64 / (self.reads_mapped + self.reads_unmapped) as f64;
     }
 }
+///
+/// Mapping statistics of one contig. Reads, bases, identity and MQ0 are counted from primary
+/// alignments, depth and breadth are counted from both primary and supplementary alignments
+///
+#[derive(Default, Debug, Clone)]
+pub struct ContigStats {
+    name: String,
+    length: u64,
+    reads: usize,         // primary alignments number
+    bases: usize,         // primary alignments seq_len
+    aligned_bases: usize, // Match of primary and supplementary alignments, used for depth
+    identity_sum: f64,    // sum of identity of primary alignments
+    reads_mq0: usize,     // primary alignments with map quality == 0
+    // disjoint reference intervals covered by alignments, start -> end of [start, end), they
+    // are merged when added so that unsorted bam doesn't keep one interval per alignment
+    covered: BTreeMap<i64, i64>,
+    covered_len: i64, // total length of covered
+}
+
+impl ContigStats {
+    fn new(name: String, length: u64) -> Self {
+        ContigStats {
+            name,
+            length,
+            ..Default::default()
+        }
+    }
+
+    fn add_primary(&mut self, seq_len: usize, identity: f32, mapq: u8) {
+        self.reads += 1;
+        self.bases += seq_len;
+        self.identity_sum += identity as f64;
+        if mapq == 0 {
+            self.reads_mq0 += 1;
+        }
+    }
+
+    fn add_covered(&mut self, start: i64, cigar_counts: &CigarCounts) {
+        self.aligned_bases += cigar_counts.matches;
+        let end = start + cigar_counts.reference_span() as i64;
+        self.add_interval(start, end);
+    }
+
+    /// merge [start, end) with the overlapping or adjacent intervals in covered
+    fn add_interval(&mut self, mut start: i64, mut end: i64) {
+        if start >= end {
+            return;
+        }
+        if let Some((&prev_start, &prev_end)) = self.covered.range(..=start).next_back() {
+            if prev_end >= end {
+                return;
+            }
+            if prev_end >= start {
+                start = prev_start;
+            }
+        }
+        while let Some((&next_start, &next_end)) = self.covered.range(start..).next() {
+            if next_start > end {
+                break;
+            }
+            end = end.max(next_end);
+            self.covered_len -= next_end - next_start;
+            self.covered.remove(&next_start);
+        }
+        self.covered_len += end - start;
+        self.covered.insert(start, end);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn bases(&self) -> usize {
+        self.bases
+    }
+
+    pub fn mean_depth(&self) -> f64 {
+        if self.length == 0 {
+            0.0
+        } else {
+            self.aligned_bases as f64 / self.length as f64
+        }
+    }
+
+    /// fraction of contig covered by at least one alignment
+    pub fn breadth(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.covered_len as f64 / self.length as f64
+    }
+
+    pub fn mean_identity(&self) -> f64 {
+        if self.reads == 0 {
+            0.0
+        } else {
+            self.identity_sum / self.reads as f64
+        }
+    }
+
+    pub fn mq0_fraction(&self) -> f64 {
+        if self.reads == 0 {
+            0.0
+        } else {
+            self.reads_mq0 as f64 / self.reads as f64
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "Contig": self.name,
+            "Length": self.length,
+            "Reads": self.reads,
+            "Bases": self.bases,
+            "MeanDepth": self.mean_depth(),
+            "Breadth": self.breadth(),
+            "MeanIdentity": self.mean_identity(),
+            "MQ0Fraction": self.mq0_fraction(),
+        })
+    }
+}
+
+impl Display for ContigStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{:.4}\t{:.6}\t{:.6}\t{:.6}",
+            self.name,
+            self.length,
+            self.reads,
+            self.bases,
+            self.mean_depth(),
+            self.breadth(),
+            self.mean_identity(),
+            self.mq0_fraction()
+        )
+    }
+}
+
+impl AddAssign for ContigStats {
+    fn add_assign(&mut self, rhs: Self) {
+        debug_assert_eq!(self.name, rhs.name);
+        self.reads += rhs.reads;
+        self.bases += rhs.bases;
+        self.aligned_bases += rhs.aligned_bases;
+        self.identity_sum += rhs.identity_sum;
+        self.reads_mq0 += rhs.reads_mq0;
+        for (start, end) in rhs.covered {
+            self.add_interval(start, end);
+        }
+    }
+}
+
 fn get_nm_aux(record: &bam::Record) -> usize {
     let nm_tag = record.aux(b"NM").unwrap_or(Aux::I32(0));
     match nm_tag {
@@ -168,13 +523,218 @@ fn get_nm_aux(record: &bam::Record) -> usize {
     }
 }
 
-fn get_cigar_base_length(cigar: &Cigar) -> usize {
-    match cigar {
-        Cigar::Match(m) | Cigar::Equal(m) | Cigar::Diff(m) => *m as usize,
-        Cigar::Ins(i) => *i as usize,
-        _ => 0usize,
+/// read quality reported by basecaller, dorado writes qs:f in newer versions and qs:i in older ones
+pub fn get_qs_aux(record: &bam::Record) -> Option<f32> {
+    match record.aux(b"qs") {
+        Ok(Aux::Float(qs)) => Some(qs),
+        Ok(Aux::Double(qs)) => Some(qs as f32),
+        Ok(Aux::I8(qs)) => Some(qs as f32),
+        Ok(Aux::U8(qs)) => Some(qs as f32),
+        Ok(Aux::I16(qs)) => Some(qs as f32),
+        Ok(Aux::U16(qs)) => Some(qs as f32),
+        Ok(Aux::I32(qs)) => Some(qs as f32),
+        Ok(Aux::U32(qs)) => Some(qs as f32),
+        _ => None,
     }
 }
+
+/// (identity, aligned fraction) of a mapped record, computed from NM and cigar as in stats
+pub fn identity_and_aligned_fraction(record: &bam::Record) -> (f32, f32) {
+    let cigar_counts = CigarCounts::from_record(record);
+    (
+        cigar_counts.identity(get_nm_aux(record)),
+        cigar_counts.aligned_fraction(),
+    )
+}
+
+fn with_len(op: Cigar, len: u32) -> Cigar {
+    match op {
+        Cigar::Match(_) => Cigar::Match(len),
+        Cigar::Ins(_) => Cigar::Ins(len),
+        Cigar::Del(_) => Cigar::Del(len),
+        Cigar::RefSkip(_) => Cigar::RefSkip(len),
+        Cigar::SoftClip(_) => Cigar::SoftClip(len),
+        Cigar::HardClip(_) => Cigar::HardClip(len),
+        Cigar::Pad(_) => Cigar::Pad(len),
+        Cigar::Equal(_) => Cigar::Equal(len),
+        Cigar::Diff(_) => Cigar::Diff(len),
+    }
+}
+
+/// remove n read bases from the start of cigar, they are merged into the hard clip. The
+/// deletions and insertions before the first aligned base left are dropped or soft clipped.
+/// Returns the new cigar, the reference bases skipped and the bases removed from SEQ
+fn clip_cigar_start(cigar: &[Cigar], n: u32) -> (Vec<Cigar>, i64, usize) {
+    if n == 0 {
+        return (cigar.to_vec(), 0, 0);
+    }
+    let mut clipped: Vec<Cigar> = vec![];
+    let (mut remaining, mut hard, mut ref_skipped, mut seq_removed) = (n, 0, 0i64, 0usize);
+    let mut aligned = false;
+    for op in cigar {
+        match *op {
+            Cigar::HardClip(len) if clipped.is_empty() => {
+                remaining -= len.min(remaining);
+                hard += len;
+            }
+            Cigar::Match(len)
+            | Cigar::Equal(len)
+            | Cigar::Diff(len)
+            | Cigar::Ins(len)
+            | Cigar::SoftClip(len) => {
+                let removed = len.min(remaining);
+                remaining -= removed;
+                hard += removed;
+                seq_removed += removed as usize;
+                let is_match = matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+                if is_match {
+                    ref_skipped += removed as i64;
+                }
+                if removed == len {
+                    continue;
+                }
+                let op = if aligned || is_match {
+                    with_len(*op, len - removed)
+                } else {
+                    Cigar::SoftClip(len - removed)
+                };
+                aligned |= is_match;
+                match (clipped.last_mut(), op) {
+                    (Some(Cigar::SoftClip(last)), Cigar::SoftClip(len)) => *last += len,
+                    _ => clipped.push(op),
+                }
+            }
+            Cigar::Del(len) | Cigar::RefSkip(len) if !aligned => ref_skipped += len as i64,
+            Cigar::Pad(_) if !aligned => {}
+            _ => clipped.push(*op),
+        }
+    }
+    if hard > 0 {
+        clipped.insert(0, Cigar::HardClip(hard));
+    }
+    (clipped, ref_skipped, seq_removed)
+}
+
+///
+/// Remove head bases from the start and tail bases from the end of the read as it was sequenced.
+/// The removed bases become hard clips and the position moves to the first aligned base left.
+/// MD, NM, MM, ML and MN no longer match the read and are removed. Returns false if the record
+/// was aligned and no aligned base is left, then it becomes an unmapped record at the same position.
+///
+pub fn clip_read(record: &mut bam::Record, head: usize, tail: usize) -> bool {
+    let (left, right) = if record.is_reverse() {
+        (tail, head)
+    } else {
+        (head, tail)
+    };
+    let seq_len = record.seq_len();
+    let cigar = record.cigar().iter().copied().collect::<Vec<_>>();
+    let (cigar, ref_skipped, seq_left, seq_right) = if cigar.is_empty() {
+        let seq_left = left.min(seq_len);
+        (cigar, 0, seq_left, right.min(seq_len - seq_left))
+    } else {
+        let (mut cigar, ref_skipped, seq_left) = clip_cigar_start(&cigar, left as u32);
+        cigar.reverse();
+        let (mut cigar, _, seq_right) = clip_cigar_start(&cigar, right as u32);
+        cigar.reverse();
+        (cigar, ref_skipped, seq_left, seq_right)
+    };
+    let start = seq_left.min(seq_len);
+    let end = seq_len.saturating_sub(seq_right).max(start);
+    let seq = record.seq().as_bytes()[start..end].to_vec();
+    let qual = record.qual()[start..end].to_vec();
+    let qname = record.qname().to_vec();
+    let is_aligned = cigar
+        .iter()
+        .any(|x| matches!(x, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_)));
+    let keeps_alignment = record.is_unmapped() || is_aligned;
+    if record.is_unmapped() || !is_aligned {
+        record.set(&qname, None, &seq, &qual);
+        record.set_flags(record.flags() | 4);
+        record.set_mapq(0);
+    } else {
+        record.set(&qname, Some(&CigarString(cigar)), &seq, &qual);
+        record.set_pos(record.pos() + ref_skipped);
+    }
+    for tag in [b"MD", b"NM", b"MM", b"ML", b"MN"] {
+        let _ = record.remove_aux(tag);
+    }
+    keeps_alignment
+}
+
+/// bases number of each kind of cigar operation for one alignment
+#[derive(Default, Debug)]
+struct CigarCounts {
+    matches: usize, // Match + Equal + Diff
+    insertions: usize,
+    deletions: usize,
+    insertion_events: usize,
+    deletion_events: usize,
+    skipped: usize, // RefSkip
+    clipped: usize, // SoftClip + HardClip
+}
+
+impl CigarCounts {
+    fn from_record(record: &bam::Record) -> Self {
+        let mut counts = CigarCounts::default();
+        for cigar in record.cigar().iter() {
+            match cigar {
+                Cigar::Match(m) | Cigar::Equal(m) | Cigar::Diff(m) => counts.matches += *m as usize,
+                Cigar::Ins(i) => {
+                    counts.insertions += *i as usize;
+                    counts.insertion_events += 1;
+                }
+                Cigar::Del(d) => {
+                    counts.deletions += *d as usize;
+                    counts.deletion_events += 1;
+                }
+                Cigar::RefSkip(n) => counts.skipped += *n as usize,
+                Cigar::SoftClip(c) | Cigar::HardClip(c) => counts.clipped += *c as usize,
+                _ => {}
+            }
+        }
+        counts
+    }
+
+    /// reference bases consumed by this alignment
+    fn reference_span(&self) -> usize {
+        self.matches + self.deletions + self.skipped
+    }
+
+    /// 1 - NM / (Match + Ins + Del)
+    fn identity(&self, nm: usize) -> f32 {
+        let columns = self.matches + self.insertions + self.deletions;
+        if columns == 0 {
+            0.0
+        } else {
+            1.0 - (nm.min(columns) as f32 / columns as f32)
+        }
+    }
+
+    /// indels of any length are counted as one difference,
+    /// 1 - (mismatches + Ins events + Del events) / (Match + Ins events + Del events)
+    fn gap_compressed_identity(&self, nm: usize) -> f32 {
+        let mismatches = nm.saturating_sub(self.insertions + self.deletions);
+        let columns = self.matches + self.insertion_events + self.deletion_events;
+        if columns == 0 {
+            0.0
+        } else {
+            let differences = mismatches + self.insertion_events + self.deletion_events;
+            1.0 - (differences.min(columns) as f32 / columns as f32)
+        }
+    }
+
+    /// (Match + Ins) / read length including clipped bases
+    fn aligned_fraction(&self) -> f32 {
+        let aligned = self.matches + self.insertions;
+        if aligned == 0 {
+            0.0
+        } else {
+            aligned as f32 / (aligned + self.clipped) as f32
+        }
+    }
+}
+
 fn bins_contig(contig_len: u64, bins_number: usize) -> Vec<(u64, u64)> {
     let mut edges = vec![];
     let step = contig_len / bins_number as u64;
@@ -278,15 +838,20 @@ pub fn check_bam_type(bam_file: &str) -> BamType {
 
 fn stats_from_bam_reader<R>(
     bam_reader: &mut R,
-    gc: bool,
+    features: SeqFeatures,
     use_dorado_q: bool,
+    qscore_source: QscoreSource,
+    mods: bool,
+    profile_depth: usize,
     region_start: i64,
-) -> (BasicBamStatistics, Vec<RecordEachStats>)
+) -> (BasicBamStatistics, Vec<RecordEachStats>, PositionProfile)
 where
     R: bam::Read,
 {
     let mut basic_bam_stats = BasicBamStatistics::default();
+    basic_bam_stats.init_contigs(bam_reader.header());
     let mut all_stats = vec![];
+    let mut profile = PositionProfile::new(profile_depth);
     let mut record = bam::Record::new();
     record.set_qname(b"InitBamRecord");
     while let Some(x) = bam_reader.read(&mut record) {
@@ -299,32 +864,51 @@ where
 
         if record.is_unmapped() {
             basic_bam_stats.reads_unmapped += 1;
-            all_stats.push(record.stats(gc, use_dorado_q));
+            let mut each_stats = record.stats(features, use_dorado_q, qscore_source);
+            if mods {
+                each_stats.mods = Some(basic_bam_stats.mods.add_record(&record));
+            }
+            all_stats.push(each_stats);
+            record.add_to_profile(&mut profile);
         } else {
             if record.pos() < region_start {
                 continue;
             }
             if record.flags() & 0x900 == 0 {
-                all_stats.push(record.stats(gc, use_dorado_q));
+                let nm = get_nm_aux(&record);
+                let cigar_counts = CigarCounts::from_record(&record);
+                let mut each_stats = record.stats(features, use_dorado_q, qscore_source);
+                let identity = cigar_counts.identity(nm);
+                if let Some(contig) = basic_bam_stats.contigs.get_mut(record.tid() as usize) {
+                    contig.add_primary(record.seq_len(), identity, record.mapq());
+                    contig.add_covered(record.pos(), &cigar_counts);
+                }
+                each_stats.identity = Some(identity);
+                each_stats.gap_compressed_identity = Some(cigar_counts.gap_compressed_identity(nm));
+                basic_bam_stats.calibration.add_record(&record);
+                if mods {
+                    each_stats.mods = Some(basic_bam_stats.mods.add_record(&record));
+                }
+                each_stats.aligned_fraction = Some(cigar_counts.aligned_fraction());
+                all_stats.push(each_stats);
+                record.add_to_profile(&mut profile);
                 basic_bam_stats.bases_mapped += record.seq_len();
                 basic_bam_stats.primary_alignment += 1;
                 basic_bam_stats.reads_mapped += 1;
                 if record.mapq() == 0 {
                     basic_bam_stats.reads_mq0 += 1;
                 }
-                let nm = get_nm_aux(&record);
-                basic_bam_stats.mismatches += nm;
-                for cigar in record.cigar().iter() {
-                    basic_bam_stats.bases_mapped_cigar += get_cigar_base_length(cigar);
-                }
+                basic_bam_stats.add_mapq(record.mapq());
+                basic_bam_stats.add_alignment(nm, &cigar_counts);
             } else {
                 if record.is_supplementary() {
                     basic_bam_stats.supplementary_alignment += 1;
                     let nm = get_nm_aux(&record);
-                    basic_bam_stats.mismatches += nm;
-                    for cigar in record.cigar().iter() {
-                        basic_bam_stats.bases_mapped_cigar += get_cigar_base_length(cigar);
+                    let cigar_counts = CigarCounts::from_record(&record);
+                    if let Some(contig) = basic_bam_stats.contigs.get_mut(record.tid() as usize) {
+                        contig.add_covered(record.pos(), &cigar_counts);
                     }
+                    basic_bam_stats.add_alignment(nm, &cigar_counts);
                 }
                 if record.is_secondary() {
                     basic_bam_stats.secondary_alignment += 1;
@@ -336,15 +920,18 @@ where
         basic_bam_stats.mismatches as f64 / basic_bam_stats.bases_mapped_cigar as f64;
     basic_bam_stats.map_rate = basic_bam_stats.reads_mapped as f64
         / (basic_bam_stats.reads_mapped + basic_bam_stats.reads_unmapped) as f64;
-    (basic_bam_stats, all_stats)
+    (basic_bam_stats, all_stats, profile)
 }
 
 fn stats_indexed_bam_fetch(
     bam_reader: &mut IndexedReader,
     region: FetchDefinition,
-    gc: bool,
+    features: SeqFeatures,
     use_dorado_q: bool,
-) -> (BasicBamStatistics, Vec<RecordEachStats>) {
+    qscore_source: QscoreSource,
+    mods: bool,
+    profile_depth: usize,
+) -> (BasicBamStatistics, Vec<RecordEachStats>, PositionProfile) {
     let region_start = match &region {
         FetchDefinition::Region(_, region_start, _) => *region_start,
         FetchDefinition::Unmapped => i64::MIN,
@@ -355,7 +942,15 @@ fn stats_indexed_bam_fetch(
     if fetch_result.is_err() {
         quit_with_error(&may_be_err_msg)
     }
-    stats_from_bam_reader(bam_reader, gc, use_dorado_q, region_start)
+    stats_from_bam_reader(
+        bam_reader,
+        features,
+        use_dorado_q,
+        qscore_source,
+        mods,
+        profile_depth,
+        region_start,
+    )
 }
 
 pub fn index_bam(bam_file: &str, thread: usize) -> Result<(), anyhow::Error> {
@@ -369,12 +964,23 @@ pub fn index_bam(bam_file: &str, thread: usize) -> Result<(), anyhow::Error> {
 pub fn stats_xam(
     bam_reader: &mut bam::Reader,
     thread: usize,
-    gc: bool,
+    features: SeqFeatures,
     use_dorado_q: bool,
-) -> (BasicBamStatistics, Vec<RecordEachStats>) {
+    qscore_source: QscoreSource,
+    mods: bool,
+    profile_depth: usize,
+) -> (BasicBamStatistics, Vec<RecordEachStats>, PositionProfile) {
     debug_assert!(thread > 0);
     bam_reader.set_threads(thread).unwrap();
-    stats_from_bam_reader(bam_reader, gc, use_dorado_q, i64::MIN)
+    stats_from_bam_reader(
+        bam_reader,
+        features,
+        use_dorado_q,
+        qscore_source,
+        mods,
+        profile_depth,
+        i64::MIN,
+    )
 }
 
 thread_local! {
@@ -387,9 +993,12 @@ thread_local! {
 pub fn stats_indexed_bam(
     bam_file: &str,
     thread: usize,
-    gc: bool,
+    features: SeqFeatures,
     use_dorado_q: bool,
-) -> (BasicBamStatistics, Vec<RecordEachStats>) {
+    qscore_source: QscoreSource,
+    mods: bool,
+    profile_depth: usize,
+) -> (BasicBamStatistics, Vec<RecordEachStats>, PositionProfile) {
     debug_assert_eq!(check_bam_type(bam_file), BamType::IndexedBam);
     let indexed_bam_reader =
         IndexedReader::from_path(bam_file).expect("Read indexed bam failed for stats_indexed_bam");
@@ -399,7 +1008,9 @@ pub fn stats_indexed_bam(
         .map(|_| bam_file.to_string())
         .collect::<Vec<String>>();
 
-    rayon::ThreadPoolBuilder::new()
+    // a dedicated pool is used here because every worker needs its own IndexedReader,
+    // and the global pool may have been built already
+    let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(thread)
         .start_handler(move |index| {
             let bam_file_string = files[index].clone();
@@ -408,23 +1019,161 @@ pub fn stats_indexed_bam(
             INDEXED_BAM_READER.set(reader);
         })
         .thread_name(|x| format!("Thread: {x}"))
-        .build_global()
+        .build()
         .expect("thread pool builder failed for stats_indexed_bam function");
 
-    let result: Vec<_> = fetch_regions
-        .into_par_iter()
-        .map(|region| {
-            INDEXED_BAM_READER.with_borrow_mut(|indexed_reader| {
-                stats_indexed_bam_fetch(indexed_reader, region, gc, use_dorado_q)
+    let result: Vec<_> = pool.install(|| {
+        fetch_regions
+            .into_par_iter()
+            .map(|region| {
+                INDEXED_BAM_READER.with_borrow_mut(|indexed_reader| {
+                    stats_indexed_bam_fetch(
+                        indexed_reader,
+                        region,
+                        features,
+                        use_dorado_q,
+                        qscore_source,
+                        mods,
+                        profile_depth,
+                    )
+                })
             })
-        })
-        .collect();
+            .collect()
+    });
 
     let mut basic_bam_stats = BasicBamStatistics::default();
     let mut all_stats = vec![];
+    let mut profile = PositionProfile::new(profile_depth);
     for x in result {
         basic_bam_stats += x.0;
         all_stats.extend(x.1);
+        profile += x.2;
+    }
+    (basic_bam_stats, all_stats, profile)
+}
+
+#[cfg(test)]
+mod bam_test {
+    use super::*;
+
+    #[test]
+    fn contig_breadth() {
+        let mut contig = ContigStats::new("chr1".to_string(), 100);
+        // unsorted, overlapping, adjacent and nested intervals
+        for (start, end) in [(50, 60), (10, 20), (15, 30), (30, 35), (52, 55), (0, 5)] {
+            contig.add_interval(start, end);
+        }
+        assert_eq!(
+            contig
+                .covered
+                .iter()
+                .map(|(s, e)| (*s, *e))
+                .collect::<Vec<_>>(),
+            vec![(0, 5), (10, 35), (50, 60)]
+        );
+        assert_eq!(contig.covered_len, 40);
+        let mut other = ContigStats::new("chr1".to_string(), 100);
+        other.add_interval(4, 51);
+        contig += other;
+        assert_eq!(contig.covered.len(), 1);
+        assert!((contig.breadth() - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn identity() {
+        let mut record = bam::Record::new();
+        // 1 mismatch, 1 inserted base and 2 deleted bases, NM = 4
+        let cigar = bam::record::CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(4),
+            Cigar::Ins(1),
+            Cigar::Match(3),
+            Cigar::Del(2),
+            Cigar::Match(2),
+        ]);
+        record.set(b"read1", Some(&cigar), b"ACGTACGTACGT", &[20; 12]);
+        let counts = CigarCounts::from_record(&record);
+        assert_eq!(counts.reference_span(), 11);
+        // 1 - 4 / (9 + 1 + 2)
+        assert!((counts.identity(4) - 8.0 / 12.0).abs() < 1e-6);
+        // 1 - (1 + 1 + 1) / (9 + 1 + 1)
+        assert!((counts.gap_compressed_identity(4) - 8.0 / 11.0).abs() < 1e-6);
+        // (9 + 1) / (9 + 1 + 2)
+        assert!((counts.aligned_fraction() - 10.0 / 12.0).abs() < 1e-6);
+        // a 10 bases deletion is one difference as well
+        record.set(
+            b"read1",
+            Some(&bam::record::CigarString(vec![
+                Cigar::Match(5),
+                Cigar::Del(10),
+                Cigar::Match(5),
+            ])),
+            b"ACGTACGTAC",
+            &[20; 10],
+        );
+        let counts = CigarCounts::from_record(&record);
+        assert!((counts.identity(10) - 0.5).abs() < 1e-6);
+        assert!((counts.gap_compressed_identity(10) - 10.0 / 11.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clip() {
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(4),
+            Cigar::Ins(1),
+            Cigar::Match(3),
+            Cigar::Del(2),
+            Cigar::Match(2),
+        ]);
+        let new_record = |flags: u16| {
+            let mut record = bam::Record::new();
+            record.set(b"read1", Some(&cigar), b"ACGTACGTACGT", &[20; 12]);
+            record.set_pos(100);
+            record.set_flags(flags);
+            record.push_aux(b"NM", Aux::I32(4)).unwrap();
+            record
+        };
+        let mut record = new_record(0);
+        assert!(clip_read(&mut record, 7, 1));
+        assert_eq!(record.cigar().to_string(), "7H3M2D1M1H");
+        assert_eq!(record.pos(), 104);
+        assert_eq!(record.seq().as_bytes(), b"TACG");
+        assert!(record.aux(b"NM").is_err());
+        // the head of a reverse strand read is at the end of the alignment
+        let mut record = new_record(16);
+        assert!(clip_read(&mut record, 1, 7));
+        assert_eq!(record.cigar().to_string(), "7H3M2D1M1H");
+        assert_eq!(record.pos(), 104);
+        // an insertion before the first aligned base is soft clipped
+        let mut record = new_record(0);
+        assert!(clip_read(&mut record, 6, 0));
+        assert_eq!(record.cigar().to_string(), "6H1S3M2D2M");
+        assert_eq!(record.pos(), 104);
+        // a deletion next to the clipped bases is skipped, hard clips are merged
+        let mut record = bam::Record::new();
+        record.set(
+            b"read1",
+            Some(&CigarString(vec![
+                Cigar::HardClip(3),
+                Cigar::Match(5),
+                Cigar::Del(10),
+                Cigar::Match(5),
+            ])),
+            b"ACGTACGTAC",
+            &[20; 10],
+        );
+        record.set_pos(0);
+        record.set_flags(0);
+        assert!(clip_read(&mut record, 8, 0));
+        assert_eq!(record.cigar().to_string(), "8H5M");
+        assert_eq!(record.pos(), 15);
+        assert_eq!(record.seq().as_bytes(), b"CGTAC");
+        // no aligned base is left
+        let mut record = new_record(0);
+        assert!(!clip_read(&mut record, 11, 1));
+        assert!(record.is_unmapped());
+        assert_eq!(record.cigar().len(), 0);
+        assert_eq!(record.seq_len(), 0);
     }
-    (basic_bam_stats, all_stats)
 }
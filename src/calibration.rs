@@ -0,0 +1,419 @@
+use crate::utils::format_counts;
+use plotly::color::NamedColor;
+use plotly::common::{ColorScale, ColorScalePalette, DashType, HoverInfo, Line, Mode};
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, GridPattern, LayoutGrid};
+use plotly::{HeatMap, Layout, Plot, Scatter};
+use rust_htslib::bam::{self, record::Aux, record::Cigar};
+use serde_json::{Value, json};
+use std::ops::AddAssign;
+
+// phred quality of bam is in [0, 93], 255 means the quality is missing
+const MAX_BASE_QUALITY: usize = 93;
+// the identity of a perfect read is capped at this phred score
+const MAX_IDENTITY_Q: f64 = 60.0;
+
+/// phred score of an error rate, capped at MAX_IDENTITY_Q
+pub fn error_rate_to_q(error_rate: f64) -> f64 {
+    (-10.0 * error_rate.max(10f64.powf(-MAX_IDENTITY_Q / 10.0)).log10()).min(MAX_IDENTITY_Q)
+}
+
+/// positions of mismatches in the aligned columns (Match + Equal + Diff) from MD tag,
+/// such as MD:Z:10A5^AC6, deletions don't take aligned columns
+fn md_mismatch_columns(md: &str) -> Option<Vec<usize>> {
+    let mut mismatches = vec![];
+    let mut column = 0usize;
+    let mut number = 0usize;
+    let mut in_deletion = false;
+    for c in md.bytes() {
+        match c {
+            b'0'..=b'9' => {
+                number = number * 10 + (c - b'0') as usize;
+                in_deletion = false;
+            }
+            b'^' => {
+                column += number;
+                number = 0;
+                in_deletion = true;
+            }
+            b'A'..=b'Z' | b'a'..=b'z' => {
+                column += number;
+                number = 0;
+                if !in_deletion {
+                    mismatches.push(column);
+                    column += 1;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(mismatches)
+}
+
+/// aligned bases and errors of each predicted base quality, the errors are mismatches and
+/// inserted bases, deletions are excluded because they have no base quality
+#[derive(Debug, Clone)]
+pub struct QualityCalibration {
+    bases: Vec<u64>,
+    errors: Vec<u64>,
+    reads_used: usize,
+    reads_skipped: usize, // no MD tag or =/X cigar, or quality is missing
+}
+
+impl Default for QualityCalibration {
+    fn default() -> Self {
+        QualityCalibration {
+            bases: vec![0; MAX_BASE_QUALITY + 1],
+            errors: vec![0; MAX_BASE_QUALITY + 1],
+            reads_used: 0,
+            reads_skipped: 0,
+        }
+    }
+}
+
+impl QualityCalibration {
+    pub fn is_empty(&self) -> bool {
+        self.reads_used == 0
+    }
+
+    pub fn add_record(&mut self, record: &bam::Record) {
+        let quals = record.qual();
+        if quals.first().is_none_or(|x| *x == 255) {
+            self.reads_skipped += 1;
+            return;
+        }
+        let cigar = record.cigar();
+        let has_equal_diff = cigar
+            .iter()
+            .any(|x| matches!(x, Cigar::Equal(_) | Cigar::Diff(_)));
+        let md_mismatches = match record.aux(b"MD") {
+            Ok(Aux::String(md)) => md_mismatch_columns(md),
+            _ => None,
+        };
+        if md_mismatches.is_none() && !has_equal_diff {
+            self.reads_skipped += 1;
+            return;
+        }
+        let md_mismatches = md_mismatches.unwrap_or_default();
+        let mut next_mismatch = md_mismatches.iter().peekable();
+        let mut query_pos = 0usize;
+        let mut column = 0usize;
+        for op in cigar.iter() {
+            match op {
+                Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                    for _ in 0..*len {
+                        let q = (quals[query_pos] as usize).min(MAX_BASE_QUALITY);
+                        self.bases[q] += 1;
+                        let is_error = match op {
+                            Cigar::Diff(_) => true,
+                            Cigar::Equal(_) => false,
+                            _ => next_mismatch.peek().is_some_and(|x| **x == column),
+                        };
+                        if next_mismatch.peek().is_some_and(|x| **x == column) {
+                            next_mismatch.next();
+                        }
+                        if is_error {
+                            self.errors[q] += 1;
+                        }
+                        query_pos += 1;
+                        column += 1;
+                    }
+                }
+                Cigar::Ins(len) => {
+                    for _ in 0..*len {
+                        let q = (quals[query_pos] as usize).min(MAX_BASE_QUALITY);
+                        self.bases[q] += 1;
+                        self.errors[q] += 1;
+                        query_pos += 1;
+                    }
+                }
+                Cigar::SoftClip(len) => query_pos += *len as usize,
+                _ => {}
+            }
+        }
+        self.reads_used += 1;
+    }
+
+    /// PredictedQ, AlignedBases, Errors, EmpiricalQ of base qualities with aligned bases,
+    /// EmpiricalQ = -10 * log10((errors + 1) / (bases + 2))
+    pub fn rows(&self) -> Vec<(usize, u64, u64, f64)> {
+        self.bases
+            .iter()
+            .zip(self.errors.iter())
+            .enumerate()
+            .filter(|(_, (bases, _))| **bases > 0)
+            .map(|(q, (bases, errors))| {
+                (
+                    q,
+                    *bases,
+                    *errors,
+                    error_rate_to_q((*errors as f64 + 1.0) / (*bases as f64 + 2.0)),
+                )
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "ReadsUsed": self.reads_used,
+            "ReadsSkipped": self.reads_skipped,
+            "Bins": self
+                .rows()
+                .into_iter()
+                .map(|(q, bases, errors, empirical_q)| {
+                    json!({
+                        "PredictedQ": q,
+                        "AlignedBases": bases,
+                        "Errors": errors,
+                        "EmpiricalQ": empirical_q,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// base level calibration curve and per-read predicted quality vs gap-compressed identity,
+    /// read_pairs are (read quality, gap-compressed identity)
+    pub fn html_div(&self, read_pairs: &[(f32, f32)], bins: usize) -> String {
+        let mut plot = Plot::new();
+        let rows = self.rows();
+        let max_q = rows.iter().map(|x| x.0).max().unwrap_or(1) as f64;
+        let base_trace = Scatter::new(
+            rows.iter().map(|x| x.0 as f64).collect::<Vec<_>>(),
+            rows.iter().map(|x| x.3).collect::<Vec<_>>(),
+        )
+        .mode(Mode::LinesMarkers)
+        .name("EmpiricalQ")
+        .hover_text_array(
+            rows.iter()
+                .map(|x| {
+                    format!(
+                        "<b>PredictedQ</b>: {}<br><b>EmpiricalQ</b>: {:.2}<br>\
+                        <b>AlignedBases</b>: {}<br><b>Errors</b>: {}",
+                        x.0,
+                        x.3,
+                        format_counts(x.1 as usize),
+                        format_counts(x.2 as usize)
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .hover_info(HoverInfo::Text)
+        .show_legend(false)
+        .x_axis("x1")
+        .y_axis("y1");
+        plot.add_trace(base_trace);
+        plot.add_trace(
+            Scatter::new(vec![0.0, max_q], vec![0.0, max_q])
+                .mode(Mode::Lines)
+                .line(Line::new().color(NamedColor::Red).dash(DashType::Dash))
+                .hover_info(HoverInfo::Skip)
+                .show_legend(false)
+                .x_axis("x1")
+                .y_axis("y1"),
+        );
+
+        // density of read quality vs phred scaled gap-compressed identity
+        let read_points = read_pairs
+            .iter()
+            .map(|(qual, identity)| (*qual as f64, error_rate_to_q(1.0 - *identity as f64)))
+            .collect::<Vec<_>>();
+        let max_read_q = read_points
+            .iter()
+            .map(|x| x.0.max(x.1))
+            .fold(1.0f64, f64::max)
+            + 1e-6;
+        let width = max_read_q / bins as f64;
+        // counts[identity q bin][read quality bin]
+        let mut counts = vec![vec![0usize; bins]; bins];
+        for (qual, identity_q) in read_points.iter() {
+            let x_idx = ((qual / width) as usize).min(bins - 1);
+            let y_idx = ((identity_q / width) as usize).min(bins - 1);
+            counts[y_idx][x_idx] += 1;
+        }
+        let z = counts
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|count| if count == 0 { None } else { Some(count) })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let axis_values = (0..bins)
+            .map(|i| (i as f64 + 0.5) * width)
+            .collect::<Vec<_>>();
+        plot.add_trace(
+            HeatMap::new(axis_values.clone(), axis_values, z)
+                .color_scale(ColorScale::Palette(ColorScalePalette::Viridis))
+                .hover_on_gaps(false)
+                .show_scale(false)
+                .hover_template(
+                    "<b>ReadQuality</b>: %{x:.2f}<br>\
+                    <b>GapCompressedIdentityQ</b>: %{y:.2f}<br>\
+                    <b>ReadsCount</b>: %{z}\
+                    <extra></extra>",
+                )
+                .x_axis("x2")
+                .y_axis("y2"),
+        );
+        plot.add_trace(
+            Scatter::new(vec![0.0, max_read_q], vec![0.0, max_read_q])
+                .mode(Mode::Lines)
+                .line(Line::new().color(NamedColor::Red).dash(DashType::Dash))
+                .hover_info(HoverInfo::Skip)
+                .show_legend(false)
+                .x_axis("x2")
+                .y_axis("y2"),
+        );
+
+        let layout = Layout::new()
+            .grid(
+                LayoutGrid::new()
+                    .rows(1)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("PredictedBaseQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("EmpiricalBaseQuality")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title("ReadQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(
+                Axis::new()
+                    .title("GapCompressedIdentityQ")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .height(500);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("quality_calibration"))
+    }
+}
+
+impl AddAssign for QualityCalibration {
+    fn add_assign(&mut self, rhs: Self) {
+        for (a, b) in self.bases.iter_mut().zip(rhs.bases) {
+            *a += b;
+        }
+        for (a, b) in self.errors.iter_mut().zip(rhs.errors) {
+            *a += b;
+        }
+        self.reads_used += rhs.reads_used;
+        self.reads_skipped += rhs.reads_skipped;
+    }
+}
+
+/// ReadQuality bin (floor of read quality), ReadsNumber, MeanGapCompressedIdentity, EmpiricalQ
+pub fn read_calibration_rows(read_pairs: &[(f32, f32)]) -> Vec<(u32, usize, f64, f64)> {
+    let mut bins: Vec<(usize, f64)> = vec![];
+    for (qual, identity) in read_pairs {
+        let idx = qual.max(0.0) as usize;
+        if bins.len() <= idx {
+            bins.resize(idx + 1, (0, 0.0));
+        }
+        bins[idx].0 += 1;
+        bins[idx].1 += *identity as f64;
+    }
+    bins.into_iter()
+        .enumerate()
+        .filter(|(_, (reads, _))| *reads > 0)
+        .map(|(q, (reads, identity_sum))| {
+            let mean_identity = identity_sum / reads as f64;
+            (
+                q as u32,
+                reads,
+                mean_identity,
+                error_rate_to_q(1.0 - mean_identity),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod calibration_test {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    /// 2S4M1I3M2D2M with a mismatch at the 2nd aligned column
+    fn record(quals: &[u8], md: Option<&str>) -> bam::Record {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(4),
+            Cigar::Ins(1),
+            Cigar::Match(3),
+            Cigar::Del(2),
+            Cigar::Match(2),
+        ]);
+        record.set(b"read1", Some(&cigar), b"ACGTACGTACGT", quals);
+        if let Some(md) = md {
+            record.push_aux(b"MD", Aux::String(md)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn md_columns() {
+        assert_eq!(md_mismatch_columns("10A5^AC6"), Some(vec![10]));
+        assert_eq!(md_mismatch_columns("0A0C3"), Some(vec![0, 1]));
+        // 0 separates a deletion from the next mismatch
+        assert_eq!(md_mismatch_columns("3^T0G2"), Some(vec![3]));
+        assert_eq!(md_mismatch_columns("3^TG2"), Some(vec![]));
+        assert_eq!(md_mismatch_columns("2C1^AA0T4"), Some(vec![2, 4]));
+        assert_eq!(md_mismatch_columns("12"), Some(vec![]));
+        assert_eq!(md_mismatch_columns("5*2"), None);
+    }
+
+    #[test]
+    fn empirical_q() {
+        assert!((error_rate_to_q(0.1) - 10.0).abs() < 1e-9);
+        assert!((error_rate_to_q(0.001) - 30.0).abs() < 1e-9);
+        assert_eq!(error_rate_to_q(0.0), MAX_IDENTITY_Q);
+        let mut calibration = QualityCalibration::default();
+        calibration.bases[20] = 98;
+        calibration.bases[10] = 8;
+        calibration.errors[10] = 4;
+        let rows = calibration.rows();
+        assert_eq!(rows.len(), 2);
+        // (0 + 1) / (98 + 2) and (4 + 1) / (8 + 2)
+        assert_eq!((rows[0].0, rows[0].1, rows[0].2), (10, 8, 4));
+        assert!((rows[0].3 - 3.0103).abs() < 1e-4);
+        assert_eq!((rows[1].0, rows[1].1, rows[1].2), (20, 98, 0));
+        assert!((rows[1].3 - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn calibration_record() {
+        let quals = [10, 10, 20, 20, 20, 20, 5, 30, 30, 30, 40, 40];
+        let mut calibration = QualityCalibration::default();
+        calibration.add_record(&record(&quals, Some("1A5^GT2")));
+        // soft clipped bases are not counted, the inserted base is an error
+        assert_eq!((calibration.bases[10], calibration.errors[10]), (0, 0));
+        assert_eq!((calibration.bases[20], calibration.errors[20]), (4, 1));
+        assert_eq!((calibration.bases[5], calibration.errors[5]), (1, 1));
+        assert_eq!((calibration.bases[30], calibration.errors[30]), (3, 0));
+        assert_eq!((calibration.bases[40], calibration.errors[40]), (2, 0));
+        // no MD tag or missing quality
+        calibration.add_record(&record(&quals, None));
+        calibration.add_record(&record(&[255; 12], Some("1A5^GT2")));
+        assert_eq!((calibration.reads_used, calibration.reads_skipped), (1, 2));
+    }
+}
@@ -0,0 +1,448 @@
+use crate::fastq::{FastqRecord, chunk_records_from_fastq};
+use crate::input_type::{InputType, check_input_type};
+use crate::utils::{
+    MatchPosition, check_and_create_dir, collect_fqs_in_dir, find_most_left_rear,
+    find_most_right_front, init_log, quit_with_error,
+};
+use bio::alphabets::dna::revcomp;
+use bio::pattern_matching::myers::{Myers, MyersBuilder};
+use clap::{Arg, ArgMatches, Command, value_parser};
+use log::info;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// the primers of SQK-PCS114 and SQK-PCB114, see the library structures at the end of utils.rs.
+// a sense read is SSPII - insert with poly(A) - CRTA, an antisense read is its reverse complement
+const SSPII: &str = "TTTCTGTTGGTGCTGATATTGCTTTVVVVTTVVVVTTVVVVTTVVVVTTTGGG";
+const CRTA: &str = "CTTGCGGGCGGCGGACTCTCCTCTGAAGATAGAGCGACAGGCAAG";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CdnaClass {
+    FullLength,
+    FivePrimeOnly,
+    ThreePrimeOnly,
+    Unclassified,
+}
+
+impl CdnaClass {
+    const ALL: [CdnaClass; 4] = [
+        CdnaClass::FullLength,
+        CdnaClass::FivePrimeOnly,
+        CdnaClass::ThreePrimeOnly,
+        CdnaClass::Unclassified,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CdnaClass::FullLength => "full_length",
+            CdnaClass::FivePrimeOnly => "5_prime_only",
+            CdnaClass::ThreePrimeOnly => "3_prime_only",
+            CdnaClass::Unclassified => "unclassified",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Clone)]
+struct CdnaPrimers {
+    sspii: Myers,
+    sspii_rc: Myers,
+    crta: Myers,
+    crta_rc: Myers,
+    left_range: usize,
+    right_range: usize,
+    max_distance: u8,
+}
+
+impl CdnaPrimers {
+    fn new(left_range: usize, right_range: usize, max_distance: u8) -> Self {
+        let mut builder = MyersBuilder::new();
+        builder.ambig(b'V', b"ACG").ambig(b'B', b"CGT");
+        CdnaPrimers {
+            sspii: builder.build_64(SSPII.as_bytes()),
+            sspii_rc: builder.build_64(revcomp(SSPII.as_bytes())),
+            crta: builder.build_64(CRTA.as_bytes()),
+            crta_rc: builder.build_64(revcomp(CRTA.as_bytes())),
+            left_range,
+            right_range,
+            max_distance,
+        }
+    }
+
+    /// the end of the primer in the first left_range bases
+    fn find_front(
+        primer: &mut Myers,
+        seq: &[u8],
+        left_range: usize,
+        max_distance: u8,
+    ) -> Option<usize> {
+        let search_seq = &seq[..left_range.min(seq.len())];
+        let matches = primer
+            .find_all(search_seq, max_distance)
+            .collect::<Vec<MatchPosition>>();
+        find_most_right_front(matches, max_distance).map(|x| x.1)
+    }
+
+    /// the start of the primer in the last right_range bases
+    fn find_rear(
+        primer: &mut Myers,
+        seq: &[u8],
+        right_range: usize,
+        max_distance: u8,
+    ) -> Option<usize> {
+        let offset = seq.len() - right_range.min(seq.len());
+        let matches = primer
+            .find_all(&seq[offset..], max_distance)
+            .collect::<Vec<MatchPosition>>();
+        find_most_left_rear(matches, max_distance).map(|x| offset + x.0)
+    }
+
+    ///
+    /// Classify the read by the primers at its ends, the primers are trimmed and the antisense
+    /// reads are reversed to the transcript sense. The reads with primers of both strands or
+    /// without insert are unclassified and kept as they are.
+    ///
+    fn classify(&mut self, read: &mut FastqRecord) -> (CdnaClass, bool) {
+        let (left, right, dist) = (self.left_range, self.right_range, self.max_distance);
+        let sense_5 = Self::find_front(&mut self.sspii, &read.seq, left, dist);
+        let sense_3 = Self::find_rear(&mut self.crta, &read.seq, right, dist);
+        let antisense_3 = Self::find_front(&mut self.crta_rc, &read.seq, left, dist);
+        let antisense_5 = Self::find_rear(&mut self.sspii_rc, &read.seq, right, dist);
+        let is_sense = sense_5.is_some() || sense_3.is_some();
+        let is_antisense = antisense_5.is_some() || antisense_3.is_some();
+        let (five_prime, three_prime, front, rear) = match (is_sense, is_antisense) {
+            (true, false) => (sense_5.is_some(), sense_3.is_some(), sense_5, sense_3),
+            (false, true) => (
+                antisense_5.is_some(),
+                antisense_3.is_some(),
+                antisense_3,
+                antisense_5,
+            ),
+            _ => return (CdnaClass::Unclassified, false),
+        };
+        let start = front.unwrap_or(0);
+        let end = rear.unwrap_or(read.seq.len());
+        if start >= end {
+            return (CdnaClass::Unclassified, false);
+        }
+        read.crop(start, end);
+        if is_antisense {
+            read.reversed();
+        }
+        let class = match (five_prime, three_prime) {
+            (true, true) => CdnaClass::FullLength,
+            (true, false) => CdnaClass::FivePrimeOnly,
+            _ => CdnaClass::ThreePrimeOnly,
+        };
+        (class, is_antisense)
+    }
+}
+
+/// reads, bases and antisense reads of each class
+#[derive(Default)]
+struct CdnaReport {
+    rows: [(u64, u64, u64); CdnaClass::ALL.len()],
+}
+
+impl CdnaReport {
+    fn add(&mut self, class: CdnaClass, bases: usize, is_antisense: bool) {
+        let row = &mut self.rows[class.index()];
+        row.0 += 1;
+        row.1 += bases as u64;
+        row.2 += is_antisense as u64;
+    }
+
+    fn write_tsv(&self, summary_file: &str) {
+        let mut writer =
+            BufWriter::new(File::create(summary_file).unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, Failed to create {summary_file}"))
+            }));
+        let total_reads = self.rows.iter().map(|x| x.0).sum::<u64>().max(1);
+        writeln!(writer, "Class\tReads\tBases\tReadsFraction\tAntisenseReads").unwrap();
+        for class in CdnaClass::ALL {
+            let (reads, bases, antisense_reads) = self.rows[class.index()];
+            writeln!(
+                writer,
+                "{}\t{reads}\t{bases}\t{:.6}\t{antisense_reads}",
+                class.name(),
+                reads as f64 / total_reads as f64
+            )
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to write {summary_file}")));
+        }
+    }
+}
+
+pub fn run_cdna(cdna_cmd: &ArgMatches) {
+    init_log();
+    let input = cdna_cmd.get_one::<String>("input").unwrap();
+    let output = cdna_cmd.get_one::<String>("output").unwrap();
+    let prefix = cdna_cmd.get_one::<String>("prefix").unwrap();
+    let left = *cdna_cmd.get_one::<usize>("left").unwrap();
+    let right = *cdna_cmd.get_one::<usize>("right").unwrap();
+    let distance = *cdna_cmd.get_one::<u8>("distance").unwrap();
+    let thread = *cdna_cmd.get_one::<u16>("thread").unwrap() as usize;
+    let chunk = *cdna_cmd.get_one::<u32>("chunk").unwrap();
+
+    let fastqs = match check_input_type(input) {
+        InputType::DirectoryContainFastqsOrFastqsGzipped => collect_fqs_in_dir(input),
+        InputType::OneFastqFile | InputType::OneFastqGzippedFile => vec![input.into()],
+        _ => quit_with_error("cdna only supports fastq, fastq.gz or a directory of them"),
+    };
+    check_and_create_dir(output);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(thread)
+        .build_global()
+        .unwrap();
+
+    let primers = CdnaPrimers::new(left, right, distance);
+    let mut writers = CdnaClass::ALL.map(|class| {
+        let fastq = format!("{output}/{prefix}.{}.fastq", class.name());
+        BufWriter::new(
+            File::create(&fastq)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {fastq}"))),
+        )
+    });
+    let mut report = CdnaReport::default();
+    for fastq in fastqs {
+        let (read_handle, receiver) =
+            chunk_records_from_fastq(fastq.to_str().unwrap(), chunk, true);
+        for records in receiver {
+            let classified = records
+                .into_par_iter()
+                .map_init(
+                    || primers.clone(),
+                    |primers, mut record| {
+                        let (class, is_antisense) = primers.classify(&mut record);
+                        (class, is_antisense, record)
+                    },
+                )
+                .collect::<Vec<_>>();
+            for (class, is_antisense, record) in classified {
+                report.add(class, record.seq.len(), is_antisense);
+                record.write(&mut writers[class.index()]).unwrap();
+            }
+        }
+        read_handle.join().unwrap();
+    }
+
+    for class in CdnaClass::ALL {
+        info!(
+            "{}: {} reads, saved into {output}/{prefix}.{}.fastq",
+            class.name(),
+            report.rows[class.index()].0,
+            class.name()
+        );
+    }
+    report.write_tsv(&format!("{output}/{prefix}.cdna_summary.tsv"));
+}
+
+pub fn cdna_cmd() -> Command {
+    Command::new("cdna")
+        .about("classify cDNA reads of SQK-PCS114/PCB114 as full-length, 5' only, 3' only or unclassified by SSPII and CRTA, trim the primers and orient the reads to transcript sense")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("fastq file, fastq.gz file or a directory containing them, the barcodes of PCB114 should be trimmed")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output directory, <prefix>.<class>.fastq and <prefix>.cdna_summary.tsv are saved in it")
+        )
+        .arg(
+            Arg::new("prefix")
+                .long("prefix")
+                .default_value("test001")
+                .help("the prefix of output files")
+        )
+        .arg(
+            Arg::new("left")
+                .short('l')
+                .long("left")
+                .default_value("200")
+                .value_parser(value_parser!(usize))
+                .help("first N bases of read used for SSPII or reverse complement of CRTA detection")
+        )
+        .arg(
+            Arg::new("right")
+                .short('r')
+                .long("right")
+                .default_value("200")
+                .value_parser(value_parser!(usize))
+                .help("last N bases of read used for CRTA or reverse complement of SSPII detection")
+        )
+        .arg(
+            Arg::new("distance")
+                .short('d')
+                .long("distance")
+                .default_value("6")
+                .value_parser(value_parser!(u8))
+                .help("max edit distance allowed between primer and read sequence")
+        )
+        .arg(
+            Arg::new("thread")
+                .short('t')
+                .long("thread")
+                .default_value("1")
+                .value_parser(value_parser!(u16).range(1..))
+                .help("number of threads")
+        )
+        .arg(
+            Arg::new("chunk")
+                .short('c')
+                .long("chunk")
+                .default_value("50000")
+                .value_parser(value_parser!(u32).range(10000..1000001))
+                .help("reads chunk size")
+        )
+}
+
+#[cfg(test)]
+mod cdna_test {
+    use super::*;
+
+    fn random_seq(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    /// the transcript of 300 random bases starting with C and a poly(A) tail
+    fn insert() -> Vec<u8> {
+        [b"C".as_slice(), &random_seq(299, 1), &[b'A'; 20]].concat()
+    }
+
+    fn sspii() -> Vec<u8> {
+        SSPII.replace('V', "A").into_bytes()
+    }
+
+    /// the quality of each base is different, so the trimmed position can be checked
+    fn quality(range: std::ops::Range<usize>) -> Vec<u8> {
+        range.map(|x| b'!' + (x % 40) as u8).collect()
+    }
+
+    fn read(seq: Vec<u8>) -> FastqRecord {
+        let quality = quality(0..seq.len());
+        FastqRecord::new("read1", None, seq, quality)
+    }
+
+    fn fastq(read: &FastqRecord) -> String {
+        let mut fastq = vec![];
+        read.write(&mut fastq).unwrap();
+        String::from_utf8(fastq).unwrap()
+    }
+
+    fn classify(seq: Vec<u8>) -> (CdnaClass, bool, FastqRecord, FastqRecord) {
+        let mut primers = CdnaPrimers::new(200, 200, 6);
+        let original = read(seq);
+        let mut read = read(original.seq.clone());
+        let (class, is_antisense) = primers.classify(&mut read);
+        (class, is_antisense, read, original)
+    }
+
+    #[test]
+    fn sense() {
+        let (junk5, junk3) = (random_seq(15, 2), random_seq(12, 3));
+        let insert = insert();
+        let crta = CRTA.as_bytes();
+        let cases = [
+            (
+                [&junk5, sspii().as_slice(), &insert, crta, &junk3].concat(),
+                CdnaClass::FullLength,
+            ),
+            (
+                [&junk5, sspii().as_slice(), &insert].concat(),
+                CdnaClass::FivePrimeOnly,
+            ),
+            (
+                [insert.as_slice(), crta, &junk3].concat(),
+                CdnaClass::ThreePrimeOnly,
+            ),
+        ];
+        for (seq, expected) in cases {
+            let start = if expected == CdnaClass::ThreePrimeOnly {
+                0
+            } else {
+                junk5.len() + sspii().len()
+            };
+            let (class, is_antisense, read, original) = classify(seq);
+            assert_eq!((class, is_antisense), (expected, false));
+            // the primers and the bases outside them are trimmed
+            let expected_read = FastqRecord::new(
+                "read1",
+                None,
+                insert.clone(),
+                quality(start..start + insert.len()),
+            );
+            assert_eq!(fastq(&read), fastq(&expected_read));
+            assert!(original.len() > read.len());
+        }
+    }
+
+    #[test]
+    fn antisense() {
+        let (junk5, junk3) = (random_seq(15, 2), random_seq(12, 3));
+        let insert = insert();
+        let crta = CRTA.as_bytes();
+        let cases = [
+            (
+                [&junk5, sspii().as_slice(), &insert, crta, &junk3].concat(),
+                CdnaClass::FullLength,
+            ),
+            (
+                [&junk5, sspii().as_slice(), &insert].concat(),
+                CdnaClass::FivePrimeOnly,
+            ),
+            (
+                [insert.as_slice(), crta, &junk3].concat(),
+                CdnaClass::ThreePrimeOnly,
+            ),
+        ];
+        for (sense_seq, expected) in cases {
+            let (class, is_antisense, read, _) = classify(revcomp(&sense_seq));
+            assert_eq!((class, is_antisense), (expected, true));
+            // reverse complemented to the transcript sense
+            assert_eq!(read.seq, insert);
+            assert_eq!(read.name, "read1_rc");
+        }
+    }
+
+    #[test]
+    fn unclassified() {
+        let insert = insert();
+        let cases = [
+            // no primer
+            insert.clone(),
+            // SSPII at both ends
+            [sspii().as_slice(), &insert, &revcomp(sspii())].concat(),
+            // CRTA of both strands
+            [
+                &revcomp(CRTA.as_bytes()),
+                insert.as_slice(),
+                CRTA.as_bytes(),
+            ]
+            .concat(),
+            // no insert between the primers
+            [sspii().as_slice(), CRTA.as_bytes()].concat(),
+        ];
+        for seq in cases {
+            let (class, is_antisense, read, original) = classify(seq);
+            assert_eq!((class, is_antisense), (CdnaClass::Unclassified, false));
+            assert_eq!(fastq(&read), fastq(&original));
+        }
+    }
+}
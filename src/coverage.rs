@@ -0,0 +1,799 @@
+use crate::input_type::{InputType, check_input_type};
+use crate::subseq::ReadsInBam;
+use crate::summary::{HTML_TAIL, html_chart_section, html_head};
+use crate::utils::{check_output_file, quit_with_error};
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use plotly::color::NamedColor;
+use plotly::common::{Line, Marker, Mode};
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, GridPattern, HoverMode, LayoutGrid};
+use plotly::{Bar, Layout, Plot, Scatter};
+use rayon::prelude::*;
+use rust_htslib::bam::record::Cigar;
+use rust_htslib::bam::{self, FetchDefinition, IndexedReader, Read};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::AddAssign;
+
+// big regions are split into chunks of this size, so that the memory of depth array is bounded
+const CHUNK_SIZE: u64 = 1_000_000;
+
+thread_local! {
+    static COVERAGE_BAM_READER: RefCell<IndexedReader> = panic!("!");
+}
+
+///
+/// Depth histogram of one region, index is depth, value is the number of bases with this depth
+///
+#[derive(Default, Debug, Clone)]
+struct DepthHistogram {
+    counts: Vec<u64>,
+}
+
+impl DepthHistogram {
+    fn add(&mut self, depth: usize, bases: u64) {
+        if self.counts.len() <= depth {
+            self.counts.resize(depth + 1, 0);
+        }
+        self.counts[depth] += bases;
+    }
+
+    fn total_bases(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    fn mean(&self) -> f64 {
+        let total = self.total_bases();
+        if total == 0 {
+            return 0.0;
+        }
+        let depth_sum = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(depth, count)| depth as f64 * *count as f64)
+            .sum::<f64>();
+        depth_sum / total as f64
+    }
+
+    fn median(&self) -> usize {
+        let total = self.total_bases();
+        let half = total / 2 + total % 2;
+        let mut accum = 0u64;
+        for (depth, count) in self.counts.iter().enumerate() {
+            accum += count;
+            if accum >= half && accum > 0 {
+                return depth;
+            }
+        }
+        0
+    }
+
+    /// fraction of bases whose depth >= threshold
+    fn fraction_at_least(&self, threshold: usize) -> f64 {
+        let total = self.total_bases();
+        if total == 0 {
+            return 0.0;
+        }
+        let covered = self.counts.iter().skip(threshold).sum::<u64>();
+        covered as f64 / total as f64
+    }
+}
+
+impl AddAssign<&DepthHistogram> for DepthHistogram {
+    fn add_assign(&mut self, rhs: &DepthHistogram) {
+        if self.counts.len() < rhs.counts.len() {
+            self.counts.resize(rhs.counts.len(), 0);
+        }
+        for (depth, count) in rhs.counts.iter().enumerate() {
+            self.counts[depth] += count;
+        }
+    }
+}
+
+/// The filters of alignments which will be counted into depth
+#[derive(Debug, Clone, Copy)]
+struct CoverageOption {
+    min_mapq: u8,
+    exclude_flag: u16,
+    bedgraph: bool,
+}
+
+/// One piece of a target region, the depth of it is computed by one worker
+struct RegionChunk {
+    region_idx: usize,
+    tid: u32,
+    start: u64,
+    end: u64,
+}
+
+/// Depth of one RegionChunk
+struct ChunkDepth {
+    region_idx: usize,
+    histogram: DepthHistogram,
+    runs: Vec<(u64, u64, u32)>, // bedGraph runs: start, end, depth
+}
+
+fn chunk_depth(
+    bam_reader: &mut IndexedReader,
+    chunk: &RegionChunk,
+    co: &CoverageOption,
+) -> ChunkDepth {
+    let chunk_len = (chunk.end - chunk.start) as usize;
+    // difference array of depth, one more position to close the last interval
+    let mut diff = vec![0i32; chunk_len + 1];
+    bam_reader
+        .fetch(FetchDefinition::Region(
+            chunk.tid as i32,
+            chunk.start as i64,
+            chunk.end as i64,
+        ))
+        .unwrap_or_else(|x| {
+            quit_with_error(&format!(
+                "{x}, Fetch region {}:{}-{} from IndexedReader failed",
+                chunk.tid, chunk.start, chunk.end
+            ))
+        });
+    let mut record = bam::Record::new();
+    while let Some(x) = bam_reader.read(&mut record) {
+        if x.is_err() {
+            quit_with_error(&format!(
+                "Parse record failed: {}",
+                String::from_utf8_lossy(record.qname())
+            ));
+        }
+        if record.flags() & co.exclude_flag != 0 || record.mapq() < co.min_mapq {
+            continue;
+        }
+        let mut ref_pos = record.pos();
+        for cigar in record.cigar().iter() {
+            match cigar {
+                Cigar::Match(m) | Cigar::Equal(m) | Cigar::Diff(m) => {
+                    let block_start = ref_pos.max(chunk.start as i64);
+                    let block_end = (ref_pos + *m as i64).min(chunk.end as i64);
+                    if block_start < block_end {
+                        diff[(block_start - chunk.start as i64) as usize] += 1;
+                        diff[(block_end - chunk.start as i64) as usize] -= 1;
+                    }
+                    ref_pos += *m as i64;
+                }
+                Cigar::Del(n) | Cigar::RefSkip(n) => ref_pos += *n as i64,
+                _ => {}
+            }
+        }
+    }
+
+    let mut histogram = DepthHistogram::default();
+    let mut runs: Vec<(u64, u64, u32)> = vec![];
+    let mut depth = 0i32;
+    for (offset, d) in diff[..chunk_len].iter().enumerate() {
+        depth += d;
+        let pos = chunk.start + offset as u64;
+        histogram.add(depth as usize, 1);
+        if co.bedgraph {
+            match runs.last_mut() {
+                Some(last) if last.2 == depth as u32 => last.1 = pos + 1,
+                _ => runs.push((pos, pos + 1, depth as u32)),
+            }
+        }
+    }
+    ChunkDepth {
+        region_idx: chunk.region_idx,
+        histogram,
+        runs,
+    }
+}
+
+/// extend the pending run (region_idx, start, end, depth) by the next run if they are adjacent in
+/// one region with the same depth, otherwise the pending run is finished and returned
+fn merge_run(
+    pending_run: &mut Option<(usize, u64, u64, u32)>,
+    run: (usize, u64, u64, u32),
+) -> Option<(usize, u64, u64, u32)> {
+    match pending_run.as_mut() {
+        Some(last) if last.0 == run.0 && last.2 == run.1 && last.3 == run.3 => {
+            last.2 = run.2;
+            None
+        }
+        _ => pending_run.replace(run),
+    }
+}
+
+fn split_regions(regions: &[(u32, u64, u64)]) -> Vec<RegionChunk> {
+    let mut chunks = vec![];
+    for (region_idx, (tid, start, end)) in regions.iter().enumerate() {
+        let mut chunk_start = *start;
+        while chunk_start < *end {
+            let chunk_end = (chunk_start + CHUNK_SIZE).min(*end);
+            chunks.push(RegionChunk {
+                region_idx,
+                tid: *tid,
+                start: chunk_start,
+                end: chunk_end,
+            });
+            chunk_start = chunk_end;
+        }
+    }
+    chunks
+}
+
+/// Target regions from bed file, or all contigs if no bed file specified
+fn target_regions(bam_file: &str, bed: Option<&String>) -> Vec<(String, u32, u64, u64)> {
+    let bam_reader = IndexedReader::from_path(bam_file)
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {bam_file}")));
+    let header = bam_reader.header();
+    match bed {
+        None => (0..header.target_count())
+            .map(|tid| {
+                (
+                    String::from_utf8_lossy(header.tid2name(tid)).to_string(),
+                    tid,
+                    0,
+                    header.target_len(tid).unwrap_or(0),
+                )
+            })
+            .collect(),
+        Some(bed) => {
+            let ReadsInBam::Regions(bed_regions) = ReadsInBam::from_bed(bed) else {
+                unreachable!()
+            };
+            bed_regions
+                .into_iter()
+                .map(|(contig, start, end)| {
+                    let tid = header.tid(contig.as_bytes()).unwrap_or_else(|| {
+                        quit_with_error(&format!("Contig {contig} in {bed} not found in bam header"))
+                    });
+                    let contig_len = header.target_len(tid).unwrap_or(0);
+                    if start >= end || end as u64 > contig_len {
+                        quit_with_error(&format!(
+                            "Bad region in {bed}: {contig}:{start}-{end}, contig length is {contig_len}"
+                        ))
+                    }
+                    (contig, tid, start as u64, end as u64)
+                })
+                .collect()
+        }
+    }
+}
+
+fn uniformity_html_div(
+    total_histogram: &DepthHistogram,
+    regions: &[(String, u32, u64, u64)],
+    histograms: &[DepthHistogram],
+) -> String {
+    let mut plot = Plot::new();
+    // fraction of target bases covered by at least x times mean depth
+    let mean = total_histogram.mean();
+    let max_depth = total_histogram.counts.len();
+    let depths = (0..max_depth).collect::<Vec<_>>();
+    let fractions = depths
+        .iter()
+        .map(|x| total_histogram.fraction_at_least(*x))
+        .collect::<Vec<_>>();
+    let normalized_depths = depths
+        .iter()
+        .map(|x| if mean > 0.0 { *x as f64 / mean } else { 0.0 })
+        .collect::<Vec<_>>();
+    let cumulative_trace = Scatter::new(normalized_depths, fractions)
+        .mode(Mode::Lines)
+        .name("Cumulative")
+        .custom_data(depths.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        .hover_template(
+            "<b>Depth</b>: %{customdata}X (%{x:.2} x mean)<br>\
+            <b>FractionOfBases</b>: %{y:.4f}\
+            <extra></extra>",
+        )
+        .x_axis("x1")
+        .y_axis("y1")
+        .show_legend(false)
+        .line(Line::new().color(NamedColor::RoyalBlue));
+    plot.add_trace(cumulative_trace);
+
+    let region_names = regions
+        .iter()
+        .map(|(contig, _, start, end)| format!("{contig}:{start}-{end}"))
+        .collect::<Vec<_>>();
+    let region_means = histograms.iter().map(|x| x.mean()).collect::<Vec<_>>();
+    let region_trace = Bar::new(region_names, region_means)
+        .hover_template("<b>%{x}</b><br><b>MeanDepth</b>: %{y:.2f}<extra></extra>")
+        .x_axis("x2")
+        .y_axis("y2")
+        .show_legend(false)
+        .marker(Marker::new().color(NamedColor::RoyalBlue));
+    plot.add_trace(region_trace);
+
+    let layout = Layout::new()
+        .hover_mode(HoverMode::Closest)
+        .grid(
+            LayoutGrid::new()
+                .rows(1)
+                .columns(2)
+                .x_gap(0.1)
+                .pattern(GridPattern::Independent),
+        )
+        .template(BuiltinTheme::PlotlyWhite.build())
+        .x_axis(
+            Axis::new()
+                .title("Depth / MeanDepth")
+                .line_color("black")
+                .show_grid(false)
+                .line_width(1),
+        )
+        .y_axis(
+            Axis::new()
+                .title("FractionOfBases >= Depth")
+                .line_color("black")
+                .line_width(1),
+        )
+        .x_axis2(
+            Axis::new()
+                .title("Region")
+                .line_color("black")
+                .show_grid(false)
+                .show_tick_labels(regions.len() <= 50)
+                .line_width(1),
+        )
+        .y_axis2(
+            Axis::new()
+                .title("MeanDepth")
+                .line_color("black")
+                .line_width(1),
+        )
+        .height(500);
+    plot.set_layout(layout);
+    plot.to_inline_html(Some("coverage_uniformity"))
+}
+
+fn write_coverage_html(
+    name: &str,
+    thresholds: &[usize],
+    total_histogram: &DepthHistogram,
+    regions: &[(String, u32, u64, u64)],
+    histograms: &[DepthHistogram],
+    html_file: &str,
+) {
+    let mut html = html_head(
+        &format!("{name} Coverage Report"),
+        &format!("{name} Coverage Report"),
+    );
+    html.push_str(
+        r#"
+        <div class="section">
+            <div class="section-header">📊 Coverage Statistics</div>
+            <div class="stats-grid">
+        "#,
+    );
+    let mut items = vec![
+        ("Regions".to_string(), regions.len().to_string()),
+        (
+            "TargetBases".to_string(),
+            total_histogram.total_bases().to_string(),
+        ),
+        (
+            "MeanDepth".to_string(),
+            format!("{:.2}", total_histogram.mean()),
+        ),
+        (
+            "MedianDepth".to_string(),
+            total_histogram.median().to_string(),
+        ),
+    ];
+    for threshold in thresholds {
+        items.push((
+            format!("Bases >= {threshold}X"),
+            format!(
+                "{:.2}%",
+                total_histogram.fraction_at_least(*threshold) * 100.0
+            ),
+        ));
+    }
+    for (key, value) in items {
+        html.push_str(&format!(
+            r#"
+                <div class="stat-card">
+                    <div class="label">{key}</div>
+                    <div class="value">{value}</div>
+                </div>
+            "#
+        ))
+    }
+    html.push_str("</div>\n</div>\n");
+    html.push_str(&html_chart_section(
+        "📊 Coverage Uniformity",
+        &uniformity_html_div(total_histogram, regions, histograms),
+    ));
+    html.push_str(HTML_TAIL);
+    std::fs::write(html_file, html).unwrap_or_else(|x| {
+        quit_with_error(&format!(
+            "{x}, Failed to write contents into report file: {html_file}"
+        ))
+    });
+}
+
+pub fn run_coverage(coverage_cmd: &ArgMatches) {
+    let input = coverage_cmd.get_one::<String>("input").unwrap();
+    let bed = coverage_cmd.get_one::<String>("bed");
+    let output = coverage_cmd.get_one::<String>("output").unwrap();
+    let bedgraph = coverage_cmd.get_one::<String>("bedgraph");
+    let report = coverage_cmd.get_one::<String>("report");
+    let name = coverage_cmd.get_one::<String>("name").unwrap();
+    let min_mapq = *coverage_cmd.get_one::<u8>("min_mapq").unwrap();
+    let exclude_flag = *coverage_cmd.get_one::<u16>("exclude_flag").unwrap();
+    let thresholds = coverage_cmd.get_one::<Vec<usize>>("thresholds").unwrap();
+    let thread = *coverage_cmd.get_one::<u16>("thread").unwrap() as usize;
+    let index = coverage_cmd.get_flag("index");
+
+    match check_input_type(input) {
+        InputType::IndexedBam => {}
+        InputType::SortedUnindexedBam if index => {
+            crate::bam::index_bam(input, thread)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to index {input}")));
+        }
+        InputType::SortedUnindexedBam => quit_with_error(&format!(
+            "{input} is sorted but not indexed, index it firstly or use --index"
+        )),
+        _ => quit_with_error("coverage only supports sorted and indexed bam file"),
+    }
+    check_output_file(output);
+    for file in [bedgraph, report].into_iter().flatten() {
+        check_output_file(file)
+    }
+
+    let co = CoverageOption {
+        min_mapq,
+        exclude_flag,
+        bedgraph: bedgraph.is_some(),
+    };
+    let regions = target_regions(input, bed);
+    let chunks = split_regions(
+        &regions
+            .iter()
+            .map(|(_, tid, start, end)| (*tid, *start, *end))
+            .collect::<Vec<_>>(),
+    );
+
+    let input_string = input.to_string();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(thread)
+        .start_handler(move |_| {
+            let reader = IndexedReader::from_path(&input_string).unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, Failed to read {input_string}"))
+            });
+            COVERAGE_BAM_READER.set(reader);
+        })
+        .thread_name(|x| format!("Thread: {x}"))
+        .build()
+        .expect("thread pool builder failed for run_coverage function");
+
+    let mut histograms = vec![DepthHistogram::default(); regions.len()];
+    let mut bedgraph_writer = bedgraph.map(|x| {
+        BufWriter::new(
+            File::create(x)
+                .unwrap_or_else(|e| quit_with_error(&format!("{e}, Failed to create {x}"))),
+        )
+    });
+    // chunks are computed batch by batch and written in order, so that bedGraph runs of the
+    // whole genome are never kept in memory at the same time
+    let write_run = |writer: &mut BufWriter<File>, run: (usize, u64, u64, u32)| {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            regions[run.0].0, run.1, run.2, run.3
+        )
+        .unwrap_or_else(|x| {
+            quit_with_error(&format!("{x}, Failed to write {}", bedgraph.unwrap()))
+        });
+    };
+    // the last run of previous chunk, it may be extended by the first run of next chunk
+    let mut pending_run: Option<(usize, u64, u64, u32)> = None;
+    for batch in chunks.chunks(thread * 4) {
+        let chunk_depths: Vec<ChunkDepth> = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|chunk| {
+                    COVERAGE_BAM_READER
+                        .with_borrow_mut(|bam_reader| chunk_depth(bam_reader, chunk, &co))
+                })
+                .collect()
+        });
+        for chunk_depth in chunk_depths {
+            histograms[chunk_depth.region_idx] += &chunk_depth.histogram;
+            if let Some(writer) = bedgraph_writer.as_mut() {
+                for (start, end, depth) in chunk_depth.runs {
+                    let run = (chunk_depth.region_idx, start, end, depth);
+                    if let Some(last) = merge_run(&mut pending_run, run) {
+                        write_run(writer, last);
+                    }
+                }
+            }
+        }
+    }
+    if let (Some(writer), Some(last)) = (bedgraph_writer.as_mut(), pending_run) {
+        write_run(writer, last);
+    }
+
+    let mut total_histogram = DepthHistogram::default();
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {output}"))),
+    );
+    let mut contents = String::from("#Contig\tStart\tEnd\tLength\tMeanDepth\tMedianDepth");
+    for threshold in thresholds {
+        contents.push_str(&format!("\tBases>={threshold}X"));
+    }
+    contents.push('\n');
+    for ((contig, _, start, end), histogram) in regions.iter().zip(histograms.iter()) {
+        total_histogram += histogram;
+        contents.push_str(&format!(
+            "{contig}\t{start}\t{end}\t{}\t{:.4}\t{}",
+            end - start,
+            histogram.mean(),
+            histogram.median()
+        ));
+        for threshold in thresholds {
+            contents.push_str(&format!("\t{:.6}", histogram.fraction_at_least(*threshold)));
+        }
+        contents.push('\n');
+    }
+    writer
+        .write_all(contents.as_bytes())
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to write {output}")));
+    if let Some(report) = report {
+        write_coverage_html(
+            name,
+            thresholds,
+            &total_histogram,
+            &regions,
+            &histograms,
+            report,
+        );
+    }
+}
+
+pub fn coverage_cmd() -> Command {
+    Command::new("coverage")
+        .about("compute per-base depth and per-region coverage summary for sorted and indexed bam file")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("the sorted and indexed bam file")
+        )
+        .arg(
+            Arg::new("bed")
+                .short('L')
+                .long("bed")
+                .help("bed file of target regions, the first 3 columns needed. all contigs in bam header will be used if not specified")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output the per-region coverage summary into this tsv file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("bedgraph")
+                .short('b')
+                .long("bedgraph")
+                .help("output per-base depth into this bedGraph file if specified, adjacent bases with the same depth are merged")
+        )
+        .arg(
+            Arg::new("report")
+                .short('r')
+                .long("report")
+                .help("output the html report with coverage uniformity plot if specified")
+        )
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .default_value("test001")
+                .help("this analysis name, will be showed in the title of the html report")
+        )
+        .arg(
+            Arg::new("min_mapq")
+                .short('Q')
+                .long("min_mapq")
+                .default_value("0")
+                .value_parser(value_parser!(u8))
+                .help("alignments with map quality less than this value will not be counted")
+        )
+        .arg(
+            Arg::new("exclude_flag")
+                .short('F')
+                .long("exclude_flag")
+                .default_value("1796")
+                .value_parser(value_parser!(u16))
+                .help("alignments with any of these flag bits will not be counted. the default is unmapped, secondary, qc-fail and duplicate")
+        )
+        .arg(
+            Arg::new("thresholds")
+                .short('T')
+                .long("thresholds")
+                .default_value("1,10,30")
+                .value_parser(|x: &str| {
+                    let mut thresholds = x
+                        .split(',')
+                        .map(|each| {
+                            each.trim().parse::<usize>().unwrap_or_else(|_| {
+                                quit_with_error("Failed to parse usize from --thresholds")
+                            })
+                        })
+                        .collect::<Vec<usize>>();
+                    thresholds.sort();
+                    Result::<Vec<usize>, anyhow::Error>::Ok(thresholds)
+                })
+                .help("report the fraction of bases whose depth is greater than or equal to these values, multiple values can be separated by comma")
+        )
+        .arg(
+            Arg::new("index")
+                .short('I')
+                .long("index")
+                .action(ArgAction::SetTrue)
+                .help("build index firstly for sorted but unindexed bam file [default: false]")
+        )
+        .arg(
+            Arg::new("thread")
+                .short('t')
+                .long("thread")
+                .default_value("1")
+                .value_parser(value_parser!(u16).range(1..))
+                .help("number of threads")
+        )
+}
+
+#[cfg(test)]
+mod coverage_test {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::CigarString;
+
+    fn alignment(name: &str, pos: i64, cigar: Vec<Cigar>, mapq: u8, flags: u16) -> bam::Record {
+        let cigar = CigarString(cigar);
+        let query_len = cigar
+            .iter()
+            .map(|x| match x {
+                Cigar::Match(n) | Cigar::Ins(n) | Cigar::SoftClip(n) => *n as usize,
+                _ => 0,
+            })
+            .sum::<usize>();
+        let mut record = bam::Record::new();
+        record.set(
+            name.as_bytes(),
+            Some(&cigar),
+            &vec![b'A'; query_len],
+            &vec![30; query_len],
+        );
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mapq(mapq);
+        record.set_flags(flags);
+        record
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = DepthHistogram::default();
+        histogram.add(0, 2);
+        histogram.add(1, 3);
+        histogram.add(3, 5);
+        assert_eq!(histogram.total_bases(), 10);
+        assert!((histogram.mean() - 1.8).abs() < 1e-9);
+        assert_eq!(histogram.median(), 1);
+        let fractions = (0..5)
+            .map(|x| histogram.fraction_at_least(x))
+            .collect::<Vec<_>>();
+        assert_eq!(fractions, vec![1.0, 0.8, 0.5, 0.5, 0.0]);
+        histogram.add(3, 1);
+        assert_eq!(histogram.median(), 3);
+
+        let empty = DepthHistogram::default();
+        assert_eq!((empty.median(), empty.fraction_at_least(1)), (0, 0.0));
+    }
+
+    #[test]
+    fn split() {
+        let chunks = split_regions(&[(0, 0, 2_500_000), (1, 100, 200), (0, 5, 5)]);
+        let chunks = chunks
+            .iter()
+            .map(|x| (x.region_idx, x.tid, x.start, x.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                (0, 0, 0, 1_000_000),
+                (0, 0, 1_000_000, 2_000_000),
+                (0, 0, 2_000_000, 2_500_000),
+                (1, 1, 100, 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn depth_across_chunks() {
+        let bam_file =
+            std::env::temp_dir().join(format!("nanofq_coverage_{}.bam", std::process::id()));
+        let bam_file = bam_file.to_str().unwrap();
+        let mut header = bam::Header::new();
+        header.push_record(
+            HeaderRecord::new(b"HD")
+                .push_tag(b"VN", "1.6")
+                .push_tag(b"SO", "coordinate"),
+        );
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 2_500_000),
+        );
+        let mut writer = bam::Writer::from_path(bam_file, &header, bam::Format::Bam).unwrap();
+        for record in [
+            alignment("r1", 999_990, vec![Cigar::Match(20)], 60, 0),
+            // low mapq, duplicate and secondary alignments are not counted
+            alignment("r2", 999_990, vec![Cigar::Match(20)], 5, 0),
+            alignment("r3", 999_990, vec![Cigar::Match(20)], 60, 1024),
+            alignment("r4", 999_990, vec![Cigar::Match(20)], 60, 256),
+            alignment("r5", 999_995, vec![Cigar::Match(10)], 60, 16),
+            alignment(
+                "r6",
+                1_500_000,
+                vec![
+                    Cigar::SoftClip(2),
+                    Cigar::Match(5),
+                    Cigar::Del(3),
+                    Cigar::Match(4),
+                    Cigar::Ins(1),
+                    Cigar::Match(1),
+                ],
+                60,
+                0,
+            ),
+        ] {
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+        crate::bam::index_bam(bam_file, 1).unwrap();
+
+        let co = CoverageOption {
+            min_mapq: 10,
+            exclude_flag: 1796,
+            bedgraph: true,
+        };
+        let mut bam_reader = IndexedReader::from_path(bam_file).unwrap();
+        let mut histogram = DepthHistogram::default();
+        let mut runs = vec![];
+        let mut pending_run = None;
+        for chunk in split_regions(&[(0, 0, 2_500_000)]) {
+            let depth = chunk_depth(&mut bam_reader, &chunk, &co);
+            histogram += &depth.histogram;
+            for (start, end, depth) in depth.runs {
+                runs.extend(merge_run(&mut pending_run, (0, start, end, depth)));
+            }
+        }
+        runs.extend(pending_run);
+        std::fs::remove_file(bam_file).unwrap();
+        std::fs::remove_file(format!("{bam_file}.bai")).unwrap();
+
+        // the runs of both sides of the chunk boundaries are merged
+        let runs = runs
+            .into_iter()
+            .map(|(_, start, end, depth)| (start, end, depth))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            vec![
+                (0, 999_990, 0),
+                (999_990, 999_995, 1),
+                (999_995, 1_000_005, 2),
+                (1_000_005, 1_000_010, 1),
+                (1_000_010, 1_500_000, 0),
+                (1_500_000, 1_500_005, 1),
+                (1_500_005, 1_500_008, 0),
+                (1_500_008, 1_500_013, 1),
+                (1_500_013, 2_500_000, 0),
+            ]
+        );
+        assert_eq!(histogram.counts, vec![2_499_970, 20, 10]);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::fastq::RecordEachStats;
+use crate::utils::format_counts;
+use plotly::common::Mode;
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, HoverMode};
+use plotly::{Layout, Plot, Scatter};
+use rust_htslib::bam::{self, record::Aux};
+use serde_json::{Value, json};
+use statrs::statistics::{Data, Median};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+/// class of a read in dorado duplex basecalling output
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DuplexClass {
+    #[default]
+    Simplex, // dx:i:0 or no dx tag
+    Duplex,       // dx:i:1
+    DuplexParent, // dx:i:-1, simplex read which has a duplex offspring
+}
+
+impl DuplexClass {
+    const ALL: [DuplexClass; 3] = [
+        DuplexClass::Simplex,
+        DuplexClass::Duplex,
+        DuplexClass::DuplexParent,
+    ];
+
+    fn from_dx(dx: i64) -> Self {
+        match dx {
+            1 => DuplexClass::Duplex,
+            -1 => DuplexClass::DuplexParent,
+            _ => DuplexClass::Simplex,
+        }
+    }
+
+    pub fn from_bam_record(record: &bam::Record) -> Self {
+        let dx = match record.aux(b"dx") {
+            Ok(Aux::I8(dx)) => dx as i64,
+            Ok(Aux::U8(dx)) => dx as i64,
+            Ok(Aux::I16(dx)) => dx as i64,
+            Ok(Aux::U16(dx)) => dx as i64,
+            Ok(Aux::I32(dx)) => dx as i64,
+            Ok(Aux::U32(dx)) => dx as i64,
+            _ => 0,
+        };
+        Self::from_dx(dx)
+    }
+
+    /// dorado writes the sam tags into the fastq header, and the name of duplex read is
+    /// the two parent read ids joined by ';'
+    pub fn from_fastq_header(name: &str, description: Option<&str>) -> Self {
+        if let Some(dx) = description.and_then(|x| {
+            x.split_ascii_whitespace()
+                .find_map(|tag| tag.strip_prefix("dx:i:"))
+        }) {
+            return Self::from_dx(dx.parse::<i64>().unwrap_or(0));
+        }
+        if name.contains(';') {
+            DuplexClass::Duplex
+        } else {
+            DuplexClass::Simplex
+        }
+    }
+
+    /// parse the class written by Display
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "simplex" => Some(DuplexClass::Simplex),
+            "duplex" => Some(DuplexClass::Duplex),
+            "duplex_parent" => Some(DuplexClass::DuplexParent),
+            _ => None,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            DuplexClass::Simplex => 0,
+            DuplexClass::Duplex => 1,
+            DuplexClass::DuplexParent => 2,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            DuplexClass::Simplex => "Simplex",
+            DuplexClass::Duplex => "Duplex",
+            DuplexClass::DuplexParent => "DuplexParent",
+        }
+    }
+}
+
+impl Display for DuplexClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let class = match self {
+            DuplexClass::Simplex => "simplex",
+            DuplexClass::Duplex => "duplex",
+            DuplexClass::DuplexParent => "duplex_parent",
+        };
+        write!(f, "{class}")
+    }
+}
+
+/// mark the simplex reads whose ids appear in the name of a duplex read as parents,
+/// for the parents without dx:i:-1 tag, such as the reads in fastq converted by samtools
+pub fn mark_duplex_parents(all_stats: &mut [RecordEachStats]) {
+    let parents = all_stats
+        .iter()
+        .filter(|x| x.duplex == DuplexClass::Duplex && x.name.contains(';'))
+        .flat_map(|x| x.name.split(';').map(|id| id.to_string()))
+        .collect::<HashSet<String>>();
+    if parents.is_empty() {
+        return;
+    }
+    all_stats
+        .iter_mut()
+        .filter(|x| x.duplex == DuplexClass::Simplex && parents.contains(&x.name))
+        .for_each(|x| x.duplex = DuplexClass::DuplexParent);
+}
+
+#[derive(Debug, Clone, Default)]
+struct DuplexClassStats {
+    reads: usize,
+    bases: usize,
+    quals: Vec<f32>,
+}
+
+impl DuplexClassStats {
+    fn mean_qual(&self) -> f64 {
+        if self.quals.is_empty() {
+            0.0
+        } else {
+            self.quals.iter().map(|x| *x as f64).sum::<f64>() / self.quals.len() as f64
+        }
+    }
+
+    fn median_qual(&self) -> f64 {
+        if self.quals.is_empty() {
+            0.0
+        } else {
+            Data::new(self.quals.iter().map(|x| *x as f64).collect::<Vec<_>>()).median()
+        }
+    }
+}
+
+/// reads, bases and quality of simplex, duplex and duplex parent reads
+#[derive(Debug, Clone, Default)]
+pub struct DuplexStats {
+    classes: [DuplexClassStats; 3],
+    parents_excluded: bool,
+}
+
+impl DuplexStats {
+    pub fn from_stats(all_stats: &[RecordEachStats], parents_excluded: bool) -> Self {
+        let mut duplex_stats = DuplexStats {
+            parents_excluded,
+            ..Default::default()
+        };
+        for each_stats in all_stats {
+            let class_stats = &mut duplex_stats.classes[each_stats.duplex.index()];
+            class_stats.reads += 1;
+            class_stats.bases += each_stats.length as usize;
+            class_stats.quals.push(each_stats.qual);
+        }
+        duplex_stats
+    }
+
+    /// no duplex read or duplex parent found
+    pub fn is_empty(&self) -> bool {
+        self.classes[1].reads == 0 && self.classes[2].reads == 0
+    }
+
+    /// each duplex read consumes two simplex reads, so duplex rate is
+    /// 2 * duplex reads / (simplex reads + duplex parent reads)
+    pub fn duplex_rate(&self) -> f64 {
+        let simplex_reads = self.classes[0].reads + self.classes[2].reads;
+        if simplex_reads == 0 {
+            0.0
+        } else {
+            2.0 * self.classes[1].reads as f64 / simplex_reads as f64
+        }
+    }
+
+    /// duplex bases / (simplex bases + duplex bases), parents are not counted
+    pub fn duplex_yield_fraction(&self) -> f64 {
+        let bases = self.classes[0].bases + self.classes[1].bases;
+        if bases == 0 {
+            0.0
+        } else {
+            self.classes[1].bases as f64 / bases as f64
+        }
+    }
+
+    /// Class, Reads, Bases, MeanQual, MedianQual
+    pub fn class_rows(&self) -> Vec<(&'static str, usize, usize, f64, f64)> {
+        DuplexClass::ALL
+            .iter()
+            .map(|class| {
+                let class_stats = &self.classes[class.index()];
+                (
+                    class.label(),
+                    class_stats.reads,
+                    class_stats.bases,
+                    class_stats.mean_qual(),
+                    class_stats.median_qual(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "DuplexRate": self.duplex_rate(),
+            "DuplexBases": self.classes[1].bases,
+            "DuplexYieldFraction": self.duplex_yield_fraction(),
+            "DuplexParentsExcluded": self.parents_excluded,
+            "Classes": self
+                .class_rows()
+                .into_iter()
+                .map(|(class, reads, bases, mean_qual, median_qual)| {
+                    json!({
+                        "Class": class,
+                        "ReadsNumber": reads,
+                        "BasesNumber": bases,
+                        "MeanQual": mean_qual,
+                        "MedianQual": median_qual,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn dict_basic_info(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("DuplexRate", format!("{:.2}%", self.duplex_rate() * 100.0)),
+            (
+                "DuplexYield",
+                format!(
+                    "{:.2}Mb({:.2}%)",
+                    self.classes[1].bases as f64 / 1_000_000.0,
+                    self.duplex_yield_fraction() * 100.0
+                ),
+            ),
+            ("DuplexReads", format_counts(self.classes[1].reads)),
+            ("DuplexParentReads", format_counts(self.classes[2].reads)),
+            (
+                "DuplexParentsExcluded",
+                if self.parents_excluded { "Yes" } else { "No" }.to_string(),
+            ),
+        ]
+    }
+
+    /// read quality distribution of each class
+    pub fn html_div(&self, bins: usize) -> String {
+        let max_qual = self
+            .classes
+            .iter()
+            .flat_map(|x| x.quals.iter())
+            .fold(1.0f32, |acc, x| acc.max(*x)) as f64;
+        let bin_width = max_qual / bins as f64;
+        let mut plot = Plot::new();
+        for class in DuplexClass::ALL {
+            let class_stats = &self.classes[class.index()];
+            if class_stats.reads == 0 {
+                continue;
+            }
+            let mut counts = vec![0usize; bins];
+            for qual in &class_stats.quals {
+                let idx = ((*qual as f64 / bin_width) as usize).min(bins - 1);
+                counts[idx] += 1;
+            }
+            let fractions = counts
+                .iter()
+                .map(|x| *x as f64 / class_stats.reads as f64)
+                .collect::<Vec<_>>();
+            let label = class.label();
+            let trace = Scatter::new(
+                (0..bins)
+                    .map(|x| (x as f64 + 0.5) * bin_width)
+                    .collect::<Vec<_>>(),
+                fractions,
+            )
+            .mode(Mode::Lines)
+            .name(label)
+            .hover_template(format!(
+                "<b>{label}</b><br><b>ReadQuality</b>: %{{x:.2f}}<br>\
+                <b>FractionOfReads</b>: %{{y:.4f}}<extra></extra>"
+            ));
+            plot.add_trace(trace);
+        }
+        let layout = Layout::new()
+            .hover_mode(HoverMode::Closest)
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("ReadQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("FractionOfReads")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(450);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("duplex_quality_distribution"))
+    }
+}
+
+impl Display for DuplexStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "#DuplexSummary\n\
+            DuplexRate\t{:.6}\t# 2*duplex/(simplex+duplex_parent)\n\
+            DuplexBases\t{}\t{:.6}\t# duplex/(simplex+duplex)\n\
+            DuplexParentsExcluded\t{}\n\
+            Class\tReadsNumber\tBasesNumber\tMeanQual\tMedianQual",
+            self.duplex_rate(),
+            self.classes[1].bases,
+            self.duplex_yield_fraction(),
+            self.parents_excluded
+        )?;
+        for (class, reads, bases, mean_qual, median_qual) in self.class_rows() {
+            writeln!(
+                f,
+                "{class}\t{reads}\t{bases}\t{mean_qual:.2}\t{median_qual:.2}"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod duplex_test {
+    use super::*;
+
+    fn stats(name: &str, length: usize, qual: f32, duplex: DuplexClass) -> RecordEachStats {
+        let mut each_stats = RecordEachStats::new(name, length, qual, None);
+        each_stats.duplex = duplex;
+        each_stats
+    }
+
+    #[test]
+    fn from_fastq_header() {
+        let class = DuplexClass::from_fastq_header("read1", Some("qs:i:20 dx:i:-1"));
+        assert_eq!(class, DuplexClass::DuplexParent);
+        let class = DuplexClass::from_fastq_header("read1;read2", Some("dx:i:1"));
+        assert_eq!(class, DuplexClass::Duplex);
+        // the dx tag wins over the name
+        let class = DuplexClass::from_fastq_header("read1;read2", Some("dx:i:0"));
+        assert_eq!(class, DuplexClass::Simplex);
+        // without dx tag, such as fastq converted by samtools
+        let class = DuplexClass::from_fastq_header("read1;read2", Some("qs:i:20"));
+        assert_eq!(class, DuplexClass::Duplex);
+        assert_eq!(
+            DuplexClass::from_fastq_header("read1", None),
+            DuplexClass::Simplex
+        );
+        assert_eq!(
+            DuplexClass::from_fastq_header("read1", Some("dx:i:x")),
+            DuplexClass::Simplex
+        );
+    }
+
+    #[test]
+    fn from_bam_record() {
+        let class = |dx: Option<Aux>| {
+            let mut record = bam::Record::new();
+            record.set(b"read1", None, b"ACGT", &[30; 4]);
+            if let Some(dx) = dx {
+                record.push_aux(b"dx", dx).unwrap();
+            }
+            DuplexClass::from_bam_record(&record)
+        };
+        assert_eq!(class(None), DuplexClass::Simplex);
+        assert_eq!(class(Some(Aux::I8(0))), DuplexClass::Simplex);
+        assert_eq!(class(Some(Aux::I8(1))), DuplexClass::Duplex);
+        assert_eq!(class(Some(Aux::U8(1))), DuplexClass::Duplex);
+        assert_eq!(class(Some(Aux::I8(-1))), DuplexClass::DuplexParent);
+        assert_eq!(class(Some(Aux::I32(-1))), DuplexClass::DuplexParent);
+        // not an integer tag
+        assert_eq!(class(Some(Aux::String("1"))), DuplexClass::Simplex);
+    }
+
+    #[test]
+    fn mark_parents() {
+        let mut all_stats = vec![
+            stats("read1", 100, 10.0, DuplexClass::Simplex),
+            stats("read2", 100, 10.0, DuplexClass::Simplex),
+            stats("read3", 100, 10.0, DuplexClass::Simplex),
+            stats("read1;read2", 100, 20.0, DuplexClass::Duplex),
+            // a duplex read from dx tag without parent ids in its name
+            stats("read4", 100, 20.0, DuplexClass::Duplex),
+        ];
+        mark_duplex_parents(&mut all_stats);
+        let classes = all_stats.iter().map(|x| x.duplex).collect::<Vec<_>>();
+        assert_eq!(
+            classes,
+            vec![
+                DuplexClass::DuplexParent,
+                DuplexClass::DuplexParent,
+                DuplexClass::Simplex,
+                DuplexClass::Duplex,
+                DuplexClass::Duplex,
+            ]
+        );
+    }
+
+    #[test]
+    fn from_stats() {
+        let all_stats = vec![
+            stats("read1", 100, 10.0, DuplexClass::DuplexParent),
+            stats("read2", 100, 12.0, DuplexClass::DuplexParent),
+            stats("read3", 200, 14.0, DuplexClass::Simplex),
+            stats("read4", 100, 20.0, DuplexClass::Duplex),
+        ];
+        let duplex_stats = DuplexStats::from_stats(&all_stats, true);
+        assert!(!duplex_stats.is_empty());
+        // 2 * 1 / (1 + 2)
+        assert!((duplex_stats.duplex_rate() - 2.0 / 3.0).abs() < 1e-9);
+        // 100 / (200 + 100), the parents are not counted
+        assert!((duplex_stats.duplex_yield_fraction() - 1.0 / 3.0).abs() < 1e-9);
+        let rows = duplex_stats.class_rows();
+        assert_eq!((rows[2].0, rows[2].1, rows[2].2), ("DuplexParent", 2, 200));
+        assert!((rows[2].3 - 11.0).abs() < 1e-9);
+        // the duplex summary doesn't depend on whether the parents are excluded
+        let included = DuplexStats::from_stats(&all_stats, false);
+        assert_eq!(duplex_stats.duplex_rate(), included.duplex_rate());
+        assert!(
+            duplex_stats
+                .to_string()
+                .contains("DuplexParentsExcluded\ttrue")
+        );
+        assert!(
+            included
+                .to_string()
+                .contains("DuplexParentsExcluded\tfalse")
+        );
+
+        assert!(DuplexStats::from_stats(&all_stats[2..3], true).is_empty());
+    }
+}
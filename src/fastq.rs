@@ -1,7 +1,16 @@
-use crate::filter::FilterOption;
-use crate::utils::{calculate_quality, complement, find_most_left_rear, find_most_right_front, gc};
+use crate::bam::get_qs_aux;
+use crate::duplex::DuplexClass;
+use crate::filter::{FailReason, FilterOption};
+use crate::modification::ReadMods;
+use crate::profile::PositionProfile;
+use crate::utils::{
+    QscoreSource, calculate_quality, complement, complexity, find_most_left_rear,
+    find_most_right_front, gc, get_bases, longest_homopolymer, min_window_quality,
+    poly_tail_length, quality_trim_range, reported_qscore_from_header,
+};
 use bio::pattern_matching::myers::Myers;
 use needletail::{Sequence, parse_fastx_file};
+use rust_htslib::bam;
 use std::fmt::{Display, Formatter};
 use std::io::Write;
 use std::sync::mpsc;
@@ -9,6 +18,11 @@ use std::sync::mpsc::Receiver;
 use std::thread::JoinHandle;
 use std::{io, str, thread};
 
+/// QUAL * of sam is stored as 0xff in bam
+pub fn has_bam_quality(record: &bam::Record) -> bool {
+    record.qual().first().is_none_or(|x| *x != 0xff)
+}
+
 #[derive(Debug)]
 pub struct FastqRecord {
     pub name: String,
@@ -30,31 +44,152 @@ impl FastqRecord {
         }
     }
 
-    pub fn stats(self, use_dorado_q: bool, use_gc: bool) -> RecordEachStats {
-        RecordEachStats {
-            name: self.name,
-            length: self.seq.len() as u32,
-            qual: calculate_quality(self.quality, use_dorado_q, false),
-            gc: if use_gc { Some(gc(self.seq)) } else { None },
+    /// the read in bam as it was sequenced, reverse strand alignments are reverse complemented
+    /// back. qs tag is kept in description so that --qscore_source works. Missing base quality
+    /// (QUAL is *) becomes the lowest quality '!', see has_bam_quality
+    pub fn from_bam_record(record: &bam::Record) -> Self {
+        let mut seq = record.seq().as_bytes();
+        let mut quality = if has_bam_quality(record) {
+            record
+                .qual()
+                .iter()
+                .map(|x| x.saturating_add(33).min(126))
+                .collect::<Vec<_>>()
+        } else {
+            vec![b'!'; seq.len()]
+        };
+        if record.is_reverse() {
+            // bam sequence may contain N and other IUPAC codes
+            seq.iter_mut()
+                .for_each(|base| *base = *get_bases().get(base).unwrap_or(&b'N'));
+            seq.reverse();
+            quality.reverse();
+        }
+        FastqRecord {
+            name: String::from_utf8_lossy(record.qname()).to_string(),
+            description: get_qs_aux(record).map(|qs| format!("qs:f:{qs}")),
+            seq,
+            quality,
+        }
+    }
+
+    pub fn stats(
+        self,
+        use_dorado_q: bool,
+        features: SeqFeatures,
+        qscore_source: QscoreSource,
+    ) -> RecordEachStats {
+        let duplex = DuplexClass::from_fastq_header(&self.name, self.description.as_deref());
+        let reported_qual = self.reported_qual();
+        let mut each_stats = RecordEachStats::new(
+            self.name,
+            self.seq.len(),
+            calculate_quality(self.quality, use_dorado_q, false),
+            if features.gc {
+                Some(gc(&self.seq))
+            } else {
+                None
+            },
+        );
+        each_stats.set_seq_features(&self.seq, features);
+        each_stats.duplex = duplex;
+        each_stats.set_reported_qual(reported_qual, qscore_source);
+        each_stats
+    }
+
+    pub fn add_to_profile(&self, profile: &mut PositionProfile) {
+        profile.add_read(&self.seq, &self.quality, 33, false)
+    }
+
+    /// crop --headcrop and --tailcrop bases, then trim the ends until a window reaches
+    /// --trim_qual. The read becomes empty if nothing is left. Returns the number of bases removed
+    /// from the start and the end
+    pub fn trim(&mut self, fo: &FilterOption) -> (usize, usize) {
+        let read_length = self.seq.len();
+        let end = read_length.saturating_sub(fo.tailcrop);
+        let mut range = (fo.headcrop.min(end), end);
+        if let Some(trim_qual) = fo.trim_qual {
+            range = quality_trim_range(&self.quality[range.0..range.1], fo.trim_window, trim_qual)
+                .map_or((0, 0), |(start, end)| (range.0 + start, range.0 + end));
+        }
+        if range != (0, read_length) {
+            self.seq.truncate(range.1);
+            self.seq.drain(..range.0);
+            self.quality.truncate(range.1);
+            self.quality.drain(..range.0);
         }
+        (range.0, read_length - range.1)
     }
 
-    pub fn is_passed(&self, fo: &FilterOption) -> bool {
+    /// the first name filter the read fails, include names, exclude names and then name regex
+    pub fn name_fail_reason(&self, fo: &FilterOption) -> Option<FailReason> {
+        if fo
+            .include_names
+            .as_ref()
+            .is_some_and(|x| !x.contains(&self.name))
+        {
+            return Some(FailReason::NotIncluded);
+        }
+        if fo
+            .exclude_names
+            .as_ref()
+            .is_some_and(|x| x.contains(&self.name))
+        {
+            return Some(FailReason::Excluded);
+        }
+        if fo
+            .name_regex
+            .as_ref()
+            .is_some_and(|x| !x.is_match(&self.name))
+        {
+            return Some(FailReason::NameNotMatched);
+        }
+        None
+    }
+
+    /// the first criterion the read fails in the order of name, length, quality, gc, complexity,
+    /// homopolymer and window quality, None if it passes all of them
+    pub fn fail_reason(&self, fo: &FilterOption) -> Option<FailReason> {
+        if let Some(reason) = self.name_fail_reason(fo) {
+            return Some(reason);
+        }
         let read_length = self.seq.len() as u32;
-        if read_length > fo.max_len || read_length < fo.min_len {
-            return false;
+        if read_length < fo.min_len {
+            return Some(FailReason::TooShort);
+        }
+        if read_length > fo.max_len {
+            return Some(FailReason::TooLong);
         }
-        let read_qual = self.qual(fo.use_dorado_q);
-        if read_qual > fo.max_qual || read_qual < fo.min_qual {
-            return false;
+        let read_qual =
+            fo.qscore_source
+                .choose(self.reported_qual(), self.qual(fo.use_dorado_q), &self.name);
+        if read_qual < fo.min_qual {
+            return Some(FailReason::LowQual);
+        }
+        if read_qual > fo.max_qual {
+            return Some(FailReason::HighQual);
         }
         if fo.use_gc {
             let gc = self.gc();
             if gc > fo.max_gc || gc < fo.min_gc {
-                return false;
+                return Some(FailReason::GcOutOfRange);
             }
         }
-        true
+        if fo.min_complexity.is_some_and(|x| self.complexity() < x) {
+            return Some(FailReason::LowComplexity);
+        }
+        if fo
+            .max_homopolymer_frac
+            .is_some_and(|x| self.homopolymer_frac() > x)
+        {
+            return Some(FailReason::LongHomopolymer);
+        }
+        if let Some(min_window_qual) = fo.min_window_qual {
+            if self.min_window_qual(fo.trim_window) < min_window_qual {
+                return Some(FailReason::LowWindowQual);
+            }
+        }
+        None
     }
 
     pub fn len(&self) -> u32 {
@@ -65,10 +200,33 @@ impl FastqRecord {
         calculate_quality(&self.quality, use_dorado_q, false)
     }
 
+    /// the lowest mean-error Q of all windows of window_size bases
+    pub fn min_window_qual(&self, window_size: usize) -> f32 {
+        min_window_quality(&self.quality, window_size)
+    }
+
+    pub fn reported_qual(&self) -> Option<f32> {
+        reported_qscore_from_header(self.description.as_deref())
+    }
+
     pub fn gc(&self) -> f32 {
         gc(&self.seq)
     }
 
+    pub fn complexity(&self) -> f32 {
+        complexity(&self.seq)
+    }
+
+    /// poly(A) tail at the 3' end or poly(T) at the 5' end, 0 if no tail
+    pub fn poly_tail(&self) -> usize {
+        poly_tail_length(&self.seq)
+    }
+
+    /// the longest homopolymer length divided by read length
+    pub fn homopolymer_frac(&self) -> f32 {
+        longest_homopolymer(&self.seq) as f32 / self.seq.len().max(1) as f32
+    }
+
     pub fn reversed(&mut self) {
         self.name.push_str("_rc");
         self.seq
@@ -78,18 +236,48 @@ impl FastqRecord {
         self.quality.reverse();
     }
 
+    /// bases [start, end) of the read as a new record named {name}_{number}, the description is kept
+    pub fn segment(&self, number: usize, start: usize, end: usize) -> FastqRecord {
+        FastqRecord {
+            name: format!("{}_{number}", self.name),
+            description: self.description.clone(),
+            seq: self.seq[start..end].to_vec(),
+            quality: self.quality[start..end].to_vec(),
+        }
+    }
+
+    /// keep bases [start, end) of the read
+    pub fn crop(&mut self, start: usize, end: usize) {
+        self.seq.truncate(end);
+        self.quality.truncate(end);
+        self.seq.drain(..start);
+        self.quality.drain(..start);
+    }
+
     pub fn write(&self, writer: &mut dyn Write) -> Result<(), io::Error> {
+        self.write_with_tag(writer, None)
+    }
+
+    /// write the record with an optional tag, such as fr:Z:too_short, appended to the header
+    pub fn write_with_tag(
+        &self,
+        writer: &mut dyn Write,
+        tag: Option<&str>,
+    ) -> Result<(), io::Error> {
         let (sep, description) = if self.description.is_some() {
             (" ", self.description.as_ref().unwrap().as_ref())
         } else {
             ("", "")
         };
+        let (tag_sep, tag) = tag.map_or(("", ""), |x| (" ", x));
         write!(
             writer,
-            "@{}{}{}\n{}\n+\n{}\n",
+            "@{}{}{}{}{}\n{}\n+\n{}\n",
             self.name,
             sep,
             description,
+            tag_sep,
+            tag,
             unsafe { str::from_utf8_unchecked(&self.seq) },
             unsafe { str::from_utf8_unchecked(&self.quality) }
         )?;
@@ -233,12 +421,30 @@ impl FastqRecord {
     }
 }
 
+/// the optional per-read measures computed from the sequence in stats
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeqFeatures {
+    pub gc: bool,
+    pub complexity: bool, // trinucleotide entropy and longest homopolymer
+    pub poly_tail: bool,  // poly(A)/poly(T) tail length
+}
+
 #[derive(Clone)]
 pub struct RecordEachStats {
     pub name: String,
     pub length: u32,
     pub qual: f32,
     pub gc: Option<f32>,
+    pub complexity: Option<f32>,              // only with --complexity
+    pub longest_homopolymer: Option<u32>,     // only with --complexity
+    pub poly_tail: Option<u32>,               // only with --poly_tail, 0 if no tail found
+    pub identity: Option<f32>,                // only for primary alignment of bam
+    pub aligned_fraction: Option<f32>,        // only for primary alignment of bam
+    pub gap_compressed_identity: Option<f32>, // only for primary alignment of bam
+    pub mods: Option<ReadMods>,               // only for bam with --mods
+    pub duplex: DuplexClass,
+    pub reported_qual: Option<f32>, // qs tag of bam or qs= in fastq header
+    pub recomputed_qual: f32,       // mean-error Q from the quality string
 }
 
 impl Display for RecordEachStats {
@@ -253,8 +459,41 @@ impl RecordEachStats {
             length: length as u32,
             qual,
             gc,
+            complexity: None,
+            longest_homopolymer: None,
+            poly_tail: None,
+            identity: None,
+            aligned_fraction: None,
+            gap_compressed_identity: None,
+            mods: None,
+            duplex: DuplexClass::Simplex,
+            reported_qual: None,
+            recomputed_qual: qual,
         }
     }
+
+    /// compute the optional measures except gc content, which is given in new
+    pub fn set_seq_features(&mut self, seq: &[u8], features: SeqFeatures) {
+        if features.complexity {
+            self.complexity = Some(complexity(seq));
+            self.longest_homopolymer = Some(longest_homopolymer(seq) as u32);
+        }
+        if features.poly_tail {
+            self.poly_tail = Some(poly_tail_length(seq) as u32);
+        }
+    }
+
+    /// the longest homopolymer length divided by read length
+    pub fn homopolymer_frac(&self) -> Option<f32> {
+        self.longest_homopolymer
+            .map(|x| x as f32 / self.length.max(1) as f32)
+    }
+
+    /// keep the reported quality and choose the read quality by qscore source
+    pub fn set_reported_qual(&mut self, reported_qual: Option<f32>, qscore_source: QscoreSource) {
+        self.reported_qual = reported_qual;
+        self.qual = qscore_source.choose(reported_qual, self.recomputed_qual, &self.name);
+    }
 }
 
 impl Display for FastqRecord {
@@ -349,3 +588,66 @@ pub fn chunk_records_from_fastq(
     });
     (handle, receiver)
 }
+
+#[cfg(test)]
+mod fastq_test {
+    use super::*;
+
+    #[test]
+    fn from_bam_record() {
+        let mut record = bam::Record::new();
+        record.set(b"read1", None, b"ACGNNT", &[10, 20, 30, 40, 50, 60]);
+        let read = FastqRecord::from_bam_record(&record);
+        assert_eq!(read.seq, b"ACGNNT");
+        assert_eq!(read.quality, b"+5?IS]");
+
+        // reverse strand with N is complemented back
+        record.set_flags(16);
+        let read = FastqRecord::from_bam_record(&record);
+        assert_eq!(read.seq, b"ANNCGT");
+        assert_eq!(read.quality, b"]SI?5+");
+
+        // QUAL * is stored as 0xff
+        record.set(b"read1", None, b"ACGNNT", &[0xff; 6]);
+        assert!(!has_bam_quality(&record));
+        let read = FastqRecord::from_bam_record(&record);
+        assert_eq!(read.seq, b"ANNCGT");
+        assert_eq!(read.quality, b"!!!!!!");
+    }
+
+    #[test]
+    fn homopolymer_frac() {
+        let read = FastqRecord::new("read1", None, b"ACGGGT".to_vec(), b"++++++".to_vec());
+        assert_eq!(read.homopolymer_frac(), 0.5);
+        let read = FastqRecord::new("read1", None, vec![], vec![]);
+        assert_eq!(read.homopolymer_frac(), 0.0);
+    }
+
+    #[test]
+    fn fail_reason_order() {
+        let mut fo = FilterOption::pass_through(false);
+        fo.max_len = u32::MAX;
+        fo.max_qual = 100.0;
+        fo.max_gc = 1.0;
+        let read = FastqRecord::new("read1", None, vec![b'A'; 100], vec![b'+'; 100]);
+        assert_eq!(read.fail_reason(&fo), None);
+
+        // all A fails both, complexity is checked before homopolymer
+        fo.max_homopolymer_frac = Some(0.5);
+        assert_eq!(read.fail_reason(&fo), Some(FailReason::LongHomopolymer));
+        fo.min_complexity = Some(0.5);
+        assert_eq!(read.fail_reason(&fo), Some(FailReason::LowComplexity));
+        // gc is checked before complexity
+        fo.use_gc = true;
+        fo.min_gc = 0.4;
+        assert_eq!(read.fail_reason(&fo), Some(FailReason::GcOutOfRange));
+        fo.use_gc = false;
+        // window quality is checked after homopolymer
+        fo.min_complexity = None;
+        fo.min_window_qual = Some(20.0);
+        fo.trim_window = 10;
+        assert_eq!(read.fail_reason(&fo), Some(FailReason::LongHomopolymer));
+        fo.max_homopolymer_frac = None;
+        assert_eq!(read.fail_reason(&fo), Some(FailReason::LowWindowQual));
+    }
+}
@@ -1,11 +1,23 @@
-use crate::fastq::{FastqRecord, chunk_records_from_fastq};
+use crate::bam::{clip_read, get_qs_aux, identity_and_aligned_fraction};
+use crate::fastq::{FastqRecord, chunk_records_from_fastq, has_bam_quality};
 use crate::input_type::{InputType, check_input_type};
-use crate::utils::{calculate_quality, check_output_file, collect_fqs_in_dir, gc, positive_f64_parse, quit_with_error};
+use crate::subseq::ReadNames;
+use crate::utils::{
+    QscoreSource, bases_parse, check_output_file, collect_fqs_in_dir, init_log, positive_f64_parse,
+    quit_with_error,
+};
 use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
-use needletail::parser::{LineEnding, write_fastq};
+use log::info;
+use needletail::parser::SequenceRecord;
 use needletail::{Sequence, parse_fastx_file};
 use rayon::prelude::*;
+use regex::Regex;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{self, Read};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
 
 #[derive(Debug, Clone, Default)]
@@ -15,13 +27,71 @@ pub struct FilterOption {
     pub min_qual: f32,
     pub max_qual: f32,
     pub use_dorado_q: bool,
+    pub qscore_source: QscoreSource,
     pub use_gc: bool,
     pub min_gc: f32,
     pub max_gc: f32,
+    pub headcrop: usize,
+    pub tailcrop: usize,
+    pub trim_qual: Option<f32>,
+    pub min_window_qual: Option<f32>,
+    pub trim_window: usize,
+    pub include_names: Option<HashSet<String>>,
+    pub exclude_names: Option<HashSet<String>>,
+    pub name_regex: Option<Regex>,
+    pub min_complexity: Option<f32>,
+    pub max_homopolymer_frac: Option<f32>,
     retain_failed: Option<String>,
+    tag_fail_reason: bool,
+    tag_poly_tail: bool,
+    min_mapq: Option<u8>,
+    min_identity: Option<f32>,
+    min_aligned_fraction: Option<f32>,
+    primary_only: bool,
+    drop_chimeric: bool,
+    include_contigs: Option<HashSet<String>>,
+    exclude_contigs: Option<HashSet<String>>,
+    bam_output: bool,
+    target_bases: Option<u64>,
+    length_weight: f64,
+    qual_weight: f64,
+    window_size: usize,
 }
 
 impl FilterOption {
+    /// no filter, trimming or tag, the reads are only passed through for_each_chunk and
+    /// ReadsWriter, e.g., by nanofq sample
+    pub(crate) fn pass_through(bam_output: bool) -> Self {
+        FilterOption {
+            bam_output,
+            ..Default::default()
+        }
+    }
+
+    /// reads are cropped or trimmed before filtering
+    fn is_trimming(&self) -> bool {
+        self.headcrop > 0 || self.tailcrop > 0 || self.trim_qual.is_some()
+    }
+
+    /// whether the filters read the base qualities of a read, the read quality of
+    /// --qscore_source tag or auto comes from the reported quality if the read has one
+    fn needs_base_quality(&self, has_reported_qual: bool) -> bool {
+        let scored_by_qual = self.target_bases.is_some() && self.qual_weight > 0.0;
+        let recomputed = self.qscore_source == QscoreSource::Recompute || !has_reported_qual;
+        self.trim_qual.is_some()
+            || self.min_window_qual.is_some()
+            || (scored_by_qual && self.window_size > 0)
+            || ((self.min_qual > 0.0 || scored_by_qual) && recomputed)
+    }
+
+    /// unmapped reads fail the alignment filters that need a mapping position or alignment
+    fn requires_alignment(&self) -> bool {
+        self.min_mapq.is_some()
+            || self.min_identity.is_some()
+            || self.min_aligned_fraction.is_some()
+            || self.include_contigs.is_some()
+    }
+
     fn set_failed_fastq_file(&self) -> Box<dyn Write> {
         match &self.retain_failed {
             None => Box::new(std::io::sink()),
@@ -36,192 +106,867 @@ impl FilterOption {
     }
 }
 
-fn fastq_filter(fastq_file: &str, fo: &FilterOption, passed_file: &str) {
-    let retain_failed = fo.retain_failed.is_some();
-    let use_gc = fo.use_gc;
-    let mut failed_writer = fo.set_failed_fastq_file();
-    let mut passed_writer = BufWriter::new(
-        std::fs::File::create(passed_file).expect(&format!("Failed to create {passed_file}")),
-    );
-    let mut records = parse_fastx_file(fastq_file).expect(&format!("Failed to read {fastq_file}"));
-    let mut read_idx = 1;
+/// why a read is not written into the output, filters are checked in this order and only the
+/// first failed one is counted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FailReason {
+    NotIncluded,
+    Excluded,
+    NameNotMatched,
+    TooShort,
+    TooLong,
+    LowQual,
+    HighQual,
+    GcOutOfRange,
+    LowComplexity,
+    LongHomopolymer,
+    LowWindowQual,
+    NotPrimary,
+    Chimeric,
+    Unmapped,
+    LowMapq,
+    LowIdentity,
+    LowAlignedFraction,
+    ContigNotIncluded,
+    ContigExcluded,
+    Subsampled, // passed all thresholds but dropped by --target_bases or --target_coverage
+}
 
-    while let Some(Ok(record)) = records.next() {
-        // check length
-        let read_len = record.num_bases() as u32;
-        if read_len > fo.max_len || read_len < fo.min_len {
-            if retain_failed {
-                write_fastq(
-                    record.id(),
-                    record.sequence(),
-                    record.qual(),
-                    &mut failed_writer,
-                    LineEnding::Unix,
-                )
-                .expect(&format!(
-                    "Failed write {}th record into failed fastq file",
-                    read_idx,
-                ));
-            }
-            continue;
+impl FailReason {
+    const ALL: [FailReason; 20] = [
+        FailReason::NotIncluded,
+        FailReason::Excluded,
+        FailReason::NameNotMatched,
+        FailReason::TooShort,
+        FailReason::TooLong,
+        FailReason::LowQual,
+        FailReason::HighQual,
+        FailReason::GcOutOfRange,
+        FailReason::LowComplexity,
+        FailReason::LongHomopolymer,
+        FailReason::LowWindowQual,
+        FailReason::NotPrimary,
+        FailReason::Chimeric,
+        FailReason::Unmapped,
+        FailReason::LowMapq,
+        FailReason::LowIdentity,
+        FailReason::LowAlignedFraction,
+        FailReason::ContigNotIncluded,
+        FailReason::ContigExcluded,
+        FailReason::Subsampled,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailReason::NotIncluded => "not_in_include_names",
+            FailReason::Excluded => "in_exclude_names",
+            FailReason::NameNotMatched => "name_regex_mismatch",
+            FailReason::TooShort => "too_short",
+            FailReason::TooLong => "too_long",
+            FailReason::LowQual => "low_qual",
+            FailReason::HighQual => "high_qual",
+            FailReason::GcOutOfRange => "gc_out_of_range",
+            FailReason::LowComplexity => "low_complexity",
+            FailReason::LongHomopolymer => "long_homopolymer",
+            FailReason::LowWindowQual => "low_window_qual",
+            FailReason::NotPrimary => "not_primary",
+            FailReason::Chimeric => "chimeric",
+            FailReason::Unmapped => "unmapped",
+            FailReason::LowMapq => "low_mapq",
+            FailReason::LowIdentity => "low_identity",
+            FailReason::LowAlignedFraction => "low_aligned_fraction",
+            FailReason::ContigNotIncluded => "contig_not_included",
+            FailReason::ContigExcluded => "contig_excluded",
+            FailReason::Subsampled => "subsampled",
         }
+    }
 
-        // check quality
-        let quals = record
-            .qual()
-            .expect(&format!("Parse quality failed at {read_idx}th record"));
-        let read_qual = calculate_quality(quals, fo.use_dorado_q, false);
-        if read_qual > fo.max_qual || read_qual < fo.min_qual {
-            println!("{}", str::from_utf8(record.id()).unwrap());
-            if retain_failed {
-                write_fastq(
-                    record.id(),
-                    record.sequence(),
-                    record.qual(),
-                    &mut failed_writer,
-                    LineEnding::Unix,
-                )
-                .expect(&format!(
-                    "Failed write {}th record into failed fastq file",
-                    read_idx,
-                ));
+    fn index(&self) -> usize {
+        Self::ALL.iter().position(|x| x == self).unwrap()
+    }
+}
+
+/// reads and bases removed by each filter, bases of a read are counted after trimming
+#[derive(Debug, Clone, Default)]
+struct FilterReport {
+    input_reads: u64,
+    input_bases: u64,
+    trimmed_reads: u64,
+    trimmed_bases: u64,
+    passed_reads: u64,
+    passed_bases: u64,
+    failed: [(u64, u64); FailReason::ALL.len()],
+}
+
+impl FilterReport {
+    fn add(&mut self, read_length: usize, fail_reason: Option<FailReason>) {
+        self.input_reads += 1;
+        self.input_bases += read_length as u64;
+        match fail_reason {
+            None => {
+                self.passed_reads += 1;
+                self.passed_bases += read_length as u64;
+            }
+            Some(reason) => {
+                let failed = &mut self.failed[reason.index()];
+                failed.0 += 1;
+                failed.1 += read_length as u64;
             }
-            continue;
         }
+    }
 
-        // check gc
-        if use_gc {
-            let gc = gc(record.sequence());
-            if gc > fo.max_gc || gc < fo.min_gc {
-                if retain_failed {
-                    write_fastq(
-                        record.id(),
-                        record.sequence(),
-                        record.qual(),
-                        &mut failed_writer,
-                        LineEnding::Unix,
-                    )
-                    .expect(&format!(
-                        "Failed write {}th record into failed fastq file",
-                        read_idx,
-                    ));
-                }
-                continue;
-            }
+    /// bases removed by --headcrop, --tailcrop and --trim_qual are also counted in input bases
+    fn add_trimmed(&mut self, trimmed: impl Iterator<Item = usize>) {
+        for x in trimmed.filter(|x| *x > 0) {
+            self.trimmed_reads += 1;
+            self.trimmed_bases += x as u64;
+            self.input_bases += x as u64;
+        }
+    }
+
+    fn merge(&mut self, other: &FilterReport) {
+        self.input_reads += other.input_reads;
+        self.input_bases += other.input_bases;
+        self.trimmed_reads += other.trimmed_reads;
+        self.trimmed_bases += other.trimmed_bases;
+        self.passed_reads += other.passed_reads;
+        self.passed_bases += other.passed_bases;
+        for (x, y) in self.failed.iter_mut().zip(other.failed.iter()) {
+            x.0 += y.0;
+            x.1 += y.1;
         }
+    }
 
-        write_fastq(
-            record.id(),
-            record.sequence(),
-            record.qual(),
+    /// (category, reads, bases) of input, trimmed, each fail reason and passed
+    fn rows(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut rows = vec![
+            ("input", self.input_reads, self.input_bases),
+            ("trimmed", self.trimmed_reads, self.trimmed_bases),
+        ];
+        for reason in FailReason::ALL {
+            let (reads, bases) = self.failed[reason.index()];
+            rows.push((reason.name(), reads, bases));
+        }
+        rows.push(("passed", self.passed_reads, self.passed_bases));
+        rows
+    }
+
+    fn bases_fraction(&self, bases: u64) -> f64 {
+        if self.input_bases == 0 {
+            0.0
+        } else {
+            bases as f64 / self.input_bases as f64
+        }
+    }
+
+    fn write_tsv(&self, tsv_file: &str) {
+        let mut writer = BufWriter::new(
+            std::fs::File::create(tsv_file)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to create {tsv_file}"))),
+        );
+        writeln!(writer, "Category\tReads\tBases\tBasesFraction").unwrap();
+        for (category, reads, bases) in self.rows() {
+            writeln!(
+                writer,
+                "{category}\t{reads}\t{bases}\t{:.4}",
+                self.bases_fraction(bases)
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_json(&self, json_file: &str) {
+        let categories = self
+            .rows()
+            .into_iter()
+            .map(|(category, reads, bases)| {
+                json!({
+                    "Category": category,
+                    "Reads": reads,
+                    "Bases": bases,
+                    "BasesFraction": self.bases_fraction(bases),
+                })
+            })
+            .collect::<Vec<_>>();
+        let file = std::fs::File::create(json_file)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to create {json_file}")));
+        serde_json::to_writer_pretty(BufWriter::new(file), &json!({ "Filter": categories }))
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to write {json_file}")));
+    }
+}
+
+fn needletail_to_fastq_record(record: &SequenceRecord, read_idx: usize) -> FastqRecord {
+    let mut headers = record.id().splitn(2, |x| x.is_ascii_whitespace());
+    let name = headers
+        .next()
+        .expect(&format!("Parse read name failed at {read_idx}th record"));
+    let name =
+        std::str::from_utf8(name).expect(&format!("Parse read name failed at {read_idx}th record"));
+    let description = headers
+        .next()
+        .map(|x| std::str::from_utf8(x).unwrap_or_default());
+    let quals = record
+        .qual()
+        .expect(&format!("Parse quality failed at {read_idx}th record"));
+    FastqRecord::new(name, description, record.sequence(), quals)
+}
+
+fn fastq_filter(fastq_file: &str, fo: &FilterOption, passed_file: &str) -> FilterReport {
+    let mut failed_writer = fo.set_failed_fastq_file();
+    let mut passed_writer = BufWriter::new(
+        std::fs::File::create(passed_file).expect(&format!("Failed to create {passed_file}")),
+    );
+    let mut report = FilterReport::default();
+    let mut records = parse_fastx_file(fastq_file).expect(&format!("Failed to read {fastq_file}"));
+    let mut read_idx = 1;
+
+    while let Some(Ok(record)) = records.next() {
+        let record = needletail_to_fastq_record(&record, read_idx);
+        let fail_reason = record.fail_reason(fo);
+        write_classified_records(
+            std::slice::from_ref(&record),
+            &[fail_reason],
+            fo,
+            &mut report,
             &mut passed_writer,
-            LineEnding::Unix,
-        )
-        .expect(&format!(
-            "Failed write {}th record into failed fastq file",
-            read_idx,
-        ));
+            &mut failed_writer,
+        );
         read_idx += 1
     }
+    report
 }
 
 fn fastq_filter_out_records(
     fastq_file: &str,
     fo: &FilterOption,
-) -> (Vec<FastqRecord>, Vec<FastqRecord>) {
+) -> (
+    Vec<FastqRecord>,
+    Vec<(FastqRecord, FailReason)>,
+    FilterReport,
+) {
     let retain_failed = fo.retain_failed.is_some();
     let mut failed_records = vec![];
     let mut passed_records = vec![];
+    let mut report = FilterReport::default();
     let mut records = parse_fastx_file(fastq_file).expect(&format!("Failed to read {fastq_file}"));
     let mut read_idx = 1;
     while let Some(Ok(record)) = records.next() {
-        let mut headers = record.id().splitn(2, |x| x.is_ascii_whitespace());
-        let name = headers
-            .next()
-            .expect(&format!("Parse read name failed at {read_idx}th record"));
-        let name =
-            str::from_utf8(name).expect(&format!("Parse read name failed at {read_idx}th record"));
-        let seq = record.sequence();
-
-        let mut is_passed = true;
-        // check length
-        let read_length = record.num_bases() as u32;
-        if read_length > fo.max_len || read_length < fo.min_len {
-            is_passed = false;
-            if !retain_failed {
-                continue;
+        let record = needletail_to_fastq_record(&record, read_idx);
+        let fail_reason = record.fail_reason(fo);
+        report.add(record.seq.len(), fail_reason);
+        match fail_reason {
+            None => passed_records.push(record),
+            Some(reason) => {
+                if retain_failed {
+                    failed_records.push((record, reason))
+                }
             }
         }
+        read_idx += 1;
+    }
+    (passed_records, failed_records, report)
+}
 
-        // check quality
-        let quals = record
-            .qual()
-            .expect(&format!("Parse quality failed at {read_idx}th record"));
-        let read_qual = calculate_quality(quals, fo.use_dorado_q, false);
-        if read_qual > fo.max_qual || read_qual < fo.min_qual {
-            is_passed = false;
-            if !retain_failed {
-                continue;
-            }
-        }
+/// tags appended to the fastq header, fr:Z:<reason> with --fail_reason and pt:i:<length> with
+/// --poly_tail, separated by space
+fn header_tag(
+    record: &FastqRecord,
+    fail_reason: Option<FailReason>,
+    fo: &FilterOption,
+) -> Option<String> {
+    let mut tags = vec![];
+    if let Some(reason) = fail_reason.filter(|_| fo.tag_fail_reason) {
+        tags.push(format!("fr:Z:{}", reason.name()));
+    }
+    if fo.tag_poly_tail {
+        tags.push(format!("pt:i:{}", record.poly_tail()));
+    }
+    (!tags.is_empty()).then(|| tags.join(" "))
+}
 
-        // check gc
-        if fo.use_gc {
-            let gc_content = gc(seq);
-            if gc_content > fo.max_gc || gc_content < fo.min_gc {
-                is_passed = false;
-                if !retain_failed {
-                    continue;
-                }
-            }
-        }
+fn write_passed_record(record: &FastqRecord, fo: &FilterOption, passed_writer: &mut dyn Write) {
+    let tag = header_tag(record, None, fo);
+    record
+        .write_with_tag(passed_writer, tag.as_deref())
+        .unwrap();
+}
 
-        let description = headers
-            .next()
-            .map(|x| str::from_utf8(x).unwrap_or_default());
-        let record = FastqRecord::new(name, description, seq, quals);
-        if is_passed {
-            passed_records.push(record)
-        } else {
-            if retain_failed {
-                failed_records.push(record)
-            }
+/// the fail reason is appended to the header as fr:Z:<reason> if --fail_reason is set
+fn write_failed_record(
+    record: &FastqRecord,
+    reason: FailReason,
+    fo: &FilterOption,
+    failed_writer: &mut dyn Write,
+) {
+    if fo.retain_failed.is_some() {
+        let tag = header_tag(record, Some(reason), fo);
+        record
+            .write_with_tag(failed_writer, tag.as_deref())
+            .unwrap();
+    }
+}
+
+/// write the passed reads into passed_writer and the failed ones into failed_writer if
+/// --retain_failed is set, all reads are counted in report
+fn write_classified_records(
+    records: &[FastqRecord],
+    fail_reasons: &[Option<FailReason>],
+    fo: &FilterOption,
+    report: &mut FilterReport,
+    passed_writer: &mut dyn Write,
+    failed_writer: &mut dyn Write,
+) {
+    for (record, fail_reason) in records.iter().zip(fail_reasons) {
+        report.add(record.seq.len(), *fail_reason);
+        match fail_reason {
+            None => write_passed_record(record, fo, passed_writer),
+            Some(reason) => write_failed_record(record, *reason, fo, failed_writer),
         }
-        read_idx += 1;
     }
-    (passed_records, failed_records)
 }
 
 fn filter_receiver(
     receiver: Receiver<Vec<FastqRecord>>,
     fo: &FilterOption,
+    report: &mut FilterReport,
     passed_writer: &mut dyn Write,
     failed_writer: &mut dyn Write,
 ) {
     for records in receiver {
         let x = records
             .par_iter()
-            .map(|x| x.is_passed(&fo))
+            .map(|x| x.fail_reason(&fo))
             .collect::<Vec<_>>();
-        for (record, is_passed) in records.iter().zip(x) {
-            if is_passed {
-                record.write(passed_writer).unwrap();
+        write_classified_records(&records, &x, fo, report, passed_writer, failed_writer);
+    }
+}
+
+/// reads of one chunk of the input. bam_records are only kept for bam output, alignment_reasons
+/// and is_primary are empty for fastq input. trimmed is the bases removed from the start and the
+/// end of each read, empty if no trimming
+#[derive(Default)]
+pub(crate) struct ReadsChunk {
+    pub(crate) records: Vec<FastqRecord>,
+    trimmed: Vec<(usize, usize)>,
+    bam_records: Vec<bam::Record>,
+    alignment_reasons: Vec<Option<FailReason>>,
+    pub(crate) is_primary: Vec<bool>,
+}
+
+impl ReadsChunk {
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn is_primary(&self, idx: usize) -> bool {
+        self.is_primary.get(idx).is_none_or(|x| *x)
+    }
+
+    /// the reads with their bam records of bam output, so that they can be kept across chunks
+    pub(crate) fn into_reads(self) -> impl Iterator<Item = (FastqRecord, Option<bam::Record>)> {
+        let mut bam_records = self.bam_records.into_iter();
+        self.records
+            .into_iter()
+            .map(move |x| (x, bam_records.next()))
+    }
+
+    /// secondary and supplementary records are only checked by the name and alignment filters,
+    /// because their sequence may be clipped or missing, they also fail with their primary read
+    /// by PrimaryDecisions
+    fn fail_reasons(&self, fo: &FilterOption) -> Vec<Option<FailReason>> {
+        self.records
+            .par_iter()
+            .enumerate()
+            .map(|(idx, record)| {
+                let read_reason = if self.is_primary(idx) {
+                    record.fail_reason(fo)
+                } else {
+                    record.name_fail_reason(fo)
+                };
+                read_reason.or(self.alignment_reasons.get(idx).copied().flatten())
+            })
+            .collect()
+    }
+}
+
+/// a chunk of primary reads collected by ReadsChunk::into_reads
+impl FromIterator<(FastqRecord, Option<bam::Record>)> for ReadsChunk {
+    fn from_iter<T: IntoIterator<Item = (FastqRecord, Option<bam::Record>)>>(iter: T) -> Self {
+        let mut reads = ReadsChunk::default();
+        for (record, bam_record) in iter {
+            reads.records.push(record);
+            if let Some(bam_record) = bam_record {
+                reads.bam_records.push(bam_record);
+                reads.is_primary.push(true);
+            }
+        }
+        reads
+    }
+}
+
+/// the first alignment filter a bam record fails
+fn alignment_fail_reason(
+    record: &bam::Record,
+    contigs: &[String],
+    fo: &FilterOption,
+) -> Option<FailReason> {
+    if fo.primary_only && (record.is_secondary() || record.is_supplementary()) {
+        return Some(FailReason::NotPrimary);
+    }
+    if fo.drop_chimeric && (record.is_supplementary() || record.aux(b"SA").is_ok()) {
+        return Some(FailReason::Chimeric);
+    }
+    if record.is_unmapped() {
+        return fo.requires_alignment().then_some(FailReason::Unmapped);
+    }
+    if fo.min_mapq.is_some_and(|x| record.mapq() < x) {
+        return Some(FailReason::LowMapq);
+    }
+    if fo.min_identity.is_some() || fo.min_aligned_fraction.is_some() {
+        let (identity, aligned_fraction) = identity_and_aligned_fraction(record);
+        if fo.min_identity.is_some_and(|x| identity < x) {
+            return Some(FailReason::LowIdentity);
+        }
+        if fo
+            .min_aligned_fraction
+            .is_some_and(|x| aligned_fraction < x)
+        {
+            return Some(FailReason::LowAlignedFraction);
+        }
+    }
+    let contig = contigs
+        .get(record.tid() as usize)
+        .map(|x| x.as_str())
+        .unwrap_or_default();
+    if fo
+        .include_contigs
+        .as_ref()
+        .is_some_and(|x| !x.contains(contig))
+    {
+        return Some(FailReason::ContigNotIncluded);
+    }
+    if fo
+        .exclude_contigs
+        .as_ref()
+        .is_some_and(|x| x.contains(contig))
+    {
+        return Some(FailReason::ContigExcluded);
+    }
+    None
+}
+
+///
+/// Decisions of the primary reads of bam input, read in a first pass, so that their secondary and
+/// supplementary records in bam output follow them wherever they are in the input: the records
+/// of a failed read fail for the same reason, and the records of a trimmed read are clipped in
+/// the same way.
+///
+#[derive(Default)]
+struct PrimaryDecisions {
+    failed: HashMap<String, FailReason>,
+    trimmed: HashMap<String, (usize, usize)>,
+}
+
+impl PrimaryDecisions {
+    fn is_needed(fo: &FilterOption) -> bool {
+        fo.bam_output && !fo.primary_only
+    }
+
+    fn add(&mut self, reads: &ReadsChunk, fail_reasons: &[Option<FailReason>]) {
+        for idx in (0..reads.len()).filter(|idx| reads.is_primary(*idx)) {
+            let name = &reads.records[idx].name;
+            if let Some(reason) = fail_reasons[idx] {
+                self.failed.insert(name.clone(), reason);
+            }
+            if let Some(trimmed) = reads.trimmed.get(idx).filter(|x| **x != (0, 0)) {
+                self.trimmed.insert(name.clone(), *trimmed);
+            }
+        }
+    }
+
+    fn apply_trims(&self, reads: &mut ReadsChunk) {
+        for idx in 0..reads.trimmed.len() {
+            if !reads.is_primary(idx) {
+                reads.trimmed[idx] = self
+                    .trimmed
+                    .get(&reads.records[idx].name)
+                    .copied()
+                    .unwrap_or_default();
+            }
+        }
+    }
+
+    fn apply_failed(&self, reads: &ReadsChunk, fail_reasons: &mut [Option<FailReason>]) {
+        for (idx, reason) in fail_reasons.iter_mut().enumerate() {
+            if reason.is_none() && !reads.is_primary(idx) {
+                *reason = self.failed.get(&reads.records[idx].name).copied();
+            }
+        }
+    }
+}
+
+/// pass the reads of input to f in chunks, the reads are in the same order in every call and
+/// they are cropped and trimmed if required. Secondary and supplementary alignments of bam are
+/// skipped for fastq output
+pub(crate) fn for_each_chunk(
+    input: &str,
+    input_t: &InputType,
+    chunk: u32,
+    fo: &FilterOption,
+    f: &mut dyn FnMut(ReadsChunk),
+) {
+    let mut f = |mut reads: ReadsChunk| {
+        // secondary and supplementary records are clipped as their primary read in bam output
+        if fo.is_trimming() {
+            let is_primary = &reads.is_primary;
+            reads.trimmed = reads
+                .records
+                .par_iter_mut()
+                .enumerate()
+                .map(|(idx, x)| {
+                    if is_primary.get(idx).is_none_or(|x| *x) {
+                        x.trim(fo)
+                    } else {
+                        (0, 0)
+                    }
+                })
+                .collect();
+        }
+        f(reads)
+    };
+    match input_t {
+        InputType::DirectoryContainFastqsOrFastqsGzipped
+        | InputType::OneFastqFile
+        | InputType::OneFastqGzippedFile => {
+            let fastqs = if *input_t == InputType::DirectoryContainFastqsOrFastqsGzipped {
+                let mut fastqs = collect_fqs_in_dir(input);
+                fastqs.sort();
+                fastqs
             } else {
+                vec![PathBuf::from(input)]
+            };
+            for fastq in fastqs {
+                let (read_handle, receiver) =
+                    chunk_records_from_fastq(fastq.to_str().unwrap(), chunk, true);
+                for records in receiver {
+                    f(ReadsChunk {
+                        records,
+                        ..Default::default()
+                    })
+                }
+                read_handle.join().unwrap();
+            }
+        }
+        _ => {
+            let mut bam_reader = bam::Reader::from_path(input)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to read {input}")));
+            let contigs = bam_reader
+                .header()
+                .target_names()
+                .iter()
+                .map(|x| String::from_utf8_lossy(x).to_string())
+                .collect::<Vec<_>>();
+            let mut reads = ReadsChunk::default();
+            for record in bam_reader.records() {
+                let record = record
+                    .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to read {input}")));
+                let is_primary = !record.is_secondary() && !record.is_supplementary();
+                if !is_primary && !fo.bam_output {
+                    continue;
+                }
+                if is_primary
+                    && !record.seq().is_empty()
+                    && !has_bam_quality(&record)
+                    && fo.needs_base_quality(get_qs_aux(&record).is_some())
+                {
+                    quit_with_error(&format!(
+                        "No base quality found for {} in {input} (QUAL is *), the quality filters and trimming can't be applied",
+                        String::from_utf8_lossy(record.qname())
+                    ))
+                }
+                reads.records.push(FastqRecord::from_bam_record(&record));
+                reads
+                    .alignment_reasons
+                    .push(alignment_fail_reason(&record, &contigs, fo));
+                reads.is_primary.push(is_primary);
+                if fo.bam_output {
+                    reads.bam_records.push(record);
+                }
+                if reads.len() as u32 >= chunk {
+                    f(std::mem::take(&mut reads))
+                }
+            }
+            f(reads)
+        }
+    }
+}
+
+/// writer of the passed or failed reads in chunks, bam output keeps the header and the
+/// alignments of input bam
+pub(crate) enum ReadsWriter {
+    Fastq(Box<dyn Write>),
+    Bam(Box<bam::Writer>),
+}
+
+impl ReadsWriter {
+    /// the reads are discarded if output is None
+    pub(crate) fn new(output: Option<&str>, input: &str, fo: &FilterOption) -> Self {
+        match output {
+            None => ReadsWriter::Fastq(Box::new(std::io::sink())),
+            Some(output) if fo.bam_output => {
+                let bam_reader = bam::Reader::from_path(input)
+                    .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to read {input}")));
+                let header = bam::Header::from_template(bam_reader.header());
+                let writer = bam::Writer::from_path(output, &header, bam::Format::Bam)
+                    .unwrap_or_else(|x| {
+                        quit_with_error(&format!("{x}, failed to create {output}"))
+                    });
+                ReadsWriter::Bam(Box::new(writer))
+            }
+            Some(output) => ReadsWriter::Fastq(Box::new(BufWriter::new(
+                std::fs::File::create(output).unwrap_or_else(|x| {
+                    quit_with_error(&format!("{x}, failed to create {output}"))
+                }),
+            ))),
+        }
+    }
+
+    /// write the idx-th read of reads with the tags of header_tag, they are aux tags in bam
+    pub(crate) fn write(
+        &mut self,
+        reads: &ReadsChunk,
+        idx: usize,
+        fail_reason: Option<FailReason>,
+        fo: &FilterOption,
+    ) {
+        match self {
+            ReadsWriter::Fastq(writer) => {
+                let tag = header_tag(&reads.records[idx], fail_reason, fo);
+                reads.records[idx]
+                    .write_with_tag(writer, tag.as_deref())
+                    .unwrap();
+            }
+            ReadsWriter::Bam(writer) => {
+                let fail_reason = fail_reason.filter(|_| fo.tag_fail_reason);
+                let (head, tail) = reads.trimmed.get(idx).copied().unwrap_or_default();
+                let result = if fail_reason.is_none() && !fo.tag_poly_tail && head + tail == 0 {
+                    writer.write(&reads.bam_records[idx])
+                } else {
+                    let mut record = reads.bam_records[idx].clone();
+                    // a secondary or supplementary alignment inside the trimmed bases is dropped
+                    if head + tail > 0
+                        && !clip_read(&mut record, head, tail)
+                        && !reads.is_primary(idx)
+                    {
+                        return;
+                    }
+                    if let Some(reason) = fail_reason {
+                        let _ = record.remove_aux(b"fr");
+                        record.push_aux(b"fr", Aux::String(reason.name())).unwrap();
+                    }
+                    if fo.tag_poly_tail {
+                        let poly_tail = reads.records[idx].poly_tail() as i32;
+                        let _ = record.remove_aux(b"pt");
+                        record.push_aux(b"pt", Aux::I32(poly_tail)).unwrap();
+                    }
+                    writer.write(&record)
+                };
+                result.unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to write bam")));
+            }
+        }
+    }
+}
+
+/// write the passed reads of a chunk into passed_writer and the failed ones into failed_writer
+/// if --retain_failed is set, all reads are counted in report
+fn write_classified_chunk(
+    reads: &ReadsChunk,
+    fail_reasons: &[Option<FailReason>],
+    fo: &FilterOption,
+    report: &mut FilterReport,
+    passed_writer: &mut ReadsWriter,
+    failed_writer: &mut ReadsWriter,
+) {
+    report.add_trimmed(
+        reads
+            .trimmed
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| reads.is_primary(*idx))
+            .map(|(_, (head, tail))| head + tail),
+    );
+    for (idx, fail_reason) in fail_reasons.iter().enumerate() {
+        report.add(reads.records[idx].seq.len(), *fail_reason);
+        match fail_reason {
+            None => passed_writer.write(reads, idx, None, fo),
+            Some(reason) => {
                 if fo.retain_failed.is_some() {
-                    record.write(failed_writer).unwrap();
+                    failed_writer.write(reads, idx, Some(*reason), fo)
                 }
             }
         }
     }
 }
 
+/// filter the reads of bam input or the reads to be trimmed
+fn filter_chunks(
+    input: &str,
+    input_t: &InputType,
+    passed_file: &str,
+    chunk: u32,
+    fo: &FilterOption,
+) -> FilterReport {
+    let mut failed_writer = ReadsWriter::new(fo.retain_failed.as_deref(), input, fo);
+    let mut passed_writer = ReadsWriter::new(Some(passed_file), input, fo);
+    let mut report = FilterReport::default();
+    let mut decisions = PrimaryDecisions::default();
+    if PrimaryDecisions::is_needed(fo) {
+        for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+            decisions.add(&reads, &reads.fail_reasons(fo))
+        });
+    }
+    for_each_chunk(input, input_t, chunk, fo, &mut |mut reads| {
+        decisions.apply_trims(&mut reads);
+        let mut fail_reasons = reads.fail_reasons(fo);
+        decisions.apply_failed(&reads, &mut fail_reasons);
+        write_classified_chunk(
+            &reads,
+            &fail_reasons,
+            fo,
+            &mut report,
+            &mut passed_writer,
+            &mut failed_writer,
+        );
+    });
+    report
+}
+
+/// length^length_weight * Q^qual_weight, Q is the lowest window quality instead of the read
+/// quality if --window_size is set and the window quality is lower
+fn read_score(record: &FastqRecord, fo: &FilterOption) -> f64 {
+    let mut qual = fo.qscore_source.choose(
+        record.reported_qual(),
+        record.qual(fo.use_dorado_q),
+        &record.name,
+    );
+    if fo.window_size > 0 {
+        qual = qual.min(record.min_window_qual(fo.window_size));
+    }
+    (record.len() as f64).powf(fo.length_weight) * (qual.max(0.0) as f64).powf(fo.qual_weight)
+}
+
+/// (index, length, score) of a read passing all thresholds
+type ScoredRead = (usize, u32, f64);
+
+/// sort the passed reads by score, the earlier read first for the same score, and keep them
+/// until target_bases reached, the read reaching it is kept. The kept reads and the dropped ones
+/// are returned
+fn select_by_score(
+    mut candidates: Vec<ScoredRead>,
+    target_bases: u64,
+) -> (Vec<ScoredRead>, Vec<ScoredRead>) {
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+    let mut kept_bases = 0u64;
+    let kept_reads = candidates
+        .iter()
+        .take_while(|(_, len, _)| {
+            let is_kept = kept_bases < target_bases;
+            kept_bases += *len as u64;
+            is_kept
+        })
+        .count();
+    let dropped = candidates.split_off(kept_reads);
+    (candidates, dropped)
+}
+
+///
+/// Filtlong-like subsampling. The reads passing all thresholds are scored by `read_score` in
+/// the first pass, and the highest scoring reads are kept until the target bases reached.
+/// The kept reads are written in the second pass in the input order.
+///
+fn subsample_by_score(
+    input: &str,
+    input_t: &InputType,
+    passed_file: &str,
+    chunk: u32,
+    fo: &FilterOption,
+    target_bases: u64,
+) -> FilterReport {
+    init_log();
+    // fail reason of each read, the reads passing the thresholds are set to Subsampled if they
+    // are not kept
+    let mut fail_reasons: Vec<Option<FailReason>> = vec![];
+    // (read length, score) of each read, None if it fails the thresholds
+    let mut scores: Vec<Option<(u32, f64)>> = vec![];
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        let chunk_reasons = reads.fail_reasons(fo);
+        scores.par_extend(
+            reads
+                .records
+                .par_iter()
+                .zip(chunk_reasons.par_iter())
+                .map(|(x, reason)| reason.is_none().then(|| (x.len(), read_score(x, fo)))),
+        );
+        fail_reasons.extend(chunk_reasons);
+    });
+    let candidates = scores
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, x)| x.map(|(len, score)| (idx, len, score)))
+        .collect::<Vec<_>>();
+    let passed_bases = candidates.iter().map(|x| x.1 as u64).sum::<u64>();
+    info!(
+        "{} of {} reads ({} bases) passed the length/quality/gc thresholds",
+        candidates.len(),
+        scores.len(),
+        passed_bases
+    );
+    let (kept, dropped) = select_by_score(candidates, target_bases);
+    for (idx, _, _) in &dropped {
+        fail_reasons[*idx] = Some(FailReason::Subsampled);
+    }
+    let kept_bases = kept.iter().map(|x| x.1 as u64).sum::<u64>();
+    if passed_bases <= target_bases {
+        info!("the passed bases don't exceed the target {target_bases}, all passed reads are kept");
+    } else {
+        info!(
+            "{} reads ({kept_bases} bases) with score >= {:.2} are kept for the target {target_bases} bases, {} passed reads are dropped",
+            kept.len(),
+            kept.last().map_or(0.0, |x| x.2),
+            dropped.len()
+        );
+    }
+
+    let mut failed_writer = ReadsWriter::new(fo.retain_failed.as_deref(), input, fo);
+    let mut passed_writer = ReadsWriter::new(Some(passed_file), input, fo);
+    let mut report = FilterReport::default();
+    let mut read_idx = 0;
+    // --target_bases implies --primary_only, the secondary and supplementary records are dropped
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        let end = read_idx + reads.len();
+        if end > fail_reasons.len() {
+            quit_with_error(&format!("{input} changed when it was filtered"))
+        }
+        write_classified_chunk(
+            &reads,
+            &fail_reasons[read_idx..end],
+            fo,
+            &mut report,
+            &mut passed_writer,
+            &mut failed_writer,
+        );
+        read_idx = end;
+    });
+    report
+}
+
 fn filter_one_fastq(
     fastq_file: &str,
     passed_file: &str,
     thread: usize,
     fo: &FilterOption,
     chunk: u32,
-) {
+) -> FilterReport {
     if thread == 1 {
         fastq_filter(fastq_file, fo, passed_file)
     } else {
@@ -230,41 +975,73 @@ fn filter_one_fastq(
         let mut passed_writer = BufWriter::new(
             std::fs::File::create(passed_file).expect(&format!("Failed to create {passed_file}")),
         );
-        filter_receiver(receiver, fo, &mut passed_writer, &mut failed_writer);
+        let mut report = FilterReport::default();
+        filter_receiver(
+            receiver,
+            fo,
+            &mut report,
+            &mut passed_writer,
+            &mut failed_writer,
+        );
         read_handle.join().unwrap();
+        report
     }
 }
 
-fn filter_fastq_dir(fastq_dir: &str, passed_file: &str, thread: usize, fo: &FilterOption) {
+fn filter_fastq_dir(
+    fastq_dir: &str,
+    passed_file: &str,
+    thread: usize,
+    fo: &FilterOption,
+) -> FilterReport {
     let fastqs = collect_fqs_in_dir(fastq_dir);
     let mut failed_writer = fo.set_failed_fastq_file();
     let mut passed_writer = BufWriter::new(
         std::fs::File::create(passed_file).expect(&format!("Failed to create {passed_file}")),
     );
+    let mut report = FilterReport::default();
     if thread == 1 {
         for fq in fastqs {
-            let (passed, failed) = fastq_filter_out_records(fq.to_str().unwrap(), fo);
+            let (passed, failed, fq_report) = fastq_filter_out_records(fq.to_str().unwrap(), fo);
             for p in passed {
-                p.write(&mut passed_writer).unwrap()
+                write_passed_record(&p, fo, &mut passed_writer)
             }
 
-            for f in failed {
-                f.write(&mut failed_writer).unwrap()
+            for (f, reason) in failed {
+                write_failed_record(&f, reason, fo, &mut failed_writer)
             }
+            report.merge(&fq_report);
         }
     } else {
         let classed_records = fastqs
             .into_par_iter()
             .map(|x| fastq_filter_out_records(x.to_str().unwrap(), fo))
             .collect::<Vec<_>>();
-        for (passed, failed) in classed_records {
+        for (passed, failed, fq_report) in classed_records {
             for p in passed {
-                p.write(&mut passed_writer).unwrap()
+                write_passed_record(&p, fo, &mut passed_writer)
             }
-            for f in failed {
-                f.write(&mut failed_writer).unwrap()
+            for (f, reason) in failed {
+                write_failed_record(&f, reason, fo, &mut failed_writer)
             }
+            report.merge(&fq_report);
+        }
+    }
+    report
+}
+
+/// --target_bases, or --target_coverage times --genome_size
+fn target_bases_arg(filter_cmd: &ArgMatches) -> Option<u64> {
+    match (
+        filter_cmd.get_one::<u64>("target_bases"),
+        filter_cmd.get_one::<f64>("target_coverage"),
+    ) {
+        (Some(target_bases), _) => Some(*target_bases),
+        (None, Some(coverage)) => {
+            let genome_size = filter_cmd.get_one::<u64>("genome_size").unwrap();
+            Some((coverage * *genome_size as f64) as u64)
         }
+        _ => None,
     }
 }
 
@@ -274,6 +1051,8 @@ pub fn run_filter(filter_cmd: &ArgMatches) {
     let min_len = filter_cmd.get_one::<u32>("min_len").unwrap();
     let max_len = filter_cmd.get_one::<u32>("max_len").unwrap();
     let use_dorado_q = filter_cmd.get_flag("use_dorado_q");
+    let qscore_source =
+        QscoreSource::from_arg(filter_cmd.get_one::<String>("qscore_source").unwrap());
     let min_qual = filter_cmd.get_one::<f64>("min_qual").unwrap();
     let max_qual = filter_cmd.get_one::<f64>("max_qual").unwrap();
     let use_gc = filter_cmd.get_flag("gc");
@@ -281,7 +1060,7 @@ pub fn run_filter(filter_cmd: &ArgMatches) {
     let max_gc = filter_cmd.get_one::<f64>("max_gc").unwrap();
     let thread = filter_cmd.get_one::<u16>("thread").unwrap();
     let chunk = filter_cmd.get_one::<u32>("chunk").unwrap();
-    // let max_bases = filter_cmd.get_one::<u64>("max_bases");
+    let target_bases = target_bases_arg(filter_cmd);
     let failed_fq_path = filter_cmd.get_one::<String>("retain_failed");
     if failed_fq_path.is_some(){
         check_output_file(failed_fq_path.unwrap())
@@ -292,23 +1071,100 @@ pub fn run_filter(filter_cmd: &ArgMatches) {
         min_qual: *min_qual as f32,
         max_qual: *max_qual as f32,
         use_dorado_q,
+        qscore_source,
         use_gc,
         min_gc: *min_gc as f32,
         max_gc: *max_gc as f32,
         retain_failed: failed_fq_path.map(|x| x.clone()),
+        target_bases,
+        length_weight: *filter_cmd.get_one::<f64>("length_weight").unwrap(),
+        qual_weight: *filter_cmd.get_one::<f64>("qual_weight").unwrap(),
+        window_size: *filter_cmd.get_one::<usize>("window_size").unwrap(),
+        headcrop: *filter_cmd.get_one::<usize>("headcrop").unwrap(),
+        tailcrop: *filter_cmd.get_one::<usize>("tailcrop").unwrap(),
+        trim_qual: filter_cmd.get_one::<f64>("trim_qual").map(|x| *x as f32),
+        min_window_qual: filter_cmd
+            .get_one::<f64>("min_window_qual")
+            .map(|x| *x as f32),
+        trim_window: *filter_cmd.get_one::<u64>("trim_window").unwrap() as usize,
+        tag_fail_reason: filter_cmd.get_flag("fail_reason"),
+        tag_poly_tail: filter_cmd.get_flag("poly_tail"),
+        include_names: filter_cmd
+            .get_one::<String>("include_names")
+            .map(|x| ReadNames::FromFile(x).get_read_names()),
+        exclude_names: filter_cmd
+            .get_one::<String>("exclude_names")
+            .map(|x| ReadNames::FromFile(x).get_read_names()),
+        name_regex: filter_cmd.get_one::<Regex>("name_regex").cloned(),
+        min_complexity: filter_cmd
+            .get_one::<f64>("min_complexity")
+            .map(|x| *x as f32),
+        max_homopolymer_frac: filter_cmd
+            .get_one::<f64>("max_homopolymer_frac")
+            .map(|x| *x as f32),
+        min_mapq: filter_cmd.get_one::<u8>("min_mapq").copied(),
+        min_identity: filter_cmd.get_one::<f64>("min_identity").map(|x| *x as f32),
+        min_aligned_fraction: filter_cmd
+            .get_one::<f64>("min_aligned_fraction")
+            .map(|x| *x as f32),
+        // only primary alignments are subsampled, so that each read is counted once
+        primary_only: filter_cmd.get_flag("primary_only") || target_bases.is_some(),
+        drop_chimeric: filter_cmd.get_flag("drop_chimeric"),
+        include_contigs: filter_cmd
+            .get_one::<String>("include_contigs")
+            .map(|x| ReadNames::FromCli(x).get_read_names()),
+        exclude_contigs: filter_cmd
+            .get_one::<String>("exclude_contigs")
+            .map(|x| ReadNames::FromCli(x).get_read_names()),
+        bam_output: output.is_some_and(|x| x.ends_with(".bam")),
     };
 
     let passed_file = output.unwrap();
     check_output_file(passed_file);
+    if failed_fq_path.is_some_and(|x| x.ends_with(".bam") != fo.bam_output) {
+        quit_with_error("--retain_failed should be in the same format as --output, fastq or bam")
+    }
+    let summary = filter_cmd.get_one::<String>("summary");
+    if let Some(summary_file) = summary {
+        check_output_file(summary_file)
+    }
+    let json = filter_cmd.get_one::<String>("json");
+    if let Some(json_file) = json {
+        check_output_file(json_file)
+    }
     let input_path = input.unwrap();
     let input_t = check_input_type(input.unwrap());
-    if thread == &1 {
+    let is_fastq_input = matches!(
+        input_t,
+        InputType::DirectoryContainFastqsOrFastqsGzipped
+            | InputType::OneFastqFile
+            | InputType::OneFastqGzippedFile
+    );
+    let has_alignment_filter = fo.requires_alignment()
+        || fo.exclude_contigs.is_some()
+        || filter_cmd.get_flag("primary_only")
+        || fo.drop_chimeric;
+    if is_fastq_input && (fo.bam_output || has_alignment_filter) {
+        quit_with_error("bam output and the alignment filters need bam/sam input")
+    }
+    let report = if fo.target_bases.is_some() || !is_fastq_input || fo.is_trimming() {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(*thread as usize)
+            .build_global()
+            .unwrap();
+        match fo.target_bases {
+            Some(target_bases) => {
+                subsample_by_score(input_path, &input_t, passed_file, *chunk, &fo, target_bases)
+            }
+            None => filter_chunks(input_path, &input_t, passed_file, *chunk, &fo),
+        }
+    } else if thread == &1 {
         match input_t {
             InputType::DirectoryContainFastqsOrFastqsGzipped => {
-                filter_fastq_dir(input_path, passed_file, 1, &fo);
+                filter_fastq_dir(input_path, passed_file, 1, &fo)
             }
             InputType::OneFastqFile | InputType::OneFastqGzippedFile => {
-                filter_one_fastq(input_path, passed_file, 1, &fo, *chunk);
+                filter_one_fastq(input_path, passed_file, 1, &fo, *chunk)
             }
             _ => quit_with_error("error"),
         }
@@ -319,13 +1175,19 @@ pub fn run_filter(filter_cmd: &ArgMatches) {
             .unwrap();
         match input_t {
             InputType::DirectoryContainFastqsOrFastqsGzipped => {
-                filter_fastq_dir(input_path, passed_file, *thread as usize, &fo);
+                filter_fastq_dir(input_path, passed_file, *thread as usize, &fo)
             }
             InputType::OneFastqFile | InputType::OneFastqGzippedFile => {
-                filter_one_fastq(input_path, passed_file, *thread as usize, &fo, *chunk);
+                filter_one_fastq(input_path, passed_file, *thread as usize, &fo, *chunk)
             }
             _ => quit_with_error("error"),
         }
+    };
+    if let Some(summary_file) = summary {
+        report.write_tsv(summary_file);
+    }
+    if let Some(json_file) = json {
+        report.write_json(json_file);
     }
 }
 
@@ -337,19 +1199,19 @@ pub fn filter_cmd() -> Command {
                 .short('i')
                 .long("input")
                 .required(true)
-                .help("the input fastq, a fastq[.gz] or a directory containing some fastq[.gz]"),
+                .help("the input, a fastq[.gz], a directory containing some fastq[.gz] or a bam/sam file whose primary reads are written as fastq unless the output is bam"),
         )
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
                 .value_parser(|output: &str| {
-                    if !(output.ends_with(".fq") || output.ends_with(".fastq")) {
-                        quit_with_error( "Error: output should ends with .fastq or .fq. Gzipped not supported", )
+                    if !(output.ends_with(".fq") || output.ends_with(".fastq") || output.ends_with(".bam")) {
+                        quit_with_error( "Error: output should ends with .fastq, .fq or .bam. Gzipped not supported", )
                     }
                     Result::<String, anyhow::Error>::Ok(output.to_string())
                 })
-                .help("output the filtered fastq into this file, it will be truncated if it exists. Compressed file is not supported")
+                .help("output the filtered fastq into this file, it will be truncated if it exists. Compressed file is not supported. For bam/sam input, the output can be a .bam file which keeps the header and alignments")
         )
         .arg(
             Arg::new("min_len")
@@ -392,6 +1254,13 @@ pub fn filter_cmd() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("use Dorado Q-score calculation: trim leading 60 bases if read length > 60 before calculate read quality")
         )
+        .arg(
+            Arg::new("qscore_source")
+                .long("qscore_source")
+                .default_value("recompute")
+                .value_parser(["recompute", "tag", "auto"])
+                .help("where the read quality comes from. recompute: mean-error Q from the quality string; tag: the basecaller reported Q in qs= or qs:f/qs:i of fastq header; auto: the reported Q if available, otherwise recompute")
+        )
         .arg(
             Arg::new("gc")
                 .long("gc")
@@ -416,6 +1285,18 @@ pub fn filter_cmd() -> Command {
                 .value_parser(|x: &str| positive_f64_parse(x, "--min_qual", 0.0, 1.0f64))
                 .help("max gc content when --gc is set")
         )
+        .arg(
+            Arg::new("min_complexity")
+                .long("min_complexity")
+                .value_parser(|x: &str| positive_f64_parse(x, "--min_complexity", 0.0, 1.0f64))
+                .help("min read complexity, the trinucleotide entropy normalized to [0, 1], homopolymers and simple repeats are close to 0")
+        )
+        .arg(
+            Arg::new("max_homopolymer_frac")
+                .long("max_homopolymer_frac")
+                .value_parser(|x: &str| positive_f64_parse(x, "--max_homopolymer_frac", 0.0, 1.0f64))
+                .help("max fraction of read length in its longest homopolymer, e.g., 0.5 removes the reads which are mostly poly-A/T")
+        )
         .arg(
             Arg::new("thread")
                 .short('t')
@@ -438,4 +1319,234 @@ pub fn filter_cmd() -> Command {
                 .long("retain_failed")
                 .help("whether to save the failed records, if set, it should be path of failed fastq")
         )
+        .arg(
+            Arg::new("fail_reason")
+                .long("fail_reason")
+                .action(ArgAction::SetTrue)
+                .requires("retain_failed")
+                .help("append the first failed filter as fr:Z:<reason> to the header of each read in --retain_failed, e.g., fr:Z:too_short")
+        )
+        .arg(
+            Arg::new("poly_tail")
+                .long("poly_tail")
+                .action(ArgAction::SetTrue)
+                .help("append the poly(A)/poly(T) tail length as pt:i:<length> to the header of each output read, or as pt aux tag for bam output, 0 if no tail")
+        )
+        .arg(
+            Arg::new("summary")
+                .long("summary")
+                .help("output the reads and bases removed by each filter into this tsv file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("output the reads and bases removed by each filter into this json file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("include_names")
+                .long("include_names")
+                .help("only keep the reads whose names are in this file, one name per line")
+        )
+        .arg(
+            Arg::new("exclude_names")
+                .long("exclude_names")
+                .help("remove the reads whose names are in this file, one name per line, e.g., known contaminant or duplex parent reads")
+        )
+        .arg(
+            Arg::new("name_regex")
+                .long("name_regex")
+                .value_parser(|x: &str| Regex::new(x))
+                .help("only keep the reads whose names match this regular expression, it matches any part of the name unless anchored with ^ and $")
+        )
+        .arg(
+            Arg::new("min_mapq")
+                .long("min_mapq")
+                .value_parser(value_parser!(u8))
+                .help("bam/sam input only, min mapping quality, unmapped reads are removed")
+        )
+        .arg(
+            Arg::new("min_identity")
+                .long("min_identity")
+                .value_parser(|x: &str| positive_f64_parse(x, "--min_identity", 0.0, 1.0f64))
+                .help("bam/sam input only, min alignment identity 1 - NM / (Match + Ins + Del), unmapped reads are removed")
+        )
+        .arg(
+            Arg::new("min_aligned_fraction")
+                .long("min_aligned_fraction")
+                .value_parser(|x: &str| positive_f64_parse(x, "--min_aligned_fraction", 0.0, 1.0f64))
+                .help("bam/sam input only, min fraction of the read bases in the alignment, unmapped reads are removed")
+        )
+        .arg(
+            Arg::new("primary_only")
+                .long("primary_only")
+                .action(ArgAction::SetTrue)
+                .help("bam/sam input only, remove secondary and supplementary alignments from bam output, they are never written into fastq output")
+        )
+        .arg(
+            Arg::new("drop_chimeric")
+                .long("drop_chimeric")
+                .action(ArgAction::SetTrue)
+                .help("bam/sam input only, remove the reads with supplementary alignments (SA tag)")
+        )
+        .arg(
+            Arg::new("include_contigs")
+                .long("include_contigs")
+                .help("bam/sam input only, only keep the reads mapped to these contigs, separated by comma, e.g., chr1,chr2. unmapped reads are removed")
+        )
+        .arg(
+            Arg::new("exclude_contigs")
+                .long("exclude_contigs")
+                .help("bam/sam input only, remove the reads mapped to these contigs, separated by comma, e.g., chrM")
+        )
+        .arg(
+            Arg::new("headcrop")
+                .long("headcrop")
+                .default_value("0")
+                .value_parser(value_parser!(usize))
+                .help("crop this number of bases from the start of each read before filtering")
+        )
+        .arg(
+            Arg::new("tailcrop")
+                .long("tailcrop")
+                .default_value("0")
+                .value_parser(value_parser!(usize))
+                .help("crop this number of bases from the end of each read before filtering")
+        )
+        .arg(
+            Arg::new("trim_qual")
+                .long("trim_qual")
+                .value_parser(|x: &str| positive_f64_parse(x, "--trim_qual", 0.0, 50.0f64))
+                .help("after cropping, trim both ends of each read until a window of --trim_window bases reaches this mean quality, reads without such window become empty")
+        )
+        .arg(
+            Arg::new("min_window_qual")
+                .long("min_window_qual")
+                .value_parser(|x: &str| positive_f64_parse(x, "--min_window_qual", 0.0, 50.0f64))
+                .help("reject reads whose worst window of --trim_window bases has a mean quality below this value, checked after trimming")
+        )
+        .arg(
+            Arg::new("trim_window")
+                .long("trim_window")
+                .default_value("50")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("window size used by --trim_qual and --min_window_qual")
+        )
+        .arg(
+            Arg::new("target_bases")
+                .long("target_bases")
+                .value_parser(|x: &str| bases_parse(x, "--target_bases"))
+                .conflicts_with("target_coverage")
+                .help("keep the highest scoring reads until this number of bases, k/m/g suffix is allowed. e.g., 5G. reads are scored by length^length_weight * quality^qual_weight after the threshold filters, the input is read twice")
+        )
+        .arg(
+            Arg::new("target_coverage")
+                .long("target_coverage")
+                .requires("genome_size")
+                .value_parser(|x: &str| positive_f64_parse(x, "--target_coverage", 0.0, 1e6))
+                .help("the same as --target_bases, but the target is this coverage of --genome_size")
+        )
+        .arg(
+            Arg::new("genome_size")
+                .long("genome_size")
+                .value_parser(|x: &str| bases_parse(x, "--genome_size"))
+                .help("genome size used with --target_coverage, k/m/g suffix is allowed. e.g., 100M")
+        )
+        .arg(
+            Arg::new("length_weight")
+                .long("length_weight")
+                .default_value("1.0")
+                .value_parser(|x: &str| positive_f64_parse(x, "--length_weight", 0.0, 10.0))
+                .help("the weight of read length in read score when --target_bases or --target_coverage is set")
+        )
+        .arg(
+            Arg::new("qual_weight")
+                .long("qual_weight")
+                .default_value("1.0")
+                .value_parser(|x: &str| positive_f64_parse(x, "--qual_weight", 0.0, 10.0))
+                .help("the weight of read quality in read score when --target_bases or --target_coverage is set")
+        )
+        .arg(
+            Arg::new("window_size")
+                .long("window_size")
+                .default_value("0")
+                .value_parser(value_parser!(usize))
+                .help("use the lowest mean quality of windows of this size in read score if it's lower than the read quality, 0 means disabled")
+        )
+}
+
+#[cfg(test)]
+mod filter_test {
+    use super::*;
+
+    fn score_option(length_weight: f64, qual_weight: f64, window_size: usize) -> FilterOption {
+        FilterOption {
+            length_weight,
+            qual_weight,
+            window_size,
+            target_bases: Some(1000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn read_score() {
+        // Q10 for all bases
+        let read = FastqRecord::new("read1", None, vec![b'A'; 100], vec![b'+'; 100]);
+        let score = |fo: &FilterOption| super::read_score(&read, fo);
+        assert!((score(&score_option(1.0, 1.0, 0)) - 1000.0).abs() < 1e-3);
+        assert!((score(&score_option(1.0, 0.0, 0)) - 100.0).abs() < 1e-9);
+        assert!((score(&score_option(0.5, 2.0, 0)) - 1000.0).abs() < 1e-2);
+
+        // Q20 except a window of Q2, the lowest window quality is used
+        let mut quality = vec![b'5'; 100];
+        quality[50..60].fill(b'#');
+        let read = FastqRecord::new("read1", None, vec![b'A'; 100], quality);
+        let whole_read = super::read_score(&read, &score_option(1.0, 1.0, 0));
+        let window = super::read_score(&read, &score_option(1.0, 1.0, 10));
+        assert!((window - 200.0).abs() < 1e-3);
+        assert!(window < whole_read);
+
+        // the reported quality with --qscore_source tag
+        let read = FastqRecord::new("read1", Some("qs:f:15"), vec![b'A'; 100], vec![b'+'; 100]);
+        let fo = FilterOption {
+            qscore_source: QscoreSource::Tag,
+            ..score_option(1.0, 1.0, 0)
+        };
+        assert!((super::read_score(&read, &fo) - 1500.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn select_by_score() {
+        let candidates = vec![(0, 100, 5.0), (1, 100, 9.0), (2, 100, 5.0), (3, 50, 7.0)];
+        let idx = |reads: &[ScoredRead]| reads.iter().map(|x| x.0).collect::<Vec<_>>();
+        // the higher score first, the earlier read first for the same score. the read reaching
+        // the target is the last one kept
+        let (kept, dropped) = super::select_by_score(candidates.clone(), 160);
+        assert_eq!((idx(&kept), idx(&dropped)), (vec![1, 3, 0], vec![2]));
+        let (kept, dropped) = super::select_by_score(candidates.clone(), 150);
+        assert_eq!((idx(&kept), idx(&dropped)), (vec![1, 3], vec![0, 2]));
+        let (kept, dropped) = super::select_by_score(candidates.clone(), 1);
+        assert_eq!((idx(&kept), idx(&dropped)), (vec![1], vec![3, 0, 2]));
+        let (kept, dropped) = super::select_by_score(candidates, 10000);
+        assert_eq!((idx(&kept), idx(&dropped)), (vec![1, 3, 0, 2], vec![]));
+    }
+
+    #[test]
+    fn target_bases_arg() {
+        let target = |args: &[&str]| {
+            let args = ["filter", "-i", "reads.fastq"].iter().chain(args);
+            super::target_bases_arg(&filter_cmd().try_get_matches_from(args).unwrap())
+        };
+        assert_eq!(target(&[]), None);
+        assert_eq!(target(&["--target_bases", "5m"]), Some(5_000_000));
+        assert_eq!(
+            target(&["--target_coverage", "30", "--genome_size", "4.5m"]),
+            Some(135_000_000)
+        );
+        assert!(
+            filter_cmd()
+                .try_get_matches_from(["filter", "-i", "reads.fastq", "--target_coverage", "30"])
+                .is_err()
+        );
+    }
 }
@@ -1,16 +1,36 @@
 mod amplicons;
 mod bam;
+mod calibration;
+mod cdna;
+mod coverage;
+mod duplex;
 mod fastq;
 mod filter;
 mod input_type;
+mod modification;
 mod primer_barcode;
+mod profile;
+mod qc;
+mod repeat;
+mod report;
+mod sample;
+mod screen;
+mod split;
 mod stats;
 mod subseq;
 mod summary;
 mod utils;
+mod watch;
 
 use crate::amplicons::{amplicons_cmd, run_amplicons};
+use crate::cdna::{cdna_cmd, run_cdna};
+use crate::coverage::{coverage_cmd, run_coverage};
 use crate::filter::{filter_cmd, run_filter};
+use crate::repeat::{repeat_cmd, run_repeat};
+use crate::report::{report_cmd, run_report};
+use crate::sample::{run_sample, sample_cmd};
+use crate::screen::{run_screen, screen_cmd};
+use crate::split::{run_split, split_cmd};
 use crate::stats::{run_stats, stats_cmd};
 use crate::subseq::{run_subseq, subseq_cmd};
 use clap::Command;
@@ -29,7 +49,14 @@ fn main() {
         .subcommand(stats_cmd())
         .subcommand(amplicons_cmd())
         .subcommand(filter_cmd())
-        .subcommand(subseq_cmd());
+        .subcommand(subseq_cmd())
+        .subcommand(coverage_cmd())
+        .subcommand(screen_cmd())
+        .subcommand(split_cmd())
+        .subcommand(cdna_cmd())
+        .subcommand(repeat_cmd())
+        .subcommand(sample_cmd())
+        .subcommand(report_cmd());
     let matches = cmd.get_matches();
 
     if let Some(stats_cmd) = matches.subcommand_matches("stats") {
@@ -40,6 +67,20 @@ fn main() {
         run_amplicons(amplicons_cmd)
     } else if let Some(subseq_cmd) = matches.subcommand_matches("subseq") {
         run_subseq(subseq_cmd)
+    } else if let Some(coverage_cmd) = matches.subcommand_matches("coverage") {
+        run_coverage(coverage_cmd)
+    } else if let Some(screen_cmd) = matches.subcommand_matches("screen") {
+        run_screen(screen_cmd)
+    } else if let Some(split_cmd) = matches.subcommand_matches("split") {
+        run_split(split_cmd)
+    } else if let Some(cdna_cmd) = matches.subcommand_matches("cdna") {
+        run_cdna(cdna_cmd)
+    } else if let Some(repeat_cmd) = matches.subcommand_matches("repeat") {
+        run_repeat(repeat_cmd)
+    } else if let Some(sample_cmd) = matches.subcommand_matches("sample") {
+        run_sample(sample_cmd)
+    } else if let Some(report_cmd) = matches.subcommand_matches("report") {
+        run_report(report_cmd)
     } else {
         unreachable!()
     };
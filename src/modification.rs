@@ -0,0 +1,614 @@
+use plotly::common::Mode;
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, GridPattern, HoverMode, LayoutGrid};
+use plotly::{Layout, Plot, Scatter};
+use rust_htslib::bam::{self, record::Aux};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::AddAssign;
+
+// ML value >= 128 means the probability of modification is greater than 0.5
+const MODIFIED_ML_THRESHOLD: u8 = 128;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ModStatus {
+    Valid,
+    Missing,      // no MM or ML tag
+    Inconsistent, // MM/ML can't match the read sequence
+}
+
+impl ModStatus {
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "valid" => Some(ModStatus::Valid),
+            "missing" => Some(ModStatus::Missing),
+            "inconsistent" => Some(ModStatus::Inconsistent),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ModStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            ModStatus::Valid => "valid",
+            ModStatus::Missing => "missing",
+            ModStatus::Inconsistent => "inconsistent",
+        };
+        write!(f, "{status}")
+    }
+}
+
+/// modification calls of one read
+#[derive(Debug, Clone, Copy)]
+pub struct ReadMods {
+    pub called: u32,   // sites called in MM tag of all modification codes
+    pub modified: u32, // called sites with probability of modification >= 0.5
+    pub status: ModStatus,
+}
+
+impl ReadMods {
+    fn new(status: ModStatus) -> Self {
+        ReadMods {
+            called: 0,
+            modified: 0,
+            status,
+        }
+    }
+
+    pub fn rate(&self) -> Option<f32> {
+        if self.called == 0 {
+            None
+        } else {
+            Some(self.modified as f32 / self.called as f32)
+        }
+    }
+}
+
+/// summary of one modification code, such as C+m
+#[derive(Debug, Clone)]
+struct ModCodeStats {
+    candidate_bases: u64, // bases which could be called as this modification in reads
+    called: u64,
+    modified: u64,
+    prob_hist: [u64; 256], // index is ML value
+}
+
+impl Default for ModCodeStats {
+    fn default() -> Self {
+        ModCodeStats {
+            candidate_bases: 0,
+            called: 0,
+            modified: 0,
+            prob_hist: [0; 256],
+        }
+    }
+}
+
+impl AddAssign for ModCodeStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.candidate_bases += rhs.candidate_bases;
+        self.called += rhs.called;
+        self.modified += rhs.modified;
+        for (idx, count) in rhs.prob_hist.iter().enumerate() {
+            self.prob_hist[idx] += count;
+        }
+    }
+}
+
+/// human-readable name of modification code
+fn mod_code_label(key: &str) -> String {
+    let code = key.get(2..).unwrap_or("");
+    let name = match code {
+        "m" => "5mC",
+        "h" => "5hmC",
+        "f" => "5fC",
+        "c" => "5caC",
+        "a" => "6mA",
+        "o" => "8oxoG",
+        "17596" => "Inosine",
+        "17802" => "Pseudouridine",
+        _ => return key.to_string(),
+    };
+    format!("{name} ({key})")
+}
+
+fn complement_or_n(base: u8) -> u8 {
+    match base {
+        b'A' | b'a' => b'T',
+        b'C' | b'c' => b'G',
+        b'G' | b'g' => b'C',
+        b'T' | b'U' | b't' | b'u' => b'A',
+        _ => b'N',
+    }
+}
+
+/// one entry of MM tag, e.g. C+m?,5,12,0
+struct MmEntry {
+    base: u8,
+    strand: u8,
+    codes: Vec<String>,
+    skips: Vec<usize>,
+}
+
+fn parse_mm_entry(entry: &str) -> Option<MmEntry> {
+    let mut items = entry.split(',');
+    let header = items.next()?.as_bytes();
+    if header.len() < 3 || !(header[1] == b'+' || header[1] == b'-') {
+        return None;
+    }
+    let mut codes_part = &header[2..];
+    if let Some(b'.' | b'?') = codes_part.last() {
+        codes_part = &codes_part[..codes_part.len() - 1];
+    }
+    if codes_part.is_empty() {
+        return None;
+    }
+    let codes = if codes_part.iter().all(|x| x.is_ascii_digit()) {
+        // ChEBI code
+        vec![String::from_utf8_lossy(codes_part).to_string()]
+    } else if codes_part.iter().all(|x| x.is_ascii_alphabetic()) {
+        codes_part
+            .iter()
+            .map(|x| (*x as char).to_string())
+            .collect()
+    } else {
+        return None;
+    };
+    let mut skips = vec![];
+    for skip in items {
+        skips.push(skip.trim().parse::<usize>().ok()?);
+    }
+    Some(MmEntry {
+        base: header[0].to_ascii_uppercase(),
+        strand: header[1],
+        codes,
+        skips,
+    })
+}
+
+///
+/// Modified base summary from MM/ML tags of all reads.
+///
+/// Sites in MM tag are counted along the read as it was sequenced, so reverse strand
+/// alignments are reverse complemented firstly. The skips are counted on the stated base of the
+/// entry, e.g. G of G-m, the strand only tells the modification is on the opposite strand.
+///
+#[derive(Debug, Default, Clone)]
+pub struct ModificationStats {
+    reads_valid: usize,
+    reads_missing: usize,
+    reads_inconsistent: usize,
+    codes: BTreeMap<String, ModCodeStats>, // key is like C+m, A+a
+}
+
+impl ModificationStats {
+    pub fn is_empty(&self) -> bool {
+        self.reads_valid == 0 && self.reads_missing == 0 && self.reads_inconsistent == 0
+    }
+
+    pub fn reads_valid(&self) -> usize {
+        self.reads_valid
+    }
+
+    pub fn add_record(&mut self, record: &bam::Record) -> ReadMods {
+        let read_mods = self.parse_record(record);
+        match read_mods.status {
+            ModStatus::Valid => self.reads_valid += 1,
+            ModStatus::Missing => self.reads_missing += 1,
+            ModStatus::Inconsistent => self.reads_inconsistent += 1,
+        }
+        read_mods
+    }
+
+    fn parse_record(&mut self, record: &bam::Record) -> ReadMods {
+        let mm = match record.aux(b"MM").or_else(|_| record.aux(b"Mm")) {
+            Ok(Aux::String(mm)) => mm,
+            Ok(_) => return ReadMods::new(ModStatus::Inconsistent),
+            Err(_) => return ReadMods::new(ModStatus::Missing),
+        };
+        let ml = match record.aux(b"ML").or_else(|_| record.aux(b"Ml")) {
+            Ok(Aux::ArrayU8(ml)) => ml.iter().collect::<Vec<u8>>(),
+            Ok(_) => return ReadMods::new(ModStatus::Inconsistent),
+            Err(_) if mm.is_empty() => vec![],
+            Err(_) => return ReadMods::new(ModStatus::Missing),
+        };
+        let mut seq = record.seq().as_bytes();
+        if record.is_reverse() {
+            seq.reverse();
+            seq.iter_mut().for_each(|x| *x = complement_or_n(*x));
+        }
+
+        // check all entries firstly, nothing is counted if the read is inconsistent
+        let mut parsed = vec![];
+        let mut ml_offset = 0usize;
+        for entry in mm.split(';').filter(|x| !x.is_empty()) {
+            let Some(mm_entry) = parse_mm_entry(entry) else {
+                return ReadMods::new(ModStatus::Inconsistent);
+            };
+            let target = mm_entry.base;
+            let candidate_bases = seq
+                .iter()
+                .filter(|x| target == b'N' || x.to_ascii_uppercase() == target)
+                .count();
+            let used_bases = mm_entry.skips.iter().map(|x| x + 1).sum::<usize>();
+            if used_bases > candidate_bases {
+                return ReadMods::new(ModStatus::Inconsistent);
+            }
+            let ml_len = mm_entry.skips.len() * mm_entry.codes.len();
+            if ml_offset + ml_len > ml.len() {
+                return ReadMods::new(ModStatus::Inconsistent);
+            }
+            parsed.push((mm_entry, candidate_bases, ml_offset));
+            ml_offset += ml_len;
+        }
+        if ml_offset != ml.len() {
+            return ReadMods::new(ModStatus::Inconsistent);
+        }
+
+        let mut read_mods = ReadMods::new(ModStatus::Valid);
+        for (mm_entry, candidate_bases, ml_offset) in parsed {
+            let codes_number = mm_entry.codes.len();
+            for (code_idx, code) in mm_entry.codes.iter().enumerate() {
+                let key = format!(
+                    "{}{}{}",
+                    mm_entry.base as char, mm_entry.strand as char, code
+                );
+                let code_stats = self.codes.entry(key).or_default();
+                code_stats.candidate_bases += candidate_bases as u64;
+                code_stats.called += mm_entry.skips.len() as u64;
+                // probabilities of multiple codes in one entry are interleaved for each site
+                for site in 0..mm_entry.skips.len() {
+                    let prob = ml[ml_offset + site * codes_number + code_idx];
+                    code_stats.prob_hist[prob as usize] += 1;
+                    if prob >= MODIFIED_ML_THRESHOLD {
+                        code_stats.modified += 1;
+                        read_mods.modified += 1;
+                    }
+                }
+                read_mods.called += mm_entry.skips.len() as u32;
+            }
+        }
+        read_mods
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "ReadsWithMods": self.reads_valid,
+            "ReadsMissingMods": self.reads_missing,
+            "ReadsInconsistentMods": self.reads_inconsistent,
+            "Codes": self
+                .codes
+                .iter()
+                .map(|(key, x)| {
+                    json!({
+                        "Code": key,
+                        "CandidateBases": x.candidate_bases,
+                        "CalledSites": x.called,
+                        "ModifiedSites": x.modified,
+                        "ProbabilityHistogram": x.prob_hist.to_vec(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn dict_basic_info(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ReadsWithMods", self.reads_valid.to_string()),
+            ("ReadsMissingMods", self.reads_missing.to_string()),
+            ("ReadsInconsistentMods", self.reads_inconsistent.to_string()),
+        ]
+    }
+
+    /// Code, CandidateBases, CalledSites, CalledFraction, ModifiedSites, ModifiedFraction
+    pub fn code_rows(&self) -> Vec<(String, u64, u64, f64, u64, f64)> {
+        self.codes
+            .iter()
+            .map(|(key, x)| {
+                (
+                    mod_code_label(key),
+                    x.candidate_bases,
+                    x.called,
+                    if x.candidate_bases == 0 {
+                        0.0
+                    } else {
+                        x.called as f64 / x.candidate_bases as f64
+                    },
+                    x.modified,
+                    if x.called == 0 {
+                        0.0
+                    } else {
+                        x.modified as f64 / x.called as f64
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// probability histogram of each modification code and per-read modification rate histogram
+    pub fn html_div(&self, read_mod_rates: &[f32], bins: usize) -> String {
+        let mut plot = Plot::new();
+        for (key, code_stats) in self.codes.iter() {
+            let label = mod_code_label(key);
+            let total = code_stats.called.max(1) as f64;
+            let probs = (0..256)
+                .map(|x| (x as f64 + 0.5) / 256.0)
+                .collect::<Vec<_>>();
+            let fractions = code_stats
+                .prob_hist
+                .iter()
+                .map(|x| *x as f64 / total)
+                .collect::<Vec<_>>();
+            let trace = Scatter::new(probs, fractions)
+                .mode(Mode::Lines)
+                .name(&label)
+                .hover_template(format!(
+                    "<b>{label}</b><br><b>Probability</b>: %{{x:.3f}}<br>\
+                    <b>FractionOfCalls</b>: %{{y:.4f}}<extra></extra>"
+                ))
+                .x_axis("x1")
+                .y_axis("y1");
+            plot.add_trace(trace);
+        }
+
+        let mut rate_counts = vec![0usize; bins];
+        for rate in read_mod_rates {
+            let idx = ((*rate as f64 * bins as f64) as usize).min(bins - 1);
+            rate_counts[idx] += 1;
+        }
+        let rate_trace = Scatter::new(
+            (0..bins)
+                .map(|x| (x as f64 + 0.5) / bins as f64)
+                .collect::<Vec<_>>(),
+            rate_counts,
+        )
+        .mode(Mode::Lines)
+        .name("ModRate")
+        .hover_template("<b>ModRate</b>: %{x:.3f}<br><b>ReadsCount</b>: %{y}<extra></extra>")
+        .x_axis("x2")
+        .y_axis("y2")
+        .show_legend(false);
+        plot.add_trace(rate_trace);
+
+        let layout = Layout::new()
+            .hover_mode(HoverMode::Closest)
+            .grid(
+                LayoutGrid::new()
+                    .rows(1)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("ModificationProbability")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("FractionOfCalls")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title("Per-read ModRate")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(450);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("modification_distribution"))
+    }
+}
+
+impl Display for ModificationStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "#ModificationSummary\n\
+            ReadsWithMods\t{}\n\
+            ReadsMissingMods\t{}\t# no MM/ML tag\n\
+            ReadsInconsistentMods\t{}\t# MM/ML inconsistent with read sequence\n\
+            Code\tCandidateBases\tCalledSites\tCalledFraction\tModifiedSites\tModifiedFraction",
+            self.reads_valid, self.reads_missing, self.reads_inconsistent
+        )?;
+        for (code, candidate, called, called_fraction, modified, modified_fraction) in
+            self.code_rows()
+        {
+            writeln!(
+                f,
+                "{code}\t{candidate}\t{called}\t{called_fraction:.6}\t{modified}\t{modified_fraction:.6}"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl AddAssign for ModificationStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.reads_valid += rhs.reads_valid;
+        self.reads_missing += rhs.reads_missing;
+        self.reads_inconsistent += rhs.reads_inconsistent;
+        for (key, code_stats) in rhs.codes {
+            *self.codes.entry(key).or_default() += code_stats;
+        }
+    }
+}
+
+#[cfg(test)]
+mod modification_test {
+    use super::*;
+    use rust_htslib::bam::record::AuxArray;
+
+    fn record(seq: &[u8], reverse: bool, mm: Option<&str>, ml: Option<&[u8]>) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(b"read1", None, seq, &vec![30; seq.len()]);
+        if reverse {
+            record.set_flags(16);
+        }
+        if let Some(mm) = mm {
+            record.push_aux(b"MM", Aux::String(mm)).unwrap();
+        }
+        if let Some(ml) = ml {
+            record
+                .push_aux(b"ML", Aux::ArrayU8(AuxArray::from(&ml)))
+                .unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn mm_entry() {
+        let entry = parse_mm_entry("C+m?,5,12,0").unwrap();
+        assert_eq!((entry.base, entry.strand), (b'C', b'+'));
+        assert_eq!(entry.codes, vec!["m"]);
+        assert_eq!(entry.skips, vec![5, 12, 0]);
+        let entry = parse_mm_entry("A+17596.,1").unwrap();
+        assert_eq!(entry.codes, vec!["17596"]);
+        let entry = parse_mm_entry("C+hm,0").unwrap();
+        assert_eq!(entry.codes, vec!["h", "m"]);
+        assert!(parse_mm_entry("C+m").unwrap().skips.is_empty());
+        assert!(parse_mm_entry("C*m,0").is_none());
+        assert!(parse_mm_entry("C+,0").is_none());
+        assert!(parse_mm_entry("C+m1,0").is_none());
+        assert!(parse_mm_entry("C+m,x").is_none());
+    }
+
+    #[test]
+    fn single_code() {
+        let mut stats = ModificationStats::default();
+        // the 1st and 3rd C are called
+        let mods = stats.add_record(&record(
+            b"ACGTCACG",
+            false,
+            Some("C+m?,0,1;"),
+            Some(&[200, 50]),
+        ));
+        assert_eq!(mods.status, ModStatus::Valid);
+        assert_eq!((mods.called, mods.modified), (2, 1));
+        assert_eq!(mods.rate(), Some(0.5));
+        let code = &stats.codes["C+m"];
+        assert_eq!(
+            (code.candidate_bases, code.called, code.modified),
+            (3, 2, 1)
+        );
+        assert_eq!((code.prob_hist[200], code.prob_hist[50]), (1, 1));
+    }
+
+    #[test]
+    fn chebi_code() {
+        let mut stats = ModificationStats::default();
+        let mods = stats.add_record(&record(b"GATTACA", false, Some("A+17596,1;"), Some(&[255])));
+        assert_eq!(
+            (mods.status, mods.called, mods.modified),
+            (ModStatus::Valid, 1, 1)
+        );
+        assert_eq!(stats.codes["A+17596"].candidate_bases, 3);
+        assert_eq!(stats.code_rows()[0].0, "Inosine (A+17596)");
+    }
+
+    #[test]
+    fn interleaved_codes() {
+        let mut stats = ModificationStats::default();
+        // ML is h1, m1, h2, m2
+        let mods = stats.add_record(&record(
+            b"CGCG",
+            false,
+            Some("C+hm,0,0;"),
+            Some(&[10, 200, 220, 5]),
+        ));
+        assert_eq!((mods.called, mods.modified), (4, 2));
+        let (h, m) = (&stats.codes["C+h"], &stats.codes["C+m"]);
+        assert_eq!((h.prob_hist[10], h.prob_hist[220], h.modified), (1, 1, 1));
+        assert_eq!((m.prob_hist[200], m.prob_hist[5], m.modified), (1, 1, 1));
+    }
+
+    #[test]
+    fn reverse_strand() {
+        let mut stats = ModificationStats::default();
+        // the read is TACC as sequenced, GGTA in bam
+        let mods = stats.add_record(&record(b"GGTA", true, Some("C+m,0,0;"), Some(&[255, 0])));
+        assert_eq!(
+            (mods.status, mods.called, mods.modified),
+            (ModStatus::Valid, 2, 1)
+        );
+        assert_eq!(stats.codes["C+m"].candidate_bases, 2);
+        // the forward strand read has no C
+        let mods = stats.add_record(&record(b"GGTA", false, Some("C+m,0,0;"), Some(&[255, 0])));
+        assert_eq!(mods.status, ModStatus::Inconsistent);
+        // G-m is counted on G of the read as sequenced, TACC has no G
+        let mods = stats.add_record(&record(b"GGTA", true, Some("G-m,1;"), Some(&[255])));
+        assert_eq!(mods.status, ModStatus::Inconsistent);
+        let mods = stats.add_record(&record(b"GGTA", false, Some("G-m,1;"), Some(&[255])));
+        assert_eq!(
+            (mods.status, mods.called, mods.modified),
+            (ModStatus::Valid, 1, 1)
+        );
+        assert_eq!(stats.codes["G-m"].candidate_bases, 2);
+        // duplex calls of both strands, C+m on the Cs and G-m on the Gs of CCGG as sequenced
+        let mods = stats.add_record(&record(
+            b"CCGG",
+            true,
+            Some("C+m,0,0;G-m,1;"),
+            Some(&[255, 255, 0]),
+        ));
+        assert_eq!(
+            (mods.status, mods.called, mods.modified),
+            (ModStatus::Valid, 3, 2)
+        );
+        assert_eq!(stats.codes["G-m"].candidate_bases, 4);
+    }
+
+    #[test]
+    fn missing_and_inconsistent() {
+        let mut stats = ModificationStats::default();
+        let seq = b"ACGTCACG";
+        let status = |stats: &mut ModificationStats, mm, ml| {
+            stats.add_record(&record(seq, false, mm, ml)).status
+        };
+        assert_eq!(status(&mut stats, None, None), ModStatus::Missing);
+        assert_eq!(status(&mut stats, Some("C+m,0;"), None), ModStatus::Missing);
+        assert_eq!(status(&mut stats, Some(""), None), ModStatus::Valid);
+        // more skips than C in the read
+        assert_eq!(
+            status(&mut stats, Some("C+m,3;"), Some(&[1])),
+            ModStatus::Inconsistent
+        );
+        // ML is shorter or longer than the sites of MM
+        assert_eq!(
+            status(&mut stats, Some("C+m,0,0;"), Some(&[1])),
+            ModStatus::Inconsistent
+        );
+        assert_eq!(
+            status(&mut stats, Some("C+m,0;"), Some(&[1, 2])),
+            ModStatus::Inconsistent
+        );
+        assert_eq!(
+            status(&mut stats, Some("C*m,0;"), Some(&[1])),
+            ModStatus::Inconsistent
+        );
+        assert_eq!(
+            (
+                stats.reads_valid,
+                stats.reads_missing,
+                stats.reads_inconsistent
+            ),
+            (1, 2, 4)
+        );
+        // nothing is counted for inconsistent reads
+        assert!(stats.codes.is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use plotly::color::NamedColor;
+use plotly::common::{Line, Mode, Title};
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, GridPattern, HoverMode, LayoutGrid};
+use plotly::{Layout, Plot, Scatter};
+use serde_json::{Value, json};
+use std::ops::AddAssign;
+
+const BASE_LABELS: [&str; 5] = ["A", "C", "G", "T", "N"];
+const BASE_COLORS: [NamedColor; 5] = [
+    NamedColor::ForestGreen,
+    NamedColor::RoyalBlue,
+    NamedColor::Orange,
+    NamedColor::Crimson,
+    NamedColor::Gray,
+];
+
+fn base_index(base: u8) -> usize {
+    match base {
+        b'A' | b'a' => 0,
+        b'C' | b'c' => 1,
+        b'G' | b'g' => 2,
+        b'T' | b't' | b'U' | b'u' => 3,
+        _ => 4,
+    }
+}
+
+///
+/// Per-position quality and base composition of reads, anchored from both the 5' end and the 3' end.
+///
+/// Only the first and the last `depth` bases of each read are counted, so the memory used
+/// doesn't depend on the read length. Positions are always in the original read orientation,
+/// so reverse strand alignments from bam are reverse complemented before being counted.
+///
+#[derive(Debug, Clone)]
+pub struct PositionProfile {
+    depth: usize,
+    head_qual_sum: Vec<u64>,
+    head_bases: Vec<[u64; 5]>, // A, C, G, T, N counts at each position from 5' end
+    tail_qual_sum: Vec<u64>,
+    tail_bases: Vec<[u64; 5]>, // A, C, G, T, N counts at each position from 3' end
+}
+
+impl PositionProfile {
+    pub fn new(depth: usize) -> Self {
+        PositionProfile {
+            depth,
+            head_qual_sum: vec![0; depth],
+            head_bases: vec![[0; 5]; depth],
+            tail_qual_sum: vec![0; depth],
+            tail_bases: vec![[0; 5]; depth],
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Depth": self.depth,
+            "HeadQualSum": self.head_qual_sum,
+            "HeadBases": self.head_bases,
+            "TailQualSum": self.tail_qual_sum,
+            "TailBases": self.tail_bases,
+        })
+    }
+
+    /// restore the profile saved by to_json, None if it's broken or its depth is not `depth`
+    pub fn from_json(value: &Value, depth: usize) -> Option<Self> {
+        let profile = PositionProfile {
+            depth: value["Depth"].as_u64()? as usize,
+            head_qual_sum: serde_json::from_value(value["HeadQualSum"].clone()).ok()?,
+            head_bases: serde_json::from_value(value["HeadBases"].clone()).ok()?,
+            tail_qual_sum: serde_json::from_value(value["TailQualSum"].clone()).ok()?,
+            tail_bases: serde_json::from_value(value["TailBases"].clone()).ok()?,
+        };
+        let is_consistent = profile.depth == depth
+            && profile.head_qual_sum.len() == depth
+            && profile.head_bases.len() == depth
+            && profile.tail_qual_sum.len() == depth
+            && profile.tail_bases.len() == depth;
+        if is_consistent { Some(profile) } else { None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth == 0 || self.head_bases[0].iter().sum::<u64>() == 0
+    }
+
+    ///
+    /// Add one read into profile.
+    ///
+    /// `qual_offset` is 33 for quality string from fastq and 0 for quality from bam record.
+    /// If `reverse` is true, `seq` and `qual` will be seen as reverse complemented read.
+    ///
+    pub fn add_read(&mut self, seq: &[u8], qual: &[u8], qual_offset: u8, reverse: bool) {
+        debug_assert_eq!(seq.len(), qual.len());
+        let read_len = seq.len();
+        let end = read_len.min(self.depth);
+        for i in 0..end {
+            // i is the distance to 5' end for head and the distance to 3' end for tail
+            let (head_idx, tail_idx) = if reverse {
+                (read_len - 1 - i, i)
+            } else {
+                (i, read_len - 1 - i)
+            };
+            let mut head_base = base_index(seq[head_idx]);
+            let mut tail_base = base_index(seq[tail_idx]);
+            if reverse {
+                head_base = if head_base < 4 { 3 - head_base } else { 4 };
+                tail_base = if tail_base < 4 { 3 - tail_base } else { 4 };
+            }
+            self.head_bases[i][head_base] += 1;
+            self.head_qual_sum[i] += qual[head_idx].saturating_sub(qual_offset) as u64;
+            self.tail_bases[i][tail_base] += 1;
+            self.tail_qual_sum[i] += qual[tail_idx].saturating_sub(qual_offset) as u64;
+        }
+    }
+
+    fn mean_qual(qual_sum: &[u64], bases: &[[u64; 5]]) -> (Vec<i64>, Vec<f64>) {
+        let mut positions = vec![];
+        let mut means = vec![];
+        for (idx, (qual, counts)) in qual_sum.iter().zip(bases.iter()).enumerate() {
+            let total = counts.iter().sum::<u64>();
+            if total == 0 {
+                break;
+            }
+            positions.push(idx as i64 + 1);
+            means.push(*qual as f64 / total as f64);
+        }
+        (positions, means)
+    }
+
+    fn composition(bases: &[[u64; 5]]) -> (Vec<i64>, [Vec<f64>; 5]) {
+        let mut positions = vec![];
+        let mut fractions: [Vec<f64>; 5] = Default::default();
+        for (idx, counts) in bases.iter().enumerate() {
+            let total = counts.iter().sum::<u64>();
+            if total == 0 {
+                break;
+            }
+            positions.push(idx as i64 + 1);
+            for (base, count) in counts.iter().enumerate() {
+                fractions[base].push(*count as f64 / total as f64);
+            }
+        }
+        (positions, fractions)
+    }
+
+    fn two_panels_layout(y_title: &str) -> Layout {
+        Layout::new()
+            .hover_mode(HoverMode::XUnified)
+            .grid(
+                LayoutGrid::new()
+                    .rows(1)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title(Title::with_text("Position from 5' end"))
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title(Title::with_text(y_title))
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title(Title::with_text("Position to 3' end"))
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(Axis::new().line_color("black").line_width(1))
+            .height(450)
+    }
+
+    /// plot mean quality of each position from both ends
+    pub fn quality_html_div(&self) -> String {
+        let mut plot = Plot::new();
+        let (head_positions, head_means) = Self::mean_qual(&self.head_qual_sum, &self.head_bases);
+        let (tail_positions, tail_means) = Self::mean_qual(&self.tail_qual_sum, &self.tail_bases);
+        let tail_positions = tail_positions.into_iter().map(|x| -x).collect::<Vec<_>>();
+        for (idx, (positions, means)) in
+            [(head_positions, head_means), (tail_positions, tail_means)]
+                .into_iter()
+                .enumerate()
+        {
+            let trace = Scatter::new(positions, means)
+                .mode(Mode::Lines)
+                .name("MeanQuality")
+                .hover_template("<b>MeanQuality</b>: %{y:.2}<extra></extra>")
+                .x_axis(format!("x{}", idx + 1))
+                .y_axis(format!("y{}", idx + 1))
+                .show_legend(false)
+                .line(Line::new().color(NamedColor::RoyalBlue));
+            plot.add_trace(trace);
+        }
+        plot.set_layout(Self::two_panels_layout("MeanQuality"));
+        plot.to_inline_html(Some("position_quality"))
+    }
+
+    /// plot A/C/G/T/N fraction of each position from both ends
+    pub fn composition_html_div(&self) -> String {
+        let mut plot = Plot::new();
+        let (head_positions, head_fractions) = Self::composition(&self.head_bases);
+        let (tail_positions, tail_fractions) = Self::composition(&self.tail_bases);
+        let tail_positions = tail_positions.into_iter().map(|x| -x).collect::<Vec<_>>();
+        for (idx, (positions, fractions)) in [
+            (head_positions, head_fractions),
+            (tail_positions, tail_fractions),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            for (base, fraction) in fractions.into_iter().enumerate() {
+                let trace = Scatter::new(positions.clone(), fraction)
+                    .mode(Mode::Lines)
+                    .name(BASE_LABELS[base])
+                    .legend_group(BASE_LABELS[base])
+                    .hover_template(format!(
+                        "<b>{}</b>: %{{y:.4f}}<extra></extra>",
+                        BASE_LABELS[base]
+                    ))
+                    .x_axis(format!("x{}", idx + 1))
+                    .y_axis(format!("y{}", idx + 1))
+                    .show_legend(idx == 0)
+                    .line(Line::new().color(BASE_COLORS[base]));
+                plot.add_trace(trace);
+            }
+        }
+        plot.set_layout(Self::two_panels_layout("BaseFraction"));
+        plot.to_inline_html(Some("position_composition"))
+    }
+}
+
+impl AddAssign for PositionProfile {
+    fn add_assign(&mut self, rhs: Self) {
+        debug_assert_eq!(self.depth, rhs.depth);
+        for i in 0..rhs.depth {
+            self.head_qual_sum[i] += rhs.head_qual_sum[i];
+            self.tail_qual_sum[i] += rhs.tail_qual_sum[i];
+            for base in 0..5 {
+                self.head_bases[i][base] += rhs.head_bases[i][base];
+                self.tail_bases[i][base] += rhs.tail_bases[i][base];
+            }
+        }
+    }
+}
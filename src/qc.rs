@@ -0,0 +1,498 @@
+use crate::utils::quit_with_error;
+use clap::ArgMatches;
+use log::warn;
+use serde_json::{Value, json};
+use std::fmt::{Display, Formatter};
+
+// exit codes of stats and report when the qc gate is not passed, 1 is used by other errors and
+// 2 by clap for bad arguments
+pub const QC_WARN_EXIT_CODE: i32 = 3;
+pub const QC_FAIL_EXIT_CODE: i32 = 4;
+
+/// the statistics checked by the qc gate
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum QcMetric {
+    MinBases,
+    MinN50,
+    MinMedianQ,
+    MaxFracBelowQ10,
+    MinMapRate,
+}
+
+impl QcMetric {
+    const ALL: [QcMetric; 5] = [
+        QcMetric::MinBases,
+        QcMetric::MinN50,
+        QcMetric::MinMedianQ,
+        QcMetric::MaxFracBelowQ10,
+        QcMetric::MinMapRate,
+    ];
+
+    /// the key in rules file and --qc_fail/--qc_warn
+    fn key(&self) -> &'static str {
+        match self {
+            QcMetric::MinBases => "min_bases",
+            QcMetric::MinN50 => "min_n50",
+            QcMetric::MinMedianQ => "min_median_q",
+            QcMetric::MaxFracBelowQ10 => "max_frac_below_q10",
+            QcMetric::MinMapRate => "min_map_rate",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.key() == key)
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            QcMetric::MinBases => "BasesNumber",
+            QcMetric::MinN50 => "N50",
+            QcMetric::MinMedianQ => "ReadMedianQual",
+            QcMetric::MaxFracBelowQ10 => "FractionBelowQ10",
+            QcMetric::MinMapRate => "MapRate",
+        }
+    }
+
+    fn is_max(&self) -> bool {
+        matches!(self, QcMetric::MaxFracBelowQ10)
+    }
+
+    fn index(&self) -> usize {
+        Self::ALL.iter().position(|x| x == self).unwrap()
+    }
+
+    /// whether the value violates the threshold
+    fn violates(&self, value: f64, threshold: f64) -> bool {
+        if self.is_max() {
+            value > threshold
+        } else {
+            value < threshold
+        }
+    }
+}
+
+/// values of the checked statistics, map rate is None for fastq and unaligned bam
+#[derive(Debug, Clone, Default)]
+pub struct QcMetrics {
+    pub bases: usize,
+    pub n50: u32,
+    pub median_qual: f32,
+    pub frac_below_q10: f64,
+    pub map_rate: Option<f64>,
+}
+
+impl QcMetrics {
+    fn value(&self, metric: QcMetric) -> Option<f64> {
+        match metric {
+            QcMetric::MinBases => Some(self.bases as f64),
+            QcMetric::MinN50 => Some(self.n50 as f64),
+            QcMetric::MinMedianQ => Some(self.median_qual as f64),
+            QcMetric::MaxFracBelowQ10 => Some(self.frac_below_q10),
+            QcMetric::MinMapRate => self.map_rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum QcStatus {
+    NotAvailable, // the statistic can't be computed from this input, such as map rate of fastq
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl QcStatus {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            QcStatus::Warn => QC_WARN_EXIT_CODE,
+            QcStatus::Fail => QC_FAIL_EXIT_CODE,
+            _ => 0,
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            QcStatus::NotAvailable => "#999999",
+            QcStatus::Pass => "#1e8449",
+            QcStatus::Warn => "#d68910",
+            QcStatus::Fail => "#c0392b",
+        }
+    }
+}
+
+impl Display for QcStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            QcStatus::NotAvailable => "NA",
+            QcStatus::Pass => "PASS",
+            QcStatus::Warn => "WARN",
+            QcStatus::Fail => "FAIL",
+        };
+        write!(f, "{status}")
+    }
+}
+
+///
+/// Warn and fail thresholds of each metric. For min_* rules a value below the threshold is
+/// flagged, for max_* rules a value above it. The rules file is a toml file like:
+/// ```toml
+/// [fail]
+/// min_bases = 1_000_000_000
+/// min_n50 = 5000
+/// [warn]
+/// min_bases = 5_000_000_000
+/// max_frac_below_q10 = 0.1
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct QcRules {
+    warn: [Option<f64>; 5],
+    fail: [Option<f64>; 5],
+}
+
+impl QcRules {
+    pub fn is_empty(&self) -> bool {
+        self.warn
+            .iter()
+            .chain(self.fail.iter())
+            .all(|x| x.is_none())
+    }
+
+    fn set(&mut self, level: &str, key: &str, threshold: f64, source: &str) {
+        let metric = QcMetric::from_key(key).unwrap_or_else(|| {
+            quit_with_error(&format!(
+                "Unknown qc rule {key} in {source}, should be one of {}",
+                QcMetric::ALL.map(|x| x.key()).join(", ")
+            ))
+        });
+        let thresholds = if level == "warn" {
+            &mut self.warn
+        } else {
+            &mut self.fail
+        };
+        thresholds[metric.index()] = Some(threshold);
+    }
+
+    fn load_toml(&mut self, rules_file: &str) {
+        let contents = std::fs::read_to_string(rules_file)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to read {rules_file}")));
+        self.load_toml_str(&contents, rules_file);
+    }
+
+    fn load_toml_str(&mut self, contents: &str, rules_file: &str) {
+        let table = contents
+            .parse::<toml::Table>()
+            .unwrap_or_else(|x| quit_with_error(&format!("Bad qc rules file {rules_file}: {x}")));
+        for (level, rules) in table {
+            if level != "warn" && level != "fail" {
+                quit_with_error(&format!(
+                    "Unknown table [{level}] in {rules_file}, only [warn] and [fail] are allowed"
+                ))
+            }
+            let Some(rules) = rules.as_table() else {
+                quit_with_error(&format!("{level} in {rules_file} should be a table"))
+            };
+            for (key, value) in rules {
+                let threshold = value
+                    .as_float()
+                    .or_else(|| value.as_integer().map(|x| x as f64))
+                    .unwrap_or_else(|| {
+                        quit_with_error(&format!(
+                            "The threshold of {key} in {rules_file} should be a number"
+                        ))
+                    });
+                self.set(&level, key, threshold, rules_file);
+            }
+        }
+    }
+
+    /// rules in the format of "min_bases=1e9,min_n50=5000"
+    fn load_arg(&mut self, level: &str, arg: &str) {
+        let arg_name = format!("--qc_{level}");
+        for rule in arg.split(',').filter(|x| !x.is_empty()) {
+            let (key, threshold) = rule
+                .split_once('=')
+                .and_then(|(key, threshold)| Some((key, threshold.parse::<f64>().ok()?)))
+                .unwrap_or_else(|| {
+                    quit_with_error(&format!(
+                        "Bad qc rule {rule} in {arg_name}, the format should be key=value"
+                    ))
+                });
+            self.set(level, key, threshold, &arg_name);
+        }
+    }
+
+    /// the rules of --qc_rules file, which are overridden by --qc_fail and --qc_warn.
+    /// None if no rule is given
+    pub fn from_arg_matches(matches: &ArgMatches) -> Option<Self> {
+        let mut rules = QcRules::default();
+        if let Some(rules_file) = matches.get_one::<String>("qc_rules") {
+            rules.load_toml(rules_file);
+        }
+        if let Some(fail) = matches.get_one::<String>("qc_fail") {
+            rules.load_arg("fail", fail);
+        }
+        if let Some(warn) = matches.get_one::<String>("qc_warn") {
+            rules.load_arg("warn", warn);
+        }
+        if rules.is_empty() { None } else { Some(rules) }
+    }
+
+    pub fn evaluate(&self, metrics: &QcMetrics) -> QcReport {
+        let mut results = vec![];
+        for metric in QcMetric::ALL {
+            let (warn, fail) = (self.warn[metric.index()], self.fail[metric.index()]);
+            if warn.is_none() && fail.is_none() {
+                continue;
+            }
+            let value = metrics.value(metric);
+            let status = match value {
+                None => {
+                    // NA is not worse than PASS, so tell the user the rule is not checked
+                    warn!(
+                        "qc rule {} can't be evaluated for this input and is ignored",
+                        metric.key()
+                    );
+                    QcStatus::NotAvailable
+                }
+                Some(v) if fail.is_some_and(|x| metric.violates(v, x)) => QcStatus::Fail,
+                Some(v) if warn.is_some_and(|x| metric.violates(v, x)) => QcStatus::Warn,
+                Some(_) => QcStatus::Pass,
+            };
+            results.push(QcResult {
+                metric,
+                value,
+                warn,
+                fail,
+                status,
+            });
+        }
+        QcReport { results }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QcResult {
+    metric: QcMetric,
+    value: Option<f64>,
+    warn: Option<f64>,
+    fail: Option<f64>,
+    status: QcStatus,
+}
+
+fn format_threshold(metric: QcMetric, threshold: Option<f64>) -> String {
+    match threshold {
+        None => "-".to_string(),
+        Some(x) => format!("{}{x}", if metric.is_max() { "<=" } else { ">=" }),
+    }
+}
+
+fn format_value(value: Option<f64>) -> String {
+    match value {
+        None => "NA".to_string(),
+        Some(x) if x.fract() == 0.0 => format!("{x}"),
+        Some(x) => format!("{x:.4}"),
+    }
+}
+
+/// status of each rule, the overall status is the worst one
+#[derive(Debug, Clone)]
+pub struct QcReport {
+    results: Vec<QcResult>,
+}
+
+impl QcReport {
+    pub fn status(&self) -> QcStatus {
+        self.results
+            .iter()
+            .map(|x| x.status)
+            .max()
+            .unwrap_or(QcStatus::Pass)
+            .max(QcStatus::Pass)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Status": self.status().to_string(),
+            "Rules": self
+                .results
+                .iter()
+                .map(|x| {
+                    json!({
+                        "Rule": x.metric.key(),
+                        "Metric": x.metric.label(),
+                        "Value": x.value,
+                        "Warn": x.warn,
+                        "Fail": x.fail,
+                        "Status": x.status.to_string(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn html_section(&self) -> String {
+        let status = self.status();
+        let mut html = format!(
+            r#"
+        <div class="section">
+            <div class="section-header">🚦 QC Gate: <span style="color: {};">{status}</span></div>
+            <table>
+                <thead>
+                    <tr><th>Rule</th><th>Metric</th><th>Value</th><th>Warn</th><th>Fail</th><th>Status</th></tr>
+                </thead>
+                <tbody>
+        "#,
+            status.color()
+        );
+        for x in &self.results {
+            html.push_str(&format!(
+                r#"
+                    <tr>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td style="color: {}; font-weight: 600;">{}</td>
+                    </tr>
+            "#,
+                x.metric.key(),
+                x.metric.label(),
+                format_value(x.value),
+                format_threshold(x.metric, x.warn),
+                format_threshold(x.metric, x.fail),
+                x.status.color(),
+                x.status
+            ));
+        }
+        html.push_str(
+            r#"
+                </tbody>
+            </table>
+        </div>
+        "#,
+        );
+        html
+    }
+
+    /// print the qc table and exit with the exit code of qc status if the gate is not passed
+    pub fn print_and_exit(&self) {
+        print!("{self}");
+        let exit_code = self.status().exit_code();
+        if exit_code != 0 {
+            std::process::exit(exit_code)
+        }
+    }
+}
+
+impl Display for QcReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "#QCGate\t{}\nRule\tMetric\tValue\tWarn\tFail\tStatus",
+            self.status()
+        )?;
+        for x in &self.results {
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                x.metric.key(),
+                x.metric.label(),
+                format_value(x.value),
+                format_threshold(x.metric, x.warn),
+                format_threshold(x.metric, x.fail),
+                x.status
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod qc_test {
+    use super::*;
+
+    fn metrics(map_rate: Option<f64>) -> QcMetrics {
+        QcMetrics {
+            bases: 2_000_000_000,
+            n50: 8000,
+            median_qual: 15.0,
+            frac_below_q10: 0.05,
+            map_rate,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let mut rules = QcRules::default();
+        assert!(rules.is_empty());
+        rules.load_toml_str(
+            "[fail]\nmin_bases = 1_000_000_000\nmin_n50 = 5000\n[warn]\nmax_frac_below_q10 = 0.1\n",
+            "rules.toml",
+        );
+        assert_eq!(rules.fail[QcMetric::MinBases.index()], Some(1e9));
+        assert_eq!(rules.fail[QcMetric::MinN50.index()], Some(5000.0));
+        assert_eq!(rules.warn[QcMetric::MaxFracBelowQ10.index()], Some(0.1));
+        assert_eq!(rules.warn[QcMetric::MinBases.index()], None);
+        // the arguments override the rules file
+        rules.load_arg("fail", "min_n50=10000,min_median_q=12");
+        rules.load_arg("warn", "min_bases=5e9,");
+        assert_eq!(rules.fail[QcMetric::MinN50.index()], Some(10000.0));
+        assert_eq!(rules.fail[QcMetric::MinMedianQ.index()], Some(12.0));
+        assert_eq!(rules.fail[QcMetric::MinBases.index()], Some(1e9));
+        assert_eq!(rules.warn[QcMetric::MinBases.index()], Some(5e9));
+        assert!(!rules.is_empty());
+    }
+
+    #[test]
+    fn evaluate_rules() {
+        let mut rules = QcRules::default();
+        rules.load_arg("fail", "min_bases=1e9,min_n50=5000");
+        let report = rules.evaluate(&metrics(None));
+        assert_eq!(report.status(), QcStatus::Pass);
+        assert_eq!(report.status().exit_code(), 0);
+
+        // warn when above the fail threshold but below the warn one
+        rules.load_arg("warn", "min_bases=5e9");
+        let report = rules.evaluate(&metrics(None));
+        assert_eq!(report.status(), QcStatus::Warn);
+        assert_eq!(report.status().exit_code(), QC_WARN_EXIT_CODE);
+
+        // max rules fail above the threshold, the worst status wins
+        rules.load_arg("fail", "max_frac_below_q10=0.01");
+        let report = rules.evaluate(&metrics(None));
+        assert_eq!(report.status(), QcStatus::Fail);
+        assert_eq!(report.status().exit_code(), QC_FAIL_EXIT_CODE);
+        let statuses = report.results.iter().map(|x| x.status).collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![QcStatus::Warn, QcStatus::Pass, QcStatus::Fail]
+        );
+        // a value equal to the threshold passes
+        let mut rules = QcRules::default();
+        rules.load_arg("fail", "min_n50=8000,max_frac_below_q10=0.05");
+        assert_eq!(rules.evaluate(&metrics(None)).status(), QcStatus::Pass);
+    }
+
+    #[test]
+    fn not_available() {
+        let mut rules = QcRules::default();
+        rules.load_arg("fail", "min_map_rate=0.9");
+        // map rate of fastq can't be evaluated, the rule doesn't fail the run
+        let report = rules.evaluate(&metrics(None));
+        assert_eq!(report.results[0].status, QcStatus::NotAvailable);
+        assert_eq!(report.status(), QcStatus::Pass);
+        assert_eq!(report.status().exit_code(), 0);
+        assert!(
+            report
+                .to_string()
+                .contains("min_map_rate\tMapRate\tNA\t-\t>=0.9\tNA")
+        );
+
+        let report = rules.evaluate(&metrics(Some(0.5)));
+        assert_eq!(report.status(), QcStatus::Fail);
+        assert_eq!(report.status().exit_code(), QC_FAIL_EXIT_CODE);
+        assert_eq!(report.to_json()["Status"], "FAIL");
+    }
+}
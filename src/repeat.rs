@@ -0,0 +1,712 @@
+use crate::input_type::{InputType, check_input_type};
+use crate::summary::{HTML_TAIL, html_chart_section, html_head};
+use crate::utils::{check_input, check_output_file, init_log, quit_with_error};
+use bio::pattern_matching::myers::Myers;
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use log::info;
+use plotly::color::NamedColor;
+use plotly::common::Marker;
+use plotly::layout::themes::BuiltinTheme;
+use plotly::layout::{Axis, BarMode, HoverMode};
+use plotly::{Bar, Layout, Plot};
+use regex::Regex;
+use rust_htslib::bam::{IndexedReader, Read, Record};
+use rust_htslib::faidx;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// two adjacent repeat sizes of the sorted reads belong to different alleles if they differ by
+// more than this number of units and this fraction of the smaller one
+const ALLELE_GAP_UNITS: usize = 2;
+const ALLELE_GAP_FRACTION: f64 = 0.1;
+const ALLELE_COLORS: [NamedColor; 4] = [
+    NamedColor::RoyalBlue,
+    NamedColor::IndianRed,
+    NamedColor::SeaGreen,
+    NamedColor::DarkOrange,
+];
+
+/// contig, start and end of Contig:Start-End, 0-based half-open
+fn parse_region(region: &str) -> (String, usize, usize) {
+    let pattern = Regex::new(r"^([^:\s]+):([0-9]+)-([0-9]+)$").unwrap();
+    let Some(caps) = pattern.captures(region) else {
+        quit_with_error(&format!("Bad region format: {region}"))
+    };
+    let start = caps[2].parse::<usize>().unwrap();
+    let end = caps[3].parse::<usize>().unwrap();
+    if start >= end {
+        quit_with_error(&format!(
+            "Bad region: {region}, start should be less than end"
+        ))
+    }
+    (caps[1].to_string(), start, end)
+}
+
+/// the flank_len bases before and after the region in reference, in upper case
+fn fetch_flanks(
+    reference: &str,
+    contig: &str,
+    start: usize,
+    end: usize,
+    flank_len: usize,
+) -> (Vec<u8>, Vec<u8>) {
+    check_input(reference);
+    let reader = faidx::Reader::from_path(reference)
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {reference}")));
+    // faidx_seq_len64 returns -1 for unknown contig
+    let contig_len = reader.fetch_seq_len(contig) as i64;
+    if contig_len <= 0 {
+        quit_with_error(&format!("Contig {contig} not found in {reference}"))
+    }
+    if start < flank_len || end + flank_len > contig_len as usize {
+        quit_with_error(&format!(
+            "The flanks of {contig}:{start}-{end} are out of {contig}, whose length is {contig_len}"
+        ))
+    }
+    // the end of faidx is inclusive
+    let fetch = |begin: usize, end: usize| {
+        let mut seq = reader
+            .fetch_seq(contig, begin, end - 1)
+            .unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, Failed to fetch {contig}:{begin}-{end}"))
+            });
+        seq.make_ascii_uppercase();
+        seq
+    };
+    (fetch(start - flank_len, start), fetch(end, end + flank_len))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpanStatus {
+    Spanning,
+    NoLeftFlank,
+    NoRightFlank,
+}
+
+impl SpanStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            SpanStatus::Spanning => "spanning",
+            SpanStatus::NoLeftFlank => "no_left_flank",
+            SpanStatus::NoRightFlank => "no_right_flank",
+        }
+    }
+}
+
+/// the repeat between the flanks of one read, the sizes are 0 if it doesn't span the region
+struct ReadRepeat {
+    name: String,
+    is_reverse: bool,
+    status: SpanStatus,
+    length: usize,
+    units: usize,
+    exact_units: usize,
+    allele: Option<usize>,
+}
+
+impl ReadRepeat {
+    fn purity(&self) -> f64 {
+        self.exact_units as f64 / self.units.max(1) as f64
+    }
+}
+
+struct RepeatFinder {
+    left_flank: Myers,
+    right_flank: Myers,
+    max_distance: u8,
+    motif: Vec<u8>,
+}
+
+impl RepeatFinder {
+    /// best match of the flank, the first one if several matches have the same distance
+    fn best_match(flank: &mut Myers, seq: &[u8], max_distance: u8) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize, u8)> = None;
+        for (start, end, distance) in flank.find_all(seq, max_distance) {
+            if best.is_none_or(|x| distance < x.2) {
+                best = Some((start, end, distance))
+            }
+        }
+        best.map(|x| (x.0, x.1))
+    }
+
+    fn find(&mut self, record: &Record) -> ReadRepeat {
+        let seq = record.seq().as_bytes();
+        let mut read_repeat = ReadRepeat {
+            name: String::from_utf8_lossy(record.qname()).to_string(),
+            is_reverse: record.is_reverse(),
+            status: SpanStatus::NoLeftFlank,
+            length: 0,
+            units: 0,
+            exact_units: 0,
+            allele: None,
+        };
+        let Some((_, left_end)) = Self::best_match(&mut self.left_flank, &seq, self.max_distance)
+        else {
+            return read_repeat;
+        };
+        let Some((right_start, _)) =
+            Self::best_match(&mut self.right_flank, &seq[left_end..], self.max_distance)
+        else {
+            read_repeat.status = SpanStatus::NoRightFlank;
+            return read_repeat;
+        };
+        let repeat = &seq[left_end..left_end + right_start];
+        let (units, exact_units) = count_units(repeat, &self.motif);
+        read_repeat.status = SpanStatus::Spanning;
+        read_repeat.length = repeat.len();
+        read_repeat.units = units;
+        read_repeat.exact_units = exact_units;
+        read_repeat
+    }
+}
+
+///
+/// Tile the repeat with motif units from left to right, a unit with at most one mismatch (no
+/// mismatch for motifs shorter than 3 bases) is counted and skipped, otherwise one base is skipped
+/// as an error or an insertion. Returns the number of units and the number of exact units
+///
+fn count_units(repeat: &[u8], motif: &[u8]) -> (usize, usize) {
+    let max_mismatches = if motif.len() >= 3 { 1 } else { 0 };
+    let mut units = 0;
+    let mut exact_units = 0;
+    let mut idx = 0;
+    while idx + motif.len() <= repeat.len() {
+        let mismatches = repeat[idx..idx + motif.len()]
+            .iter()
+            .zip(motif)
+            .filter(|(x, y)| !x.eq_ignore_ascii_case(y))
+            .count();
+        if mismatches <= max_mismatches {
+            units += 1;
+            exact_units += (mismatches == 0) as usize;
+            idx += motif.len();
+        } else {
+            idx += 1;
+        }
+    }
+    (units, exact_units)
+}
+
+/// repeat units of the reads supporting one allele
+struct Allele {
+    min: usize,
+    max: usize,
+    median: f64,
+    reads: usize,
+}
+
+///
+/// Split the sorted repeat units of the spanning reads at the gaps, the groups with at least
+/// min_reads reads are alleles. Only the max_alleles groups with the most reads are kept, they are
+/// numbered from the shortest one and the allele of each read is set
+///
+fn cluster_alleles(
+    read_repeats: &mut [ReadRepeat],
+    max_alleles: usize,
+    min_reads: usize,
+) -> Vec<Allele> {
+    let mut units = read_repeats
+        .iter()
+        .filter(|x| x.status == SpanStatus::Spanning)
+        .map(|x| x.units)
+        .collect::<Vec<_>>();
+    units.sort();
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for each in units {
+        match groups.last_mut() {
+            Some(group)
+                if each - group.last().unwrap()
+                    <= ALLELE_GAP_UNITS
+                        .max((*group.last().unwrap() as f64 * ALLELE_GAP_FRACTION) as usize) =>
+            {
+                group.push(each)
+            }
+            _ => groups.push(vec![each]),
+        }
+    }
+    groups.retain(|x| x.len() >= min_reads);
+    // stable sort keeps the shorter group first if two groups have the same reads
+    groups.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    groups.truncate(max_alleles);
+    groups.sort_by_key(|x| x[0]);
+    let alleles = groups
+        .iter()
+        .map(|group| {
+            let n = group.len();
+            Allele {
+                min: group[0],
+                max: group[n - 1],
+                median: if n % 2 == 0 {
+                    (group[n / 2 - 1] + group[n / 2]) as f64 / 2.0
+                } else {
+                    group[n / 2] as f64
+                },
+                reads: n,
+            }
+        })
+        .collect::<Vec<_>>();
+    for read_repeat in read_repeats.iter_mut() {
+        if read_repeat.status == SpanStatus::Spanning {
+            read_repeat.allele = alleles
+                .iter()
+                .position(|x| (x.min..=x.max).contains(&read_repeat.units));
+        }
+    }
+    alleles
+}
+
+fn write_reads_tsv(read_repeats: &[ReadRepeat], output: &str) {
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {output}"))),
+    );
+    writeln!(
+        writer,
+        "ReadName\tStrand\tStatus\tRepeatLength\tRepeatUnits\tPurity\tAllele"
+    )
+    .unwrap();
+    for x in read_repeats {
+        let sizes = if x.status == SpanStatus::Spanning {
+            format!("{}\t{}\t{:.4}", x.length, x.units, x.purity())
+        } else {
+            "NA\tNA\tNA".to_string()
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{sizes}\t{}",
+            x.name,
+            if x.is_reverse { '-' } else { '+' },
+            x.status.name(),
+            x.allele.map_or("NA".to_string(), |x| (x + 1).to_string())
+        )
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to write {output}")));
+    }
+}
+
+/// bars of reads count at each repeat units, colored by allele
+fn repeat_histogram_html_div(read_repeats: &[ReadRepeat], alleles: &[Allele]) -> String {
+    let mut plot = Plot::new();
+    for allele_idx in (0..alleles.len()).map(Some).chain([None]) {
+        let mut counts = BTreeMap::new();
+        for x in read_repeats
+            .iter()
+            .filter(|x| x.status == SpanStatus::Spanning && x.allele == allele_idx)
+        {
+            *counts.entry(x.units).or_insert(0usize) += 1;
+        }
+        if counts.is_empty() {
+            continue;
+        }
+        let (name, color) = match allele_idx {
+            Some(idx) => (
+                format!("Allele{}", idx + 1),
+                ALLELE_COLORS[idx % ALLELE_COLORS.len()],
+            ),
+            None => ("Unassigned".to_string(), NamedColor::Gray),
+        };
+        let trace = Bar::new(
+            counts.keys().copied().collect::<Vec<_>>(),
+            counts.values().copied().collect::<Vec<_>>(),
+        )
+        .name(&name)
+        .hover_template(format!(
+            "<b>{name}</b><br><b>RepeatUnits</b>: %{{x}}<br><b>ReadsCount</b>: %{{y}}<extra></extra>"
+        ))
+        .marker(Marker::new().color(color));
+        plot.add_trace(trace);
+    }
+    let layout = Layout::new()
+        .hover_mode(HoverMode::Closest)
+        .bar_mode(BarMode::Stack)
+        .template(BuiltinTheme::PlotlyWhite.build())
+        .x_axis(
+            Axis::new()
+                .title("RepeatUnits")
+                .line_color("black")
+                .show_grid(false)
+                .line_width(1),
+        )
+        .y_axis(
+            Axis::new()
+                .title("ReadsCount")
+                .line_color("black")
+                .line_width(1),
+        )
+        .height(500);
+    plot.set_layout(layout);
+    plot.to_inline_html(Some("repeat_histogram"))
+}
+
+fn write_repeat_html(
+    name: &str,
+    region: &str,
+    motif: &str,
+    read_repeats: &[ReadRepeat],
+    alleles: &[Allele],
+    html_file: &str,
+) {
+    let mut html = html_head(
+        &format!("{name} Repeat Report"),
+        &format!("{name} Repeat Report"),
+    );
+    html.push_str(
+        r#"
+        <div class="section">
+            <div class="section-header">📊 Repeat Statistics</div>
+            <div class="stats-grid">
+        "#,
+    );
+    let spanning_reads = read_repeats
+        .iter()
+        .filter(|x| x.status == SpanStatus::Spanning)
+        .count();
+    let mut items = vec![
+        ("Region".to_string(), region.to_string()),
+        ("Motif".to_string(), motif.to_string()),
+        ("Reads".to_string(), read_repeats.len().to_string()),
+        ("SpanningReads".to_string(), spanning_reads.to_string()),
+    ];
+    for (idx, allele) in alleles.iter().enumerate() {
+        items.push((
+            format!("Allele{}", idx + 1),
+            format!(
+                "{:.1} ({}-{}) units, {} reads",
+                allele.median, allele.min, allele.max, allele.reads
+            ),
+        ));
+    }
+    for (key, value) in items {
+        html.push_str(&format!(
+            r#"
+                <div class="stat-card">
+                    <div class="label">{key}</div>
+                    <div class="value">{value}</div>
+                </div>
+            "#
+        ))
+    }
+    html.push_str("</div>\n</div>\n");
+    html.push_str(&html_chart_section(
+        "📊 Repeat Length Distribution",
+        &repeat_histogram_html_div(read_repeats, alleles),
+    ));
+    html.push_str(HTML_TAIL);
+    std::fs::write(html_file, html).unwrap_or_else(|x| {
+        quit_with_error(&format!(
+            "{x}, Failed to write contents into report file: {html_file}"
+        ))
+    });
+}
+
+pub fn run_repeat(repeat_cmd: &ArgMatches) {
+    init_log();
+    let input = repeat_cmd.get_one::<String>("input").unwrap();
+    let reference = repeat_cmd.get_one::<String>("reference").unwrap();
+    let region = repeat_cmd.get_one::<String>("region").unwrap();
+    let motif = repeat_cmd.get_one::<String>("motif").unwrap();
+    let output = repeat_cmd.get_one::<String>("output").unwrap();
+    let report = repeat_cmd.get_one::<String>("report");
+    let name = repeat_cmd.get_one::<String>("name").unwrap();
+    let flank_len = *repeat_cmd.get_one::<u64>("flank_len").unwrap() as usize;
+    let distance = *repeat_cmd.get_one::<u8>("distance").unwrap();
+    let min_mapq = *repeat_cmd.get_one::<u8>("min_mapq").unwrap();
+    let max_alleles = *repeat_cmd.get_one::<u64>("max_alleles").unwrap() as usize;
+    let min_reads = *repeat_cmd.get_one::<u64>("min_reads").unwrap() as usize;
+
+    let index = repeat_cmd.get_flag("index");
+
+    match check_input_type(input) {
+        InputType::IndexedBam => {}
+        InputType::SortedUnindexedBam if index => {
+            crate::bam::index_bam(input, 1)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to index {input}")));
+        }
+        InputType::SortedUnindexedBam => quit_with_error(&format!(
+            "{input} is sorted but not indexed, index it firstly or use --index"
+        )),
+        _ => quit_with_error("repeat only supports sorted and indexed bam file"),
+    }
+    check_output_file(output);
+    if let Some(report) = report {
+        check_output_file(report)
+    }
+    let motif = motif.to_ascii_uppercase();
+    if !motif.bytes().all(|x| b"ACGT".contains(&x)) {
+        quit_with_error(&format!(
+            "Bad motif: {motif}, only A, C, G and T are allowed"
+        ))
+    }
+    let (contig, start, end) = parse_region(region);
+    let (left_flank, right_flank) = fetch_flanks(reference, &contig, start, end, flank_len);
+    let mut finder = RepeatFinder {
+        left_flank: Myers::new(&left_flank),
+        right_flank: Myers::new(&right_flank),
+        max_distance: distance,
+        motif: motif.as_bytes().to_vec(),
+    };
+
+    let mut bam_reader = IndexedReader::from_path(input)
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {input}")));
+    if bam_reader.header().tid(contig.as_bytes()).is_none() {
+        quit_with_error(&format!("Contig {contig} not found in {input}"))
+    }
+    bam_reader
+        .fetch((contig.as_bytes(), start as i64, end as i64))
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to fetch {region}")));
+    let mut read_repeats = vec![];
+    let mut record = Record::new();
+    while let Some(result) = bam_reader.read(&mut record) {
+        result.unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {input}")));
+        // 0xF04: unmapped, secondary, qc-fail, duplicate and supplementary
+        if record.flags() & 0xF04 != 0 || record.mapq() < min_mapq {
+            continue;
+        }
+        read_repeats.push(finder.find(&record));
+    }
+    let alleles = cluster_alleles(&mut read_repeats, max_alleles, min_reads);
+
+    let spanning_reads = read_repeats
+        .iter()
+        .filter(|x| x.status == SpanStatus::Spanning)
+        .count();
+    info!(
+        "{spanning_reads} of {} reads span {region} with both flanks",
+        read_repeats.len()
+    );
+    for (idx, allele) in alleles.iter().enumerate() {
+        info!(
+            "Allele{}: {:.1} {motif} units (range {}-{}), supported by {} reads",
+            idx + 1,
+            allele.median,
+            allele.min,
+            allele.max,
+            allele.reads
+        );
+    }
+    write_reads_tsv(&read_repeats, output);
+    if let Some(report) = report {
+        write_repeat_html(name, region, &motif, &read_repeats, &alleles, report);
+    }
+}
+
+pub fn repeat_cmd() -> Command {
+    Command::new("repeat")
+        .about("size the short tandem repeat in a region from the reads spanning it, and cluster the repeat sizes into alleles")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("the sorted and indexed bam file")
+        )
+        .arg(
+            Arg::new("index")
+                .short('I')
+                .long("index")
+                .action(ArgAction::SetTrue)
+                .help("build index firstly for sorted but unindexed bam file [default: false]")
+        )
+        .arg(
+            Arg::new("reference")
+                .short('R')
+                .long("reference")
+                .required(true)
+                .help("the reference fasta of the bam, the flanks of the region are taken from it. fai index will be built if it doesn't exist")
+        )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .required(true)
+                .help("the repeat region. format: Contig:Start-End, 0-based half-open interval. e.g., chrX:147912050-147912110")
+        )
+        .arg(
+            Arg::new("motif")
+                .long("motif")
+                .required(true)
+                .help("the repeat unit on the forward strand of the reference. e.g., CGG")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output the repeat of each read into this tsv file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("report")
+                .short('r')
+                .long("report")
+                .help("output the html report with the repeat length histogram if specified")
+        )
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .default_value("test001")
+                .help("this analysis name, will be showed in the title of the html report")
+        )
+        .arg(
+            Arg::new("flank_len")
+                .long("flank_len")
+                .default_value("50")
+                .value_parser(value_parser!(u64).range(10..=64))
+                .help("length of the flanks before and after the region searched in each read")
+        )
+        .arg(
+            Arg::new("distance")
+                .short('d')
+                .long("distance")
+                .default_value("8")
+                .value_parser(value_parser!(u8))
+                .help("max edit distance allowed between flank and read sequence")
+        )
+        .arg(
+            Arg::new("min_mapq")
+                .short('Q')
+                .long("min_mapq")
+                .default_value("0")
+                .value_parser(value_parser!(u8))
+                .help("alignments with map quality less than this value are not used. secondary, supplementary, qc-fail and duplicate alignments are never used")
+        )
+        .arg(
+            Arg::new("max_alleles")
+                .long("max_alleles")
+                .default_value("2")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("max number of alleles, e.g., 1 for chrX of male")
+        )
+        .arg(
+            Arg::new("min_reads")
+                .long("min_reads")
+                .default_value("3")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("min spanning reads supporting an allele")
+        )
+}
+
+#[cfg(test)]
+mod repeat_test {
+    use super::*;
+
+    fn repeat(parts: &[(&str, usize)]) -> Vec<u8> {
+        parts
+            .iter()
+            .flat_map(|(unit, times)| unit.repeat(*times).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn pure_repeat() {
+        assert_eq!(count_units(&repeat(&[("CGG", 20)]), b"CGG"), (20, 20));
+        assert_eq!(count_units(&repeat(&[("cgg", 5)]), b"CGG"), (5, 5));
+        assert_eq!(count_units(&repeat(&[("CA", 15)]), b"CA"), (15, 15));
+        assert_eq!(count_units(b"CG", b"CGG"), (0, 0));
+    }
+
+    #[test]
+    fn interrupted_repeat() {
+        // the AGG interruption of FMR1 is one mismatch
+        let seq = repeat(&[("CGG", 10), ("AGG", 1), ("CGG", 9)]);
+        assert_eq!(count_units(&seq, b"CGG"), (20, 19));
+        // a unit with two mismatches is skipped
+        let seq = repeat(&[("CGG", 10), ("AAG", 1), ("CGG", 9)]);
+        assert_eq!(count_units(&seq, b"CGG"), (19, 19));
+        // no mismatch is allowed in a dinucleotide unit
+        let seq = repeat(&[("CA", 7), ("CT", 1), ("CA", 7)]);
+        assert_eq!(count_units(&seq, b"CA"), (14, 14));
+    }
+
+    #[test]
+    fn repeat_with_indels() {
+        // an inserted base is skipped
+        let seq = repeat(&[("CGG", 10), ("T", 1), ("CGG", 10)]);
+        assert_eq!(count_units(&seq, b"CGG"), (20, 20));
+        // a unit with a deleted base is counted with one mismatch
+        let seq = repeat(&[("CGG", 10), ("CG", 1), ("CGG", 10)]);
+        assert_eq!(count_units(&seq, b"CGG"), (20, 19));
+    }
+
+    fn read_repeats(units: &[usize]) -> Vec<ReadRepeat> {
+        let mut read_repeats = units
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| ReadRepeat {
+                name: format!("read{idx}"),
+                is_reverse: false,
+                status: SpanStatus::Spanning,
+                length: x * 3,
+                units: *x,
+                exact_units: *x,
+                allele: None,
+            })
+            .collect::<Vec<_>>();
+        read_repeats.push(ReadRepeat {
+            name: "not_spanning".to_string(),
+            is_reverse: true,
+            status: SpanStatus::NoRightFlank,
+            length: 0,
+            units: 0,
+            exact_units: 0,
+            allele: None,
+        });
+        read_repeats
+    }
+
+    #[test]
+    fn bimodal_alleles() {
+        let mut reads = read_repeats(&[60, 20, 21, 62, 19, 22, 58, 20, 63, 61]);
+        let alleles = cluster_alleles(&mut reads, 2, 2);
+        assert_eq!(alleles.len(), 2);
+        assert_eq!(
+            (alleles[0].min, alleles[0].max, alleles[0].reads),
+            (19, 22, 5)
+        );
+        assert_eq!(alleles[0].median, 20.0);
+        assert_eq!(
+            (alleles[1].min, alleles[1].max, alleles[1].reads),
+            (58, 63, 5)
+        );
+        assert_eq!(alleles[1].median, 61.0);
+        let read_alleles = reads.iter().map(|x| x.allele).collect::<Vec<_>>();
+        assert_eq!(
+            read_alleles,
+            vec![
+                Some(1),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(1),
+                Some(1),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn allele_filters() {
+        // the singleton is not an allele with min_reads 2
+        let mut reads = read_repeats(&[20, 20, 21, 100, 60, 61, 62]);
+        let alleles = cluster_alleles(&mut reads, 2, 2);
+        assert_eq!(
+            alleles.iter().map(|x| x.min).collect::<Vec<_>>(),
+            vec![20, 60]
+        );
+        assert_eq!(reads[3].allele, None);
+        // only the allele with the most reads is kept
+        let alleles = cluster_alleles(&mut reads, 1, 2);
+        assert_eq!(alleles.len(), 1);
+        assert_eq!((alleles[0].min, alleles[0].reads), (20, 3));
+        assert_eq!(reads[4].allele, None);
+        // the gap between long expansions scales with the repeat size
+        let mut reads = read_repeats(&[200, 215, 230, 20]);
+        let alleles = cluster_alleles(&mut reads, 2, 1);
+        assert_eq!(
+            alleles.iter().map(|x| (x.min, x.max)).collect::<Vec<_>>(),
+            vec![(20, 20), (200, 230)]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use crate::bam::BasicBamStatistics;
+use crate::duplex::DuplexClass;
+use crate::fastq::RecordEachStats;
+use crate::modification::{ModStatus, ReadMods};
+use crate::profile::PositionProfile;
+use crate::qc::QcRules;
+use crate::stats::stats_cmd;
+use crate::summary::SummaryStats;
+use crate::utils::{check_input, check_output_file, init_log, quit_with_error};
+use clap::{Arg, ArgMatches, Command};
+use std::io::{BufRead, BufReader};
+
+// the arguments of stats which only affect the summary, they are shared by report
+const SHARED_STATS_ARGS: [&str; 13] = [
+    "report",
+    "name",
+    "summary",
+    "json",
+    "topn",
+    "quality",
+    "length",
+    "bins",
+    "quantile",
+    "exclude_duplex_parents",
+    "qc_rules",
+    "qc_fail",
+    "qc_warn",
+];
+
+/// column index of each optional field in the stats tsv
+#[derive(Debug, Default)]
+struct TsvColumns {
+    gc: Option<usize>,
+    complexity: Option<usize>, // Complexity, followed by LongestHomopolymer
+    poly_tail: Option<usize>,
+    mods: Option<usize>,       // ModSites, followed by ModRate and ModStatus
+    duplex: Option<usize>,
+}
+
+impl TsvColumns {
+    fn from_header(header: &str) -> Self {
+        let fields = header
+            .trim_start_matches('#')
+            .split('\t')
+            .collect::<Vec<_>>();
+        let position = |name: &str| fields.iter().position(|x| *x == name);
+        TsvColumns {
+            gc: position("GCContent"),
+            complexity: position("Complexity"),
+            poly_tail: position("PolyTailLength"),
+            mods: position("ModSites"),
+            duplex: position("DuplexClass"),
+        }
+    }
+
+    /// the tsv saved by older versions has no column header, only GC content may follow the
+    /// read quality
+    fn guess(fields: &[&str]) -> Self {
+        TsvColumns {
+            gc: if fields.len() == 4 { Some(3) } else { None },
+            ..Default::default()
+        }
+    }
+}
+
+/// load the per-read stats saved by `nanofq stats --output`, returns the analysis name in the
+/// first line, all stats and whether all reads have GC content
+pub fn load_stats_tsv(tsv_file: &str) -> (Option<String>, Vec<RecordEachStats>, bool) {
+    let file = std::fs::File::open(tsv_file)
+        .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to open {tsv_file}")));
+    let mut name = None;
+    let mut columns = None;
+    let mut all_stats = vec![];
+    let mut use_gc = true;
+    for (line_idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|x| {
+            quit_with_error(&format!(
+                "{x}, failed to read line {} of {tsv_file}",
+                line_idx + 1
+            ))
+        });
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            if comment.starts_with("ReadName\t") {
+                columns = Some(TsvColumns::from_header(&line));
+            } else if line_idx == 0 {
+                name = comment.split(" stats result").next().map(|x| x.to_string());
+            }
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let bad_line = || -> ! {
+            quit_with_error(&format!(
+                "Bad stats record at line {} of {tsv_file}: {line}",
+                line_idx + 1
+            ))
+        };
+        if fields.len() < 3 {
+            bad_line()
+        }
+        let columns = columns.get_or_insert_with(|| TsvColumns::guess(&fields));
+        let length = fields[1].parse::<usize>().unwrap_or_else(|_| bad_line());
+        let qual = fields[2].parse::<f32>().unwrap_or_else(|_| bad_line());
+        let gc = columns.gc.map(|idx| {
+            fields
+                .get(idx)
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or_else(|| bad_line())
+        });
+        use_gc &= gc.is_some();
+        let mut each_stats = RecordEachStats::new(fields[0], length, qual, gc);
+        if let Some(idx) = columns.complexity {
+            let (Some(complexity), Some(longest_homopolymer)) = (
+                fields.get(idx).and_then(|x| x.parse::<f32>().ok()),
+                fields.get(idx + 1).and_then(|x| x.parse::<u32>().ok()),
+            ) else {
+                bad_line()
+            };
+            each_stats.complexity = Some(complexity);
+            each_stats.longest_homopolymer = Some(longest_homopolymer);
+        }
+        if let Some(idx) = columns.poly_tail {
+            each_stats.poly_tail = Some(
+                fields
+                    .get(idx)
+                    .and_then(|x| x.parse::<u32>().ok())
+                    .unwrap_or_else(|| bad_line()),
+            );
+        }
+        if let Some(idx) = columns.mods {
+            let (Some(called), Some(rate), Some(status)) =
+                (fields.get(idx), fields.get(idx + 1), fields.get(idx + 2))
+            else {
+                bad_line()
+            };
+            // NA means the read has no MM/ML tag in a bam parsed with --mods
+            if let Some(status) = ModStatus::from_label(status) {
+                let called = called.parse::<u32>().unwrap_or_default();
+                let rate = rate.parse::<f32>().unwrap_or_default();
+                each_stats.mods = Some(ReadMods {
+                    called,
+                    modified: (called as f32 * rate).round() as u32,
+                    status,
+                });
+            }
+        }
+        if let Some(idx) = columns.duplex {
+            each_stats.duplex = fields
+                .get(idx)
+                .and_then(|x| DuplexClass::from_label(x))
+                .unwrap_or_else(|| bad_line());
+        }
+        all_stats.push(each_stats);
+    }
+    let use_gc = use_gc && !all_stats.is_empty();
+    (name, all_stats, use_gc)
+}
+
+pub fn run_report(report_cmd: &ArgMatches) {
+    init_log();
+    let tsv_files = report_cmd.get_one::<Vec<String>>("from_tsv").unwrap();
+    let report = report_cmd.get_one::<String>("report").unwrap();
+    let summary = report_cmd.get_one::<String>("summary");
+    let json = report_cmd.get_one::<String>("json");
+    let topn = report_cmd.get_one::<u32>("topn").unwrap();
+    let quality = report_cmd.get_one::<Vec<f64>>("quality").unwrap();
+    let lengths = report_cmd.get_one::<Vec<u32>>("length");
+    let bins = report_cmd.get_one::<u32>("bins").unwrap();
+    let quantile = report_cmd.get_one::<f64>("quantile").unwrap();
+    let exclude_duplex_parents = report_cmd.get_flag("exclude_duplex_parents");
+    let qc_rules = QcRules::from_arg_matches(report_cmd);
+    for tsv_file in tsv_files {
+        check_input(tsv_file);
+    }
+    check_output_file(report);
+    if let Some(summary_file) = summary {
+        check_output_file(summary_file)
+    }
+    if let Some(json_file) = json {
+        check_output_file(json_file)
+    }
+
+    let mut all_stats = vec![];
+    let mut tsv_name = None;
+    let mut use_gc = true;
+    for tsv_file in tsv_files {
+        let (name, stats, has_gc) = load_stats_tsv(tsv_file);
+        if stats.is_empty() {
+            quit_with_error(&format!("No stats record found in {tsv_file}"))
+        }
+        tsv_name = tsv_name.or(name);
+        use_gc &= has_gc;
+        all_stats.extend(stats);
+    }
+    // the analysis name in tsv is used unless --name is given
+    let analysis_name = report_cmd
+        .get_one::<String>("name")
+        .cloned()
+        .or(tsv_name)
+        .unwrap_or_else(|| "test001".to_string());
+
+    let mut stats_summary = SummaryStats::new(
+        all_stats,
+        lengths.map(|x| x.as_slice()),
+        quality,
+        use_gc,
+        *topn as usize,
+        exclude_duplex_parents,
+    );
+    // per-position profile and bam statistics are not saved in tsv
+    let basic_bam_stats = BasicBamStatistics::default();
+    let qc_report = qc_rules.map(|rules| {
+        let qc_report = rules.evaluate(&stats_summary.qc_metrics(&basic_bam_stats));
+        stats_summary.set_qc_report(qc_report.clone());
+        qc_report
+    });
+    if let Some(summary_file) = summary {
+        stats_summary.write_summary_to_text(&analysis_name, &basic_bam_stats, summary_file);
+    }
+    if let Some(json_file) = json {
+        stats_summary.write_summary_to_json(&analysis_name, &basic_bam_stats, json_file);
+    }
+    stats_summary.write_to_html_file(
+        &analysis_name,
+        *bins as usize,
+        *quantile,
+        &PositionProfile::new(0),
+        &basic_bam_stats,
+        report,
+    );
+    if let Some(qc_report) = qc_report {
+        qc_report.print_and_exit()
+    }
+}
+
+pub fn report_cmd() -> Command {
+    let stats_cmd = stats_cmd();
+    Command::new("report")
+        .about("regenerate html report, summary and json from the per-read stats tsv files saved by stats --output")
+        .arg(
+            Arg::new("from_tsv")
+                .long("from_tsv")
+                .required(true)
+                .value_parser(|x: &str| {
+                    Result::<Vec<String>, anyhow::Error>::Ok(
+                        x.split(',').map(|each| each.to_string()).collect(),
+                    )
+                })
+                .help("the stats tsv files saved by stats --output, multiple files can be separated by comma and will be merged. the per-position profile, alignment, per-contig, base quality calibration and modified base sections need the reads or alignments, they are not saved in the tsv and are left out")
+        )
+        .args(
+            stats_cmd
+                .get_arguments()
+                .filter(|x| SHARED_STATS_ARGS.contains(&x.get_id().as_str()))
+                .cloned(),
+        )
+        .mut_arg("name", |arg| {
+            arg.default_value(None)
+                .help("this analysis name, the name in the first line of the first tsv file is used if not specified")
+        })
+}
@@ -0,0 +1,500 @@
+use crate::fastq::FastqRecord;
+use crate::filter::{FilterOption, ReadsChunk, ReadsWriter, for_each_chunk};
+use crate::input_type::{InputType, check_input_type};
+use crate::utils::{bases_parse, check_output_file, init_log, positive_f64_parse, quit_with_error};
+use clap::{Arg, ArgGroup, ArgMatches, Command, value_parser};
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rust_htslib::bam;
+
+/// how many reads are sampled
+#[derive(Debug, Clone, Copy)]
+enum SampleTarget {
+    Fraction(f64),
+    Reads(u64),
+    Bases(u64),
+}
+
+/// secondary and supplementary alignments are never sampled, so that each read is counted once
+fn is_candidate(reads: &ReadsChunk, idx: usize) -> bool {
+    reads.is_primary.get(idx).is_none_or(|x| *x)
+}
+
+///
+/// Keep each read with the probability of fraction, the random numbers are drawn in the input
+/// order, so the sampled reads only depend on the seed
+///
+fn sample_fraction(
+    input: &str,
+    input_t: &InputType,
+    chunk: u32,
+    fo: &FilterOption,
+    writer: &mut ReadsWriter,
+    rng: &mut ChaCha8Rng,
+    fraction: f64,
+) -> (usize, u64) {
+    let mut kept_reads = 0;
+    let mut kept_bases = 0;
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        for idx in 0..reads.len() {
+            if is_candidate(&reads, idx) && rng.gen_bool(fraction) {
+                writer.write(&reads, idx, None, fo);
+                kept_reads += 1;
+                kept_bases += reads.records[idx].seq.len() as u64;
+            }
+        }
+    });
+    (kept_reads, kept_bases)
+}
+
+///
+/// Keep exactly n reads by reservoir sampling (algorithm R) in one pass. The reservoir holds the
+/// reads with their input index, they are sorted by the index and written at the end
+///
+fn sample_reads(
+    input: &str,
+    input_t: &InputType,
+    chunk: u32,
+    fo: &FilterOption,
+    writer: &mut ReadsWriter,
+    rng: &mut ChaCha8Rng,
+    n: u64,
+) -> (usize, u64) {
+    let mut records = 0;
+    let mut reservoir: Vec<(usize, (FastqRecord, Option<bam::Record>))> = vec![];
+    let mut seen = 0u64;
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        // the slot of the reservoir taken by each read, drawn in the input order
+        let mut slots = vec![None; reads.len()];
+        for (idx, slot) in slots.iter_mut().enumerate() {
+            if !is_candidate(&reads, idx) {
+                continue;
+            }
+            if seen < n {
+                *slot = Some(seen as usize)
+            } else {
+                let j = rng.gen_range(0..=seen);
+                if j < n {
+                    *slot = Some(j as usize)
+                }
+            }
+            seen += 1;
+        }
+        let chunk_records = reads.len();
+        for (idx, (slot, read)) in slots.into_iter().zip(reads.into_reads()).enumerate() {
+            match slot {
+                Some(slot) if slot == reservoir.len() => reservoir.push((records + idx, read)),
+                Some(slot) => reservoir[slot] = (records + idx, read),
+                None => {}
+            }
+        }
+        records += chunk_records;
+    });
+    reservoir.sort_unstable_by_key(|(record_idx, _)| *record_idx);
+    let reads = reservoir
+        .into_iter()
+        .map(|(_, read)| read)
+        .collect::<ReadsChunk>();
+    for idx in 0..reads.len() {
+        writer.write(&reads, idx, None, fo);
+    }
+    (
+        reads.len(),
+        reads.records.iter().map(|x| x.seq.len() as u64).sum(),
+    )
+}
+
+///
+/// Choose the reads to keep in the first pass, the kept flag of each input record is returned.
+/// The reads are taken in a random order until n bases reached
+///
+fn choose_reads(
+    input: &str,
+    input_t: &InputType,
+    chunk: u32,
+    fo: &FilterOption,
+    rng: &mut ChaCha8Rng,
+    n: u64,
+) -> Vec<bool> {
+    let mut records = 0;
+    // (random key, index, length) of all reads
+    let mut keys: Vec<(u64, usize, usize)> = vec![];
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        for idx in 0..reads.len() {
+            if is_candidate(&reads, idx) {
+                keys.push((rng.r#gen(), records + idx, reads.records[idx].seq.len()));
+            }
+        }
+        records += reads.len();
+    });
+    // the index breaks the tie of keys, so the order doesn't depend on the sort
+    keys.sort_unstable();
+    let mut kept = vec![false; records];
+    let mut bases = 0u64;
+    for (_, record_idx, len) in keys {
+        if bases >= n {
+            break;
+        }
+        kept[record_idx] = true;
+        bases += len as u64;
+    }
+    kept
+}
+
+/// write the kept reads in the input order in the second pass
+fn write_kept_reads(
+    input: &str,
+    input_t: &InputType,
+    chunk: u32,
+    fo: &FilterOption,
+    writer: &mut ReadsWriter,
+    kept: &[bool],
+) -> (usize, u64) {
+    let mut records = 0;
+    let mut kept_reads = 0;
+    let mut kept_bases = 0;
+    for_each_chunk(input, input_t, chunk, fo, &mut |reads| {
+        if records + reads.len() > kept.len() {
+            quit_with_error(&format!("{input} changed when it was sampled"))
+        }
+        for idx in 0..reads.len() {
+            if kept[records + idx] {
+                writer.write(&reads, idx, None, fo);
+                kept_reads += 1;
+                kept_bases += reads.records[idx].seq.len() as u64;
+            }
+        }
+        records += reads.len();
+    });
+    (kept_reads, kept_bases)
+}
+
+pub fn run_sample(sample_cmd: &ArgMatches) {
+    init_log();
+    let input = sample_cmd.get_one::<String>("input").unwrap();
+    let output = sample_cmd.get_one::<String>("output").unwrap();
+    let seed = *sample_cmd.get_one::<u64>("seed").unwrap();
+    let chunk = *sample_cmd.get_one::<u32>("chunk").unwrap();
+    let target = match (
+        sample_cmd.get_one::<f64>("fraction"),
+        sample_cmd.get_one::<u64>("reads"),
+        sample_cmd.get_one::<u64>("bases"),
+    ) {
+        (Some(fraction), _, _) => SampleTarget::Fraction(*fraction),
+        (_, Some(reads), _) => SampleTarget::Reads(*reads),
+        (_, _, Some(bases)) => SampleTarget::Bases(*bases),
+        _ => unreachable!(),
+    };
+
+    let input_t = check_input_type(input);
+    check_output_file(output);
+    let fo = FilterOption::pass_through(output.ends_with(".bam"));
+    let is_fastq_input = matches!(
+        input_t,
+        InputType::DirectoryContainFastqsOrFastqsGzipped
+            | InputType::OneFastqFile
+            | InputType::OneFastqGzippedFile
+    );
+    if is_fastq_input && output.ends_with(".bam") {
+        quit_with_error("bam output needs bam/sam input")
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut writer = ReadsWriter::new(Some(output), input, &fo);
+    let (kept_reads, kept_bases) = match target {
+        SampleTarget::Fraction(fraction) => {
+            sample_fraction(input, &input_t, chunk, &fo, &mut writer, &mut rng, fraction)
+        }
+        SampleTarget::Reads(n) => {
+            sample_reads(input, &input_t, chunk, &fo, &mut writer, &mut rng, n)
+        }
+        SampleTarget::Bases(n) => {
+            let kept = choose_reads(input, &input_t, chunk, &fo, &mut rng, n);
+            write_kept_reads(input, &input_t, chunk, &fo, &mut writer, &kept)
+        }
+    };
+    match target {
+        SampleTarget::Reads(n) if (kept_reads as u64) < n => {
+            info!("the input has less than {n} reads, all reads are kept")
+        }
+        SampleTarget::Bases(n) if kept_bases < n => {
+            info!("the input has less than {n} bases, all reads are kept")
+        }
+        _ => {}
+    }
+    info!("{kept_reads} reads ({kept_bases} bases) are sampled with seed {seed} into {output}");
+}
+
+pub fn sample_cmd() -> Command {
+    Command::new("sample")
+        .about("randomly sample a fraction of reads, a number of reads or a number of bases from fastq or bam, the result is reproducible with the same seed")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("fastq file, fastq.gz file, a directory containing them, bam or sam file")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output the sampled reads into this file in the input order, bam if it ends with .bam, which keeps the header and alignments of bam input, otherwise fastq")
+        )
+        .arg(
+            Arg::new("fraction")
+                .short('f')
+                .long("fraction")
+                .value_parser(|x: &str| positive_f64_parse(x, "--fraction", 0.0, 1.0))
+                .help("keep each read with this probability, the input is read once")
+        )
+        .arg(
+            Arg::new("reads")
+                .short('n')
+                .long("reads")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("keep exactly this number of reads by reservoir sampling, the input is read once and these reads are held in memory")
+        )
+        .arg(
+            Arg::new("bases")
+                .short('b')
+                .long("bases")
+                .value_parser(|x: &str| bases_parse(x, "--bases"))
+                .help("keep random reads until this number of bases, k/m/g suffix is allowed. e.g., 5G. the input is read twice")
+        )
+        .group(
+            ArgGroup::new("target")
+                .args(["fraction", "reads", "bases"])
+                .required(true)
+        )
+        .arg(
+            Arg::new("seed")
+                .short('s')
+                .long("seed")
+                .default_value("11")
+                .value_parser(value_parser!(u64))
+                .help("random seed, the same seed and input always give the same reads")
+        )
+        .arg(
+            Arg::new("chunk")
+                .short('c')
+                .long("chunk")
+                .default_value("50000")
+                .value_parser(value_parser!(u32).range(10000..1000001))
+                .help("reads chunk size, it doesn't change the sampled reads")
+        )
+}
+
+#[cfg(test)]
+mod sample_test {
+    use super::*;
+    use rust_htslib::bam::Read;
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn temp_file(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("nanofq_sample_{}_{name}", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    /// reads r0, r1, ... the i-th read has i % 7 + 1 bases
+    fn read_len(idx: usize) -> usize {
+        idx % 7 + 1
+    }
+
+    fn write_fastq(path: &str, reads: usize) {
+        let mut fastq = String::new();
+        for idx in 0..reads {
+            let len = read_len(idx);
+            fastq.push_str(&format!(
+                "@r{idx}\n{}\n+\n{}\n",
+                "A".repeat(len),
+                "5".repeat(len)
+            ));
+        }
+        std::fs::write(path, fastq).unwrap();
+    }
+
+    /// sample into a temp fastq or bam and return the names of the sampled reads
+    fn sampled_names(
+        input: &str,
+        input_t: &InputType,
+        output: &str,
+        target: SampleTarget,
+        chunk: u32,
+        seed: u64,
+    ) -> Vec<String> {
+        let fo = FilterOption::pass_through(output.ends_with(".bam"));
+        let mut writer = ReadsWriter::new(Some(output), input, &fo);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        match target {
+            SampleTarget::Fraction(fraction) => {
+                sample_fraction(input, input_t, chunk, &fo, &mut writer, &mut rng, fraction)
+            }
+            SampleTarget::Reads(n) => {
+                sample_reads(input, input_t, chunk, &fo, &mut writer, &mut rng, n)
+            }
+            SampleTarget::Bases(n) => {
+                let kept = choose_reads(input, input_t, chunk, &fo, &mut rng, n);
+                write_kept_reads(input, input_t, chunk, &fo, &mut writer, &kept)
+            }
+        };
+        drop(writer);
+        let names = if output.ends_with(".bam") {
+            bam::Reader::from_path(output)
+                .unwrap()
+                .records()
+                .map(|x| String::from_utf8(x.unwrap().qname().to_vec()).unwrap())
+                .collect()
+        } else {
+            std::fs::read_to_string(output)
+                .unwrap()
+                .lines()
+                .step_by(4)
+                .map(|x| x[1..].to_string())
+                .collect()
+        };
+        std::fs::remove_file(output).unwrap();
+        names
+    }
+
+    fn name_idx(names: &[String]) -> Vec<usize> {
+        names.iter().map(|x| x[1..].parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn chunk_size_independent() {
+        let input = temp_file("chunks.fastq");
+        write_fastq(&input, 1000);
+        let output = temp_file("chunks.out.fastq");
+        for target in [
+            SampleTarget::Fraction(0.1),
+            SampleTarget::Reads(50),
+            SampleTarget::Bases(500),
+        ] {
+            let names = [7, 100, 10000].map(|chunk| {
+                sampled_names(&input, &InputType::OneFastqFile, &output, target, chunk, 11)
+            });
+            assert!(!names[0].is_empty());
+            assert_eq!(names[0], names[1]);
+            assert_eq!(names[0], names[2]);
+            let other_seed =
+                sampled_names(&input, &InputType::OneFastqFile, &output, target, 7, 12);
+            assert_ne!(names[0], other_seed);
+        }
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn reservoir() {
+        let input = temp_file("reservoir.fastq");
+        write_fastq(&input, 1000);
+        let output = temp_file("reservoir.out.fastq");
+        let target = SampleTarget::Reads(50);
+        let idx = name_idx(&sampled_names(
+            &input,
+            &InputType::OneFastqFile,
+            &output,
+            target,
+            64,
+            11,
+        ));
+        assert_eq!(idx.len(), 50);
+        // in the input order
+        assert!(idx.windows(2).all(|x| x[0] < x[1]));
+        // all reads are kept if n exceeds the input
+        let target = SampleTarget::Reads(2000);
+        let idx = name_idx(&sampled_names(
+            &input,
+            &InputType::OneFastqFile,
+            &output,
+            target,
+            64,
+            11,
+        ));
+        assert_eq!(idx, (0..1000).collect::<Vec<_>>());
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn bases_budget() {
+        let input = temp_file("bases.fastq");
+        write_fastq(&input, 1000);
+        let fo = FilterOption::pass_through(false);
+        let n = 500;
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        let kept = choose_reads(&input, &InputType::OneFastqFile, 64, &fo, &mut rng, n);
+        // the reads are taken in the order of their random keys, drawn in the input order
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        let mut keys = (0..1000)
+            .map(|idx| (rng.r#gen::<u64>(), idx))
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        let mut bases = 0;
+        let mut taken = 0;
+        while bases < n {
+            bases += read_len(keys[taken].1) as u64;
+            taken += 1;
+        }
+        let mut expected = vec![false; 1000];
+        keys[..taken]
+            .iter()
+            .for_each(|(_, idx)| expected[*idx] = true);
+        assert_eq!(kept, expected);
+        // the last read reaches the budget
+        assert!(bases >= n && bases - (read_len(keys[taken - 1].1) as u64) < n);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        let kept = choose_reads(&input, &InputType::OneFastqFile, 64, &fo, &mut rng, 1 << 40);
+        assert!(kept.iter().all(|x| *x));
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn primary_only() {
+        let input = temp_file("primary.bam");
+        let mut header = bam::Header::new();
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
+        );
+        let mut writer = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+        // r1 has a secondary and r2 has a supplementary alignment
+        for (idx, (name, flags)) in [
+            ("r0", 0),
+            ("r1", 0),
+            ("r1", 256),
+            ("r2", 0),
+            ("r2", 2048),
+            ("r3", 16),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut record = bam::Record::new();
+            let cigar = CigarString(vec![Cigar::Match(4)]);
+            record.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+            record.set_tid(0);
+            record.set_pos(idx as i64 * 10);
+            record.set_mapq(60);
+            record.set_flags(*flags);
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+
+        let output = temp_file("primary.out.bam");
+        for target in [
+            SampleTarget::Fraction(1.0),
+            SampleTarget::Reads(10),
+            SampleTarget::Bases(1000),
+        ] {
+            let names = sampled_names(&input, &InputType::UnsortedBam, &output, target, 2, 11);
+            assert_eq!(names, vec!["r0", "r1", "r2", "r3"]);
+        }
+        std::fs::remove_file(&input).unwrap();
+    }
+}
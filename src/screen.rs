@@ -0,0 +1,505 @@
+use crate::fastq::{FastqRecord, chunk_records_from_fastq};
+use crate::input_type::{InputType, check_input_type};
+use crate::utils::{
+    check_output_file, collect_fqs_in_dir, init_log, positive_f64_parse, quit_with_error,
+};
+use clap::{Arg, ArgMatches, Command, value_parser};
+use log::info;
+use needletail::parse_fastx_file;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// the minimizers shared by more than one reference
+const SHARED: u32 = u32::MAX;
+// reference sequences are read and indexed in batches of this many bases, so that only one batch
+// of sequences and their k-mers is in memory besides the index of all minimizers
+const INDEX_BATCH_BASES: usize = 500_000_000;
+
+///
+/// Invertible integer hash of minimap2, it makes the minimizers of low-complexity k-mers
+/// (e.g. poly-A) not always the smallest ones
+///
+fn hash64(key: u64, mask: u64) -> u64 {
+    let mut key = (!key).wrapping_add(key << 21) & mask;
+    key ^= key >> 24;
+    key = (key.wrapping_add(key << 3).wrapping_add(key << 8)) & mask;
+    key ^= key >> 14;
+    key = (key.wrapping_add(key << 2).wrapping_add(key << 4)) & mask;
+    key ^= key >> 28;
+    key.wrapping_add(key << 31) & mask
+}
+
+///
+/// Sorted and deduplicated hashes of the canonical (w, k)-minimizers of the sequence,
+/// k-mers containing non-ACGT bases are skipped
+///
+fn minimizers(seq: &[u8], k: usize, w: usize) -> Vec<u64> {
+    let mask = (1u64 << (2 * k)) - 1;
+    let shift = 2 * (k as u64 - 1);
+    let mut fwd = 0u64;
+    let mut rev = 0u64;
+    let mut valid = 0usize;
+    let mut kmer_hashes = Vec::with_capacity(seq.len());
+    let mut result = vec![];
+    let flush = |kmer_hashes: &mut Vec<u64>, result: &mut Vec<u64>| {
+        if kmer_hashes.len() < w {
+            result.extend(kmer_hashes.iter().min());
+        } else {
+            result.extend(kmer_hashes.windows(w).map(|x| *x.iter().min().unwrap()));
+        }
+        kmer_hashes.clear();
+    };
+    for base in seq {
+        let code = match base {
+            b'A' | b'a' => 0u64,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _ => {
+                valid = 0;
+                flush(&mut kmer_hashes, &mut result);
+                continue;
+            }
+        };
+        fwd = ((fwd << 2) | code) & mask;
+        rev = (rev >> 2) | ((3 - code) << shift);
+        valid += 1;
+        if valid >= k {
+            kmer_hashes.push(hash64(fwd.min(rev), mask));
+        }
+    }
+    flush(&mut kmer_hashes, &mut result);
+    result.sort_unstable();
+    result.dedup();
+    result
+}
+
+///
+/// In-memory minimizer index of the reference set, each minimizer points to the reference
+/// containing it, or SHARED if it's found in several references. The minimizers are kept in a
+/// sorted array and searched by binary search, 12 bytes per minimizer, about 2 / (w + 1)
+/// minimizers per reference base.
+///
+struct KmerIndex {
+    k: usize,
+    w: usize,
+    names: Vec<String>,
+    lengths: Vec<usize>,
+    minimizer_counts: Vec<usize>,
+    minimizers: Vec<u64>,
+    minimizer_refs: Vec<u32>, // the reference of each minimizer
+}
+
+impl KmerIndex {
+    fn build(ref_file: &str, k: usize, w: usize) -> Self {
+        let mut index = KmerIndex {
+            k,
+            w,
+            names: vec![],
+            lengths: vec![],
+            minimizer_counts: vec![],
+            minimizers: vec![],
+            minimizer_refs: vec![],
+        };
+        let mut records = parse_fastx_file(ref_file)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to read {ref_file}")));
+        let mut batch: Vec<Vec<u8>> = vec![];
+        let mut batch_bases = 0;
+        while let Some(record) = records.next() {
+            let record = record
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to parse {ref_file}")));
+            let name = record
+                .id()
+                .split(|x| x.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default();
+            index.names.push(String::from_utf8_lossy(name).to_string());
+            let seq = record.seq().to_vec();
+            index.lengths.push(seq.len());
+            batch_bases += seq.len();
+            batch.push(seq);
+            if batch_bases >= INDEX_BATCH_BASES {
+                index.add_batch(&mut batch);
+                batch_bases = 0;
+            }
+        }
+        index.add_batch(&mut batch);
+        if index.names.is_empty() {
+            quit_with_error(&format!("No reference sequence found in {ref_file}"))
+        }
+        index
+    }
+
+    /// index the minimizers of the last batch.len() references, they are sorted and merged into
+    /// the minimizers of the previous batches
+    fn add_batch(&mut self, batch: &mut Vec<Vec<u8>>) {
+        let first_idx = self.names.len() - batch.len();
+        let batch_minimizers = batch
+            .par_iter()
+            .map(|seq| minimizers(seq, self.k, self.w))
+            .collect::<Vec<_>>();
+        batch.clear();
+        self.minimizer_counts
+            .extend(batch_minimizers.iter().map(|x| x.len()));
+        let mut new_minimizers = batch_minimizers
+            .into_iter()
+            .enumerate()
+            .flat_map(|(idx, x)| x.into_iter().map(move |m| (m, (first_idx + idx) as u32)))
+            .collect::<Vec<_>>();
+        new_minimizers.par_sort_unstable();
+
+        let old_minimizers = std::mem::take(&mut self.minimizers);
+        let old_refs = std::mem::take(&mut self.minimizer_refs);
+        let capacity = old_minimizers.len() + new_minimizers.len();
+        let (mut minimizers, mut refs) =
+            (Vec::with_capacity(capacity), Vec::with_capacity(capacity));
+        let mut push = |minimizer: u64, ref_idx: u32| {
+            if minimizers.last() == Some(&minimizer) {
+                let last = refs.last_mut().unwrap();
+                if *last != ref_idx {
+                    *last = SHARED
+                }
+            } else {
+                minimizers.push(minimizer);
+                refs.push(ref_idx);
+            }
+        };
+        let mut old = old_minimizers.into_iter().zip(old_refs).peekable();
+        for (minimizer, ref_idx) in new_minimizers {
+            while let Some(x) = old.next_if(|x| x.0 <= minimizer) {
+                push(x.0, x.1)
+            }
+            push(minimizer, ref_idx)
+        }
+        old.for_each(|x| push(x.0, x.1));
+        minimizers.shrink_to_fit();
+        refs.shrink_to_fit();
+        self.minimizers = minimizers;
+        self.minimizer_refs = refs;
+    }
+
+    fn get(&self, minimizer: u64) -> Option<u32> {
+        self.minimizers
+            .binary_search(&minimizer)
+            .ok()
+            .map(|x| self.minimizer_refs[x])
+    }
+
+    ///
+    /// The fraction of read minimizers found in the reference set, and the reference with the
+    /// most unique hits. The reference is None if the read only hits the shared minimizers.
+    ///
+    fn classify(&self, seq: &[u8]) -> (f32, Option<u32>) {
+        let read_minimizers = minimizers(seq, self.k, self.w);
+        if read_minimizers.is_empty() {
+            return (0.0, None);
+        }
+        let mut hits = 0;
+        let mut ref_hits: HashMap<u32, u32> = HashMap::new();
+        for minimizer in &read_minimizers {
+            if let Some(ref_idx) = self.get(*minimizer) {
+                hits += 1;
+                if ref_idx != SHARED {
+                    *ref_hits.entry(ref_idx).or_insert(0) += 1;
+                }
+            }
+        }
+        // ties go to the first reference in the fasta, so that the result doesn't depend on
+        // the hash map order
+        let best_ref = ref_hits
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|x| x.0);
+        (hits as f32 / read_minimizers.len() as f32, best_ref)
+    }
+}
+
+///
+/// Reads and bases of each reference, the last two rows are the reads hitting only the shared
+/// minimizers and the clean reads
+///
+struct ScreenReport {
+    rows: Vec<(u64, u64)>,
+}
+
+impl ScreenReport {
+    fn new(ref_count: usize) -> Self {
+        ScreenReport {
+            rows: vec![(0, 0); ref_count + 2],
+        }
+    }
+
+    fn add(&mut self, row: usize, bases: usize) {
+        self.rows[row].0 += 1;
+        self.rows[row].1 += bases as u64;
+    }
+
+    fn total(&self) -> (u64, u64) {
+        self.rows
+            .iter()
+            .fold((0, 0), |acc, x| (acc.0 + x.0, acc.1 + x.1))
+    }
+
+    fn write_tsv(&self, index: &KmerIndex, hits_file: &str) {
+        let mut writer =
+            BufWriter::new(File::create(hits_file).unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, Failed to create {hits_file}"))
+            }));
+        let (total_reads, total_bases) = self.total();
+        let fraction = |x: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64
+            }
+        };
+        writeln!(
+            writer,
+            "Reference\tLength\tMinimizers\tReads\tBases\tReadsFraction\tBasesFraction"
+        )
+        .unwrap();
+        for (row, (reads, bases)) in self.rows.iter().enumerate() {
+            let (name, length, minimizer_count) = if row < index.names.len() {
+                (
+                    index.names[row].as_str(),
+                    index.lengths[row],
+                    index.minimizer_counts[row],
+                )
+            } else if row == index.names.len() {
+                ("ambiguous", 0, 0)
+            } else {
+                ("clean", 0, 0)
+            };
+            writeln!(
+                writer,
+                "{name}\t{length}\t{minimizer_count}\t{reads}\t{bases}\t{:.6}\t{:.6}",
+                fraction(*reads, total_reads),
+                fraction(*bases, total_bases)
+            )
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to write {hits_file}")));
+        }
+    }
+}
+
+fn screen_records(
+    records: &[FastqRecord],
+    index: &KmerIndex,
+    min_fraction: f32,
+    report: &mut ScreenReport,
+    clean_writer: &mut dyn Write,
+    contaminant_writer: &mut Option<BufWriter<File>>,
+) {
+    let classes = records
+        .par_iter()
+        .map(|x| index.classify(&x.seq))
+        .collect::<Vec<_>>();
+    let ambiguous_row = index.names.len();
+    for (record, (fraction, best_ref)) in records.iter().zip(classes) {
+        if fraction < min_fraction {
+            report.add(ambiguous_row + 1, record.seq.len());
+            record.write(clean_writer).unwrap();
+            continue;
+        }
+        let row = best_ref.map_or(ambiguous_row, |x| x as usize);
+        report.add(row, record.seq.len());
+        if let Some(writer) = contaminant_writer {
+            let ref_name = best_ref.map_or("ambiguous", |x| index.names[x as usize].as_str());
+            record
+                .write_with_tag(writer, Some(&format!("ct:Z:{ref_name}")))
+                .unwrap();
+        }
+    }
+}
+
+pub fn run_screen(screen_cmd: &ArgMatches) {
+    init_log();
+    let input = screen_cmd.get_one::<String>("input").unwrap();
+    let ref_file = screen_cmd.get_one::<String>("ref").unwrap();
+    let output = screen_cmd.get_one::<String>("output").unwrap();
+    let contaminants = screen_cmd.get_one::<String>("contaminants");
+    let hits = screen_cmd.get_one::<String>("hits");
+    let k = *screen_cmd.get_one::<u8>("kmer").unwrap() as usize;
+    let w = *screen_cmd.get_one::<u8>("window").unwrap() as usize;
+    let min_fraction = *screen_cmd.get_one::<f64>("min_fraction").unwrap() as f32;
+    let thread = *screen_cmd.get_one::<u16>("thread").unwrap() as usize;
+    let chunk = *screen_cmd.get_one::<u32>("chunk").unwrap();
+
+    check_output_file(output);
+    for each in contaminants.iter().chain(hits.iter()) {
+        check_output_file(each)
+    }
+    let fastqs = match check_input_type(input) {
+        InputType::DirectoryContainFastqsOrFastqsGzipped => collect_fqs_in_dir(input),
+        InputType::OneFastqFile | InputType::OneFastqGzippedFile => vec![input.into()],
+        _ => quit_with_error("screen only supports fastq, fastq.gz or a directory of them"),
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(thread)
+        .build_global()
+        .unwrap();
+
+    let index = KmerIndex::build(ref_file, k, w);
+    info!(
+        "{} minimizers indexed from {} references in {ref_file}",
+        index.minimizers.len(),
+        index.names.len()
+    );
+
+    let mut clean_writer = BufWriter::new(
+        File::create(output)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {output}"))),
+    );
+    let mut contaminant_writer = contaminants.map(|x| {
+        BufWriter::new(
+            File::create(x)
+                .unwrap_or_else(|e| quit_with_error(&format!("{e}, Failed to create {x}"))),
+        )
+    });
+    let mut report = ScreenReport::new(index.names.len());
+    for fastq in fastqs {
+        let (read_handle, receiver) =
+            chunk_records_from_fastq(fastq.to_str().unwrap(), chunk, true);
+        for records in receiver {
+            screen_records(
+                &records,
+                &index,
+                min_fraction,
+                &mut report,
+                &mut clean_writer,
+                &mut contaminant_writer,
+            );
+        }
+        read_handle.join().unwrap();
+    }
+
+    let (total_reads, total_bases) = report.total();
+    let (clean_reads, clean_bases) = report.rows[index.names.len() + 1];
+    info!(
+        "{} of {total_reads} reads ({} of {total_bases} bases) are contaminants",
+        total_reads - clean_reads,
+        total_bases - clean_bases
+    );
+    if let Some(hits_file) = hits {
+        report.write_tsv(&index, hits_file);
+    }
+}
+
+pub fn screen_cmd() -> Command {
+    Command::new("screen")
+        .about("remove contaminant reads, e.g., lambda control, E. coli or host reads, by the k-mers shared with the reference sequences")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("fastq file, fastq.gz file or a directory containing them")
+        )
+        .arg(
+            Arg::new("ref")
+                .long("ref")
+                .required(true)
+                .help("fasta file of the contaminant sequences, each sequence is reported as one reference in --hits. the minimizer index is kept in memory, about 24 / (w + 1) bytes per reference base, e.g., 7 GB for a human genome with the default -w, and up to twice as much while indexing")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output the clean reads into this fastq file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("contaminants")
+                .long("contaminants")
+                .help("output the contaminant reads into this fastq file, the best reference is appended to the header as ct:Z:<reference>")
+        )
+        .arg(
+            Arg::new("hits")
+                .long("hits")
+                .help("output the reads and bases assigned to each reference into this tsv file, it will be truncated if it exists")
+        )
+        .arg(
+            Arg::new("kmer")
+                .short('k')
+                .long("kmer")
+                .default_value("21")
+                .value_parser(value_parser!(u8).range(11..32))
+                .help("k-mer size of the minimizers, small values cause random hits to a big reference like human")
+        )
+        .arg(
+            Arg::new("window")
+                .short('w')
+                .long("window")
+                .default_value("10")
+                .value_parser(value_parser!(u8).range(1..))
+                .help("one minimizer is sampled from every window of this number of k-mers, 1 means all k-mers are used")
+        )
+        .arg(
+            Arg::new("min_fraction")
+                .short('f')
+                .long("min_fraction")
+                .default_value("0.1")
+                .value_parser(|x: &str| positive_f64_parse(x, "--min_fraction", 0.0, 1.0f64))
+                .help("reads with at least this fraction of minimizers found in the references are contaminants")
+        )
+        .arg(
+            Arg::new("thread")
+                .short('t')
+                .long("thread")
+                .default_value("1")
+                .value_parser(value_parser!(u16).range(1..))
+                .help("number of threads")
+        )
+        .arg(
+            Arg::new("chunk")
+                .short('c')
+                .long("chunk")
+                .default_value("50000")
+                .value_parser(value_parser!(u32).range(10000..1000001))
+                .help("reads chunk size")
+        )
+}
+
+#[cfg(test)]
+mod screen_test {
+    use super::*;
+
+    #[test]
+    fn index_batches() {
+        let seqs: [&[u8]; 3] = [
+            b"ACGTTGCATGCATCGATCGTAGCTAGCTAGGATCGATCGGCTA",
+            b"TTGACCGATGCATGCTAGCTAGTCGATCGATGCTAGCTAGCAT",
+            b"GGCATGCATCGATCGTAGCTAGCTAGGATCCCCAGTGTACAGT",
+        ];
+        let (k, w) = (11, 3);
+        let new_index = |names: &[&str]| KmerIndex {
+            k,
+            w,
+            names: names.iter().map(|x| x.to_string()).collect(),
+            lengths: vec![],
+            minimizer_counts: vec![],
+            minimizers: vec![],
+            minimizer_refs: vec![],
+        };
+        let mut one_batch = new_index(&["ref0", "ref1", "ref2"]);
+        one_batch.add_batch(&mut seqs.iter().map(|x| x.to_vec()).collect());
+        let mut two_batches = new_index(&["ref0", "ref1"]);
+        two_batches.add_batch(&mut vec![seqs[0].to_vec(), seqs[1].to_vec()]);
+        two_batches.names.push("ref2".to_string());
+        two_batches.add_batch(&mut vec![seqs[2].to_vec()]);
+        assert_eq!(one_batch.minimizers, two_batches.minimizers);
+        assert_eq!(one_batch.minimizer_refs, two_batches.minimizer_refs);
+        assert_eq!(one_batch.minimizer_counts, two_batches.minimizer_counts);
+        assert!(one_batch.minimizers.windows(2).all(|x| x[0] < x[1]));
+
+        // the minimizers of the first and the third reference overlap
+        let first = minimizers(seqs[0], k, w);
+        let third = minimizers(seqs[2], k, w);
+        let shared = first.iter().find(|x| third.contains(x)).unwrap();
+        assert_eq!(one_batch.get(*shared), Some(SHARED));
+        let only_first = first.iter().find(|x| !third.contains(x)).unwrap();
+        assert_eq!(one_batch.get(*only_first), Some(0));
+        assert_eq!(one_batch.classify(seqs[1]), (1.0, Some(1)));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::amplicons::parse_primers_from_cli;
+use crate::fastq::{FastqRecord, chunk_records_from_fastq};
+use crate::input_type::{InputType, check_input_type};
+use crate::primer_barcode::Primer;
+use crate::utils::{
+    MatchPosition, check_output_file, collect_fqs_in_dir, init_log, quit_with_error,
+};
+use ahash::HashMap;
+use bio::pattern_matching::myers::Myers;
+use clap::{Arg, ArgMatches, Command, value_parser};
+use log::info;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// the adapter of SQK-LSK114, see the library structures at the end of utils.rs. a ligation
+// chimera has LA_ADAPTER_3 of the first molecule and/or LA_ADAPTER_5 of the second molecule
+// inside the read, in either orientation
+const LA_ADAPTER_5: &str = "CCTGTACTTCGTTCAGTTACGTATTGCT";
+const LA_ADAPTER_3: &str = "AGCAATACGTAACTGAACGAAGTACAGG";
+
+/// where a match of the pattern cuts the read
+#[derive(Clone, Copy)]
+enum CutSide {
+    // the adapter itself is removed
+    Whole,
+    // the primer starts a new molecule, it's kept at the start of the next segment
+    Before,
+    // the primer ends a molecule, it's kept at the end of the previous segment
+    After,
+}
+
+#[derive(Clone)]
+struct SplitPattern {
+    name: String,
+    myers: Myers,
+    side: CutSide,
+}
+
+///
+/// One junction inside the read, bases [start, end) are removed when the read is split.
+/// patterns are the indexes of the patterns found at this junction
+///
+pub struct SplitPoint {
+    pub start: usize,
+    pub end: usize,
+    pub distance: u8,
+    patterns: Vec<usize>,
+}
+
+///
+/// Search the whole read for the ligation adapters or the primers of amplicons, and split the
+/// chimeric reads and the concatemers at the matches which are at least min_len bases away
+/// from both ends of the read. The matches near the ends are left to the adapter/barcode trimming.
+///
+#[derive(Clone)]
+pub struct ChimeraSplitter {
+    patterns: Vec<SplitPattern>,
+    max_distance: u8,
+    min_len: usize,
+}
+
+impl ChimeraSplitter {
+    pub fn from_adapter(max_distance: u8, min_len: usize) -> Self {
+        let patterns = [
+            ("LA_ADAPTER_5", LA_ADAPTER_5),
+            ("LA_ADAPTER_3", LA_ADAPTER_3),
+        ]
+        .into_iter()
+        .map(|(name, seq)| SplitPattern {
+            name: name.to_string(),
+            myers: Myers::new(seq.as_bytes()),
+            side: CutSide::Whole,
+        })
+        .collect();
+        ChimeraSplitter {
+            patterns,
+            max_distance,
+            min_len,
+        }
+    }
+
+    /// a concatemer is fwd ... rev_rc fwd ... rev_rc, or its reverse complement
+    pub fn from_primers(
+        primers: &HashMap<String, Primer>,
+        max_distance: u8,
+        min_len: usize,
+    ) -> Self {
+        let mut primers = primers.values().collect::<Vec<_>>();
+        primers.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut patterns = vec![];
+        for primer in primers {
+            for (suffix, myers, side) in [
+                ("fwd", primer.fwd_myers(), CutSide::Before),
+                ("rev", primer.rev_myers(), CutSide::Before),
+                ("rev_rc", primer.rev_rc_myers(), CutSide::After),
+                ("fwd_rc", primer.fwd_rc_myers(), CutSide::After),
+            ] {
+                patterns.push(SplitPattern {
+                    name: format!("{}_{suffix}", primer.name),
+                    myers,
+                    side,
+                })
+            }
+        }
+        ChimeraSplitter {
+            patterns,
+            max_distance,
+            min_len,
+        }
+    }
+
+    /// names of the patterns found at the split point, separated by comma
+    pub fn pattern_names(&self, point: &SplitPoint) -> String {
+        point
+            .patterns
+            .iter()
+            .map(|x| self.patterns[*x].name.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// sorted junctions inside the read, empty if the read isn't chimeric
+    pub fn find_split_points(&mut self, seq: &[u8]) -> Vec<SplitPoint> {
+        let mut points = vec![];
+        for (idx, pattern) in self.patterns.iter_mut().enumerate() {
+            let matches = pattern.myers.find_all(seq, self.max_distance);
+            for (start, end, distance) in best_matches(matches) {
+                let (start, end) = match pattern.side {
+                    CutSide::Whole => (start, end),
+                    CutSide::Before => (start, start),
+                    CutSide::After => (end, end),
+                };
+                if start >= self.min_len && end + self.min_len <= seq.len() {
+                    points.push(SplitPoint {
+                        start,
+                        end,
+                        distance,
+                        patterns: vec![idx],
+                    })
+                }
+            }
+        }
+        points.sort_by_key(|x| (x.start, x.end));
+        // the matches closer than min_len belong to the same junction, e.g., rev_rc of the first
+        // amplicon and fwd of the second one, the bases between them are never kept
+        let mut merged: Vec<SplitPoint> = vec![];
+        for point in points {
+            match merged.last_mut() {
+                Some(last) if point.start < last.end + self.min_len => {
+                    last.end = last.end.max(point.end);
+                    last.distance = last.distance.min(point.distance);
+                    last.patterns.extend(point.patterns);
+                    last.patterns.sort();
+                    last.patterns.dedup();
+                }
+                _ => merged.push(point),
+            }
+        }
+        merged
+    }
+
+    /// segments between the split points, the segments shorter than min_len are dropped
+    pub fn split(&self, read: &FastqRecord, points: &[SplitPoint]) -> Vec<FastqRecord> {
+        let mut bounds = vec![0];
+        for point in points {
+            bounds.push(point.start);
+            bounds.push(point.end);
+        }
+        bounds.push(read.seq.len());
+        bounds
+            .chunks(2)
+            .filter(|x| x[1] - x[0] >= self.min_len)
+            .enumerate()
+            .map(|(idx, x)| read.segment(idx + 1, x[0], x[1]))
+            .collect()
+    }
+}
+
+/// myers reports every end position within max distance, keep the best one of overlapping matches
+fn best_matches(matches: impl Iterator<Item = MatchPosition>) -> Vec<MatchPosition> {
+    let mut best: Vec<MatchPosition> = vec![];
+    for each in matches {
+        match best.last_mut() {
+            Some(last) if each.0 < last.1 => {
+                if each.2 < last.2 {
+                    *last = each
+                }
+            }
+            _ => best.push(each),
+        }
+    }
+    best
+}
+
+fn split_records(
+    records: Vec<FastqRecord>,
+    splitter: &ChimeraSplitter,
+    writer: &mut dyn Write,
+    points_writer: &mut Option<BufWriter<File>>,
+) -> (usize, usize) {
+    let all_points = records
+        .par_iter()
+        .map_init(
+            || splitter.clone(),
+            |splitter, record| splitter.find_split_points(&record.seq),
+        )
+        .collect::<Vec<_>>();
+    let mut chimeric_reads = 0;
+    let mut segments = 0;
+    for (record, points) in records.into_iter().zip(all_points) {
+        if points.is_empty() {
+            record.write(writer).unwrap();
+            continue;
+        }
+        chimeric_reads += 1;
+        for segment in splitter.split(&record, &points) {
+            segment.write(writer).unwrap();
+            segments += 1;
+        }
+        if let Some(points_writer) = points_writer {
+            for point in &points {
+                writeln!(
+                    points_writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    record.name,
+                    record.seq.len(),
+                    point.start,
+                    point.end,
+                    splitter.pattern_names(point),
+                    point.distance
+                )
+                .unwrap();
+            }
+        }
+    }
+    (chimeric_reads, segments)
+}
+
+pub fn run_split(split_cmd: &ArgMatches) {
+    init_log();
+    let input = split_cmd.get_one::<String>("input").unwrap();
+    let output = split_cmd.get_one::<String>("output").unwrap();
+    let split_points = split_cmd.get_one::<String>("split_points");
+    let distance = *split_cmd.get_one::<u8>("distance").unwrap();
+    let min_len = *split_cmd.get_one::<u64>("min_len").unwrap() as usize;
+    let thread = *split_cmd.get_one::<u16>("thread").unwrap() as usize;
+    let chunk = *split_cmd.get_one::<u32>("chunk").unwrap();
+
+    check_output_file(output);
+    if let Some(split_points) = split_points {
+        check_output_file(split_points)
+    }
+    let fastqs = match check_input_type(input) {
+        InputType::DirectoryContainFastqsOrFastqsGzipped => collect_fqs_in_dir(input),
+        InputType::OneFastqFile | InputType::OneFastqGzippedFile => vec![input.into()],
+        _ => quit_with_error("split only supports fastq, fastq.gz or a directory of them"),
+    };
+    let splitter = match split_cmd.get_one::<String>("primers") {
+        Some(primers) => ChimeraSplitter::from_primers(
+            &parse_primers_from_cli(primers, "primer"),
+            distance,
+            min_len,
+        ),
+        None => ChimeraSplitter::from_adapter(distance, min_len),
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(thread)
+        .build_global()
+        .unwrap();
+
+    let mut writer = BufWriter::new(
+        File::create(output)
+            .unwrap_or_else(|x| quit_with_error(&format!("{x}, Failed to create {output}"))),
+    );
+    let mut points_writer = split_points.map(|x| {
+        let mut points_writer = BufWriter::new(
+            File::create(x)
+                .unwrap_or_else(|e| quit_with_error(&format!("{e}, Failed to create {x}"))),
+        );
+        writeln!(
+            points_writer,
+            "ReadName\tReadLength\tStart\tEnd\tPatterns\tDistance"
+        )
+        .unwrap();
+        points_writer
+    });
+    let mut total_reads = 0;
+    let mut chimeric_reads = 0;
+    let mut segments = 0;
+    for fastq in fastqs {
+        let (read_handle, receiver) =
+            chunk_records_from_fastq(fastq.to_str().unwrap(), chunk, true);
+        for records in receiver {
+            total_reads += records.len();
+            let (chunk_chimeric_reads, chunk_segments) =
+                split_records(records, &splitter, &mut writer, &mut points_writer);
+            chimeric_reads += chunk_chimeric_reads;
+            segments += chunk_segments;
+        }
+        read_handle.join().unwrap();
+    }
+    info!(
+        "{chimeric_reads} of {total_reads} reads are chimeric, they are split into {segments} segments"
+    );
+}
+
+pub fn split_cmd() -> Command {
+    Command::new("split")
+        .about("split the chimeric reads at the internal ligation adapters, or the concatemers of amplicons at the internal primers")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .required(true)
+                .help("fastq file, fastq.gz file or a directory containing them")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .help("output the reads into this fastq file, the chimeric reads are replaced by their segments named <read>_1, <read>_2 ...")
+        )
+        .arg(
+            Arg::new("split_points")
+                .long("split_points")
+                .help("output the split points of the chimeric reads into this tsv file, the bases between Start and End are removed")
+        )
+        .arg(
+            Arg::new("primers")
+                .short('p')
+                .long("primers")
+                .help("split amplicon concatemers at these primers instead of the ligation adapter. format: \"PrimerName,FwdPrimer,RevPrimer[;...]\" or a file with each line format: PrimerName\\tFwdPrimer\\tRevPrimer")
+        )
+        .arg(
+            Arg::new("distance")
+                .short('d')
+                .long("distance")
+                .default_value("3")
+                .value_parser(value_parser!(u8))
+                .help("max edit distance allowed between adapter/primer and read sequence")
+        )
+        .arg(
+            Arg::new("min_len")
+                .short('l')
+                .long("min_len")
+                .default_value("100")
+                .value_parser(value_parser!(u64).range(1..))
+                .help("the matches within this number of bases from the read ends are not split, and the shorter segments are dropped")
+        )
+        .arg(
+            Arg::new("thread")
+                .short('t')
+                .long("thread")
+                .default_value("1")
+                .value_parser(value_parser!(u16).range(1..))
+                .help("number of threads")
+        )
+        .arg(
+            Arg::new("chunk")
+                .short('c')
+                .long("chunk")
+                .default_value("50000")
+                .value_parser(value_parser!(u32).range(10000..1000001))
+                .help("reads chunk size")
+        )
+}
+
+#[cfg(test)]
+mod split_test {
+    use super::*;
+    use bio::alphabets::dna::revcomp;
+
+    /// a reproducible random sequence without any adapter or primer
+    fn random_seq(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    fn read(seq: Vec<u8>) -> FastqRecord {
+        let quality = vec![b'5'; seq.len()];
+        FastqRecord::new("read", None, seq, quality)
+    }
+
+    #[test]
+    fn best_match() {
+        let matches = vec![(10, 20, 2), (11, 21, 1), (12, 22, 2), (40, 50, 0)];
+        assert_eq!(
+            best_matches(matches.into_iter()),
+            vec![(11, 21, 1), (40, 50, 0)]
+        );
+    }
+
+    #[test]
+    fn adapter_chimera() {
+        let mut splitter = ChimeraSplitter::from_adapter(3, 100);
+        // the 3' adapter of the first molecule joined with the 5' adapter of the second one
+        let mut seq = random_seq(300, 1);
+        seq.extend(LA_ADAPTER_3.as_bytes());
+        seq.extend(LA_ADAPTER_5.as_bytes());
+        seq.extend(random_seq(300, 2));
+        let points = splitter.find_split_points(&seq);
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].start, points[0].end), (300, 356));
+        assert_eq!(points[0].distance, 0);
+        assert_eq!(
+            splitter.pattern_names(&points[0]),
+            "LA_ADAPTER_5,LA_ADAPTER_3"
+        );
+        let segments = splitter.split(&read(seq.clone()), &points);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].name, "read_1");
+        assert_eq!(segments[0].seq, random_seq(300, 1));
+        assert_eq!(segments[1].name, "read_2");
+        assert_eq!(segments[1].seq, random_seq(300, 2));
+        // a chimera can also be read in the reverse orientation
+        assert_eq!(splitter.find_split_points(&revcomp(&seq)).len(), 1);
+        assert!(splitter.find_split_points(&random_seq(600, 3)).is_empty());
+    }
+
+    #[test]
+    fn adapter_near_ends() {
+        let mut splitter = ChimeraSplitter::from_adapter(3, 100);
+        let adapter_at = |start: usize, total: usize| {
+            let mut seq = random_seq(start, 4);
+            seq.extend(LA_ADAPTER_3.as_bytes());
+            let rest = total - seq.len();
+            seq.extend(random_seq(rest, 5));
+            seq
+        };
+        // the adapter must be min_len bases away from both ends
+        assert!(splitter.find_split_points(&adapter_at(99, 400)).is_empty());
+        assert_eq!(splitter.find_split_points(&adapter_at(100, 400)).len(), 1);
+        assert_eq!(splitter.find_split_points(&adapter_at(272, 400)).len(), 1);
+        assert!(splitter.find_split_points(&adapter_at(273, 400)).is_empty());
+        // the segments shorter than min_len are dropped
+        let mut seq = adapter_at(100, 400);
+        seq.extend(LA_ADAPTER_5.as_bytes());
+        seq.extend(random_seq(150, 6));
+        let points = splitter.find_split_points(&seq);
+        assert_eq!(points.len(), 2);
+        let segments = splitter.split(&read(seq), &points);
+        assert_eq!(
+            segments.iter().map(|x| x.seq.len()).collect::<Vec<_>>(),
+            vec![100, 272, 150]
+        );
+    }
+
+    #[test]
+    fn primer_concatemer() {
+        let fwd = b"GCAACAACAACCTTTCATCCT";
+        let rev = b"TATTTGACAGGATTTATGTGTA";
+        let primers = Primer::new("p1", fwd, rev).name2primer();
+        let mut splitter = ChimeraSplitter::from_primers(&primers, 2, 100);
+        let amplicon = |seed: u64| {
+            let mut seq = fwd.to_vec();
+            seq.extend(random_seq(300, seed));
+            seq.extend(revcomp(rev));
+            seq
+        };
+        // two amplicons joined by 10 bases, the primers at the read ends aren't split
+        let mut seq = amplicon(7);
+        let first_len = seq.len();
+        seq.extend(random_seq(10, 8));
+        seq.extend(amplicon(9));
+        let points = splitter.find_split_points(&seq);
+        assert_eq!(points.len(), 1);
+        // rev_rc of the first amplicon and fwd of the second one are merged into one junction,
+        // the bases between them are removed
+        assert_eq!(
+            (points[0].start, points[0].end),
+            (first_len, first_len + 10)
+        );
+        assert_eq!(splitter.pattern_names(&points[0]), "p1_fwd,p1_rev_rc");
+        let segments = splitter.split(&read(seq.clone()), &points);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].seq, amplicon(7));
+        assert_eq!(segments[1].seq, amplicon(9));
+        // the reverse complement is split at the same junction
+        let points = splitter.find_split_points(&revcomp(&seq));
+        assert_eq!(points.len(), 1);
+        assert_eq!(
+            (points[0].start, points[0].end),
+            (seq.len() - first_len - 10, seq.len() - first_len)
+        );
+        assert_eq!(splitter.pattern_names(&points[0]), "p1_rev,p1_fwd_rc");
+    }
+}
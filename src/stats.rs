@@ -1,11 +1,15 @@
 use crate::bam::{BasicBamStatistics, index_bam, stats_indexed_bam, stats_xam};
-use crate::fastq::{FastqRecord, RecordEachStats, chunk_records_from_fastq};
+use crate::duplex::DuplexClass;
+use crate::fastq::{FastqRecord, RecordEachStats, SeqFeatures, chunk_records_from_fastq};
 use crate::input_type::{InputType, check_input_type};
+use crate::profile::PositionProfile;
+use crate::qc::QcRules;
 use crate::summary::SummaryStats;
 use crate::utils::{
-    calculate_quality, check_input, check_output_file, collect_fqs_in_dir, gc, positive_f64_parse,
-    quit_with_error,
+    QscoreSource, calculate_quality, check_input, check_output_file, collect_fqs_in_dir, gc,
+    init_log, positive_f64_parse, quit_with_error, reported_qscore_from_header,
 };
+use crate::watch::run_watch;
 use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
 use needletail::{Sequence, parse_fastx_file};
 use rayon::prelude::*;
@@ -15,22 +19,50 @@ use std::sync::mpsc::Receiver;
 fn stats_receiver(
     receiver: Receiver<Vec<FastqRecord>>,
     use_dorado_q: bool,
-    use_gc: bool,
-) -> Vec<RecordEachStats> {
+    qscore_source: QscoreSource,
+    features: SeqFeatures,
+    profile_depth: usize,
+) -> (Vec<RecordEachStats>, PositionProfile) {
     let mut all_stats = vec![];
+    let mut profile = PositionProfile::new(profile_depth);
     for records in receiver {
+        if profile_depth > 0 {
+            profile += records
+                .par_iter()
+                .fold(
+                    || PositionProfile::new(profile_depth),
+                    |mut chunk_profile, x| {
+                        x.add_to_profile(&mut chunk_profile);
+                        chunk_profile
+                    },
+                )
+                .reduce(
+                    || PositionProfile::new(profile_depth),
+                    |mut a, b| {
+                        a += b;
+                        a
+                    },
+                );
+        }
         all_stats.extend(
             records
                 .into_par_iter()
-                .map(|x| x.stats(use_dorado_q, use_gc))
+                .map(|x| x.stats(use_dorado_q, features, qscore_source))
                 .collect::<Vec<RecordEachStats>>(),
         )
     }
-    all_stats
+    (all_stats, profile)
 }
 
-pub fn fastq_stats(fastq_file: &str, use_dorado_q: bool, use_gc: bool) -> Vec<RecordEachStats> {
+pub fn fastq_stats(
+    fastq_file: &str,
+    use_dorado_q: bool,
+    qscore_source: QscoreSource,
+    features: SeqFeatures,
+    profile_depth: usize,
+) -> (Vec<RecordEachStats>, PositionProfile) {
     let mut v = vec![];
+    let mut profile = PositionProfile::new(profile_depth);
     let mut records = parse_fastx_file(fastq_file).expect(&format!("Failed to read {fastq_file}"));
     let mut read_idx = 1;
     while let Some(Ok(record)) = records.next() {
@@ -40,33 +72,57 @@ pub fn fastq_stats(fastq_file: &str, use_dorado_q: bool, use_gc: bool) -> Vec<Re
             .expect(&format!("Parse read name failed at {read_idx}th record"));
         let name =
             str::from_utf8(name).expect(&format!("Parse read name failed at {read_idx}th record"));
+        let description = headers
+            .next()
+            .map(|x| std::str::from_utf8(x).unwrap_or_default());
         let seq = record.sequence();
         let quals = record
             .qual()
             .expect(&format!("Parse quality failed at {read_idx}th record"));
+        if profile_depth > 0 {
+            profile.add_read(seq, quals, 33, false);
+        }
         let read_q = calculate_quality(quals, use_dorado_q, false);
-        v.push(RecordEachStats::new(
+        let mut each_stats = RecordEachStats::new(
             name,
             record.num_bases(),
             read_q,
-            if use_gc { Some(gc(seq)) } else { None },
-        ));
+            if features.gc { Some(gc(seq)) } else { None },
+        );
+        each_stats.set_seq_features(seq, features);
+        each_stats.duplex = DuplexClass::from_fastq_header(name, description);
+        each_stats.set_reported_qual(reported_qscore_from_header(description), qscore_source);
+        v.push(each_stats);
         read_idx += 1;
     }
-    v
+    (v, profile)
 }
-fn stats_one_fastq(
+pub fn stats_one_fastq(
     fastq_file: &str,
     thread: usize,
     use_dorado_q: bool,
-    use_gc: bool,
+    qscore_source: QscoreSource,
+    features: SeqFeatures,
     chunk: u32,
-) -> Vec<RecordEachStats> {
+    profile_depth: usize,
+) -> (Vec<RecordEachStats>, PositionProfile) {
     if thread == 1 {
-        fastq_stats(fastq_file, use_dorado_q, use_gc)
+        fastq_stats(
+            fastq_file,
+            use_dorado_q,
+            qscore_source,
+            features,
+            profile_depth,
+        )
     } else {
-        let (read_handle, receiver) = chunk_records_from_fastq(fastq_file, chunk, false);
-        let x = stats_receiver(receiver, use_dorado_q, use_gc);
+        let (read_handle, receiver) = chunk_records_from_fastq(fastq_file, chunk, true);
+        let x = stats_receiver(
+            receiver,
+            use_dorado_q,
+            qscore_source,
+            features,
+            profile_depth,
+        );
         read_handle.join().unwrap();
         x
     }
@@ -76,40 +132,77 @@ fn stats_fastq_dir(
     fastq_dir: &str,
     thread: usize,
     use_dorado_q: bool,
-    use_gc: bool,
-) -> Vec<RecordEachStats> {
+    qscore_source: QscoreSource,
+    features: SeqFeatures,
+    profile_depth: usize,
+) -> (Vec<RecordEachStats>, PositionProfile) {
     let fastqs = collect_fqs_in_dir(fastq_dir);
+    let merge = |mut acc: (Vec<RecordEachStats>, PositionProfile),
+                 x: (Vec<RecordEachStats>, PositionProfile)| {
+        acc.0.extend(x.0);
+        acc.1 += x.1;
+        acc
+    };
     if thread == 1 {
         fastqs
             .into_iter()
-            .map(|x| fastq_stats(x.to_str().unwrap(), use_dorado_q, use_gc))
-            .flatten()
-            .collect()
+            .map(|x| {
+                fastq_stats(
+                    x.to_str().unwrap(),
+                    use_dorado_q,
+                    qscore_source,
+                    features,
+                    profile_depth,
+                )
+            })
+            .fold((vec![], PositionProfile::new(profile_depth)), merge)
     } else {
         fastqs
             .into_par_iter()
-            .map(|x| fastq_stats(x.to_str().unwrap(), use_dorado_q, use_gc))
-            .flatten()
-            .collect()
+            .map(|x| {
+                fastq_stats(
+                    x.to_str().unwrap(),
+                    use_dorado_q,
+                    qscore_source,
+                    features,
+                    profile_depth,
+                )
+            })
+            .reduce(|| (vec![], PositionProfile::new(profile_depth)), merge)
     }
 }
 
 pub fn run_stats(stats_cmd: &ArgMatches) {
+    if let Some(watch_dir) = stats_cmd.get_one::<String>("watch") {
+        return run_watch(stats_cmd, watch_dir);
+    }
+    init_log();
     let input_file = stats_cmd.get_one::<String>("input").unwrap();
     let report = stats_cmd.get_one::<String>("report").unwrap();
     let analysis_name = stats_cmd.get_one::<String>("name").unwrap();
     let output = stats_cmd.get_one::<String>("output");
     let summary = stats_cmd.get_one::<String>("summary");
+    let json = stats_cmd.get_one::<String>("json");
     let topn = stats_cmd.get_one::<u32>("topn").unwrap();
     let quality = stats_cmd.get_one::<Vec<f64>>("quality").unwrap();
     let use_dorado_q = stats_cmd.get_flag("use_dorado_q");
+    let qscore_source =
+        QscoreSource::from_arg(stats_cmd.get_one::<String>("qscore_source").unwrap());
     let lengths = stats_cmd.get_one::<Vec<u32>>("length");
-    let use_gc = stats_cmd.get_flag("gc");
+    let features = SeqFeatures {
+        gc: stats_cmd.get_flag("gc"),
+        complexity: stats_cmd.get_flag("complexity"),
+        poly_tail: stats_cmd.get_flag("poly_tail"),
+    };
     let index = stats_cmd.get_flag("index");
     let thread = stats_cmd.get_one::<u16>("thread").unwrap();
     let chunk = stats_cmd.get_one::<u32>("chunk").unwrap();
     let bins = stats_cmd.get_one::<u32>("bins").unwrap();
     let quantile = stats_cmd.get_one::<f64>("quantile").unwrap();
+    let profile_depth = *stats_cmd.get_one::<usize>("profile_depth").unwrap();
+    let mods = stats_cmd.get_flag("mods");
+    let exclude_duplex_parents = stats_cmd.get_flag("exclude_duplex_parents");
+    let qc_rules = QcRules::from_arg_matches(stats_cmd);
     // let input_file = input.unwrap();
     let input_t = check_input_type(input_file);
     check_input(input_file);
@@ -117,6 +210,9 @@ pub fn run_stats(stats_cmd: &ArgMatches) {
     if summary.is_some() {
         check_output_file(summary.unwrap())
     }
+    if let Some(json_file) = json {
+        check_output_file(json_file)
+    }
     if output.is_some() {
         check_output_file(output.unwrap())
     }
@@ -129,55 +225,115 @@ pub fn run_stats(stats_cmd: &ArgMatches) {
     }
 
     let mut basic_bam_stats = BasicBamStatistics::default();
-    let all_stats = match input_t {
-        InputType::DirectoryContainFastqsOrFastqsGzipped => {
-            stats_fastq_dir(input_file, *thread as usize, use_dorado_q, use_gc)
-        }
-        InputType::OneFastqFile | InputType::OneFastqGzippedFile => {
-            stats_one_fastq(input_file, *thread as usize, use_dorado_q, use_gc, *chunk)
-        }
+    let (all_stats, profile) = match input_t {
+        InputType::DirectoryContainFastqsOrFastqsGzipped => stats_fastq_dir(
+            input_file,
+            *thread as usize,
+            use_dorado_q,
+            qscore_source,
+            features,
+            profile_depth,
+        ),
+        InputType::OneFastqFile | InputType::OneFastqGzippedFile => stats_one_fastq(
+            input_file,
+            *thread as usize,
+            use_dorado_q,
+            qscore_source,
+            features,
+            *chunk,
+            profile_depth,
+        ),
         InputType::OneSamFile | InputType::UnsortedBam | InputType::UnalignedBam => {
             let mut bam_reader = rust_htslib::bam::Reader::from_path(input_file)
                 .expect(&format!("Failed to read {}", input_file));
-            let (basic_bam_stats_, all_stats) =
-                stats_xam(&mut bam_reader, *thread as usize, use_gc, use_dorado_q);
+            let (basic_bam_stats_, all_stats, profile) = stats_xam(
+                &mut bam_reader,
+                *thread as usize,
+                features,
+                use_dorado_q,
+                qscore_source,
+                mods,
+                profile_depth,
+            );
             basic_bam_stats = basic_bam_stats_;
-            all_stats
+            (all_stats, profile)
         }
         InputType::SortedUnindexedBam => {
-            let (basic_bam_stats_, all_stats) = if index {
+            let (basic_bam_stats_, all_stats, profile) = if index {
                 index_bam(input_file, *thread as usize)
                     .expect(&format!("Failed to index {}", input_file));
-                stats_indexed_bam(input_file, *thread as usize, use_dorado_q, use_gc)
+                stats_indexed_bam(
+                    input_file,
+                    *thread as usize,
+                    features,
+                    use_dorado_q,
+                    qscore_source,
+                    mods,
+                    profile_depth,
+                )
             } else {
                 let mut bam_reader = rust_htslib::bam::Reader::from_path(input_file)
                     .expect(&format!("Failed to read {}", input_file));
-                stats_xam(&mut bam_reader, *thread as usize, use_dorado_q, use_gc)
+                stats_xam(
+                    &mut bam_reader,
+                    *thread as usize,
+                    features,
+                    use_dorado_q,
+                    qscore_source,
+                    mods,
+                    profile_depth,
+                )
             };
             basic_bam_stats = basic_bam_stats_;
-            all_stats
+            (all_stats, profile)
         }
         InputType::IndexedBam => {
-            let (basic_bam_stats_, all_stats) =
-                stats_indexed_bam(input_file, *thread as usize, use_dorado_q, use_gc);
+            let (basic_bam_stats_, all_stats, profile) = stats_indexed_bam(
+                input_file,
+                *thread as usize,
+                features,
+                use_dorado_q,
+                qscore_source,
+                mods,
+                profile_depth,
+            );
             basic_bam_stats = basic_bam_stats_;
-            all_stats
+            (all_stats, profile)
         }
     };
     let mut stats_summary = SummaryStats::new(
         all_stats,
         lengths.map(|x| x.as_slice()),
         quality,
-        use_gc,
+        features.gc,
         *topn as usize,
+        exclude_duplex_parents,
     );
+    let qc_report = qc_rules.map(|rules| {
+        let qc_report = rules.evaluate(&stats_summary.qc_metrics(&basic_bam_stats));
+        stats_summary.set_qc_report(qc_report.clone());
+        qc_report
+    });
     if output.is_some() {
         stats_summary.save_all_stats(analysis_name, output.unwrap());
     }
     if summary.is_some() {
         stats_summary.write_summary_to_text(analysis_name, &basic_bam_stats, summary.unwrap());
     }
-    stats_summary.write_to_html_file(analysis_name, *bins as usize, *quantile, report);
+    if let Some(json_file) = json {
+        stats_summary.write_summary_to_json(analysis_name, &basic_bam_stats, json_file);
+    }
+    stats_summary.write_to_html_file(
+        analysis_name,
+        *bins as usize,
+        *quantile,
+        &profile,
+        &basic_bam_stats,
+        report,
+    );
+    if let Some(qc_report) = qc_report {
+        qc_report.print_and_exit()
+    }
 }
 
 pub fn stats_cmd() -> Command {
@@ -187,7 +343,8 @@ pub fn stats_cmd() -> Command {
             Arg::new("input")
                 .short('i')
                 .long("input")
-                .required(true)
+                .required_unless_present("watch")
+                .conflicts_with("watch")
                 .help("the input file, could be
     1. a single fastq[.gz]
     2. a directory containing some fastq[.gz]
@@ -218,6 +375,11 @@ pub fn stats_cmd() -> Command {
                 .long("summary")
                 .help("output stats summary into this file if specified, it will be truncated if it exists")
         )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("output stats summary into this json file if specified, it will be truncated if it exists")
+        )
         .arg(
             Arg::new("topn")
                 .short('n')
@@ -233,6 +395,13 @@ pub fn stats_cmd() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("use dorado q-score calculation. this means the leading 60 bases will be trimmed if the read length is longer than 60 when calculating the read Q-value")
         )
+        .arg(
+            Arg::new("qscore_source")
+                .long("qscore_source")
+                .default_value("recompute")
+                .value_parser(["recompute", "tag", "auto"])
+                .help("where the read quality comes from. recompute: mean-error Q from the quality string; tag: the basecaller reported Q in qs:f/qs:i tag of bam or qs= in fastq header; auto: the reported Q if available, otherwise recompute")
+        )
         .arg(
             Arg::new("quality")
                 .short('q')
@@ -283,6 +452,30 @@ pub fn stats_cmd() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("whether to calculate the GC content [default: false]")
         )
+        .arg(
+            Arg::new("complexity")
+                .long("complexity")
+                .action(ArgAction::SetTrue)
+                .help("compute the complexity (trinucleotide entropy normalized to [0, 1]) and the longest homopolymer of each read, they are shown as a distribution and saved in the stats result")
+        )
+        .arg(
+            Arg::new("poly_tail")
+                .long("poly_tail")
+                .action(ArgAction::SetTrue)
+                .help("estimate the poly(A) tail length at the 3' end or poly(T) at the 5' end of each read in base space, 0 if no tail, it's shown as a distribution and saved in the stats result. for oriented cDNA reads (nanofq cdna) or direct RNA reads")
+        )
+        .arg(
+            Arg::new("mods")
+                .long("mods")
+                .action(ArgAction::SetTrue)
+                .help("parse MM/ML tags of bam/sam records to summarize modified bases, only for bam or sam input [default: false]")
+        )
+        .arg(
+            Arg::new("exclude_duplex_parents")
+                .long("exclude_duplex_parents")
+                .action(ArgAction::SetTrue)
+                .help("exclude the simplex parents of duplex reads (dx:i:-1) from all basic statistics, the duplex summary is not affected [default: false]")
+        )
         .arg(
             Arg::new("index")
                 .short('I')
@@ -321,4 +514,57 @@ pub fn stats_cmd() -> Command {
                 .value_parser(|x:&str| positive_f64_parse(x, "--quantile",  0.0f64, 0.5f64))
                 .help("the top quantile of reads lengths will be excluded from the read length distribution in html report")
         )
+        .arg(
+            Arg::new("profile_depth")
+                .long("profile_depth")
+                .default_value("500")
+                .value_parser(value_parser!(usize))
+                .help("count per-position quality and base composition for the first and last N bases of reads in html report, 0 means disabled")
+        )
+        .arg(
+            Arg::new("qc_rules")
+                .long("qc_rules")
+                .help("a toml file of qc rules with [fail] and [warn] tables, the keys are min_bases, min_n50, min_median_q, max_frac_below_q10 and min_map_rate. the qc table is printed and added to the outputs, and the program exits with 3 for WARN and 4 for FAIL")
+        )
+        .arg(
+            Arg::new("qc_fail")
+                .long("qc_fail")
+                .help("qc rules that fail the run, override the same rules in --qc_rules. e.g., --qc_fail min_bases=1e9,min_n50=5000")
+        )
+        .arg(
+            Arg::new("qc_warn")
+                .long("qc_warn")
+                .help("qc rules that warn the run, override the same rules in --qc_rules. e.g., --qc_warn min_median_q=12,max_frac_below_q10=0.1")
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("watch a sequencing output directory instead of --input, the completed fastq[.gz] and bam files in it and its sub directories are added to the statistics as they appear")
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .default_value("10")
+                .value_parser(value_parser!(f64))
+                .help("rewrite the report and other outputs every N minutes in --watch mode if new reads are found")
+        )
+        .arg(
+            Arg::new("poll")
+                .long("poll")
+                .default_value("60")
+                .value_parser(value_parser!(u64))
+                .help("scan the watched directory every N seconds, a file is regarded as completed when its size and modified time are unchanged between two scans")
+        )
+        .arg(
+            Arg::new("idle_exit")
+                .long("idle_exit")
+                .default_value("0")
+                .value_parser(value_parser!(f64))
+                .help("write the final outputs and exit if no new file is found in the last N minutes in --watch mode, 0 means never exit")
+        )
+        .arg(
+            Arg::new("watch_state")
+                .long("watch_state")
+                .help("the directory to save the processed files and per-read stats in --watch mode, a restarted watch skips the processed files. [default: {report}.watch]")
+        )
 }
@@ -85,7 +85,7 @@ impl<'a> ReadsInBam<'a> {
 }
 
 impl<'a> ReadNames<'a> {
-    fn get_read_names(self) -> HashSet<String> {
+    pub fn get_read_names(self) -> HashSet<String> {
         let mut read_names_set = HashSet::new();
         match &self {
             ReadNames::FromCli(names) => {
@@ -1,14 +1,19 @@
 use crate::bam::BasicBamStatistics;
-use crate::fastq::RecordEachStats;
-use crate::utils::format_counts;
+use crate::calibration::read_calibration_rows;
+use crate::duplex::{DuplexClass, DuplexStats, mark_duplex_parents};
+use crate::fastq::{RecordEachStats, SeqFeatures};
+use crate::profile::PositionProfile;
+use crate::qc::{QcMetrics, QcReport};
+use crate::utils::{format_counts, quit_with_error};
 use ndhistogram::axis::Uniform;
 use ndhistogram::{Histogram, ndhistogram};
 use plotly::color::NamedColor;
-use plotly::common::Marker;
+use plotly::common::{ColorScale, ColorScalePalette, DashType, HoverInfo, Line, Marker, Mode};
 use plotly::layout::themes::BuiltinTheme;
-use plotly::layout::{Axis, GridPattern, HoverMode, LayoutGrid};
-use plotly::{Bar,  Layout, Plot};
+use plotly::layout::{Axis, AxisType, GridPattern, HoverMode, LayoutGrid};
+use plotly::{Bar, HeatMap, Layout, Plot, Scatter};
 use rayon::prelude::*;
+use serde_json::{Value, json};
 use statrs::statistics::{Data, Distribution, Max, Median, Min, OrderStatistics, Statistics};
 use std::cmp::Reverse;
 use std::io::Write;
@@ -101,6 +106,29 @@ impl BasicStatistics {
         contents.push_str(&format!("ReadQualQuan75:\t{:.2}\n", self.quantile75_qual));
         contents
     }
+    fn to_json(&self) -> Value {
+        json!({
+            "ReadsNumber": self.reads_number,
+            "BasesNumber": self.bases_number,
+            "N10": self.n10,
+            "N50": self.n50,
+            "N90": self.n90,
+            "ReadMinLen": self.min_len,
+            "ReadMaxLen": self.max_len,
+            "ReadMeanLen": self.mean_len,
+            "ReadStdLen": self.std_len,
+            "ReadLenQuan25": self.quantile25_len,
+            "ReadMedianLen": self.median_len,
+            "ReadLenQuan75": self.quantile75_len,
+            "ReadMinQual": self.min_qual,
+            "ReadMaxQual": self.max_qual,
+            "ReadMeanQual": self.mean_qual,
+            "ReadStdQual": self.std_qual,
+            "ReadQualQuan25": self.quantile25_qual,
+            "ReadMedianQual": self.median_qual,
+            "ReadQualQuan75": self.quantile75_qual,
+        })
+    }
     fn dict_basic_info(&self) -> Vec<(&'static str, String)> {
         let mut items = vec![];
         items.push(("ReadsNumber", self.reads_number.to_string()));
@@ -136,6 +164,224 @@ impl BasicStatistics {
     }
 }
 
+///
+/// The head of html report, including the embedded plotly.js, styles and the page header.
+/// It ends with an opened `<div class="container">`, which is closed by `HTML_TAIL`
+///
+pub fn html_head(title: &str, heading: &str) -> String {
+    let home_page = "https://github.com/aadali/nanofq";
+    format!(
+        r#"
+        <!DOCTYPE html>
+<html lang="zh">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{title}</title>
+    {}
+    <style>
+        body {{
+            font-family: "Segoe UI", "Microsoft YaHei", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background-color: #f4f6f9;
+        }}
+        .header {{
+            background: linear-gradient(135deg, #1a5276, #2E86AB);
+            color: white;
+            padding: 25px 40px;
+            box-shadow: 0 4px 12px rgba(0,0,0,0.15);
+        }}
+        .header-content {{
+            max-width: 1400px;
+            margin: 0 auto;
+            display: flex;
+            justify-content: space-between;
+            align-items: center;
+        }}
+        .header h1 {{ margin: 0; font-size: 28px; }}
+        .header .meta {{ font-size: 14px; opacity: 0.9; }}
+
+        .container {{
+            max-width: 1400px;
+            margin: 30px auto;
+            padding: 0 20px;
+        }}
+
+        .section {{
+            background: white;
+            margin-bottom: 30px;
+            border-radius: 12px;
+            box-shadow: 0 4px 20px rgba(0,0,0,0.08);
+            overflow: hidden;
+        }}
+        .section-header {{
+            background: #2E86AB;
+            color: white;
+            padding: 12px 25px;
+            font-size: 18px;
+            font-weight: 600;
+        }}
+
+        table {{
+            width: 100%;
+            border-collapse: collapse;
+        }}
+        th, td {{
+            padding: 12px 20px;
+            text-align: left;
+            border-bottom: 1px solid #eee;
+        }}
+        th {{
+            background: #f8f9fa;
+            font-weight: 600;
+            color: #333;
+        }}
+        tr:hover {{
+            background: #f8f9fa;
+        }}
+
+        .stats-grid {{
+            display: grid;
+            grid-template-columns: repeat(auto-fit, minmax(220px, 1fr));
+            gap: 15px;
+            padding: 25px;
+        }}
+        .stat-card {{
+            background: #f8f9fa;
+            padding: 15px 20px;
+            border-radius: 8px;
+            border-left: 5px solid #2E86AB;
+        }}
+        .stat-card .label {{ font-size: 13px; color: #666; }}
+        .stat-card .value {{ font-size: 20px; font-weight: 600; color: #1a5276; margin-top: 5px; }}
+
+        .chart-section {{
+            padding: 25px;
+        }}
+
+        .plotly-graph-div {{
+            width: 100% !important;
+        }}
+    </style>
+</head>
+<body>
+    <!-- Header -->
+    <div class="header">
+        <div class="header-content">
+            <div>
+                <h1>🧬 {heading}</h1>
+                <div class="meta">Generated on {} • by <a href="{home_page}" target="_blank" style="color:#ffffff">nanofq</a></div>
+            </div>
+        </div>
+    </div>
+
+    <div class="container">
+        "#,
+        Plot::offline_js_sources(),
+        chrono::Local::now().format("%Y-%m-%d %H:%M").to_string()
+    )
+}
+
+pub const HTML_TAIL: &str = r#"
+    </div>
+</body>
+</html>
+
+        "#;
+
+/// column header of the per-read stats tsv, the optional columns are only written when used
+pub fn stats_tsv_header(features: SeqFeatures, use_mods: bool, use_duplex: bool) -> String {
+    format!(
+        "#ReadName\tReadLength\tReadQuality{}{}{}{}{}",
+        if features.gc { "\tGCContent" } else { "" },
+        if features.complexity {
+            "\tComplexity\tLongestHomopolymer"
+        } else {
+            ""
+        },
+        if features.poly_tail {
+            "\tPolyTailLength"
+        } else {
+            ""
+        },
+        if use_mods {
+            "\tModSites\tModRate\tModStatus"
+        } else {
+            ""
+        },
+        if use_duplex { "\tDuplexClass" } else { "" }
+    )
+}
+
+/// one line of the per-read stats tsv, see stats_tsv_header
+pub fn stats_tsv_line(
+    x: &RecordEachStats,
+    features: SeqFeatures,
+    use_mods: bool,
+    use_duplex: bool,
+) -> String {
+    format!(
+        "{}\t{}\t{:.6}{}{}{}{}{}",
+        x.name,
+        x.length,
+        x.qual,
+        if features.gc {
+            format!("\t{:.2}", x.gc.unwrap())
+        } else {
+            "".to_string()
+        },
+        if features.complexity {
+            format!(
+                "\t{:.4}\t{}",
+                x.complexity.unwrap(),
+                x.longest_homopolymer.unwrap()
+            )
+        } else {
+            "".to_string()
+        },
+        if features.poly_tail {
+            format!("\t{}", x.poly_tail.unwrap())
+        } else {
+            "".to_string()
+        },
+        match (use_mods, x.mods) {
+            (false, _) => "".to_string(),
+            (true, Some(mods)) => format!(
+                "\t{}\t{}\t{}",
+                mods.called,
+                mods.rate()
+                    .map_or("NA".to_string(), |rate| format!("{rate:.4}")),
+                mods.status
+            ),
+            (true, None) => "\tNA\tNA\tNA".to_string(),
+        },
+        if use_duplex {
+            format!("\t{}", x.duplex)
+        } else {
+            "".to_string()
+        }
+    )
+}
+
+///
+/// One report section containing a plotly figure div
+///
+pub fn html_chart_section(title: &str, fig_html_string: &str) -> String {
+    format!(
+        r#"
+        <div class="section">
+            <div class="section-header">{title}</div>
+            <div class="chart-section">
+                <div style="max-width: 1400px; margin: 0 auto;">
+                {fig_html_string}
+                </div>
+            </div>
+        </div>
+        "#
+    )
+}
+
 struct PlotInfo {
     positions: Vec<f64>,
     height: Vec<usize>,
@@ -144,28 +390,75 @@ struct PlotInfo {
 
 pub struct SummaryStats<'a> {
     all_stats: Vec<RecordEachStats>,
+    excluded: Vec<RecordEachStats>, // duplex parents left out of the summary
     read_lengths: Option<&'a [u32]>,
     read_qualities: &'a [f64],
     use_gc: bool,
     n: usize,
+    duplex: DuplexStats,
+    qc_report: Option<QcReport>,
 }
 impl<'a> SummaryStats<'a> {
     pub fn new(
-        all_stats: Vec<RecordEachStats>,
+        mut all_stats: Vec<RecordEachStats>,
         read_lengths: Option<&'a [u32]>,
         read_qualities: &'a [f64],
         use_gc: bool,
         n: usize,
+        exclude_duplex_parents: bool,
     ) -> Self {
+        mark_duplex_parents(&mut all_stats);
+        let duplex = DuplexStats::from_stats(&all_stats, exclude_duplex_parents);
+        let mut excluded = vec![];
+        if exclude_duplex_parents {
+            (excluded, all_stats) = all_stats
+                .into_iter()
+                .partition(|x| x.duplex == DuplexClass::DuplexParent);
+        }
         SummaryStats {
             all_stats,
+            excluded,
             read_lengths,
             read_qualities,
             use_gc,
             n,
+            duplex,
+            qc_report: None,
+        }
+    }
+
+    /// all reads given to new, including the excluded duplex parents, in any order
+    pub fn into_all_stats(self) -> Vec<RecordEachStats> {
+        let mut all_stats = self.all_stats;
+        all_stats.extend(self.excluded);
+        all_stats
+    }
+
+    /// the statistics checked by qc rules
+    pub fn qc_metrics(&self, basic_bam_stats: &BasicBamStatistics) -> QcMetrics {
+        let (total_reads, total_bases) = self.get_reads_and_bases();
+        if total_reads == 0 {
+            return QcMetrics {
+                map_rate: basic_bam_stats.map_rate(),
+                ..Default::default()
+            };
+        }
+        let basic_stats = self.get_basic_stats(total_reads, total_bases);
+        let below_q10 = self.all_stats.iter().filter(|x| x.qual < 10.0).count();
+        QcMetrics {
+            bases: total_bases,
+            n50: basic_stats.n50,
+            median_qual: basic_stats.median_qual,
+            frac_below_q10: below_q10 as f64 / total_reads as f64,
+            map_rate: basic_bam_stats.map_rate(),
         }
     }
 
+    /// the qc table is added to the html report, summary and json
+    pub fn set_qc_report(&mut self, qc_report: QcReport) {
+        self.qc_report = Some(qc_report);
+    }
+
     fn get_reads_and_bases(&self) -> (usize, usize) {
         let total_bases = self
             .all_stats
@@ -548,156 +841,677 @@ impl<'a> SummaryStats<'a> {
             )
             .height(800);
         plot.set_layout(layout);
-        plot.to_html()
+        plot.to_inline_html(Some("length_quality_distribution"))
     }
 
-    pub fn save_all_stats(&self, name: &str, out_file: &str) {
-        let output_file = std::fs::File::create(out_file)
-            .expect(&format!("Failed to create and open {out_file}"));
-        let mut writer = std::io::BufWriter::new(output_file);
-        let _ = writeln!(
-            &mut writer,
-            "#{name} stats result generated on {}",
-            chrono::Local::now().format("%Y-%m-%d %H:%M").to_string()
-        );
-        for x in &self.all_stats {
-            writeln!(
-                &mut writer,
-                "{}\t{}\t{:.6}{}",
-                x.name,
-                x.length,
-                x.qual,
-                if self.use_gc {
-                    format!("\t{:.2}", x.gc.unwrap())
-                } else {
-                    "".to_string()
-                }
-            )
-            .unwrap()
+    ///
+    /// 2D density of read length against read quality. Length is binned in log10 space so that
+    /// both short fragments and ultra-long reads are visible in the same plot
+    ///
+    fn length_quality_density_html_div(&self, bins: usize) -> String {
+        let log_lengths = self
+            .all_stats
+            .iter()
+            .map(|x| (x.length.max(1) as f64).log10())
+            .collect::<Vec<_>>();
+        let min_log = log_lengths.iter().cloned().fold(f64::INFINITY, f64::min);
+        let mut max_log = log_lengths
+            .iter()
+            .cloned()
+            .fold(f64::NEG_INFINITY, f64::max);
+        let min_qual = self
+            .all_stats
+            .iter()
+            .map(|x| x.qual as f64)
+            .fold(f64::INFINITY, f64::min);
+        let mut max_qual = self
+            .all_stats
+            .iter()
+            .map(|x| x.qual as f64)
+            .fold(f64::NEG_INFINITY, f64::max);
+        // make sure the last value falls into the last bin
+        max_log = if max_log > min_log {
+            max_log + 1e-9
+        } else {
+            min_log + 1.0
+        };
+        max_qual = if max_qual > min_qual {
+            max_qual + 1e-6
+        } else {
+            min_qual + 1.0
+        };
+        let log_width = (max_log - min_log) / bins as f64;
+        let qual_width = (max_qual - min_qual) / bins as f64;
+
+        // counts[quality bin][length bin]
+        let mut counts = vec![vec![0usize; bins]; bins];
+        for (each_stats, log_len) in self.all_stats.iter().zip(log_lengths.iter()) {
+            let len_idx = (((log_len - min_log) / log_width) as usize).min(bins - 1);
+            let qual_idx =
+                (((each_stats.qual as f64 - min_qual) / qual_width) as usize).min(bins - 1);
+            counts[qual_idx][len_idx] += 1;
         }
+        let z = counts
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|count| if count == 0 { None } else { Some(count) })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let x = (0..bins)
+            .map(|i| 10f64.powf(min_log + (i as f64 + 0.5) * log_width))
+            .collect::<Vec<_>>();
+        let y = (0..bins)
+            .map(|i| min_qual + (i as f64 + 0.5) * qual_width)
+            .collect::<Vec<_>>();
+        let trace = HeatMap::new(x, y, z)
+            .color_scale(ColorScale::Palette(ColorScalePalette::Viridis))
+            .hover_on_gaps(false)
+            .hover_template(
+                "<b>ReadLength</b>: %{x:.0f}<br>\
+                <b>ReadQuality</b>: %{y:.2f}<br>\
+                <b>ReadsCount</b>: %{z}\
+                <extra></extra>",
+            );
+        let mut plot = Plot::new();
+        plot.add_trace(trace);
+        let layout = Layout::new()
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("ReadLength")
+                    .type_(AxisType::Log)
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReadQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .height(600);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("length_quality_density"))
     }
 
-    pub fn write_to_html_file(
-        &mut self,
-        name: &str,
-        bins: usize,
-        length_quantile: f64,
-        html_file: &str,
-    ) {
-        let home_page = "https://github.com/aadali/nanofq";
-        let mut html = format!(
-            r#"
-        <!DOCTYPE html>
-<html lang="zh">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>{name} Stats Summary Report</title>
-    <style>
-        body {{
-            font-family: "Segoe UI", "Microsoft YaHei", Arial, sans-serif;
-            margin: 0;
-            padding: 0;
-            background-color: #f4f6f9;
-        }}
-        .header {{
-            background: linear-gradient(135deg, #1a5276, #2E86AB);
-            color: white;
-            padding: 25px 40px;
-            box-shadow: 0 4px 12px rgba(0,0,0,0.15);
-        }}
-        .header-content {{
-            max-width: 1400px;
-            margin: 0 auto;
-            display: flex;
-            justify-content: space-between;
-            align-items: center;
-        }}
-        .header h1 {{ margin: 0; font-size: 28px; }}
-        .header .meta {{ font-size: 14px; opacity: 0.9; }}
-
-        .container {{
-            max-width: 1400px;
-            margin: 30px auto;
-            padding: 0 20px;
-        }}
+    ///
+    /// Density of basecaller reported read quality vs recomputed read quality,
+    /// only reads with reported quality are included
+    ///
+    fn reported_quality_html_div(&self, bins: usize) -> String {
+        let pairs = self
+            .all_stats
+            .iter()
+            .filter_map(|x| {
+                x.reported_qual
+                    .map(|q| (x.recomputed_qual as f64, q as f64))
+            })
+            .collect::<Vec<_>>();
+        let min_qual = pairs
+            .iter()
+            .map(|x| x.0.min(x.1))
+            .fold(f64::INFINITY, f64::min);
+        let mut max_qual = pairs
+            .iter()
+            .map(|x| x.0.max(x.1))
+            .fold(f64::NEG_INFINITY, f64::max);
+        max_qual = if max_qual > min_qual {
+            max_qual + 1e-6
+        } else {
+            min_qual + 1.0
+        };
+        let qual_width = (max_qual - min_qual) / bins as f64;
 
-        .section {{
-            background: white;
-            margin-bottom: 30px;
-            border-radius: 12px;
-            box-shadow: 0 4px 20px rgba(0,0,0,0.08);
-            overflow: hidden;
-        }}
-        .section-header {{
-            background: #2E86AB;
-            color: white;
-            padding: 12px 25px;
-            font-size: 18px;
-            font-weight: 600;
-        }}
+        // counts[reported bin][recomputed bin]
+        let mut counts = vec![vec![0usize; bins]; bins];
+        for (recomputed, reported) in pairs.iter() {
+            let x_idx = (((recomputed - min_qual) / qual_width) as usize).min(bins - 1);
+            let y_idx = (((reported - min_qual) / qual_width) as usize).min(bins - 1);
+            counts[y_idx][x_idx] += 1;
+        }
+        let z = counts
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|count| if count == 0 { None } else { Some(count) })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let axis_values = (0..bins)
+            .map(|i| min_qual + (i as f64 + 0.5) * qual_width)
+            .collect::<Vec<_>>();
+        let trace = HeatMap::new(axis_values.clone(), axis_values, z)
+            .color_scale(ColorScale::Palette(ColorScalePalette::Viridis))
+            .hover_on_gaps(false)
+            .hover_template(
+                "<b>RecomputedQuality</b>: %{x:.2f}<br>\
+                <b>ReportedQuality</b>: %{y:.2f}<br>\
+                <b>ReadsCount</b>: %{z}\
+                <extra></extra>",
+            );
+        let diagonal = Scatter::new(vec![min_qual, max_qual], vec![min_qual, max_qual])
+            .mode(Mode::Lines)
+            .line(Line::new().color(NamedColor::Red).dash(DashType::Dash))
+            .hover_info(HoverInfo::Skip)
+            .show_legend(false);
+        let mut plot = Plot::new();
+        plot.add_trace(trace);
+        plot.add_trace(diagonal);
+        let layout = Layout::new()
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("RecomputedQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReportedQuality")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .height(600);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("reported_quality_density"))
+    }
 
-        table {{
-            width: 100%;
-            border-collapse: collapse;
-        }}
-        th, td {{
-            padding: 12px 20px;
-            text-align: left;
-            border-bottom: 1px solid #eee;
-        }}
-        th {{
-            background: #f8f9fa;
-            font-weight: 600;
-            color: #333;
-        }}
-        tr:hover {{
-            background: #f8f9fa;
-        }}
+    /// (read quality, gap-compressed identity) of primary alignments
+    fn read_calibration_pairs(&self) -> Vec<(f32, f32)> {
+        self.all_stats
+            .iter()
+            .filter_map(|x| x.gap_compressed_identity.map(|identity| (x.qual, identity)))
+            .collect()
+    }
 
-        .stats-grid {{
-            display: grid;
-            grid-template-columns: repeat(auto-fit, minmax(220px, 1fr));
-            gap: 15px;
-            padding: 25px;
-        }}
-        .stat-card {{
-            background: #f8f9fa;
-            padding: 15px 20px;
-            border-radius: 8px;
-            border-left: 5px solid #2E86AB;
-        }}
-        .stat-card .label {{ font-size: 13px; color: #666; }}
-        .stat-card .value {{ font-size: 20px; font-weight: 600; color: #1a5276; margin-top: 5px; }}
+    ///
+    /// Histogram of values in [0, 1], the lower edge is the minimum of values so that
+    /// the high identity reads will not be squeezed into few bins
+    ///
+    fn fraction_histogram(values: &[f64], bins: usize, range: &str) -> PlotInfo {
+        let mut min_value = values.iter().cloned().fold(1.0, f64::min);
+        if min_value >= 1.0 {
+            min_value = 0.0;
+        }
+        let mut hist = ndhistogram!(Uniform::new(bins, min_value, 1.0 + 1e-9).unwrap());
+        for value in values {
+            hist.fill(value);
+        }
+        let mut bar_positions = vec![];
+        let mut bar_height = vec![];
+        let mut current_accum = 0usize;
+        let mut hover_template_array = vec![];
+        for item in hist.iter() {
+            if item.bin.start().is_none() || item.bin.end().is_none() {
+                continue;
+            }
+            let value = *item.value as usize;
+            current_accum += value;
+            let start = item.bin.start().unwrap();
+            let end = item.bin.end().unwrap();
+            bar_positions.push((start + end) / 2.0);
+            bar_height.push(value);
+            hover_template_array.push(format!(
+                "<b>{range}</b>: [{:.4}, {:.4})<br>\
+                <b>ReadsCount</b>: {}<br>\
+                <b>AccumReadsCount</b>: {}\
+                <extra></extra>",
+                start,
+                end.min(1.0),
+                format_counts(value),
+                format_counts(current_accum)
+            ));
+        }
+        PlotInfo {
+            positions: bar_positions,
+            height: bar_height,
+            hover_template_array,
+        }
+    }
 
-        .chart-section {{
-            padding: 25px;
-        }}
+    ///
+    /// Per-read identity and aligned fraction of primary alignments, map quality of primary
+    /// alignments and the substitutions/insertions/deletions rate
+    ///
+    fn alignment_html_div(&self, bins: usize, basic_bam_stats: &BasicBamStatistics) -> String {
+        let identities = self
+            .all_stats
+            .iter()
+            .filter_map(|x| x.identity.map(|v| v as f64))
+            .collect::<Vec<_>>();
+        let aligned_fractions = self
+            .all_stats
+            .iter()
+            .filter_map(|x| x.aligned_fraction.map(|v| v as f64))
+            .collect::<Vec<_>>();
+        let mut plot_infos = vec![
+            Self::fraction_histogram(&identities, bins, "IdentityRange"),
+            Self::fraction_histogram(&aligned_fractions, bins, "AlignedFractionRange"),
+        ];
 
-        .plotly-graph-div {{
-            width: 100% !important;
-        }}
-    </style>
-</head>
-<body>
-    <!-- Header -->
-    <div class="header">
-        <div class="header-content">
-            <div>
-                <h1>🧬 {name} Stats Sequencing Report</h1>
-                <div class="meta">Generated on {} • by <a href="{home_page}" target="_blank" style="color:#ffffff">nanofq</a></div>
-            </div>
-        </div>
-    </div>
+        let mapq_counts = basic_bam_stats.mapq_counts();
+        let total_mapped = mapq_counts.iter().sum::<usize>();
+        let mut current_accum = 0usize;
+        let mut mapq_hover_template_array = vec![];
+        for (mapq, count) in mapq_counts.iter().enumerate() {
+            current_accum += count;
+            mapq_hover_template_array.push(format!(
+                "<b>MapQ</b>: {mapq}<br>\
+                <b>ReadsCount</b>: {}({:.2}%)<br>\
+                <b>AccumReadsCount</b>: {}\
+                <extra></extra>",
+                format_counts(*count),
+                *count as f64 / total_mapped as f64 * 100.0,
+                format_counts(current_accum)
+            ));
+        }
+        plot_infos.push(PlotInfo {
+            positions: (0..mapq_counts.len()).map(|x| x as f64).collect(),
+            height: mapq_counts.to_vec(),
+            hover_template_array: mapq_hover_template_array,
+        });
+
+        let mut plot = Plot::new();
+        for (idx, plot_info) in plot_infos.into_iter().enumerate() {
+            let trace = Bar::new(plot_info.positions, plot_info.height)
+                .hover_template_array(plot_info.hover_template_array)
+                .x_axis(format!("x{}", idx + 1))
+                .y_axis(format!("y{}", idx + 1))
+                .show_legend(false)
+                .marker(Marker::new().color(NamedColor::RoyalBlue));
+            plot.add_trace(trace);
+        }
+
+        let bases_mapped_cigar = basic_bam_stats.bases_mapped_cigar();
+        let error_breakdown = basic_bam_stats.error_breakdown();
+        let error_trace = Bar::new(
+            error_breakdown.iter().map(|x| x.0).collect::<Vec<_>>(),
+            error_breakdown
+                .iter()
+                .map(|x| x.1 as f64 / bases_mapped_cigar as f64)
+                .collect::<Vec<_>>(),
+        )
+        .hover_template_array(
+            error_breakdown
+                .iter()
+                .map(|(class, count)| {
+                    format!(
+                        "<b>{class}</b>: {}<br>\
+                        <b>Rate</b>: %{{y:.6f}}\
+                        <extra></extra>",
+                        format_counts(*count)
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .x_axis("x4")
+        .y_axis("y4")
+        .show_legend(false)
+        .marker(Marker::new().color(NamedColor::Crimson));
+        plot.add_trace(error_trace);
+
+        let layout = Layout::new()
+            .hover_mode(HoverMode::XUnified)
+            .grid(
+                LayoutGrid::new()
+                    .rows(2)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .y_gap(0.15)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("Identity")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title("AlignedFraction")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(Axis::new().line_color("black").line_width(1))
+            .x_axis3(
+                Axis::new()
+                    .title("MapQ")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis3(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis4(
+                Axis::new()
+                    .title("ErrorType")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis4(
+                Axis::new()
+                    .title("Rate (per BasesMappedCigar)")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(800);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("alignment_distribution"))
+    }
+
+    /// GC content histogram weighted by reads count and by bases count
+    fn gc_html_div(&self, bins: usize) -> String {
+        let mut gc_hist = ndhistogram!(Uniform::new(bins, 0.0, 1.0 + 1e-9).unwrap());
+        let mut weighted_gc_hist = gc_hist.clone();
+        for each_stats in self.all_stats.iter() {
+            let gc = each_stats.gc.unwrap() as f64;
+            gc_hist.fill(&gc);
+            weighted_gc_hist.fill_with(&gc, each_stats.length as f64);
+        }
+        let mut plot = Plot::new();
+        for (idx, hist) in [gc_hist, weighted_gc_hist].iter().enumerate() {
+            let count = if idx == 0 { "ReadsCount" } else { "BasesCount" };
+            let mut bar_positions = vec![];
+            let mut bar_height = vec![];
+            let mut hover_template_array = vec![];
+            for item in hist.iter() {
+                if item.bin.start().is_none() || item.bin.end().is_none() {
+                    continue;
+                }
+                let value = *item.value as usize;
+                let start = item.bin.start().unwrap();
+                let end = item.bin.end().unwrap();
+                bar_positions.push((start + end) / 2.0);
+                bar_height.push(value);
+                hover_template_array.push(format!(
+                    "<b>GCRange</b>: [{:.2}, {:.2})<br>\
+                    <b>{count}</b>: {}\
+                    <extra></extra>",
+                    start,
+                    end.min(1.0),
+                    format_counts(value)
+                ));
+            }
+            let trace = Bar::new(bar_positions, bar_height)
+                .hover_template_array(hover_template_array)
+                .x_axis(format!("x{}", idx + 1))
+                .y_axis(format!("y{}", idx + 1))
+                .show_legend(false)
+                .marker(Marker::new().color(NamedColor::RoyalBlue));
+            plot.add_trace(trace);
+        }
+        let layout = Layout::new()
+            .hover_mode(HoverMode::XUnified)
+            .grid(
+                LayoutGrid::new()
+                    .rows(1)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("GCContent")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title("GCContent")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(
+                Axis::new()
+                    .title("BasesCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(450);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("gc_distribution"))
+    }
+
+    /// complexity and longest homopolymer are computed for all reads with --complexity
+    fn use_complexity(&self) -> bool {
+        !self.all_stats.is_empty() && self.all_stats.iter().all(|x| x.complexity.is_some())
+    }
+
+    /// histograms of read complexity and longest homopolymer fraction weighted by reads count
+    fn complexity_html_div(&self, bins: usize) -> String {
+        let mut complexity_hist = ndhistogram!(Uniform::new(bins, 0.0, 1.0 + 1e-9).unwrap());
+        let mut homopolymer_hist = complexity_hist.clone();
+        for each_stats in self.all_stats.iter() {
+            complexity_hist.fill(&(each_stats.complexity.unwrap() as f64));
+            homopolymer_hist.fill(&(each_stats.homopolymer_frac().unwrap() as f64));
+        }
+        let mut plot = Plot::new();
+        let titles = ["Complexity", "LongestHomopolymerFraction"];
+        for (idx, hist) in [complexity_hist, homopolymer_hist].iter().enumerate() {
+            let mut bar_positions = vec![];
+            let mut bar_height = vec![];
+            let mut hover_template_array = vec![];
+            for item in hist.iter() {
+                if item.bin.start().is_none() || item.bin.end().is_none() {
+                    continue;
+                }
+                let value = *item.value as usize;
+                let start = item.bin.start().unwrap();
+                let end = item.bin.end().unwrap();
+                bar_positions.push((start + end) / 2.0);
+                bar_height.push(value);
+                hover_template_array.push(format!(
+                    "<b>{}</b>: [{:.2}, {:.2})<br>\
+                    <b>ReadsCount</b>: {}\
+                    <extra></extra>",
+                    titles[idx],
+                    start,
+                    end.min(1.0),
+                    format_counts(value)
+                ));
+            }
+            let trace = Bar::new(bar_positions, bar_height)
+                .hover_template_array(hover_template_array)
+                .x_axis(format!("x{}", idx + 1))
+                .y_axis(format!("y{}", idx + 1))
+                .show_legend(false)
+                .marker(Marker::new().color(NamedColor::RoyalBlue));
+            plot.add_trace(trace);
+        }
+        let layout = Layout::new()
+            .hover_mode(HoverMode::XUnified)
+            .grid(
+                LayoutGrid::new()
+                    .rows(1)
+                    .columns(2)
+                    .x_gap(0.1)
+                    .pattern(GridPattern::Independent),
+            )
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title(titles[0])
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .x_axis2(
+                Axis::new()
+                    .title(titles[1])
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis2(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(450);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("complexity_distribution"))
+    }
+
+    /// poly(A)/poly(T) tail length is computed for all reads with --poly_tail
+    fn use_poly_tail(&self) -> bool {
+        !self.all_stats.is_empty() && self.all_stats.iter().all(|x| x.poly_tail.is_some())
+    }
 
-    <div class="container">
+    /// histogram of the tail length of the reads with a tail, the reads without tail are counted
+    /// in the title
+    fn poly_tail_html_div(&self, bins: usize) -> String {
+        let tails = self
+            .all_stats
+            .iter()
+            .filter_map(|x| x.poly_tail.filter(|len| *len > 0))
+            .collect::<Vec<_>>();
+        let max_tail = tails.iter().max().copied().unwrap_or(1) as f64;
+        let mut hist = ndhistogram!(Uniform::new(bins, 0.0, max_tail + 1.0).unwrap());
+        for tail in &tails {
+            hist.fill(&(*tail as f64));
+        }
+        let mut bar_positions = vec![];
+        let mut bar_height = vec![];
+        let mut hover_template_array = vec![];
+        for item in hist.iter() {
+            if item.bin.start().is_none() || item.bin.end().is_none() {
+                continue;
+            }
+            let value = *item.value as usize;
+            let start = item.bin.start().unwrap();
+            let end = item.bin.end().unwrap();
+            bar_positions.push((start + end) / 2.0);
+            bar_height.push(value);
+            hover_template_array.push(format!(
+                "<b>PolyTailLength</b>: [{:.0}, {:.0})<br>\
+                <b>ReadsCount</b>: {}\
+                <extra></extra>",
+                start,
+                end,
+                format_counts(value)
+            ));
+        }
+        let trace = Bar::new(bar_positions, bar_height)
+            .hover_template_array(hover_template_array)
+            .show_legend(false)
+            .marker(Marker::new().color(NamedColor::RoyalBlue));
+        let mut plot = Plot::new();
+        plot.add_trace(trace);
+        let layout = Layout::new()
+            .title(format!(
+                "{} of {} reads have a poly(A)/poly(T) tail",
+                format_counts(tails.len()),
+                format_counts(self.all_stats.len())
+            ))
+            .hover_mode(HoverMode::XUnified)
+            .template(BuiltinTheme::PlotlyWhite.build())
+            .x_axis(
+                Axis::new()
+                    .title("PolyTailLength")
+                    .line_color("black")
+                    .show_grid(false)
+                    .line_width(1),
+            )
+            .y_axis(
+                Axis::new()
+                    .title("ReadsCount")
+                    .line_color("black")
+                    .line_width(1),
+            )
+            .height(450);
+        plot.set_layout(layout);
+        plot.to_inline_html(Some("poly_tail_distribution"))
+    }
+
+    pub fn save_all_stats(&self, name: &str, out_file: &str) {
+        let output_file = std::fs::File::create(out_file)
+            .expect(&format!("Failed to create and open {out_file}"));
+        let mut writer = std::io::BufWriter::new(output_file);
+        let _ = writeln!(
+            &mut writer,
+            "#{name} stats result generated on {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M").to_string()
+        );
+        let features = SeqFeatures {
+            gc: self.use_gc,
+            complexity: self.use_complexity(),
+            poly_tail: self.use_poly_tail(),
+        };
+        // the excluded duplex parents are kept, they are marked by the duplex column
+        let all_stats = self.all_stats.iter().chain(&self.excluded);
+        let use_mods = all_stats.clone().any(|x| x.mods.is_some());
+        let use_duplex = !self.duplex.is_empty();
+        let _ = writeln!(
+            &mut writer,
+            "{}",
+            stats_tsv_header(features, use_mods, use_duplex)
+        );
+        for x in all_stats {
+            writeln!(
+                &mut writer,
+                "{}",
+                stats_tsv_line(x, features, use_mods, use_duplex)
+            )
+            .unwrap()
+        }
+    }
 
+    pub fn write_to_html_file(
+        &mut self,
+        name: &str,
+        bins: usize,
+        length_quantile: f64,
+        profile: &PositionProfile,
+        basic_bam_stats: &BasicBamStatistics,
+        html_file: &str,
+    ) {
+        let mut html = html_head(
+            &format!("{name} Stats Summary Report"),
+            &format!("{name} Stats Sequencing Report"),
+        );
+        html.push_str(
+            r#"
         <!-- Basic Statistics -->
         <div class="section">
             <div class="section-header">📊 Basic Statistics</div>
             <div class="stats-grid">
         "#,
-            chrono::Local::now().format("%Y-%m-%d %H:%M").to_string()
         );
 
         let (total_reads, total_bases) = self.get_reads_and_bases();
@@ -713,6 +1527,9 @@ impl<'a> SummaryStats<'a> {
             ))
         }
         html.push_str("</div>\n</div>\n");
+        if let Some(qc_report) = &self.qc_report {
+            html.push_str(&qc_report.html_section());
+        }
 
         let (sub_reads_infos_opt, topn_length) =
             self.get_length_sub_reads_info(total_reads, total_bases);
@@ -905,12 +1722,264 @@ impl<'a> SummaryStats<'a> {
             r#"
                     </div>
         </div>
-    </div>
-</body>
-</html>
-
         "#,
         );
+        html.push_str(&html_chart_section(
+            "📊 Read Length Vs Quality Density",
+            &self.length_quality_density_html_div(bins),
+        ));
+        if self.all_stats.iter().any(|x| x.reported_qual.is_some()) {
+            html.push_str(&html_chart_section(
+                "📊 Reported Vs Recomputed Read Quality",
+                &self.reported_quality_html_div(bins),
+            ));
+        }
+        if self.use_gc {
+            html.push_str(&html_chart_section(
+                "📊 GC Content Distribution",
+                &self.gc_html_div(bins),
+            ));
+        }
+        if self.use_complexity() {
+            html.push_str(&html_chart_section(
+                "📊 Read Complexity Distribution",
+                &self.complexity_html_div(bins),
+            ));
+        }
+        if self.use_poly_tail() {
+            html.push_str(&html_chart_section(
+                "📊 Poly(A) Tail Length Distribution",
+                &self.poly_tail_html_div(bins),
+            ));
+        }
+        if !profile.is_empty() {
+            html.push_str(&html_chart_section(
+                "📊 Per-position Read Quality",
+                &profile.quality_html_div(),
+            ));
+            html.push_str(&html_chart_section(
+                "📊 Per-position Base Composition",
+                &profile.composition_html_div(),
+            ));
+        }
+        if !self.duplex.is_empty() {
+            html.push_str(
+                r#"
+        <div class="section">
+            <div class="section-header">🧬 Duplex Reads</div>
+            <div class="stats-grid">
+            "#,
+            );
+            for (key, value) in self.duplex.dict_basic_info() {
+                html.push_str(&format!(
+                    r#"
+                <div class="stat-card">
+                    <div class="label">{key}</div>
+                    <div class="value">{value}</div>
+                </div>
+            "#
+                ))
+            }
+            html.push_str(
+                r#"
+            </div>
+            <table>
+                <thead>
+                    <tr><th>Class</th><th>ReadsNumber</th><th>BasesNumber</th><th>MeanQual</th><th>MedianQual</th></tr>
+                </thead>
+                <tbody>
+            "#,
+            );
+            for (class, reads, bases, mean_qual, median_qual) in self.duplex.class_rows() {
+                html.push_str(&format!(
+                    r#"
+                    <tr>
+                        <td>{class}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{mean_qual:.2}</td>
+                        <td>{median_qual:.2}</td>
+                    </tr>
+                "#,
+                    format_counts(reads),
+                    format_counts(bases),
+                ));
+            }
+            html.push_str(&format!(
+                r#"
+                </tbody>
+            </table>
+            <div class="chart-section">
+                <div style="max-width: 1400px; margin: 0 auto;">
+                {}
+                </div>
+            </div>
+        </div>
+            "#,
+                self.duplex.html_div(bins)
+            ));
+        }
+        if !basic_bam_stats.is_empty() {
+            html.push_str(
+                r#"
+        <div class="section">
+            <div class="section-header">🧭 Alignment Statistics</div>
+            <div class="stats-grid">
+            "#,
+            );
+            for (key, value) in basic_bam_stats.dict_basic_info() {
+                html.push_str(&format!(
+                    r#"
+                <div class="stat-card">
+                    <div class="label">{key}</div>
+                    <div class="value">{value}</div>
+                </div>
+            "#
+                ))
+            }
+            html.push_str("</div>\n");
+            if basic_bam_stats.has_mapped_reads() {
+                html.push_str(&format!(
+                    r#"
+            <div class="chart-section">
+                <div style="max-width: 1400px; margin: 0 auto;">
+                {}
+                </div>
+            </div>
+            "#,
+                    self.alignment_html_div(bins, basic_bam_stats)
+                ));
+            }
+            if !basic_bam_stats.calibration().is_empty() {
+                html.push_str(&format!(
+                    r#"
+            <div class="chart-section">
+                <div style="max-width: 1400px; margin: 0 auto;">
+                {}
+                </div>
+            </div>
+            "#,
+                    basic_bam_stats
+                        .calibration()
+                        .html_div(&self.read_calibration_pairs(), bins)
+                ));
+            }
+            html.push_str("</div>\n");
+            if basic_bam_stats.has_mapped_reads() {
+                html.push_str(
+                    r#"
+        <div class="section">
+            <div class="section-header">🧭 Per-contig Mapping Statistics</div>
+            <table>
+                <thead>
+                    <tr><th>Contig</th><th>Length (bp)</th><th>Reads</th><th>Bases</th><th>MeanDepth</th><th>Breadth</th><th>MeanIdentity</th><th>MQ0Fraction</th></tr>
+                </thead>
+                <tbody>
+            "#,
+                );
+                // contigs without any reads are only written into summary and json file
+                for contig in basic_bam_stats.contigs().iter().filter(|x| x.reads() > 0) {
+                    html.push_str(&format!(
+                        r#"
+                    <tr>
+                        <td style="font-family: monospace; font-size: 12px;">{}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{}</td>
+                        <td>{:.2}</td>
+                        <td>{:.2}%</td>
+                        <td>{:.4}</td>
+                        <td>{:.2}%</td>
+                    </tr>
+                "#,
+                        contig.name(),
+                        contig.length(),
+                        contig.reads(),
+                        format_counts(contig.bases()),
+                        contig.mean_depth(),
+                        contig.breadth() * 100.0,
+                        contig.mean_identity(),
+                        contig.mq0_fraction() * 100.0
+                    ));
+                }
+                html.push_str(
+                    r#"
+                </tbody>
+            </table>
+        </div>
+            "#,
+                );
+            }
+        }
+        let mods = basic_bam_stats.mods();
+        if !mods.is_empty() {
+            html.push_str(
+                r#"
+        <div class="section">
+            <div class="section-header">🧪 Base Modifications</div>
+            <div class="stats-grid">
+            "#,
+            );
+            for (key, value) in mods.dict_basic_info() {
+                html.push_str(&format!(
+                    r#"
+                <div class="stat-card">
+                    <div class="label">{key}</div>
+                    <div class="value">{value}</div>
+                </div>
+            "#
+                ))
+            }
+            html.push_str(
+                r#"
+            </div>
+            <table>
+                <thead>
+                    <tr><th>Modification</th><th>CandidateBases</th><th>CalledSites (Fraction)</th><th>ModifiedSites (Fraction)</th></tr>
+                </thead>
+                <tbody>
+            "#,
+            );
+            for (code, candidate, called, called_fraction, modified, modified_fraction) in
+                mods.code_rows()
+            {
+                html.push_str(&format!(
+                    r#"
+                    <tr>
+                        <td>{code}</td>
+                        <td>{}</td>
+                        <td>{}({:.2}%)</td>
+                        <td>{}({:.2}%)</td>
+                    </tr>
+                "#,
+                    format_counts(candidate as usize),
+                    format_counts(called as usize),
+                    called_fraction * 100.0,
+                    format_counts(modified as usize),
+                    modified_fraction * 100.0
+                ));
+            }
+            html.push_str("</tbody>\n</table>\n");
+            if mods.reads_valid() > 0 {
+                let read_mod_rates = self
+                    .all_stats
+                    .iter()
+                    .filter_map(|x| x.mods.and_then(|m| m.rate()))
+                    .collect::<Vec<_>>();
+                html.push_str(&format!(
+                    r#"
+            <div class="chart-section">
+                <div style="max-width: 1400px; margin: 0 auto;">
+                {}
+                </div>
+            </div>
+            "#,
+                    mods.html_div(&read_mod_rates, bins)
+                ));
+            }
+            html.push_str("</div>\n");
+        }
+        html.push_str(HTML_TAIL);
         std::fs::write(html_file, html).expect(&format!(
             "Failed to write contents into report file: {html_file}"
         ));
@@ -926,6 +1995,9 @@ impl<'a> SummaryStats<'a> {
         let basic_stats = self.get_basic_stats(total_reads, total_bases);
         let mut contents = format!("AnalysisName:\t{name}\n");
         contents.push_str(&basic_stats.basic_info());
+        if let Some(qc_report) = &self.qc_report {
+            contents.push_str(&qc_report.to_string());
+        }
         let (sub_lengths_reads_infos_opt, topn_length) =
             self.get_length_sub_reads_info(total_reads, total_bases);
         let (sub_quals_reads_infos, topn_qual) =
@@ -998,6 +2070,9 @@ impl<'a> SummaryStats<'a> {
                 }
             ))
         }
+        if !self.duplex.is_empty() {
+            contents.push_str(&self.duplex.to_string());
+        }
         if !basic_bam_stats.is_empty() {
             contents.push_str(&basic_bam_stats.to_string());
         }
@@ -1005,6 +2080,72 @@ impl<'a> SummaryStats<'a> {
             "Failed to write summary info into {summary_file}. The summary info is:\n{contents}"
         ))
     }
+
+    pub fn write_summary_to_json(
+        &mut self,
+        name: &str,
+        basic_bam_stats: &BasicBamStatistics,
+        json_file: &str,
+    ) {
+        let (total_reads, total_bases) = self.get_reads_and_bases();
+        let basic_stats = self.get_basic_stats(total_reads, total_bases);
+        let (sub_lengths_reads_infos_opt, _) =
+            self.get_length_sub_reads_info(total_reads, total_bases);
+        let (sub_quals_reads_infos, _) = self.get_quality_sub_reads_info(total_reads, total_bases);
+        let sub_reads_infos_json = |infos: Vec<SubReadsInfo>| {
+            infos
+                .into_iter()
+                .map(|x| {
+                    json!({
+                        "Class": x.class,
+                        "ReadsNumber": x.reads_count,
+                        "ReadsPercent": x.reads_percent,
+                        "BasesNumber": x.bases_count,
+                        "BasesPercent": x.bases_percent,
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut summary = json!({
+            "AnalysisName": name,
+            "BasicStatistics": basic_stats.to_json(),
+            "ReadQuality": sub_reads_infos_json(sub_quals_reads_infos),
+        });
+        if let Some(sub_lengths_reads_infos) = sub_lengths_reads_infos_opt {
+            summary["ReadLength"] = json!(sub_reads_infos_json(sub_lengths_reads_infos));
+        }
+        if let Some(qc_report) = &self.qc_report {
+            summary["QC"] = qc_report.to_json();
+        }
+        if !self.duplex.is_empty() {
+            summary["Duplex"] = self.duplex.to_json();
+        }
+        if !basic_bam_stats.is_empty() {
+            summary["BamStatistics"] = basic_bam_stats.to_json();
+        }
+        let read_pairs = self.read_calibration_pairs();
+        if !read_pairs.is_empty() {
+            summary["ReadQualityCalibration"] = json!(
+                read_calibration_rows(&read_pairs)
+                    .into_iter()
+                    .map(|(q, reads, mean_identity, empirical_q)| {
+                        json!({
+                            "ReadQuality": q,
+                            "ReadsNumber": reads,
+                            "MeanGapCompressedIdentity": mean_identity,
+                            "EmpiricalQ": empirical_q,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            );
+        }
+        let contents = serde_json::to_string_pretty(&summary).unwrap();
+        std::fs::write(json_file, &contents).unwrap_or_else(|_| {
+            quit_with_error(&format!(
+                "Failed to write summary info into {json_file}. The summary info is:\n{contents}"
+            ))
+        })
+    }
 }
 
 // pub fn get_summary(
@@ -1212,3 +2353,36 @@ impl<'a> SummaryStats<'a> {
 //     ));
 //     basic_stats
 // }
+
+#[cfg(test)]
+mod summary_test {
+    use super::*;
+    use crate::report::load_stats_tsv;
+
+    #[test]
+    fn save_excluded_parents() {
+        let mut all_stats = ["read1", "read2", "read3", "read1;read2"]
+            .iter()
+            .map(|name| RecordEachStats::new(*name, 100, 10.0, None))
+            .collect::<Vec<_>>();
+        all_stats[3].duplex = DuplexClass::Duplex;
+        let summary = SummaryStats::new(all_stats, None, &[], false, 5, true);
+        assert_eq!(summary.all_stats.len(), 2);
+
+        let tsv = std::env::temp_dir().join(format!("nanofq_summary_{}.tsv", std::process::id()));
+        let tsv = tsv.to_str().unwrap();
+        summary.save_all_stats("test", tsv);
+        let (_, loaded, _) = load_stats_tsv(tsv);
+        std::fs::remove_file(tsv).unwrap();
+        assert_eq!(loaded.len(), 4);
+        let parents = loaded
+            .iter()
+            .filter(|x| x.duplex == DuplexClass::DuplexParent)
+            .count();
+        assert_eq!(parents, 2);
+        assert_eq!(
+            DuplexStats::from_stats(&loaded, true).duplex_rate(),
+            summary.duplex.duplex_rate()
+        );
+    }
+}
@@ -61,6 +61,23 @@ pub fn _positive_int_parse(
     Ok(parsed_usize)
 }
 
+/// parse bases number with an optional k/m/g suffix, such as 5G, 100m or 1.5M
+pub fn bases_parse(x: &str, para: &str) -> Result<u64, anyhow::Error> {
+    let lower = x.trim().to_ascii_lowercase();
+    let (number, unit) = match lower.char_indices().last() {
+        Some((idx, 'k')) => (&lower[..idx], 1e3),
+        Some((idx, 'm')) => (&lower[..idx], 1e6),
+        Some((idx, 'g')) => (&lower[..idx], 1e9),
+        _ => (lower.as_str(), 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(value) if value > 0.0 => Ok((value * unit) as u64),
+        _ => quit_with_error(&format!(
+            "Error: {para} must be a positive number with an optional k/m/g suffix, such as 5G"
+        )),
+    }
+}
+
 pub fn positive_f64_parse(x: &str, para: &str, min: f64, max: f64) -> Result<f64, anyhow::Error> {
     let coef = 10000.0;
     let parsed_f64 = match x.parse::<f64>() {
@@ -253,7 +270,7 @@ pub fn calculate_quality<T: AsRef<[u8]>>(
     is_bam: bool,
 ) -> f32 {
     let quality = quality.as_ref();
-    debug_assert!(quality.as_ref().len() > 0);
+    debug_assert!(!quality.is_empty());
     if use_dorado_quality {
         if quality.len() > DORADO_TRIM_LEADING_BASE_NUMBER {
             if is_bam {
@@ -277,6 +294,102 @@ pub fn calculate_quality<T: AsRef<[u8]>>(
     }
 }
 
+/// where the read quality comes from
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum QscoreSource {
+    #[default]
+    Recompute, // recompute mean-error Q from the quality string
+    Tag,       // basecaller reported Q, qs tag of bam or qs= in fastq header
+    Auto,      // reported Q if available, otherwise recompute
+}
+
+impl QscoreSource {
+    pub fn from_arg(source: &str) -> Self {
+        match source {
+            "recompute" => QscoreSource::Recompute,
+            "tag" => QscoreSource::Tag,
+            "auto" => QscoreSource::Auto,
+            _ => quit_with_error(&format!(
+                "Invalid --qscore_source: {source}, should be one of recompute, tag and auto"
+            )),
+        }
+    }
+
+    pub fn choose(&self, reported: Option<f32>, recomputed: f32, read_name: &str) -> f32 {
+        match (self, reported) {
+            (QscoreSource::Recompute, _) => recomputed,
+            (_, Some(reported)) => reported,
+            (QscoreSource::Auto, None) => recomputed,
+            (QscoreSource::Tag, None) => quit_with_error(&format!(
+                "No reported quality found for {read_name}, use --qscore_source auto or recompute instead"
+            )),
+        }
+    }
+}
+
+/// parse the read quality reported by basecaller from fastq header, such as qs=12.3,
+/// or the sam tags written by dorado: qs:f:12.3, qs:i:12
+pub fn reported_qscore_from_header(description: Option<&str>) -> Option<f32> {
+    description?.split_ascii_whitespace().find_map(|x| {
+        x.strip_prefix("qs=")
+            .or_else(|| x.strip_prefix("qs:f:"))
+            .or_else(|| x.strip_prefix("qs:i:"))
+            .and_then(|qs| qs.parse::<f32>().ok())
+    })
+}
+
+/// the lowest mean-error Q of all windows of window_size bases in fastq quality string,
+/// the mean-error Q of whole read is returned if the read is shorter than window_size
+pub fn min_window_quality<T: AsRef<[u8]>>(quality: T, window_size: usize) -> f32 {
+    let quality = quality.as_ref();
+    if quality.len() <= window_size || window_size == 0 {
+        return calculate_fastq_record_q(quality);
+    }
+    let err_probs = quality
+        .iter()
+        .map(|x| ERR_PROB_TABLE[*x as usize])
+        .collect::<Vec<_>>();
+    let mut window_sum = err_probs[..window_size].iter().sum::<f64>();
+    let mut max_sum = window_sum;
+    for i in window_size..err_probs.len() {
+        window_sum += err_probs[i] - err_probs[i - window_size];
+        max_sum = max_sum.max(window_sum);
+    }
+    (-10.0 * (max_sum / window_size as f64).log10()) as f32
+}
+
+/// the range [start, end) between the first and the last window of window_size bases whose
+/// mean-error Q >= min_qual in fastq quality string, None if no such window
+pub fn quality_trim_range<T: AsRef<[u8]>>(
+    quality: T,
+    window_size: usize,
+    min_qual: f32,
+) -> Option<(usize, usize)> {
+    let quality = quality.as_ref();
+    if quality.is_empty() {
+        return None;
+    }
+    if quality.len() <= window_size {
+        return (calculate_fastq_record_q(quality) >= min_qual).then_some((0, quality.len()));
+    }
+    let max_err = 10f64.powf(-min_qual as f64 / 10.0) * window_size as f64;
+    let err_probs = quality
+        .iter()
+        .map(|x| ERR_PROB_TABLE[*x as usize])
+        .collect::<Vec<_>>();
+    // error sum of each window, window i covers [i, i + window_size)
+    let mut window_sums = Vec::with_capacity(err_probs.len() - window_size + 1);
+    let mut window_sum = err_probs[..window_size].iter().sum::<f64>();
+    window_sums.push(window_sum);
+    for i in window_size..err_probs.len() {
+        window_sum += err_probs[i] - err_probs[i - window_size];
+        window_sums.push(window_sum);
+    }
+    let start = window_sums.iter().position(|x| *x <= max_err)?;
+    let last = window_sums.iter().rposition(|x| *x <= max_err)?;
+    Some((start, last + window_size))
+}
+
 fn calculate_fastq_record_q<T: AsRef<[u8]>>(quality: T) -> f32 {
     let quality = quality.as_ref();
     let x = (quality
@@ -318,6 +431,101 @@ pub fn gc<T: AsRef<[u8]>>(sequence: T) -> f32 {
     gc as f32
 }
 
+/// Shannon entropy of the trinucleotides in sequence, normalized to [0, 1] by the max entropy
+/// of this number of trinucleotides like the entropy filter of prinseq. Homopolymers and simple
+/// repeats are close to 0 and random sequences close to 1. Trinucleotides containing bases other
+/// than ACGT are skipped
+pub fn complexity<T: AsRef<[u8]>>(sequence: T) -> f32 {
+    let sequence = sequence.as_ref();
+    let mut counts = [0u32; 64];
+    let mut total = 0u32;
+    for kmer in sequence.windows(3) {
+        let mut idx = 0;
+        let mut is_acgt = true;
+        for base in kmer {
+            let code = match base {
+                b'A' | b'a' => 0,
+                b'C' | b'c' => 1,
+                b'G' | b'g' => 2,
+                b'T' | b't' => 3,
+                _ => {
+                    is_acgt = false;
+                    break;
+                }
+            };
+            idx = idx * 4 + code;
+        }
+        if is_acgt {
+            counts[idx] += 1;
+            total += 1;
+        }
+    }
+    if total < 2 {
+        return 0.0;
+    }
+    let entropy = counts
+        .iter()
+        .filter(|x| **x > 0)
+        .map(|x| {
+            let p = *x as f64 / total as f64;
+            p * (1.0 / p).log2()
+        })
+        .sum::<f64>();
+    (entropy / (total.min(64) as f64).log2()) as f32
+}
+
+/// length of the longest run of the same base in sequence
+pub fn longest_homopolymer<T: AsRef<[u8]>>(sequence: T) -> usize {
+    sequence
+        .as_ref()
+        .chunk_by(|a, b| a.eq_ignore_ascii_case(b))
+        .map(|x| x.len())
+        .max()
+        .unwrap_or(0)
+}
+
+// the poly(A) tail may start after this number of bases from the read end, e.g., the adapter and
+// barcode left on the untrimmed reads
+const POLY_TAIL_MAX_OFFSET: usize = 100;
+// the shorter runs are common inside transcripts
+const MIN_POLY_TAIL: usize = 10;
+// extending the tail stops when its score drops this much below the best score
+const POLY_TAIL_X_DROP: i64 = 20;
+
+/// length of the best run of target base scanned from the read end inward, the target base scores
+/// 1 and other bases -2, so that a few basecalling errors inside the tail are tolerated
+fn poly_run_from_end<'a>(bases: impl Iterator<Item = &'a u8>, target: u8) -> usize {
+    let mut score = 0i64;
+    let mut best_score = 0i64;
+    let mut run_start = 0;
+    let mut best_length = 0;
+    for (idx, base) in bases.enumerate() {
+        if score <= 0 && idx < POLY_TAIL_MAX_OFFSET {
+            score = 0;
+            run_start = idx;
+        }
+        score += if base.eq_ignore_ascii_case(&target) { 1 } else { -2 };
+        if score > best_score {
+            best_score = score;
+            best_length = idx + 1 - run_start;
+        } else if idx >= POLY_TAIL_MAX_OFFSET && score < best_score - POLY_TAIL_X_DROP {
+            break;
+        }
+    }
+    if best_length >= MIN_POLY_TAIL {
+        best_length
+    } else {
+        0
+    }
+}
+
+/// base-space length of the poly(A) tail at the 3' end, or the poly(T) at the 5' end of the
+/// reverse complemented reads. 0 if no tail is found
+pub fn poly_tail_length<T: AsRef<[u8]>>(sequence: T) -> usize {
+    let sequence = sequence.as_ref();
+    poly_run_from_end(sequence.iter().rev(), b'A').max(poly_run_from_end(sequence.iter(), b'T'))
+}
+
 pub fn collect_fqs_in_dir(path: &str) -> Vec<PathBuf> {
     let all_fqs = Path::new(path)
         .read_dir()
@@ -690,3 +898,107 @@ The full structure of CRTA is like below:
 CRTA:                      5'-CTTGCGGGCGGCGGACTCTCCTCTGAAGATAGAGCGACAGGCAAGT-3'
 CRTA_REV_COM:   3'-TTTTTTTTTTTGAACGCCCGCCGCCTGAGAGGAGACTTCTATCTCGCTGTCCGTTCA-5'
 */
+
+#[cfg(test)]
+mod utils_test {
+    use super::*;
+    use bio::alphabets::dna::revcomp;
+
+    /// a reproducible transcript body of C and G, so that only the added runs count
+    fn body(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"CG"[(state >> 63) as usize]
+            })
+            .collect()
+    }
+
+    fn with_tail(tail: &[u8], after_tail: &[u8]) -> Vec<u8> {
+        let mut seq = body(300, 1);
+        seq.extend(tail);
+        seq.extend(after_tail);
+        seq
+    }
+
+    #[test]
+    fn poly_tail() {
+        assert_eq!(poly_tail_length(with_tail(&[b'A'; 30], b"")), 30);
+        // the 5' poly(T) of an antisense read
+        assert_eq!(poly_tail_length(revcomp(with_tail(&[b'A'; 30], b""))), 30);
+        // lower case is soft masked
+        assert_eq!(poly_tail_length(with_tail(&[b'a'; 20], b"")), 20);
+    }
+
+    #[test]
+    fn poly_tail_with_errors() {
+        let tail = [&[b'A'; 12][..], b"G", &[b'A'; 8], b"C", &[b'A'; 10]].concat();
+        assert_eq!(poly_tail_length(with_tail(&tail, b"")), 32);
+        // the errors at the end of the tail are not included
+        let tail = [&[b'A'; 25][..], b"GCA"].concat();
+        assert_eq!(poly_tail_length(with_tail(&tail, b"")), 25);
+    }
+
+    #[test]
+    fn poly_tail_behind_adapter() {
+        // CRTA and the reverse complement of BP01 are left on the untrimmed reads
+        let adapter = b"CTTGCGGGCGGCGGACTCTCCTCTGAAGATAGAGCGACAGGCAAGTCACAAAGACACCGACAACTTTCTT";
+        let seq = with_tail(&[b'A'; 30], adapter);
+        assert_eq!(poly_tail_length(&seq), 30);
+        assert_eq!(poly_tail_length(revcomp(&seq)), 30);
+    }
+
+    #[test]
+    fn no_poly_tail() {
+        assert_eq!(poly_tail_length(body(300, 2)), 0);
+        // shorter runs are common inside transcripts
+        assert_eq!(poly_tail_length(with_tail(&[b'A'; 9], b"")), 0);
+        // an A-rich region further than POLY_TAIL_MAX_OFFSET from the end is not a tail
+        let seq = with_tail(&[b'A'; 30], &body(POLY_TAIL_MAX_OFFSET + 50, 3));
+        assert_eq!(poly_tail_length(&seq), 0);
+        assert_eq!(poly_tail_length(b""), 0);
+    }
+
+    #[test]
+    fn complexity_of_repeats() {
+        assert_eq!(complexity(vec![b'A'; 1000]), 0.0);
+        // two trinucleotides ACA and CAC in 998 windows
+        let dinucleotide = b"AC".repeat(500);
+        assert!(complexity(&dinucleotide) < 0.2);
+        // the random body of C and G only has 8 trinucleotides
+        assert!(complexity(body(1000, 2)) < 0.6);
+        assert_eq!(complexity(b""), 0.0);
+        assert_eq!(complexity(b"ACG"), 0.0);
+    }
+
+    #[test]
+    fn complexity_of_mixed_seq() {
+        let mut state = 3u64;
+        let seq = (0..1000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect::<Vec<_>>();
+        assert!(complexity(&seq) > 0.95);
+        // case is ignored
+        assert_eq!(complexity(seq.to_ascii_lowercase()), complexity(&seq));
+        // the trinucleotides with N are skipped, ACG and CGT are counted twice
+        assert_eq!(complexity(b"ACGTNACGT"), 0.5);
+        assert_eq!(complexity(b"NNNNNN"), 0.0);
+    }
+
+    #[test]
+    fn longest_homopolymer_run() {
+        assert_eq!(longest_homopolymer(b""), 0);
+        assert_eq!(longest_homopolymer(b"A"), 1);
+        assert_eq!(longest_homopolymer(b"ACGGGTA"), 3);
+        // case is ignored
+        assert_eq!(longest_homopolymer(b"ACGGTaaAAc"), 4);
+    }
+}
@@ -0,0 +1,524 @@
+use crate::bam::{BasicBamStatistics, stats_xam};
+use crate::fastq::{RecordEachStats, SeqFeatures};
+use crate::input_type::{InputType, check_input_type};
+use crate::profile::PositionProfile;
+use crate::qc::QcRules;
+use crate::report::load_stats_tsv;
+use crate::stats::stats_one_fastq;
+use crate::summary::{SummaryStats, stats_tsv_header, stats_tsv_line};
+use crate::utils::{QscoreSource, check_and_create_dir, init_log, quit_with_error};
+use clap::ArgMatches;
+use log::info;
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// files in the state directory. the reads of each processed file are appended to stats.tsv,
+// then state.json is atomically replaced with the processed files, the length of stats.tsv and
+// the profile including the file, so a file is either committed in both of them or in neither
+const STATS_FILE: &str = "stats.tsv";
+const STATE_FILE: &str = "state.json";
+
+struct WatchOption {
+    thread: usize,
+    chunk: u32,
+    use_dorado_q: bool,
+    qscore_source: QscoreSource,
+    features: SeqFeatures,
+    profile_depth: usize,
+}
+
+fn is_watched_file(path: &Path) -> bool {
+    let file_name = path
+        .file_name()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or_default();
+    !file_name.starts_with('.')
+        && [".fastq", ".fq", ".fastq.gz", ".fq.gz", ".bam"]
+            .iter()
+            .any(|x| file_name.ends_with(x))
+}
+
+/// collect fastq[.gz] and bam files in dir and its sub directories, such as fastq_pass/barcode01
+fn scan_dir(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            scan_dir(&path, files)
+        } else if is_watched_file(&path) {
+            files.push(path)
+        }
+    }
+}
+
+/// the unprocessed files in watch_dir which are completed. observed keeps the size and modified
+/// time of the files seen in the last poll, a file is regarded as completed when both of them
+/// are unchanged between two polls
+fn completed_files(
+    watch_dir: &Path,
+    processed: &HashSet<PathBuf>,
+    observed: &mut HashMap<PathBuf, (u64, SystemTime)>,
+) -> Vec<PathBuf> {
+    let mut files = vec![];
+    scan_dir(watch_dir, &mut files);
+    files.sort();
+    let mut completed = vec![];
+    for file in files {
+        if processed.contains(&file) {
+            continue;
+        }
+        let Ok(metadata) = file.metadata() else {
+            continue;
+        };
+        let current = (
+            metadata.len(),
+            metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        );
+        if current.0 > 0 && observed.get(&file) == Some(&current) {
+            observed.remove(&file);
+            completed.push(file);
+        } else {
+            observed.insert(file, current);
+        }
+    }
+    completed
+}
+
+fn stats_one_file(file: &Path, wo: &WatchOption) -> (Vec<RecordEachStats>, PositionProfile) {
+    let file_str = file.to_str().unwrap();
+    match check_input_type(file_str) {
+        InputType::OneFastqFile | InputType::OneFastqGzippedFile => stats_one_fastq(
+            file_str,
+            wo.thread,
+            wo.use_dorado_q,
+            wo.qscore_source,
+            wo.features,
+            wo.chunk,
+            wo.profile_depth,
+        ),
+        InputType::DirectoryContainFastqsOrFastqsGzipped => unreachable!(),
+        _ => {
+            let mut bam_reader = rust_htslib::bam::Reader::from_path(file_str)
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to read {file_str}")));
+            // only the read level statistics are kept in watch mode
+            let (_, all_stats, profile) = stats_xam(
+                &mut bam_reader,
+                wo.thread,
+                wo.features,
+                wo.use_dorado_q,
+                wo.qscore_source,
+                false,
+                wo.profile_depth,
+            );
+            (all_stats, profile)
+        }
+    }
+}
+
+/// write into a temporary file first and then rename it, so that the output is never half written
+fn write_atomically(path: &str, write: impl FnOnce(&str)) {
+    let tmp_path = format!("{path}.tmp");
+    write(&tmp_path);
+    std::fs::rename(&tmp_path, path).unwrap_or_else(|x| {
+        quit_with_error(&format!("{x}, failed to rename {tmp_path} to {path}"))
+    });
+}
+
+///
+/// Accumulators of the watched directory. They are persisted in the state directory, so that
+/// the processed files are skipped and their statistics are restored after a restart.
+///
+struct WatchState {
+    state_dir: PathBuf,
+    processed: HashSet<PathBuf>,
+    stats_bytes: u64, // length of stats.tsv committed in state.json
+    all_stats: Vec<RecordEachStats>,
+    profile: PositionProfile,
+    features: SeqFeatures,
+}
+
+impl WatchState {
+    fn load(state_dir: &str, features: SeqFeatures, profile_depth: usize) -> Self {
+        check_and_create_dir(state_dir);
+        let state_dir = PathBuf::from(state_dir);
+        let state_file = state_dir.join(STATE_FILE);
+        let stats_file = state_dir.join(STATS_FILE);
+        let state = state_file.exists().then(|| {
+            std::fs::read_to_string(&state_file)
+                .ok()
+                .and_then(|x| serde_json::from_str::<Value>(&x).ok())
+                .unwrap_or_else(|| {
+                    quit_with_error(&format!("Failed to parse {}", state_file.display()))
+                })
+        });
+        let Some(state) = state else {
+            // nothing is committed, the reads of an interrupted first file are dropped
+            let header = stats_tsv_header(features, false, true) + "\n";
+            std::fs::write(&stats_file, &header).unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, failed to create {}", stats_file.display()))
+            });
+            return WatchState {
+                state_dir,
+                processed: HashSet::new(),
+                stats_bytes: header.len() as u64,
+                all_stats: vec![],
+                profile: PositionProfile::new(profile_depth),
+                features,
+            };
+        };
+        let processed = state["processed"]
+            .as_array()
+            .map(|x| {
+                x.iter()
+                    .filter_map(|x| x.as_str().map(PathBuf::from))
+                    .collect::<HashSet<_>>()
+            })
+            .unwrap_or_default();
+        let stats_bytes = state["stats_bytes"].as_u64().unwrap_or_default();
+        let profile = PositionProfile::from_json(&state["profile"], profile_depth)
+            .unwrap_or_else(|| PositionProfile::new(profile_depth));
+        // drop the reads appended after the last commit, their file is processed again
+        let writer = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&stats_file)
+            .unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, failed to open {}", stats_file.display()))
+            });
+        if writer.metadata().map(|x| x.len()).unwrap_or_default() < stats_bytes {
+            quit_with_error(&format!(
+                "{} is shorter than recorded in {}, use another --watch_state",
+                stats_file.display(),
+                state_file.display()
+            ))
+        }
+        writer.set_len(stats_bytes).unwrap_or_else(|x| {
+            quit_with_error(&format!("{x}, failed to truncate {}", stats_file.display()))
+        });
+        drop(writer);
+
+        let (_, all_stats, has_gc) = load_stats_tsv(stats_file.to_str().unwrap());
+        if features.gc && !has_gc && !all_stats.is_empty() {
+            quit_with_error(&format!(
+                "GC content is not saved in {}, remove --gc or use another --watch_state",
+                stats_file.display()
+            ))
+        }
+        if features.complexity && all_stats.iter().any(|x| x.complexity.is_none()) {
+            quit_with_error(&format!(
+                "Complexity is not saved in {}, remove --complexity or use another --watch_state",
+                stats_file.display()
+            ))
+        }
+        if features.poly_tail && all_stats.iter().any(|x| x.poly_tail.is_none()) {
+            quit_with_error(&format!(
+                "Poly(A) tail length is not saved in {}, remove --poly_tail or use another --watch_state",
+                stats_file.display()
+            ))
+        }
+        info!(
+            "{} processed files and {} reads restored from {}",
+            processed.len(),
+            all_stats.len(),
+            state_dir.display()
+        );
+        WatchState {
+            state_dir,
+            processed,
+            stats_bytes,
+            all_stats,
+            profile,
+            features,
+        }
+    }
+
+    /// add the statistics of one completed file, the reads are appended to stats.tsv before
+    /// state.json commits them
+    fn add_file(
+        &mut self,
+        file: PathBuf,
+        all_stats: Vec<RecordEachStats>,
+        profile: PositionProfile,
+    ) {
+        let stats_file = self.state_dir.join(STATS_FILE);
+        let mut writer = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&stats_file)
+            .unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, failed to open {}", stats_file.display()))
+            });
+        let contents = all_stats
+            .iter()
+            .map(|x| stats_tsv_line(x, self.features, false, true) + "\n")
+            .collect::<String>();
+        writer
+            .write_all(contents.as_bytes())
+            .and_then(|_| writer.sync_data())
+            .unwrap_or_else(|x| {
+                quit_with_error(&format!("{x}, failed to write {}", stats_file.display()))
+            });
+        self.stats_bytes += contents.len() as u64;
+        self.profile += profile;
+        self.all_stats.extend(all_stats);
+        self.processed.insert(file);
+
+        let mut processed = self
+            .processed
+            .iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        processed.sort();
+        let state = json!({
+            "processed": processed,
+            "stats_bytes": self.stats_bytes,
+            "profile": self.profile.to_json(),
+        });
+        let state_file = self.state_dir.join(STATE_FILE);
+        write_atomically(state_file.to_str().unwrap(), |tmp_path| {
+            std::fs::write(tmp_path, state.to_string())
+                .unwrap_or_else(|x| quit_with_error(&format!("{x}, failed to write {tmp_path}")))
+        });
+    }
+}
+
+pub fn run_watch(stats_cmd: &ArgMatches, watch_dir: &str) {
+    init_log();
+    let report = stats_cmd.get_one::<String>("report").unwrap();
+    let analysis_name = stats_cmd.get_one::<String>("name").unwrap();
+    let output = stats_cmd.get_one::<String>("output");
+    let summary = stats_cmd.get_one::<String>("summary");
+    let json = stats_cmd.get_one::<String>("json");
+    let topn = *stats_cmd.get_one::<u32>("topn").unwrap() as usize;
+    let quality = stats_cmd.get_one::<Vec<f64>>("quality").unwrap();
+    let lengths = stats_cmd.get_one::<Vec<u32>>("length");
+    let bins = *stats_cmd.get_one::<u32>("bins").unwrap() as usize;
+    let quantile = *stats_cmd.get_one::<f64>("quantile").unwrap();
+    let exclude_duplex_parents = stats_cmd.get_flag("exclude_duplex_parents");
+    let qc_rules = QcRules::from_arg_matches(stats_cmd);
+    let interval = Duration::from_secs_f64(60.0 * *stats_cmd.get_one::<f64>("interval").unwrap());
+    let poll = Duration::from_secs(*stats_cmd.get_one::<u64>("poll").unwrap());
+    let idle_exit = Duration::from_secs_f64(60.0 * *stats_cmd.get_one::<f64>("idle_exit").unwrap());
+    let state_dir = stats_cmd
+        .get_one::<String>("watch_state")
+        .cloned()
+        .unwrap_or_else(|| format!("{report}.watch"));
+    let wo = WatchOption {
+        thread: *stats_cmd.get_one::<u16>("thread").unwrap() as usize,
+        chunk: *stats_cmd.get_one::<u32>("chunk").unwrap(),
+        use_dorado_q: stats_cmd.get_flag("use_dorado_q"),
+        qscore_source: QscoreSource::from_arg(
+            stats_cmd.get_one::<String>("qscore_source").unwrap(),
+        ),
+        features: SeqFeatures {
+            gc: stats_cmd.get_flag("gc"),
+            complexity: stats_cmd.get_flag("complexity"),
+            poly_tail: stats_cmd.get_flag("poly_tail"),
+        },
+        profile_depth: *stats_cmd.get_one::<usize>("profile_depth").unwrap(),
+    };
+    if stats_cmd.get_flag("mods") {
+        quit_with_error(
+            "--mods is not supported with --watch, the modification summary is not saved in --watch_state",
+        )
+    }
+    if !Path::new(watch_dir).is_dir() {
+        quit_with_error(&format!("{watch_dir} is not a directory"))
+    }
+    if wo.thread != 1 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(wo.thread)
+            .build_global()
+            .unwrap()
+    }
+
+    let write_outputs = |state: &mut WatchState| {
+        // the reads are lent to the summary and taken back, so they are not copied every time
+        let mut stats_summary = SummaryStats::new(
+            std::mem::take(&mut state.all_stats),
+            lengths.map(|x| x.as_slice()),
+            quality,
+            state.features.gc,
+            topn,
+            exclude_duplex_parents,
+        );
+        let basic_bam_stats = BasicBamStatistics::default();
+        let qc_report = qc_rules.as_ref().map(|rules| {
+            let qc_report = rules.evaluate(&stats_summary.qc_metrics(&basic_bam_stats));
+            stats_summary.set_qc_report(qc_report.clone());
+            qc_report
+        });
+        if let Some(output_file) = output {
+            write_atomically(output_file, |x| {
+                stats_summary.save_all_stats(analysis_name, x)
+            });
+        }
+        if let Some(summary_file) = summary {
+            write_atomically(summary_file, |x| {
+                stats_summary.write_summary_to_text(analysis_name, &basic_bam_stats, x)
+            });
+        }
+        if let Some(json_file) = json {
+            write_atomically(json_file, |x| {
+                stats_summary.write_summary_to_json(analysis_name, &basic_bam_stats, x)
+            });
+        }
+        write_atomically(report, |x| {
+            stats_summary.write_to_html_file(
+                analysis_name,
+                bins,
+                quantile,
+                &state.profile,
+                &basic_bam_stats,
+                x,
+            )
+        });
+        state.all_stats = stats_summary.into_all_stats();
+        info!(
+            "report updated with {} reads from {} files",
+            state.all_stats.len(),
+            state.processed.len()
+        );
+        qc_report
+    };
+
+    let mut state = WatchState::load(&state_dir, wo.features, wo.profile_depth);
+    let mut observed: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+    let mut last_report: Option<Instant> = None;
+    let mut last_new_file = Instant::now();
+    let mut has_new_reads = !state.all_stats.is_empty();
+    // the qc gate is checked on the last written outputs when exiting
+    let mut qc_report = None;
+    info!("watching {watch_dir}, state is saved in {state_dir}");
+    loop {
+        for file in completed_files(Path::new(watch_dir), &state.processed, &mut observed) {
+            let (all_stats, profile) = stats_one_file(&file, &wo);
+            info!("{} reads found in {}", all_stats.len(), file.display());
+            state.add_file(file, all_stats, profile);
+            last_new_file = Instant::now();
+            has_new_reads = true;
+        }
+        let is_due = last_report.is_none_or(|x| x.elapsed() >= interval);
+        let is_idle = !idle_exit.is_zero() && last_new_file.elapsed() >= idle_exit;
+        if has_new_reads && !state.all_stats.is_empty() && (is_due || is_idle) {
+            qc_report = write_outputs(&mut state);
+            last_report = Some(Instant::now());
+            has_new_reads = false;
+        }
+        if is_idle {
+            info!(
+                "no new file found in the last {:.1} minutes, exit",
+                idle_exit.as_secs_f64() / 60.0
+            );
+            if let Some(qc_report) = &qc_report {
+                qc_report.print_and_exit()
+            }
+            break;
+        }
+        std::thread::sleep(poll);
+    }
+}
+
+#[cfg(test)]
+mod watch_test {
+    use super::*;
+
+    fn write_fastq(path: &Path, names: &[&str]) {
+        let fastq = names
+            .iter()
+            .map(|x| format!("@{x}\nACGTACGTAC\n+\n5555555555\n"))
+            .collect::<String>();
+        std::fs::write(path, fastq).unwrap();
+    }
+
+    fn names(state: &WatchState) -> Vec<String> {
+        let mut names = state
+            .all_stats
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn resume_after_crash() {
+        let test_dir = std::env::temp_dir().join(format!("nanofq_watch_{}", std::process::id()));
+        let watch_dir = test_dir.join("fastq_pass");
+        let state_dir = test_dir.join("state");
+        let state_dir = state_dir.to_str().unwrap();
+        std::fs::create_dir_all(&watch_dir).unwrap();
+        let wo = WatchOption {
+            thread: 1,
+            chunk: 10000,
+            use_dorado_q: false,
+            qscore_source: QscoreSource::Recompute,
+            features: SeqFeatures::default(),
+            profile_depth: 500,
+        };
+        let (file_a, file_b) = (watch_dir.join("a.fastq"), watch_dir.join("b.fastq"));
+        write_fastq(&file_a, &["a1", "a2"]);
+        write_fastq(&file_b, &["b1"]);
+
+        // a file is completed when its size and modified time are unchanged in two polls
+        let mut state = WatchState::load(state_dir, wo.features, wo.profile_depth);
+        let mut observed = HashMap::new();
+        assert!(completed_files(&watch_dir, &state.processed, &mut observed).is_empty());
+        write_fastq(&file_b, &["b1", "b2", "b3"]);
+        let completed = completed_files(&watch_dir, &state.processed, &mut observed);
+        assert_eq!(completed, vec![file_a.clone()]);
+        for file in completed {
+            let (all_stats, profile) = stats_one_file(&file, &wo);
+            state.add_file(file, all_stats, profile);
+        }
+        let committed_bytes = state.stats_bytes;
+
+        // crash after the reads of b are appended to stats.tsv but before state.json is replaced
+        assert_eq!(
+            completed_files(&watch_dir, &state.processed, &mut observed),
+            vec![file_b.clone()]
+        );
+        let (all_stats, _) = stats_one_file(&file_b, &wo);
+        let contents = all_stats
+            .iter()
+            .map(|x| stats_tsv_line(x, wo.features, false, true) + "\n")
+            .collect::<String>();
+        let stats_file = Path::new(state_dir).join(STATS_FILE);
+        let mut writer = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&stats_file)
+            .unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+        drop(writer);
+        std::fs::write(Path::new(state_dir).join(format!("{STATE_FILE}.tmp")), "{").unwrap();
+        drop(state);
+
+        // the uncommitted reads are dropped and b is processed again, a is skipped
+        let mut state = WatchState::load(state_dir, wo.features, wo.profile_depth);
+        assert_eq!(names(&state), vec!["a1", "a2"]);
+        assert_eq!(state.stats_bytes, committed_bytes);
+        assert_eq!(stats_file.metadata().unwrap().len(), committed_bytes);
+        assert_eq!(state.processed, HashSet::from([file_a.clone()]));
+        let mut observed = HashMap::new();
+        assert!(completed_files(&watch_dir, &state.processed, &mut observed).is_empty());
+        let completed = completed_files(&watch_dir, &state.processed, &mut observed);
+        assert_eq!(completed, vec![file_b.clone()]);
+        for file in completed {
+            let (all_stats, profile) = stats_one_file(&file, &wo);
+            state.add_file(file, all_stats, profile);
+        }
+        drop(state);
+
+        // no duplicate or missing read after another restart
+        let state = WatchState::load(state_dir, wo.features, wo.profile_depth);
+        assert_eq!(names(&state), vec!["a1", "a2", "b1", "b2", "b3"]);
+        assert_eq!(state.processed, HashSet::from([file_a, file_b]));
+        let mut observed = HashMap::new();
+        completed_files(&watch_dir, &state.processed, &mut observed);
+        assert!(completed_files(&watch_dir, &state.processed, &mut observed).is_empty());
+        std::fs::remove_dir_all(&test_dir).unwrap();
+    }
+}